@@ -0,0 +1,304 @@
+//! # BenchExporter
+//!
+//! `BenchExporter` quantifies the overhead of scaphandre itself. For a given
+//! duration, it refreshes the topology and generates all the metrics at each
+//! step, as the other exporters do, then prints the CPU time, memory and
+//! energy scaphandre used, and the time each iteration took.
+use crate::exporters::self_metrics::SelfMetrics;
+use crate::exporters::utils::get_hostname;
+use crate::exporters::*;
+use crate::sensors::Sensor;
+use clap::Arg;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An Exporter that measures the resources used by scaphandre to measure the
+/// host, and prints a summary.
+pub struct BenchExporter {
+    sensor: Box<dyn Sensor>,
+}
+
+impl Exporter for BenchExporter {
+    /// Runs the benchmark and prints its report.
+    fn run(&mut self, parameters: ArgMatches) {
+        let duration: u64 = parameters
+            .value_of("duration")
+            .unwrap()
+            .parse()
+            .expect("Wrong duration value, should be a number of seconds");
+        let step: u64 = parameters
+            .value_of("step_duration")
+            .unwrap()
+            .parse()
+            .expect("Wrong step_duration value, should be a number of seconds");
+
+        let report = self.measure(Duration::from_secs(duration), Duration::from_secs(step));
+        #[cfg(feature = "json")]
+        let output = if parameters.is_present("json") {
+            report.to_json()
+        } else {
+            report.to_string()
+        };
+        #[cfg(not(feature = "json"))]
+        let output = report.to_string();
+        println!("{output}");
+    }
+
+    /// Returns options needed for that exporter.
+    fn get_options() -> Vec<clap::Arg<'static, 'static>> {
+        let mut options = Vec::new();
+        let arg = Arg::with_name("duration")
+            .default_value("60")
+            .help("Time spent measuring the overhead of scaphandre, in seconds.")
+            .long("duration")
+            .short("d")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("step_duration")
+            .default_value("1")
+            .help("Time between two iterations (refresh of the topology and generation of the metrics), in seconds.")
+            .long("step")
+            .short("s")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        #[cfg(feature = "json")]
+        {
+            let arg = Arg::with_name("json")
+                .help("Print the report as JSON.")
+                .long("json")
+                .required(false)
+                .takes_value(false);
+            options.push(arg);
+        }
+
+        options
+    }
+}
+
+impl BenchExporter {
+    /// Instantiates and returns a new BenchExporter
+    pub fn new(sensor: Box<dyn Sensor>) -> BenchExporter {
+        BenchExporter { sensor }
+    }
+
+    /// Runs an iteration every `step` during `duration`, measuring how long
+    /// each one takes and what scaphandre consumes.
+    fn measure(&mut self, duration: Duration, step: Duration) -> BenchReport {
+        let topology = self.sensor.get_topology().unwrap();
+        let mut metric_generator = MetricGenerator::new(topology, get_hostname(), false, false);
+        metric_generator.topology.refresh();
+
+        let mut report = BenchReport::default();
+        let start_cpu_seconds = read_self_cpu_seconds();
+        let start = Instant::now();
+        let mut last_iteration = Instant::now();
+        while start.elapsed() < duration {
+            thread::sleep(step);
+            let iteration_start = Instant::now();
+            metric_generator.topology.refresh();
+            metric_generator.gen_all_metrics();
+            let _ = metric_generator.pop_metrics();
+            report.iterations.push(iteration_start.elapsed());
+
+            let elapsed = last_iteration.elapsed().as_secs_f64();
+            last_iteration = Instant::now();
+            let metrics = SelfMetrics::collect(&metric_generator.topology);
+            if let Some(microwatts) = metrics
+                .power_microwatts
+                .as_ref()
+                .and_then(|power| power.value_f64())
+            {
+                report.energy_microjoules += microwatts * elapsed;
+            }
+            if let Some(memory) = metrics.memory {
+                report.max_resident_set_size_bytes = report
+                    .max_resident_set_size_bytes
+                    .max(memory.resident_set_size_bytes);
+            }
+        }
+        report.duration = start.elapsed();
+        if let (Some(start_cpu_seconds), Some(end_cpu_seconds)) =
+            (start_cpu_seconds, read_self_cpu_seconds())
+        {
+            report.cpu_seconds = end_cpu_seconds - start_cpu_seconds;
+        }
+        report
+    }
+}
+
+/// Returns the CPU time consumed by scaphandre since it started, in seconds,
+/// from the user and system time of /proc/self/stat. Returns None on other
+/// systems.
+fn read_self_cpu_seconds() -> Option<f64> {
+    #[cfg(target_os = "linux")]
+    {
+        let stat = procfs::process::Process::myself().ok()?.stat;
+        let ticks_per_second = procfs::ticks_per_second().ok()?;
+        cpu_seconds_from_ticks(stat.utime + stat.stime, ticks_per_second)
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Converts a CPU time in clock ticks to seconds.
+fn cpu_seconds_from_ticks(ticks: u64, ticks_per_second: i64) -> Option<f64> {
+    if ticks_per_second > 0 {
+        Some(ticks as f64 / ticks_per_second as f64)
+    } else {
+        None
+    }
+}
+
+/// Resources used by scaphandre during a benchmark.
+#[derive(Debug, Default)]
+struct BenchReport {
+    duration: Duration,
+    /// Time taken by each iteration.
+    iterations: Vec<Duration>,
+    /// CPU time consumed by scaphandre, in seconds.
+    cpu_seconds: f64,
+    /// Energy attributed to scaphandre, in microjoules.
+    energy_microjoules: f64,
+    /// Highest resident set size of scaphandre.
+    max_resident_set_size_bytes: u64,
+}
+
+impl BenchReport {
+    /// Returns the mean time taken by an iteration.
+    fn mean_iteration(&self) -> Duration {
+        if self.iterations.is_empty() {
+            return Duration::ZERO;
+        }
+        self.iterations.iter().sum::<Duration>() / self.iterations.len() as u32
+    }
+
+    /// Returns the time under which `percentile` percent of the iterations
+    /// took, with the nearest-rank method.
+    fn percentile_iteration(&self, percentile: f64) -> Duration {
+        let mut iterations = self.iterations.clone();
+        iterations.sort_unstable();
+        let rank = (percentile / 100.0 * iterations.len() as f64).ceil() as usize;
+        iterations
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the CPU consumed by scaphandre over the benchmark, in percent
+    /// of a core.
+    fn cpu_usage_percent(&self) -> f64 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds > 0.0 {
+            self.cpu_seconds / seconds * 100.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the energy attributed to scaphandre, in joules.
+    fn energy_joules(&self) -> f64 {
+        self.energy_microjoules / 1_000_000.0
+    }
+
+    /// Returns the report as a JSON object.
+    #[cfg(feature = "json")]
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "duration_seconds": self.duration.as_secs_f64(),
+            "iterations": self.iterations.len(),
+            "iteration_mean_seconds": self.mean_iteration().as_secs_f64(),
+            "iteration_p95_seconds": self.percentile_iteration(95.0).as_secs_f64(),
+            "cpu_seconds": self.cpu_seconds,
+            "cpu_usage_percent": self.cpu_usage_percent(),
+            "max_resident_set_size_bytes": self.max_resident_set_size_bytes,
+            "energy_joules": self.energy_joules(),
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Duration: {:.3} s, {} iterations",
+            self.duration.as_secs_f64(),
+            self.iterations.len()
+        )?;
+        writeln!(
+            f,
+            "Iteration time: mean {:.3} ms, p95 {:.3} ms",
+            self.mean_iteration().as_secs_f64() * 1000.0,
+            self.percentile_iteration(95.0).as_secs_f64() * 1000.0
+        )?;
+        writeln!(
+            f,
+            "CPU time: {:.3} s ({:.2} % of a core)",
+            self.cpu_seconds,
+            self.cpu_usage_percent()
+        )?;
+        writeln!(
+            f,
+            "Max resident memory: {:.1} MB",
+            self.max_resident_set_size_bytes as f64 / 1_000_000.0
+        )?;
+        write!(f, "Self energy: {:.3} J", self.energy_joules())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_summarizes_iterations() {
+        let report = BenchReport {
+            duration: Duration::from_secs(20),
+            iterations: (1..=20).rev().map(Duration::from_millis).collect(),
+            cpu_seconds: 0.5,
+            energy_microjoules: 2_000_000.0,
+            max_resident_set_size_bytes: 12_500_000,
+        };
+        assert_eq!(report.mean_iteration(), Duration::from_micros(10_500));
+        assert_eq!(report.percentile_iteration(95.0), Duration::from_millis(19));
+        assert_eq!(
+            report.percentile_iteration(100.0),
+            Duration::from_millis(20)
+        );
+        assert_eq!(report.cpu_usage_percent(), 2.5);
+        assert_eq!(
+            report.to_string(),
+            "Duration: 20.000 s, 20 iterations\nIteration time: mean 10.500 ms, p95 19.000 ms\nCPU time: 0.500 s (2.50 % of a core)\nMax resident memory: 12.5 MB\nSelf energy: 2.000 J"
+        );
+        assert_eq!(
+            BenchReport::default().percentile_iteration(95.0),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn cpu_time_converted_from_ticks() {
+        assert_eq!(cpu_seconds_from_ticks(250, 100), Some(2.5));
+        assert_eq!(cpu_seconds_from_ticks(250, 0), None);
+        #[cfg(target_os = "linux")]
+        assert!(read_self_cpu_seconds().is_some());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,196 @@
+//! # Energy windows
+//!
+//! Accumulates the energy consumed by entities (host, sockets, processes...)
+//! between wall-clock aligned boundaries, like every 15 minutes from the top of
+//! the hour, to report an exact energy total per window instead of sampled
+//! power. The energy is taken from the cumulative counters of the topology:
+//! what a counter gained since the previous measurement is split between the
+//! windows the two measurements fall in, in proportion to the time spent in
+//! each, so no energy is counted twice or lost between two windows.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
+/// Energy consumed by each entity during a window.
+#[derive(Debug, Clone)]
+pub struct EnergyWindow<K> {
+    /// Start of the window, since the epoch.
+    pub start: Duration,
+    /// End of the window, since the epoch.
+    pub end: Duration,
+    /// True if the measurements don't cover the whole window: scaphandre
+    /// started during it, or measurements stopped for more than a window
+    /// before it.
+    pub partial: bool,
+    /// Energy consumed by each entity during the window, in microjoules.
+    pub energy: HashMap<K, u64>,
+}
+
+/// Accumulates the energy of entities identified by K in aligned windows.
+#[derive(Debug, Clone)]
+pub struct EnergyWindows<K> {
+    length: Duration,
+    /// Start of the current window, None before the first measurement.
+    start: Option<Duration>,
+    /// True if the current window isn't fully covered by measurements.
+    partial: bool,
+    /// Time of the previous measurement, None before the first one.
+    last_timestamp: Option<Duration>,
+    /// Last value of the cumulative counter of each entity, in microjoules.
+    last_totals: HashMap<K, u64>,
+    /// Energy consumed by each entity during the current window, in microjoules.
+    energy: HashMap<K, u64>,
+}
+
+impl<K: Eq + Hash + Clone> EnergyWindows<K> {
+    /// Instantiates EnergyWindows, with windows of `length` aligned on
+    /// multiples of `length` since the epoch. Returns an error if the length is
+    /// shorter than a second.
+    pub fn new(length: Duration) -> Result<EnergyWindows<K>, String> {
+        if length < Duration::from_secs(1) {
+            return Err(format!(
+                "energy windows should last at least a second, got {length:?}"
+            ));
+        }
+        Ok(EnergyWindows {
+            length,
+            start: None,
+            partial: true,
+            last_timestamp: None,
+            last_totals: HashMap::new(),
+            energy: HashMap::new(),
+        })
+    }
+
+    /// Returns the start of the window `timestamp` falls in.
+    fn get_window_start(&self, timestamp: Duration) -> Duration {
+        let length = self.length.as_nanos();
+        let start = timestamp.as_nanos() / length * length;
+        Duration::new(
+            (start / 1_000_000_000) as u64,
+            (start % 1_000_000_000) as u32,
+        )
+    }
+
+    /// Adds the energy measured at `timestamp`, given as the cumulative counter
+    /// of each entity in microjoules, counted from the start of scaphandre or
+    /// from when the entity was first seen. A counter lower than its previous
+    /// value, like the one of a reused pid, is considered as restarted from zero.
+    ///
+    /// If `timestamp` is past the end of the current window, what the counters
+    /// gained since the previous measurement is split in proportion to time:
+    /// the share of the time before the end goes in the current window, which
+    /// is returned, the rest in the window `timestamp` falls in. The share of
+    /// windows without any measurement also goes in the latter, flagged partial.
+    pub fn update<I>(&mut self, timestamp: Duration, totals: I) -> Option<EnergyWindow<K>>
+    where
+        I: IntoIterator<Item = (K, u64)>,
+    {
+        let mut gains = vec![];
+        let mut last_totals = HashMap::new();
+        for (entity, total) in totals {
+            let gained = match self.last_totals.get(&entity) {
+                Some(last) if total >= *last => total - last,
+                _ => total,
+            };
+            gains.push((entity.clone(), gained));
+            last_totals.insert(entity, total);
+        }
+        // entities that are gone are forgotten once their energy was counted
+        self.last_totals = last_totals;
+        let last_timestamp = self.last_timestamp.replace(timestamp);
+
+        let window_start = self.get_window_start(timestamp);
+        let closed = match self.start {
+            None => {
+                self.start = Some(window_start);
+                self.partial = timestamp > window_start;
+                None
+            }
+            Some(start) if window_start > start => {
+                let end = start + self.length;
+                // share of the time since the previous measurement spent in the current window
+                let share = match last_timestamp {
+                    Some(last) if timestamp > last => {
+                        end.saturating_sub(last).as_secs_f64() / (timestamp - last).as_secs_f64()
+                    }
+                    _ => 0.0,
+                };
+                for (entity, gained) in gains.iter_mut() {
+                    let before_end = (*gained as f64 * share.min(1.0)).round() as u64;
+                    *self.energy.entry(entity.clone()).or_insert(0) += before_end;
+                    *gained -= before_end;
+                }
+                let window = EnergyWindow {
+                    start,
+                    end,
+                    partial: self.partial,
+                    energy: std::mem::take(&mut self.energy),
+                };
+                // the energy consumed while no measurement was done is in this window
+                self.partial = window_start > end;
+                self.start = Some(window_start);
+                Some(window)
+            }
+            Some(_) => None,
+        };
+        for (entity, gained) in gains {
+            *self.energy.entry(entity).or_insert(0) += gained;
+        }
+        closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn energy_accumulated_in_aligned_windows() {
+        let mut windows = EnergyWindows::new(Duration::from_secs(900)).unwrap();
+        assert!(EnergyWindows::<i32>::new(Duration::from_millis(10)).is_err());
+        let at = |secs| Duration::from_secs(secs);
+        // started mid-window
+        assert!(windows.update(at(1000), vec![("host", 100)]).is_none());
+        assert!(windows
+            .update(at(1500), vec![("host", 300), ("pid", 50)])
+            .is_none());
+        // 300s of the 310s since the previous measurement are in the first window,
+        // a reused pid restarts from zero
+        let first = windows
+            .update(at(1810), vec![("host", 920), ("pid", 31)])
+            .unwrap();
+        assert_eq!(
+            (first.start, first.end, first.partial),
+            (at(900), at(1800), true)
+        );
+        assert_eq!(first.energy[&"host"], 300 + 600);
+        assert_eq!(first.energy[&"pid"], 50 + 30);
+        // measured right at the end of the window
+        let second = windows.update(at(2700), vec![("host", 1400)]).unwrap();
+        assert_eq!((second.start, second.partial), (at(1800), false));
+        assert_eq!(second.energy[&"host"], 20 + 480);
+        assert_eq!(second.energy[&"pid"], 1);
+        // no measurement during a whole window, its share goes in the next one
+        let third = windows.update(at(4600), vec![("host", 1590)]).unwrap();
+        assert_eq!((third.start, third.partial), (at(2700), false));
+        assert_eq!(third.energy[&"host"], 90);
+        let fourth = windows.update(at(5400), vec![("host", 1590)]).unwrap();
+        assert_eq!((fourth.start, fourth.partial), (at(4500), true));
+        assert_eq!(fourth.energy[&"host"], 100);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
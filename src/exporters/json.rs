@@ -1,11 +1,21 @@
+use crate::exporters::energy_window::{EnergyWindow, EnergyWindows};
+use crate::exporters::labels::{get_label_option, get_label_provider_option, get_label_providers};
+use crate::exporters::self_metrics::SelfMetrics;
+use crate::exporters::utils::{
+    apply_topology_options, get_measure_interval, get_measure_interval_option, topology_options,
+    wait_for_next_step,
+};
 use crate::exporters::*;
-use crate::sensors::Sensor;
+use crate::sensors::{RecordGenerator, Sensor};
 use clap::Arg;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::fs::File;
+#[cfg(unix)]
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
-use std::thread;
 use std::time::{Duration, Instant};
 
 /// An Exporter that displays power consumption data of the host
@@ -13,6 +23,15 @@ use std::time::{Duration, Instant};
 pub struct JSONExporter {
     sensor: Box<dyn Sensor>,
     reports: Vec<Report>,
+    /// Energy accumulated in the current window, if --energy-window is used.
+    energy_windows: Option<EnergyWindows<EnergyEntity>>,
+    /// Reports of the windows written to the file, if --energy-window is used.
+    window_reports: Vec<EnergyWindowReport>,
+    /// Unix socket reports are sent to, as NDJSON frames, if --unix-socket is used.
+    #[cfg(unix)]
+    unix_socket: Option<UnixSocketWriter>,
+    /// Description of the values of the reports, added to each of them.
+    metadata: Option<Metadata>,
 }
 
 impl Exporter for JSONExporter {
@@ -85,31 +104,180 @@ impl Exporter for JSONExporter {
         //    .takes_value(false);
         //options.push(arg);
 
+        #[cfg(unix)]
+        {
+            let arg = Arg::with_name("unix_socket")
+                .help("Unix socket to send reports to, as NDJSON frames, instead of printing them.")
+                .long("unix-socket")
+                .required(false)
+                .takes_value(true);
+            options.push(arg);
+
+            let arg = Arg::with_name("unix_socket_mode")
+                .help("Either connect to an existing unix socket, or create it and listen for clients.")
+                .long("unix-socket-mode")
+                .default_value("connect")
+                .possible_values(&["connect", "listen"])
+                .required(false)
+                .takes_value(true);
+            options.push(arg);
+        }
+
+        let arg = Arg::with_name("compact")
+            .help("Write each report printed or written to a file on a single line, instead of indenting it.")
+            .long("compact")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("fields")
+            .help("Comma-separated list of the fields to keep in each report, like host,sockets to drop the consumers. All fields are kept by default.")
+            .long("fields")
+            .possible_values(&REPORT_FIELDS)
+            .use_delimiter(true)
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("energy_window")
+            .help("Report the energy consumed by the host, sockets and processes during each window of this many seconds, aligned on the clock (900 reports every quarter of an hour from the top of the hour), instead of reporting power every step.")
+            .long("energy-window")
+            .required(false)
+            .takes_value(true)
+            .validator(|seconds| match seconds.parse::<u64>() {
+                Ok(seconds) if seconds >= 1 => Ok(()),
+                _ => Err(format!("should be a number of seconds, at least 1, got {seconds}")),
+            });
+        options.push(arg);
+
+        options.push(get_measure_interval_option());
+        options.extend(topology_options());
+        options.push(utils::get_hostname_option());
+
+        options.push(get_label_option());
+        options.push(get_label_provider_option());
+
         options
     }
 }
 
+/// Top-level fields of a report, that can be selected with `--fields`.
+const REPORT_FIELDS: [&str; 7] = [
+    "host",
+    "consumers",
+    "other",
+    "sockets",
+    "labels",
+    "scaphandre",
+    "metadata",
+];
+
+/// Unit of the power values (`consumption` fields).
+const POWER_UNIT: &str = "microwatts";
+/// Unit of the energy values (`energy` fields).
+const ENERGY_UNIT: &str = "microjoules";
+/// Unit of the `timestamp`, `start` and `end` fields.
+const TIMESTAMP_UNIT: &str = "seconds since the epoch";
+
+/// Units of the values of the reports.
+#[derive(Serialize, Deserialize, Clone)]
+struct Units {
+    consumption: String,
+    energy: String,
+    timestamp: String,
+}
+
+impl Default for Units {
+    fn default() -> Units {
+        Units {
+            consumption: String::from(POWER_UNIT),
+            energy: String::from(ENERGY_UNIT),
+            timestamp: String::from(TIMESTAMP_UNIT),
+        }
+    }
+}
+
+/// Describes how the values of a report were measured, so that tools
+/// ingesting it don't have to guess.
+#[derive(Serialize, Deserialize, Clone)]
+struct Metadata {
+    /// Name of the sensor measuring the power, as given to `--sensor`.
+    sensor: String,
+    /// Time between two measurements, in seconds.
+    step_seconds: f64,
+    /// Time between the measurements averaged over a step, in seconds, if
+    /// `--measure-interval` is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    measure_interval_seconds: Option<f64>,
+    /// Duration of the windows, in seconds, if `--energy-window` is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    energy_window_seconds: Option<u64>,
+    units: Units,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Domain {
     name: String,
     consumption: f32,
+    consumption_unit: String,
+    /// Number of records in the buffer of the domain.
+    records_nb: usize,
     timestamp: f64,
 }
 #[derive(Serialize, Deserialize)]
 struct Socket {
     id: u16,
     consumption: f32,
+    consumption_unit: String,
+    /// Energy counter of the socket, in microjoules.
+    energy: Option<u64>,
+    energy_unit: String,
     domains: Vec<Domain>,
     timestamp: f64,
 }
 
+/// What scaphandre measured about itself, as in the `scaph_self_*` metrics.
+#[derive(Serialize, Deserialize)]
+struct SelfReport {
+    version: String,
+    cpu_usage_percent: Option<f64>,
+    /// Resident set size, in bytes.
+    memory_bytes: Option<u64>,
+    /// Size in memory of the records and stats stored, in bytes.
+    buffers_size_bytes: usize,
+}
+
+impl From<SelfMetrics> for SelfReport {
+    fn from(self_metrics: SelfMetrics) -> SelfReport {
+        SelfReport {
+            cpu_usage_percent: self_metrics.get_cpu_usage_percent_value(),
+            memory_bytes: self_metrics
+                .memory
+                .map(|memory| memory.resident_set_size_bytes),
+            buffers_size_bytes: self_metrics.buffers_size_bytes,
+            version: self_metrics.version,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct Consumer {
     exe: PathBuf,
     pid: i32,
     consumption: f32,
+    consumption_unit: String,
     timestamp: f64,
     container: Option<Container>,
+    pod: Option<Pod>,
+}
+/// Power of the processes aggregated under `--min-power-microwatts`.
+#[derive(Serialize, Deserialize)]
+struct OtherConsumers {
+    consumption: f32,
+    consumption_unit: String,
+    /// Number of processes aggregated.
+    processes: usize,
+    timestamp: f64,
 }
 #[derive(Serialize, Deserialize)]
 struct Container {
@@ -117,16 +285,80 @@ struct Container {
     runtime: String,
     scheduler: String,
 }
+/// Kubernetes pod a process runs in.
+#[derive(Serialize, Deserialize)]
+struct Pod {
+    namespace: String,
+    name: String,
+    container: Option<String>,
+}
 #[derive(Serialize, Deserialize)]
 struct Host {
     consumption: f32,
+    consumption_unit: String,
     timestamp: f64,
 }
 #[derive(Serialize, Deserialize)]
-struct Report {
+struct HostEnergy {
+    /// Energy consumed during the window, in microjoules.
+    energy: u64,
+    energy_unit: String,
+}
+#[derive(Serialize, Deserialize)]
+struct SocketEnergy {
+    id: u16,
+    /// Energy consumed during the window, in microjoules.
+    energy: u64,
+    energy_unit: String,
+}
+#[derive(Serialize, Deserialize)]
+struct ConsumerEnergy {
+    exe: PathBuf,
+    pid: i32,
+    /// Energy consumed during the window, in microjoules.
+    energy: u64,
+    energy_unit: String,
+}
+/// Energy consumed during a window, with --energy-window.
+#[derive(Serialize, Deserialize)]
+struct EnergyWindowReport {
+    start: f64,
+    end: f64,
+    /// True if scaphandre started during the window, or if no measurement was
+    /// done for a while before it.
+    partial: bool,
+    host: Option<HostEnergy>,
+    sockets: Vec<SocketEnergy>,
+    consumers: Vec<ConsumerEnergy>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+}
+
+/// Entity whose energy is accumulated in windows.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum EnergyEntity {
+    Host,
+    Socket(u16),
+    /// Process by pid and exe, so that a reused pid is another entity.
+    Process(i32, String),
+}
+
+/// Power consumption of the host, its top consumers and sockets at a step.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Report {
     host: Host,
     consumers: Vec<Consumer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    other: Option<OtherConsumers>,
     sockets: Vec<Socket>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    labels: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scaphandre: Option<SelfReport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
 }
 
 impl JSONExporter {
@@ -135,6 +367,11 @@ impl JSONExporter {
         JSONExporter {
             sensor,
             reports: Vec::new(),
+            energy_windows: None,
+            window_reports: Vec::new(),
+            #[cfg(unix)]
+            unix_socket: None,
+            metadata: None,
         }
     }
 
@@ -143,10 +380,34 @@ impl JSONExporter {
         let topology = self.sensor.get_topology().unwrap();
         let mut metric_generator = MetricGenerator::new(
             topology,
-            utils::get_hostname(),
+            utils::resolve_hostname(&parameters),
             parameters.is_present("qemu"),
             parameters.is_present("containers"),
         );
+        metric_generator.set_label_providers(get_label_providers(&parameters));
+        apply_topology_options(&mut metric_generator.topology, &parameters);
+        // the value is checked by the validator of the option
+        let energy_window_seconds: Option<u64> = parameters
+            .value_of("energy_window")
+            .and_then(|seconds| seconds.parse().ok());
+        if let Some(seconds) = energy_window_seconds {
+            match EnergyWindows::new(Duration::from_secs(seconds)) {
+                Ok(windows) => {
+                    self.energy_windows = Some(windows);
+                    metric_generator.topology.enable_process_energy();
+                }
+                Err(e) => warn!("Energy windows disabled: {}", e),
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = parameters.value_of("unix_socket") {
+            self.unix_socket = Some(match parameters.value_of("unix_socket_mode") {
+                Some("listen") => UnixSocketWriter::listen(PathBuf::from(path))
+                    .expect("Couldn't listen on the unix socket"),
+                _ => UnixSocketWriter::connect(PathBuf::from(path)),
+            });
+        }
 
         // We have a default value of 2s so it is safe to unwrap the option
         // Panic if a non numerical value is passed
@@ -161,6 +422,16 @@ impl JSONExporter {
             .parse()
             .expect("Wrong step_duration_nano value, should be a number of nano seconds");
 
+        let step = Duration::new(step_duration, step_duration_nano);
+        let measure_interval = get_measure_interval(&parameters);
+        self.metadata = Some(Metadata {
+            sensor: self.sensor.get_name(),
+            step_seconds: step.as_secs_f64(),
+            measure_interval_seconds: measure_interval.map(|interval| interval.as_secs_f64()),
+            energy_window_seconds,
+            units: Units::default(),
+        });
+
         info!("Measurement step is: {}s", step_duration);
         if let Some(timeout) = parameters.value_of("timeout") {
             let now = Instant::now();
@@ -168,19 +439,77 @@ impl JSONExporter {
             let timeout_secs: u64 = timeout.parse().unwrap();
             while now.elapsed().as_secs() <= timeout_secs {
                 self.iterate(&parameters, &mut metric_generator);
-                thread::sleep(Duration::new(step_duration, step_duration_nano));
+                wait_for_next_step(&mut metric_generator.topology, step, measure_interval);
             }
         } else {
             loop {
                 self.iterate(&parameters, &mut metric_generator);
-                thread::sleep(Duration::new(step_duration, step_duration_nano));
+                wait_for_next_step(&mut metric_generator.topology, step, measure_interval);
             }
         }
     }
 
     fn iterate(&mut self, parameters: &ArgMatches, metric_generator: &mut MetricGenerator) {
         metric_generator.topology.refresh();
-        self.retrieve_metrics(parameters, metric_generator);
+        if self.energy_windows.is_some() {
+            self.accumulate_energy(parameters, metric_generator);
+        } else {
+            self.retrieve_metrics(parameters, metric_generator);
+        }
+    }
+
+    /// Adds the energy measured by the last refresh to the current window, and
+    /// writes the report of the previous window if it just ended.
+    fn accumulate_energy(&mut self, parameters: &ArgMatches, metric_generator: &MetricGenerator) {
+        let topology = &metric_generator.topology;
+        let mut totals = vec![];
+        if let Some(energy) = topology.get_total_energy_microjoules_since_start() {
+            totals.extend(energy.value_u64().map(|e| (EnergyEntity::Host, e)));
+        }
+        for socket in topology.get_sockets_passive() {
+            if socket.get_total_energy_microjoules_since_start().is_some() {
+                totals.push((
+                    EnergyEntity::Socket(socket.id),
+                    socket.energy_since_start_microjoules,
+                ));
+            }
+        }
+        for pid in topology.proc_tracker.get_alive_pids() {
+            if let Some(energy) = topology
+                .get_process_energy_microjoules(pid)
+                .and_then(|e| e.value_u64())
+            {
+                let exe = topology.proc_tracker.get_process_name(pid);
+                totals.push((EnergyEntity::Process(pid, exe), energy));
+            }
+        }
+        let window = match &mut self.energy_windows {
+            Some(windows) => windows.update(current_system_time_since_epoch(), totals),
+            None => None,
+        };
+        if let Some(window) = window {
+            let mut report = build_window_report(window, metric_generator.get_labels());
+            report.metadata = self.metadata.clone();
+            self.write_window_report(parameters, report);
+        }
+    }
+
+    /// Prints the report of a window, or writes it to the file or unix socket.
+    fn write_window_report(&mut self, parameters: &ArgMatches, report: EnergyWindowReport) {
+        #[cfg(unix)]
+        if let Some(unix_socket) = &mut self.unix_socket {
+            unix_socket.send(&serialize_reports(&report, None, false));
+            return;
+        }
+        let pretty = !parameters.is_present("compact");
+        let file_path = parameters.value_of("file_path").unwrap();
+        if file_path.is_empty() {
+            println!("{}", serialize_reports(&report, None, pretty));
+        } else {
+            self.window_reports.push(report);
+            let json = serialize_reports(&self.window_reports, None, pretty);
+            fs::write(file_path, json).expect("Unable to write file");
+        }
     }
 
     fn retrieve_metrics(
@@ -188,45 +517,113 @@ impl JSONExporter {
         parameters: &ArgMatches,
         metric_generator: &mut MetricGenerator,
     ) {
-        metric_generator.gen_all_metrics();
-
-        let metrics = metric_generator.pop_metrics();
-        let mut metrics_iter = metrics.iter();
-        let mut host_report: Option<Host> = None;
-        if let Some(host_metric) = metrics_iter.find(|x| x.name == "scaph_host_power_microwatts") {
-            let host_power_string = format!("{}", host_metric.metric_value);
-            let host_power_f32 = host_power_string.parse::<f32>().unwrap();
-            if host_power_f32 > 0.0 {
-                host_report = Some(Host {
-                    consumption: host_power_f32,
-                    timestamp: host_metric.timestamp.as_secs_f64(),
-                });
+        match build_report(parameters, metric_generator) {
+            Some(mut report) => {
+                report.metadata = self.metadata.clone();
+                let fields: Option<Vec<&str>> = parameters
+                    .values_of("fields")
+                    .map(|fields| fields.collect());
+
+                #[cfg(unix)]
+                if let Some(unix_socket) = &mut self.unix_socket {
+                    // frames are NDJSON, they can't be indented
+                    let json = serialize_reports(&report, fields.as_deref(), false);
+                    unix_socket.send(&json);
+                    return;
+                }
+
+                let pretty = !parameters.is_present("compact");
+                let file_path = parameters.value_of("file_path").unwrap();
+                // Print json
+                if file_path.is_empty() {
+                    let json = serialize_reports(&report, fields.as_deref(), pretty);
+                    println!("{}", &json);
+                } else {
+                    self.reports.push(report);
+                    // Serialize it to a JSON string.
+                    let json = serialize_reports(&self.reports, fields.as_deref(), pretty);
+                    let _ = File::create(file_path);
+                    fs::write(file_path, json).expect("Unable to write file");
+                }
             }
-        } else {
-            info!("didn't find host metric");
-        };
+            None => {
+                info!("No data yet, didn't write report.");
+            }
+        }
+    }
+}
 
-        let consumers = metric_generator.topology.proc_tracker.get_top_consumers(
-            parameters
-                .value_of("max_top_consumers")
-                .unwrap_or("10")
-                .parse::<u16>()
-                .unwrap(),
-        );
-        let top_consumers = consumers
+/// Returns the pod of a process from its labels, as resolved with the
+/// kubernetes API (`kubernetes_pod_*` labels) or else with the kubelet.
+fn get_pod(attributes: &HashMap<String, String>) -> Option<Pod> {
+    let label = |key: &str| attributes.get(key).cloned();
+    match (
+        label("kubernetes_pod_namespace"),
+        label("kubernetes_pod_name"),
+    ) {
+        (Some(namespace), Some(name)) => Some(Pod {
+            namespace,
+            name,
+            container: None,
+        }),
+        _ => Some(Pod {
+            namespace: label("namespace")?,
+            name: label("pod")?,
+            container: label("container"),
+        }),
+    }
+}
+
+/// Generates the metrics of the last refresh and returns the report of the
+/// host, its top consumers and sockets, or None if the host power isn't known yet.
+pub(super) fn build_report(
+    parameters: &ArgMatches,
+    metric_generator: &mut MetricGenerator,
+) -> Option<Report> {
+    metric_generator.gen_all_metrics();
+
+    let metrics = metric_generator.pop_metrics();
+    let mut metrics_iter = metrics.iter();
+    let mut host_report: Option<Host> = None;
+    if let Some(host_metric) = metrics_iter.find(|x| x.name == "scaph_host_power_microwatts") {
+        let host_power_string = format!("{}", host_metric.metric_value);
+        let host_power_f32 = host_power_string.parse::<f32>().unwrap();
+        if host_power_f32 > 0.0 {
+            host_report = Some(Host {
+                consumption: host_power_f32,
+                consumption_unit: String::from(POWER_UNIT),
+                timestamp: host_metric.timestamp.as_secs_f64(),
+            });
+        }
+    } else {
+        info!("didn't find host metric");
+    };
+
+    let consumers = metric_generator.topology.proc_tracker.get_top_consumers(
+        parameters
+            .value_of("max_top_consumers")
+            .unwrap_or("10")
+            .parse::<u16>()
+            .unwrap(),
+    );
+    let top_consumers =
+        consumers
             .iter()
             .filter_map(|(process, _value)| {
                 metrics
                     .iter()
                     .find(|x| {
                         x.name == "scaph_process_power_consumption_microwatts"
-                            && process.pid
-                                == x.attributes.get("pid").unwrap().parse::<i32>().unwrap()
+                            && x.attributes
+                                .get("pid")
+                                .and_then(|pid| pid.parse::<i32>().ok())
+                                == Some(process.pid)
                     })
                     .map(|metric| Consumer {
                         exe: PathBuf::from(metric.attributes.get("exe").unwrap()),
                         pid: process.pid,
                         consumption: format!("{}", metric.metric_value).parse::<f32>().unwrap(),
+                        consumption_unit: String::from(POWER_UNIT),
                         timestamp: metric.timestamp.as_secs_f64(),
                         container: match parameters.is_present("containers") {
                             true => metric.attributes.get("container_id").map(|container_id| {
@@ -248,85 +645,254 @@ impl JSONExporter {
                             }),
                             false => None,
                         },
+                        pod: get_pod(&metric.attributes),
                     })
             })
             .collect::<Vec<_>>();
+    let other = metrics
+        .iter()
+        .find(|metric| metric.is_other_processes())
+        .map(|metric| OtherConsumers {
+            consumption: format!("{}", metric.metric_value)
+                .parse::<f32>()
+                .unwrap_or_default(),
+            consumption_unit: String::from(POWER_UNIT),
+            processes: metric
+                .attributes
+                .get("processes")
+                .and_then(|processes| processes.parse().ok())
+                .unwrap_or_default(),
+            timestamp: metric.timestamp.as_secs_f64(),
+        });
 
-        let all_sockets = metric_generator
-            .topology
-            .get_sockets_passive()
-            .iter()
-            .filter_map(|socket| {
-                if let Some(metric) = metrics_iter.find(|x| {
-                    if x.name == "scaph_socket_power_microwatts" {
-                        socket.id
-                            == x.attributes
-                                .get("socket_id")
-                                .unwrap()
-                                .parse::<u16>()
-                                .unwrap()
-                    } else {
-                        info!("socket not found ! ");
-                        false
+    let all_sockets = metric_generator
+        .topology
+        .get_sockets_passive()
+        .iter()
+        .filter_map(|socket| {
+            let power = socket.get_records_diff_power_microwatts()?;
+            let domains = socket
+                .get_domains_passive()
+                .iter()
+                .map(|domain| {
+                    let domain_power = domain.get_records_diff_power_microwatts();
+                    Domain {
+                        name: domain.name.clone(),
+                        consumption: domain_power
+                            .as_ref()
+                            .and_then(|p| p.value.parse::<f32>().ok())
+                            .unwrap_or_default(),
+                        consumption_unit: String::from(POWER_UNIT),
+                        records_nb: domain.record_buffer.len(),
+                        timestamp: domain_power
+                            .map(|p| p.timestamp.as_secs_f64())
+                            .unwrap_or_default(),
                     }
-                }) {
-                    let socket_power = format!("{}", metric.metric_value).parse::<f32>().unwrap();
-
-                    let domains = metrics
-                        .iter()
-                        .filter(|x| {
-                            x.name == "scaph_domain_power_microwatts"
-                                && x.attributes
-                                    .get("socket_id")
-                                    .unwrap()
-                                    .parse::<u16>()
-                                    .unwrap()
-                                    == socket.id
-                        })
-                        .map(|d| Domain {
-                            name: d.name.clone(),
-                            consumption: format!("{}", d.metric_value).parse::<f32>().unwrap(),
-                            timestamp: d.timestamp.as_secs_f64(),
-                        })
-                        .collect::<Vec<_>>();
-
-                    Some(Socket {
-                        id: socket.id,
-                        consumption: socket_power,
-                        domains,
-                        timestamp: metric.timestamp.as_secs_f64(),
-                    })
-                } else {
-                    None
-                }
+                })
+                .collect::<Vec<_>>();
+
+            Some(Socket {
+                id: socket.id,
+                consumption: power.value.parse::<f32>().unwrap_or_default(),
+                consumption_unit: String::from(POWER_UNIT),
+                energy: socket
+                    .get_records_passive()
+                    .last()
+                    .and_then(|record| record.value.trim().parse::<u64>().ok()),
+                energy_unit: String::from(ENERGY_UNIT),
+                domains,
+                timestamp: power.timestamp.as_secs_f64(),
             })
-            .collect::<Vec<_>>();
+        })
+        .collect::<Vec<_>>();
 
-        match host_report {
-            Some(host) => {
-                let report = Report {
-                    host,
-                    consumers: top_consumers,
-                    sockets: all_sockets,
-                };
+    host_report.map(|host| Report {
+        host,
+        consumers: top_consumers,
+        other,
+        sockets: all_sockets,
+        labels: metric_generator.get_labels(),
+        scaphandre: Some(SelfMetrics::collect(&metric_generator.topology).into()),
+        metadata: None,
+    })
+}
 
-                let file_path = parameters.value_of("file_path").unwrap();
-                // Print json
-                if file_path.is_empty() {
-                    let json: String =
-                        serde_json::to_string(&report).expect("Unable to parse report");
-                    println!("{}", &json);
-                } else {
-                    self.reports.push(report);
-                    // Serialize it to a JSON string.
-                    let json: String =
-                        serde_json::to_string(&self.reports).expect("Unable to parse report");
-                    let _ = File::create(file_path);
-                    fs::write(file_path, json).expect("Unable to write file");
+/// Returns the report of a window, the consumers using the most energy first.
+fn build_window_report(
+    window: EnergyWindow<EnergyEntity>,
+    labels: HashMap<String, String>,
+) -> EnergyWindowReport {
+    let mut report = EnergyWindowReport {
+        start: window.start.as_secs_f64(),
+        end: window.end.as_secs_f64(),
+        partial: window.partial,
+        host: None,
+        sockets: vec![],
+        consumers: vec![],
+        labels,
+        metadata: None,
+    };
+    for (entity, energy) in window.energy {
+        match entity {
+            EnergyEntity::Host => {
+                report.host = Some(HostEnergy {
+                    energy,
+                    energy_unit: String::from(ENERGY_UNIT),
+                })
+            }
+            EnergyEntity::Socket(id) => report.sockets.push(SocketEnergy {
+                id,
+                energy,
+                energy_unit: String::from(ENERGY_UNIT),
+            }),
+            EnergyEntity::Process(pid, exe) => report.consumers.push(ConsumerEnergy {
+                exe: PathBuf::from(exe),
+                pid,
+                energy,
+                energy_unit: String::from(ENERGY_UNIT),
+            }),
+        }
+    }
+    report.sockets.sort_by_key(|socket| socket.id);
+    report
+        .consumers
+        .sort_by(|a, b| b.energy.cmp(&a.energy).then(a.pid.cmp(&b.pid)));
+    report
+}
+
+/// Serializes a report, or a list of reports, keeping only the top-level
+/// `fields` of each report if given, and indenting it if `pretty`.
+pub(super) fn serialize_reports<T: Serialize>(
+    reports: &T,
+    fields: Option<&[&str]>,
+    pretty: bool,
+) -> String {
+    let mut value = serde_json::to_value(reports).expect("Unable to parse report");
+    if let Some(fields) = fields {
+        let select = |report: serde_json::Value| match report {
+            serde_json::Value::Object(report) => serde_json::Value::Object(
+                report
+                    .into_iter()
+                    .filter(|(field, _)| fields.contains(&field.as_str()))
+                    .collect(),
+            ),
+            report => report,
+        };
+        value = match value {
+            serde_json::Value::Array(reports) => {
+                serde_json::Value::Array(reports.into_iter().map(select).collect())
+            }
+            report => select(report),
+        };
+    }
+    if pretty {
+        serde_json::to_string_pretty(&value).expect("Unable to parse report")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Sends NDJSON frames on a unix socket, either connecting to it as a client
+/// or listening on it for clients.
+#[cfg(unix)]
+enum UnixSocketWriter {
+    /// Connected to a socket that is listened by a collector. The connection is
+    /// (re)opened when needed, so a restart of the collector is handled.
+    Client {
+        path: PathBuf,
+        stream: Option<UnixStream>,
+    },
+    /// Listening on a socket, frames are sent to all connected clients.
+    Server {
+        listener: UnixListener,
+        clients: Vec<UnixStream>,
+    },
+}
+
+#[cfg(unix)]
+impl UnixSocketWriter {
+    /// Returns a writer connecting to the socket at path.
+    fn connect(path: PathBuf) -> UnixSocketWriter {
+        UnixSocketWriter::Client { path, stream: None }
+    }
+
+    /// Creates the socket at path, replacing any stale one, and returns a writer listening on it.
+    fn listen(path: PathBuf) -> io::Result<UnixSocketWriter> {
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(UnixSocketWriter::Server {
+            listener,
+            clients: vec![],
+        })
+    }
+
+    /// Sends a frame (a line) on the socket. Writes never block the
+    /// measurements: a peer too slow to read the frames (its socket buffer is
+    /// full) is dropped. On a broken connection, a client reconnects and sends
+    /// the frame again, a server drops the client.
+    fn send(&mut self, frame: &str) {
+        let line = format!("{frame}\n");
+        match self {
+            UnixSocketWriter::Client { path, stream } => {
+                for _ in 0..2 {
+                    if stream.is_none() {
+                        match UnixStream::connect(&path).and_then(|s| {
+                            s.set_nonblocking(true)?;
+                            Ok(s)
+                        }) {
+                            Ok(s) => *stream = Some(s),
+                            Err(err) => {
+                                warn!("Couldn't connect to unix socket {:?}: {}", path, err);
+                                return;
+                            }
+                        }
+                    }
+                    match stream.as_mut().unwrap().write_all(line.as_bytes()) {
+                        Ok(()) => return,
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            warn!(
+                                "Unix socket {:?} is not read fast enough, dropping the connection",
+                                path
+                            );
+                            *stream = None;
+                            return;
+                        }
+                        Err(err) => {
+                            warn!(
+                                "Couldn't write to unix socket {:?}: {}, reconnecting",
+                                path, err
+                            );
+                            *stream = None;
+                        }
+                    }
                 }
             }
-            None => {
-                info!("No data yet, didn't write report.");
+            UnixSocketWriter::Server { listener, clients } => {
+                loop {
+                    match listener.accept() {
+                        Ok((client, _)) => match client.set_nonblocking(true) {
+                            Ok(()) => clients.push(client),
+                            Err(err) => warn!("Couldn't set up unix socket client: {}", err),
+                        },
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            warn!("Couldn't accept unix socket client: {}", err);
+                            break;
+                        }
+                    }
+                }
+                clients.retain_mut(|client| match client.write_all(line.as_bytes()) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        if err.kind() == io::ErrorKind::WouldBlock {
+                            warn!("Unix socket client is not reading fast enough, dropping it");
+                        }
+                        false
+                    }
+                });
             }
         }
     }
@@ -336,6 +902,140 @@ impl JSONExporter {
 mod tests {
     //#[test]
     //fn get_cons_socket0() {}
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_client_reconnects() {
+        use super::*;
+        use std::io::{BufRead, BufReader};
+
+        let path = std::env::temp_dir().join(format!(
+            "scaphandre_test_json_reconnect_{}.sock",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let mut writer = UnixSocketWriter::connect(path.clone());
+        // no collector listening yet, the frame is dropped
+        writer.send("{\"lost\":true}");
+
+        let listener = UnixListener::bind(&path).unwrap();
+        writer.send("{\"frame\":1}");
+        let (stream, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"frame\":1}\n");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_slow_client_dropped() {
+        use super::*;
+
+        let path =
+            std::env::temp_dir().join(format!("scaphandre_test_json_{}.sock", std::process::id()));
+        let mut writer = UnixSocketWriter::listen(path.clone()).unwrap();
+        // connected, but never reading
+        let _client = UnixStream::connect(&path).unwrap();
+        let frame = "x".repeat(64 * 1024);
+        for _ in 0..64 {
+            writer.send(&frame);
+        }
+        match writer {
+            UnixSocketWriter::Server { clients, .. } => assert!(clients.is_empty()),
+            _ => panic!("Expected a listening writer"),
+        }
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn report_fields_selected() {
+        use super::*;
+
+        let report = Report {
+            host: Host {
+                consumption: 10.0,
+                consumption_unit: String::from(POWER_UNIT),
+                timestamp: 1.0,
+            },
+            consumers: vec![],
+            other: None,
+            sockets: vec![],
+            labels: HashMap::new(),
+            scaphandre: None,
+            metadata: Some(Metadata {
+                sensor: String::from("powercap_rapl"),
+                step_seconds: 2.0,
+                measure_interval_seconds: None,
+                energy_window_seconds: None,
+                units: Units::default(),
+            }),
+        };
+        assert_eq!(
+            serialize_reports(&report, Some(&["host"]), false),
+            "{\"host\":{\"consumption\":10.0,\"consumption_unit\":\"microwatts\",\"timestamp\":1.0}}"
+        );
+        assert_eq!(
+            serialize_reports(&report, Some(&["metadata"]), false),
+            r#"{"metadata":{"sensor":"powercap_rapl","step_seconds":2.0,"units":{"consumption":"microwatts","energy":"microjoules","timestamp":"seconds since the epoch"}}}"#
+        );
+        assert_eq!(
+            serialize_reports(&vec![report], Some(&["consumers"]), true),
+            "[\n  {\n    \"consumers\": []\n  }\n]"
+        );
+    }
+
+    #[test]
+    fn window_report_sorted_by_energy() {
+        use super::*;
+
+        let mut energy = HashMap::new();
+        energy.insert(EnergyEntity::Host, 900);
+        energy.insert(EnergyEntity::Socket(1), 400);
+        energy.insert(EnergyEntity::Socket(0), 500);
+        energy.insert(EnergyEntity::Process(42, String::from("stress")), 100);
+        energy.insert(EnergyEntity::Process(7, String::from("rustc")), 300);
+        let window = EnergyWindow {
+            start: Duration::from_secs(900),
+            end: Duration::from_secs(1800),
+            partial: true,
+            energy,
+        };
+        let report = build_window_report(window, HashMap::new());
+        let json = serialize_reports(&report, None, false);
+        assert_eq!(
+            json,
+            r#"{"consumers":[{"energy":300,"energy_unit":"microjoules","exe":"rustc","pid":7},{"energy":100,"energy_unit":"microjoules","exe":"stress","pid":42}],"end":1800.0,"host":{"energy":900,"energy_unit":"microjoules"},"partial":true,"sockets":[{"energy":500,"energy_unit":"microjoules","id":0},{"energy":400,"energy_unit":"microjoules","id":1}],"start":900.0}"#
+        );
+    }
+
+    #[test]
+    fn pod_from_labels() {
+        use super::*;
+
+        let labels = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (String::from(*k), String::from(*v)))
+                .collect()
+        };
+        let kubelet = labels(&[
+            ("namespace", "web"),
+            ("pod", "front-1"),
+            ("container", "nginx"),
+        ]);
+        let pod = get_pod(&kubelet).unwrap();
+        assert_eq!(
+            (pod.namespace.as_str(), pod.name.as_str(), pod.container),
+            ("web", "front-1", Some(String::from("nginx")))
+        );
+        let api = labels(&[
+            ("kubernetes_pod_namespace", "web"),
+            ("kubernetes_pod_name", "front-1"),
+        ]);
+        assert_eq!(get_pod(&api).unwrap().name, "front-1");
+        assert!(get_pod(&labels(&[("pod", "front-1")])).is_none());
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
@@ -0,0 +1,304 @@
+//! # Labels
+//!
+//! The labels module provides [LabelProvider] implementations, giving the labels
+//! that every exporter attaches to the metrics it sends or exposes.
+use crate::exporters::utils::{get_hostname, get_hostname_override};
+use clap::{Arg, ArgMatches};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+/// Prefix of the environment variables read by [EnvLabelProvider].
+const DEFAULT_ENV_LABEL_PREFIX: &str = "SCAPH_LABEL_";
+
+/// Files holding the id of the instance, tried in order: the one written by
+/// cloud-init, then the machine id.
+const INSTANCE_ID_FILES: [&str; 2] = ["/var/lib/cloud/data/instance-id", "/etc/machine-id"];
+
+/// A LabelProvider gives labels (key + value) to be attached to all the metrics
+/// of an exporter. Implement it to inject your own (dynamic) labels.
+pub trait LabelProvider: Send {
+    /// Returns the labels to attach to the metrics.
+    fn get_labels(&self) -> HashMap<String, String>;
+}
+
+/// Provides static labels, as given with the `--label key=value` option.
+pub struct StaticLabelProvider {
+    labels: HashMap<String, String>,
+}
+
+impl StaticLabelProvider {
+    /// Instantiates StaticLabelProvider and returns the instance.
+    pub fn new(labels: HashMap<String, String>) -> StaticLabelProvider {
+        StaticLabelProvider { labels }
+    }
+
+    /// Parses `key=value` strings and returns a StaticLabelProvider holding those labels.
+    pub fn from_values(values: &[&str]) -> Result<StaticLabelProvider, String> {
+        let mut labels = HashMap::new();
+        for value in values {
+            let (key, value) = parse_label(value)?;
+            labels.insert(key, value);
+        }
+        Ok(StaticLabelProvider::new(labels))
+    }
+}
+
+impl LabelProvider for StaticLabelProvider {
+    fn get_labels(&self) -> HashMap<String, String> {
+        self.labels.clone()
+    }
+}
+
+/// Provides labels from environment variables starting with a prefix, read
+/// once when the provider is created. `SCAPH_LABEL_REGION=eu-west` gives the
+/// label `region="eu-west"`. This is useful to get a cloud region injected by
+/// the orchestrator.
+pub struct EnvLabelProvider {
+    labels: HashMap<String, String>,
+}
+
+impl EnvLabelProvider {
+    /// Instantiates EnvLabelProvider with the variables starting with `prefix`
+    /// and returns the instance.
+    pub fn new(prefix: &str) -> EnvLabelProvider {
+        EnvLabelProvider {
+            labels: env_labels(prefix, env::vars()),
+        }
+    }
+}
+
+impl Default for EnvLabelProvider {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENV_LABEL_PREFIX)
+    }
+}
+
+impl LabelProvider for EnvLabelProvider {
+    fn get_labels(&self) -> HashMap<String, String> {
+        self.labels.clone()
+    }
+}
+
+/// Returns the labels of the variables starting with `prefix`, keyed by the
+/// lowercase rest of the variable name.
+fn env_labels(
+    prefix: &str,
+    vars: impl Iterator<Item = (String, String)>,
+) -> HashMap<String, String> {
+    vars.filter_map(|(k, v)| {
+        k.strip_prefix(prefix)
+            .filter(|key| !key.is_empty())
+            .map(|key| (key.to_lowercase(), v))
+    })
+    .collect()
+}
+
+/// Provides the `hostname` label: the name given with `--hostname` or
+/// `SCAPH_HOSTNAME`, or the system hostname.
+pub struct HostnameLabelProvider {
+    hostname: String,
+}
+
+impl HostnameLabelProvider {
+    /// Instantiates HostnameLabelProvider and returns the instance.
+    pub fn new(hostname: String) -> HostnameLabelProvider {
+        HostnameLabelProvider { hostname }
+    }
+}
+
+impl LabelProvider for HostnameLabelProvider {
+    fn get_labels(&self) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        labels.insert(String::from("hostname"), self.hostname.clone());
+        labels
+    }
+}
+
+/// Provides the `instance_id` label, read once from the cloud-init instance id
+/// or the machine id. Gives no label if none of them could be read.
+pub struct InstanceIdLabelProvider {
+    instance_id: Option<String>,
+}
+
+impl InstanceIdLabelProvider {
+    /// Instantiates InstanceIdLabelProvider, reading the first instance id
+    /// found in `paths`, and returns the instance.
+    pub fn new(paths: &[&str]) -> InstanceIdLabelProvider {
+        let instance_id = paths.iter().find_map(|path| {
+            fs::read_to_string(path)
+                .ok()
+                .map(|id| String::from(id.trim()))
+                .filter(|id| !id.is_empty())
+        });
+        if instance_id.is_none() {
+            warn!("Couldn't read the instance id from {:?}", paths);
+        }
+        InstanceIdLabelProvider { instance_id }
+    }
+}
+
+impl Default for InstanceIdLabelProvider {
+    fn default() -> Self {
+        Self::new(&INSTANCE_ID_FILES)
+    }
+}
+
+impl LabelProvider for InstanceIdLabelProvider {
+    fn get_labels(&self) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        if let Some(instance_id) = &self.instance_id {
+            labels.insert(String::from("instance_id"), instance_id.clone());
+        }
+        labels
+    }
+}
+
+/// Splits a `key=value` string into the label key and value.
+pub fn parse_label(label: &str) -> Result<(String, String), String> {
+    match label.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((String::from(key.trim()), String::from(value)))
+        }
+        _ => Err(format!("Invalid label '{label}', should be key=value")),
+    }
+}
+
+/// Returns the `--label` option, shared by the exporters attaching labels to their metrics.
+pub fn get_label_option() -> Arg<'static, 'static> {
+    Arg::with_name("label")
+        .help("Static label to attach to all metrics, as key=value. Can be used multiple times.")
+        .long("label")
+        .required(false)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .validator(|value| parse_label(&value).map(|_| ()))
+}
+
+/// Returns the `--label-provider` option, enabling the providers of dynamic labels.
+pub fn get_label_provider_option() -> Arg<'static, 'static> {
+    Arg::with_name("label_provider")
+        .help("Dynamic label to attach to all metrics: hostname (the --hostname value or the system hostname) or instance_id (the cloud-init instance id, or the machine id). Can be used multiple times.")
+        .long("label-provider")
+        .required(false)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .possible_values(&["hostname", "instance_id"])
+}
+
+/// Returns the label providers matching the exporter parameters: static labels
+/// from `--label`, labels from `SCAPH_LABEL_*` environment variables and the
+/// dynamic labels enabled with `--label-provider`.
+pub fn get_label_providers(parameters: &ArgMatches) -> Vec<Box<dyn LabelProvider>> {
+    let mut providers: Vec<Box<dyn LabelProvider>> = vec![Box::new(EnvLabelProvider::default())];
+    if let Some(values) = parameters.values_of("label") {
+        // values are checked by the validator of the option
+        let labels = values.filter_map(|value| parse_label(value).ok()).collect();
+        providers.push(Box::new(StaticLabelProvider::new(labels)));
+    }
+    if let Some(names) = parameters.values_of("label_provider") {
+        for name in names {
+            match name {
+                "hostname" => providers.push(Box::new(HostnameLabelProvider::new(
+                    get_hostname_override(parameters).unwrap_or_else(get_hostname),
+                ))),
+                "instance_id" => providers.push(Box::new(InstanceIdLabelProvider::default())),
+                _ => {}
+            }
+        }
+    }
+    providers
+}
+
+/// Adds the labels of all providers to attributes. Labels already present in
+/// attributes are kept, so metric specific labels always win.
+pub fn merge_labels(
+    providers: &[Box<dyn LabelProvider>],
+    attributes: &mut HashMap<String, String>,
+) {
+    for provider in providers {
+        for (k, v) in provider.get_labels() {
+            attributes.entry(k).or_insert(v);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_label_ok() {
+        assert_eq!(
+            parse_label("region=eu-west=1").unwrap(),
+            (String::from("region"), String::from("eu-west=1"))
+        );
+    }
+
+    #[test]
+    fn parse_label_ko() {
+        assert!(parse_label("region").is_err());
+        assert!(parse_label("=eu-west").is_err());
+    }
+
+    #[test]
+    fn merge_labels_keeps_metric_labels() {
+        let providers: Vec<Box<dyn LabelProvider>> = vec![Box::new(
+            StaticLabelProvider::from_values(&["pid=0", "region=eu-west"]).unwrap(),
+        )];
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("pid"), String::from("42"));
+        merge_labels(&providers, &mut attributes);
+        assert_eq!(attributes.get("pid").unwrap(), "42");
+        assert_eq!(attributes.get("region").unwrap(), "eu-west");
+    }
+
+    #[test]
+    fn dynamic_labels_provided() {
+        let vars = vec![
+            (String::from("SCAPH_LABEL_REGION"), String::from("eu-west")),
+            (String::from("SCAPH_LABEL_"), String::from("ignored")),
+            (String::from("HOME"), String::from("/root")),
+        ];
+        let labels = env_labels(DEFAULT_ENV_LABEL_PREFIX, vars.into_iter());
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels.get("region").unwrap(), "eu-west");
+
+        let path =
+            std::env::temp_dir().join(format!("scaphandre_instance_id_{}", std::process::id()));
+        fs::write(&path, "i-0123456789\n").unwrap();
+        let provider =
+            InstanceIdLabelProvider::new(&["/nonexistent/instance-id", path.to_str().unwrap()]);
+        assert_eq!(
+            provider.get_labels().get("instance_id").unwrap(),
+            "i-0123456789"
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(InstanceIdLabelProvider::new(&["/nonexistent/instance-id"])
+            .get_labels()
+            .is_empty());
+        assert_eq!(
+            HostnameLabelProvider::new(String::from("node-1"))
+                .get_labels()
+                .get("hostname")
+                .unwrap(),
+            "node-1"
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -2,27 +2,38 @@
 //!
 //! `Exporter` is the root for all exporters. It defines the [Exporter] trait
 //! needed to implement an exporter.
+pub mod bench;
+pub mod energy_window;
 pub mod json;
+pub mod labels;
 #[cfg(feature = "prometheus")]
 pub mod prometheus;
 #[cfg(target_os = "linux")]
 pub mod qemu;
+pub mod queue;
+#[cfg(feature = "prometheus")]
+pub mod remote_write;
 #[cfg(feature = "riemann")]
 pub mod riemann;
+pub mod self_metrics;
 pub mod stdout;
 pub mod utils;
 #[cfg(feature = "warpten")]
 pub mod warpten;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+pub mod wrap;
 use crate::sensors::{
-    utils::{current_system_time_since_epoch, page_size, IProcess},
-    RecordGenerator, Topology,
+    hybrid::CoreType, utils::current_system_time_since_epoch, OtherProcessesPower, RecordGenerator,
+    Topology,
 };
 use chrono::Utc;
 use clap::ArgMatches;
-use std::collections::HashMap;
+use labels::{merge_labels, LabelProvider};
+use self_metrics::SelfMetrics;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::time::Duration;
-use utils::get_scaphandre_version;
 #[cfg(feature = "containers")]
 use {
     docker_sync::{container::Container, Docker},
@@ -31,6 +42,9 @@ use {
     utils::{get_docker_client, get_kubernetes_client},
 };
 
+/// pid and exe of the processes aggregated under `--min-power-microwatts`.
+pub const OTHER_PROCESSES: &str = "other";
+
 /// General metric definition.
 #[derive(Debug)]
 struct Metric {
@@ -59,6 +73,34 @@ struct Metric {
     timestamp: Duration,
 }
 
+impl Metric {
+    /// Returns the same metric in watts, as a float, named with a `_watts`
+    /// suffix, if this metric is a power in microwatts.
+    fn to_watts(&self) -> Option<Metric> {
+        let name = self.name.strip_suffix("_microwatts")?;
+        let microwatts = format!("{}", self.metric_value).parse::<f64>().ok()?;
+        Some(Metric {
+            name: format!("{name}_watts"),
+            metric_type: self.metric_type.clone(),
+            ttl: self.ttl,
+            hostname: self.hostname.clone(),
+            state: self.state.clone(),
+            tags: self.tags.clone(),
+            attributes: self.attributes.clone(),
+            description: self.description.replace("microwatts", "watts"),
+            metric_value: MetricValueType::FloatDouble(microwatts / 1_000_000.0),
+            timestamp: self.timestamp,
+        })
+    }
+
+    /// Returns true if this metric is the power of the processes aggregated
+    /// in the "other" series, rather than the power of a single process.
+    fn is_other_processes(&self) -> bool {
+        self.name == "scaph_process_power_consumption_microwatts"
+            && self.attributes.get("pid").map(String::as_str) == Some(OTHER_PROCESSES)
+    }
+}
+
 #[derive(Clone)]
 enum MetricValueType {
     // IntSigned(i64),
@@ -151,6 +193,20 @@ struct MetricGenerator {
     ///
     #[cfg(feature = "containers")]
     pods_last_check: String,
+    /// `label_providers` give the labels attached to every metric popped.
+    label_providers: Vec<Box<dyn LabelProvider>>,
+    /// Tells MetricGenerator to also generate the power of each process tree.
+    aggregate_by_tree: bool,
+    /// Tells MetricGenerator to also generate the power of each user.
+    aggregate_by_user: bool,
+    /// Tells MetricGenerator to also give each power in microwatts in watts.
+    watts: bool,
+    /// Maximum number of processes with their own series, the others being
+    /// aggregated, if set.
+    max_process_series: Option<usize>,
+    /// Tells MetricGenerator to also give the raw value of the energy counter
+    /// of each RAPL domain.
+    raw_energy: bool,
 }
 
 /// This is not mandatory to use MetricGenerator methods. Exporter can use dedicated
@@ -211,6 +267,12 @@ impl MetricGenerator {
                 pods,
                 pods_last_check: String::from(""),
                 //kubernetes_version,
+                label_providers: vec![],
+                aggregate_by_tree: false,
+                aggregate_by_user: false,
+                watts: false,
+                max_process_series: None,
+                raw_energy: false,
             }
         }
         #[cfg(not(feature = "containers"))]
@@ -219,18 +281,62 @@ impl MetricGenerator {
             topology,
             hostname,
             #[cfg(target_os = "linux")]
-            qemu,
+            qemu: _qemu,
+            label_providers: vec![],
+            aggregate_by_tree: false,
+            aggregate_by_user: false,
+            watts: false,
+            max_process_series: None,
+            raw_energy: false,
         }
     }
 
+    /// Sets the label providers whose labels are attached to every metric.
+    fn set_label_providers(&mut self, label_providers: Vec<Box<dyn LabelProvider>>) {
+        self.label_providers = label_providers;
+    }
+
+    /// Tells MetricGenerator to generate, for each process, the power of the
+    /// process and all its descendants.
+    fn set_aggregate_by_tree(&mut self, aggregate_by_tree: bool) {
+        self.aggregate_by_tree = aggregate_by_tree;
+    }
+
+    /// Tells MetricGenerator to generate the power of all the processes of each user.
+    fn set_aggregate_by_user(&mut self, aggregate_by_user: bool) {
+        self.aggregate_by_user = aggregate_by_user;
+    }
+
+    /// Limits the number of processes with their own series to the
+    /// `max_process_series` top consumers. The power of the others is
+    /// aggregated in the series of the "other" processes.
+    fn set_max_process_series(&mut self, max_process_series: Option<usize>) {
+        self.max_process_series = max_process_series;
+    }
+
+    /// Tells MetricGenerator to generate the raw values of the energy counters
+    /// of the RAPL domains, and the range after which they wrap.
+    fn set_raw_energy(&mut self, raw_energy: bool) {
+        self.raw_energy = raw_energy;
+    }
+
+    /// Tells MetricGenerator to pop, along with each metric in microwatts,
+    /// the same metric in watts.
+    fn set_watts(&mut self, watts: bool) {
+        self.watts = watts;
+    }
+
+    /// Returns the labels currently given by the label providers.
+    fn get_labels(&self) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        merge_labels(&self.label_providers, &mut labels);
+        labels
+    }
+
     /// Generate all scaphandre internal metrics.
     fn gen_self_metrics(&mut self) {
-        #[cfg(target_os = "linux")]
-        let myself = IProcess::myself().unwrap();
-        #[cfg(target_os = "windows")]
-        let myself = IProcess::myself(self.topology.get_proc_tracker()).unwrap();
-
-        let default_timestamp = current_system_time_since_epoch();
+        let self_metrics = SelfMetrics::collect(&self.topology);
+        let default_timestamp = self_metrics.timestamp;
         self.data.push(Metric {
             name: String::from("scaph_self_version"),
             metric_type: String::from("gauge"),
@@ -241,13 +347,36 @@ impl MetricGenerator {
             tags: vec!["scaphandre".to_string()],
             attributes: HashMap::new(),
             description: String::from("Version number of scaphandre represented as a float."),
-            metric_value: MetricValueType::Text(get_scaphandre_version()),
+            metric_value: MetricValueType::Text(self_metrics.version.clone()),
         });
 
-        if let Some(metric_value) = self
-            .topology
-            .get_process_cpu_consumption_percentage(myself.pid)
-        {
+        let basis = &self.topology.attribution_basis;
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("basis"), String::from(basis.as_str()));
+        attributes.insert(
+            String::from("system_time_weight"),
+            basis.system_weight().to_string(),
+        );
+        attributes.insert(
+            String::from("strategy"),
+            String::from(self.topology.attribution_strategy.name()),
+        );
+        self.data.push(Metric {
+            name: String::from("scaph_self_attribution_basis_info"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            timestamp: default_timestamp,
+            tags: vec!["scaphandre".to_string()],
+            attributes,
+            description: String::from(
+                "Always 1, labeled with the CPU time of processes their power is attributed from, and the strategy dividing it.",
+            ),
+            metric_value: MetricValueType::IntUnsigned(1),
+        });
+
+        if let Some(metric_value) = &self_metrics.cpu_usage_percent {
             self.data.push(Metric {
                 name: String::from("scaph_self_cpu_usage_percent"),
                 metric_type: String::from("gauge"),
@@ -264,8 +393,24 @@ impl MetricGenerator {
             });
         }
 
-        if let Ok(metric_value) = myself.statm() {
-            let value = metric_value.size * page_size().unwrap() as u64;
+        if let Some(power) = &self_metrics.power_microwatts {
+            self.data.push(Metric {
+                name: String::from("scaph_self_power_microwatts"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: power.timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from(
+                    "Power consumption attributed to scaphandre itself, in microwatts.",
+                ),
+                metric_value: MetricValueType::Text(power.value.clone()),
+            });
+        }
+
+        if let Some(memory) = &self_metrics.memory {
             self.data.push(Metric {
                 name: String::from("scaph_self_mem_total_program_size"),
                 metric_type: String::from("gauge"),
@@ -276,10 +421,9 @@ impl MetricGenerator {
                 tags: vec!["scaphandre".to_string()],
                 attributes: HashMap::new(),
                 description: String::from("Total program size, measured in bytes."),
-                metric_value: MetricValueType::IntUnsigned(value),
+                metric_value: MetricValueType::IntUnsigned(memory.total_program_size_bytes),
             });
 
-            let value = metric_value.resident * page_size().unwrap() as u64;
             self.data.push(Metric {
                 name: String::from("scaph_self_mem_resident_set_size"),
                 metric_type: String::from("gauge"),
@@ -290,10 +434,9 @@ impl MetricGenerator {
                 tags: vec!["scaphandre".to_string()],
                 attributes: HashMap::new(),
                 description: String::from("Resident set size, measured in bytes."),
-                metric_value: MetricValueType::IntUnsigned(value),
+                metric_value: MetricValueType::IntUnsigned(memory.resident_set_size_bytes),
             });
 
-            let value = metric_value.shared * page_size().unwrap() as u64;
             self.data.push(Metric {
                 name: String::from("scaph_self_mem_shared_resident_size"),
                 metric_type: String::from("gauge"),
@@ -306,14 +449,10 @@ impl MetricGenerator {
                 description: String::from(
                     "Number of resident shared bytes (i.e., backed by a file).",
                 ),
-                metric_value: MetricValueType::IntUnsigned(value),
+                metric_value: MetricValueType::IntUnsigned(memory.shared_resident_size_bytes),
             });
         }
 
-        let topo_stat_buffer_len = self.topology.stat_buffer.len();
-        let topo_record_buffer_len = self.topology.record_buffer.len();
-        let topo_procs_len = self.topology.proc_tracker.procs.len();
-
         self.data.push(Metric {
             name: String::from("scaph_self_topo_stats_nb"),
             metric_type: String::from("gauge"),
@@ -324,7 +463,7 @@ impl MetricGenerator {
             tags: vec!["scaphandre".to_string()],
             attributes: HashMap::new(),
             description: String::from("Number of CPUStat traces stored for the host."),
-            metric_value: MetricValueType::IntUnsigned(topo_stat_buffer_len as u64),
+            metric_value: MetricValueType::IntUnsigned(self_metrics.topo_stats_nb as u64),
         });
 
         self.data.push(Metric {
@@ -337,7 +476,7 @@ impl MetricGenerator {
             tags: vec!["scaphandre".to_string()],
             attributes: HashMap::new(),
             description: String::from("Number of energy consumption Records stored for the host."),
-            metric_value: MetricValueType::IntUnsigned(topo_record_buffer_len as u64),
+            metric_value: MetricValueType::IntUnsigned(self_metrics.topo_records_nb as u64),
         });
 
         self.data.push(Metric {
@@ -350,12 +489,60 @@ impl MetricGenerator {
             tags: vec!["scaphandre".to_string()],
             attributes: HashMap::new(),
             description: String::from("Number of processes monitored for the host."),
-            metric_value: MetricValueType::IntUnsigned(topo_procs_len as u64),
+            metric_value: MetricValueType::IntUnsigned(self_metrics.topo_procs_nb as u64),
         });
 
-        for socket in &self.topology.sockets {
+        self.data.push(Metric {
+            name: String::from("scaph_self_buffers_size_bytes"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: default_timestamp,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes: HashMap::new(),
+            description: String::from(
+                "Size in memory of the records and stats stored for the host, its sockets, domains and processes.",
+            ),
+            metric_value: MetricValueType::IntUnsigned(self_metrics.buffers_size_bytes as u64),
+        });
+
+        for (kind, count) in &self_metrics.anomaly_counts {
             let mut attributes = HashMap::new();
-            attributes.insert("socket_id".to_string(), socket.id.to_string());
+            attributes.insert(String::from("kind"), String::from(*kind));
+            self.data.push(Metric {
+                name: String::from("scaph_self_anomaly_count"),
+                metric_type: String::from("counter"),
+                ttl: 60.0,
+                timestamp: default_timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes,
+                description: String::from(
+                    "Number of implausible measurements detected since the start, by kind.",
+                ),
+                metric_value: MetricValueType::IntUnsigned(*count),
+            });
+        }
+
+        self.data.push(Metric {
+            name: String::from("scaph_self_buffers_evictions_total"),
+            metric_type: String::from("counter"),
+            ttl: 60.0,
+            timestamp: default_timestamp,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes: HashMap::new(),
+            description: String::from(
+                "Number of records and stats evicted to keep the buffers under --max-buffers-size-kB.",
+            ),
+            metric_value: MetricValueType::IntUnsigned(self_metrics.buffers_evictions),
+        });
+
+        for socket in &self_metrics.sockets {
+            let mut attributes: HashMap<String, String> = socket.labels.iter().cloned().collect();
 
             self.data.push(Metric {
                 name: String::from("scaph_self_socket_stats_nb"),
@@ -367,7 +554,7 @@ impl MetricGenerator {
                 tags: vec!["scaphandre".to_string()],
                 attributes: attributes.clone(),
                 description: String::from("Number of CPUStat traces stored for each socket"),
-                metric_value: MetricValueType::IntUnsigned(socket.stat_buffer.len() as u64),
+                metric_value: MetricValueType::IntUnsigned(socket.stats_nb as u64),
             });
 
             self.data.push(Metric {
@@ -382,11 +569,11 @@ impl MetricGenerator {
                 description: String::from(
                     "Number of energy consumption Records stored for each socket",
                 ),
-                metric_value: MetricValueType::IntUnsigned(socket.record_buffer.len() as u64),
+                metric_value: MetricValueType::IntUnsigned(socket.records_nb as u64),
             });
 
-            for domain in &socket.domains {
-                attributes.insert("rapl_domain_name".to_string(), domain.name.to_string());
+            for (domain_name, records_nb) in &socket.domains_records_nb {
+                attributes.insert("rapl_domain_name".to_string(), domain_name.to_string());
 
                 self.data.push(Metric {
                     name: String::from("scaph_self_domain_records_nb"),
@@ -400,7 +587,7 @@ impl MetricGenerator {
                     description: String::from(
                         "Number of energy consumption Records stored for a Domain",
                     ),
-                    metric_value: MetricValueType::IntUnsigned(domain.record_buffer.len() as u64),
+                    metric_value: MetricValueType::IntUnsigned(*records_nb as u64),
                 });
             }
         }
@@ -408,6 +595,31 @@ impl MetricGenerator {
 
     /// Generate host metrics.
     fn gen_host_metrics(&mut self) {
+        let info = self.topology.get_host_info();
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("cpu_model"), info.cpu_model);
+        attributes.insert(String::from("kernel"), info.kernel);
+        attributes.insert(String::from("hostname"), info.hostname);
+        attributes.insert(String::from("sockets"), info.sockets.to_string());
+        attributes.insert(
+            String::from("power_source"),
+            String::from(self.topology.get_host_power_source()),
+        );
+        self.data.push(Metric {
+            name: String::from("scaph_host_info"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: current_system_time_since_epoch(),
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes,
+            description: String::from(
+                "Static information about the host (CPU model, kernel version, hostname, number of sockets and what the host power is measured from) as labels, always 1.",
+            ),
+            metric_value: MetricValueType::IntUnsigned(1),
+        });
+
         let records = self.topology.get_records_passive();
 
         // metrics
@@ -430,7 +642,46 @@ impl MetricGenerator {
                     metric_value: MetricValueType::Text(host_energy_microjoules),
                 });
 
-            if let Some(power) = self.topology.get_records_diff_power_microwatts() {
+            if let Some((timestamp, microjoules)) = self
+                .topology
+                .get_total_energy_microjoules_since_start()
+                .and_then(|energy| Some((energy.timestamp, energy.value_u64()?)))
+            {
+                self.data.push(Metric {
+                    name: String::from("scaph_host_energy_since_start_microjoules"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: String::from(
+                        "Energy consumed by the whole host since scaphandre started, in microjoules.",
+                    ),
+                    metric_value: MetricValueType::IntUnsigned(microjoules),
+                });
+            }
+
+            if let Some(power) = self.topology.get_host_power_microwatts() {
+                let smoothed = self.topology.get_smoothed_host_power_microwatts();
+                if smoothed.is_some() {
+                    self.data.push(Metric {
+                        name: String::from("scaph_host_power_raw_microwatts"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: power.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: HashMap::new(),
+                        description: String::from(
+                            "Power measurement on the whole host, before smoothing, in microwatts",
+                        ),
+                        metric_value: MetricValueType::Text(power.value.clone()),
+                    });
+                }
+                let power = smoothed.unwrap_or(power);
                 self.data.push(Metric {
                     name: String::from("scaph_host_power_microwatts"),
                     metric_type: String::from("gauge"),
@@ -444,6 +695,133 @@ impl MetricGenerator {
                     metric_value: MetricValueType::Text(power.value),
                 });
             }
+
+            if let Some(interval) = self.topology.get_records_diff_interval() {
+                self.data.push(Metric {
+                    name: String::from("scaph_host_power_interval_seconds"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: record.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: String::from("Time actually elapsed between the two measurements the host power is computed from, in seconds"),
+                    metric_value: MetricValueType::FloatDouble(interval.as_secs_f64()),
+                });
+            }
+
+            let extremes = self.topology.get_host_power_extremes();
+            for (name, value, description) in [
+                (
+                    "scaph_host_power_max_microwatts",
+                    extremes.get_max_microwatts(),
+                    "Highest power measured on the whole host since scaphandre started or the extremes were reset, in microwatts",
+                ),
+                (
+                    "scaph_host_power_min_microwatts",
+                    extremes.get_min_microwatts(),
+                    "Lowest power measured on the whole host since scaphandre started or the extremes were reset, in microwatts",
+                ),
+            ] {
+                if let Some(value) = value {
+                    self.data.push(Metric {
+                        name: String::from(name),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: record.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: HashMap::new(),
+                        description: String::from(description),
+                        metric_value: MetricValueType::IntUnsigned(value),
+                    });
+                }
+            }
+
+            if let Some(power) = self.topology.get_power_per_core_microwatts() {
+                self.data.push(Metric {
+                    name: String::from("scaph_host_power_per_core_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: String::from("Power measurement on the whole host divided by its number of logical cores, in microwatts"),
+                    metric_value: MetricValueType::Text(power.value),
+                });
+            }
+
+            let mut cores_by_type: HashMap<CoreType, u64> = HashMap::new();
+            for core_type in self.topology.get_core_types().into_values() {
+                *cores_by_type.entry(core_type).or_default() += 1;
+            }
+            for (core_type, cores) in cores_by_type {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("core_type"), core_type.to_string());
+                self.data.push(Metric {
+                    name: String::from("scaph_host_cores"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: record.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from("Number of logical cores of each type (performance or efficiency) of a hybrid CPU"),
+                    metric_value: MetricValueType::IntUnsigned(cores),
+                });
+            }
+
+            for (source, power) in self.topology.get_sources_power_microwatts() {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("source"), source);
+                self.data.push(Metric {
+                    name: String::from("scaph_source_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from("Power of the whole host measured by an additional sensor (like a smart plug), in microwatts"),
+                    metric_value: MetricValueType::Text(power.value),
+                });
+            }
+
+            if let Some(power) = self.topology.get_idle_power_microwatts() {
+                self.data.push(Metric {
+                    name: String::from("scaph_host_idle_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: String::from("Estimated idle (baseline) power of the whole host, over the baseline window, in microwatts"),
+                    metric_value: MetricValueType::Text(power.value),
+                });
+            }
+
+            if let Some(power) = self.topology.get_active_power_microwatts() {
+                self.data.push(Metric {
+                    name: String::from("scaph_host_active_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: HashMap::new(),
+                    description: String::from("Power of the whole host above its idle power, induced by the workloads, in microwatts"),
+                    metric_value: MetricValueType::Text(power.value),
+                });
+            }
         }
     }
 
@@ -458,7 +836,7 @@ impl MetricGenerator {
                 let metric_timestamp = metric.timestamp;
 
                 let mut attributes = HashMap::new();
-                attributes.insert("socket_id".to_string(), socket.id.to_string());
+                attributes.extend(socket.get_labels());
 
                 self.data.push(Metric {
                     name: String::from("scaph_socket_energy_microjoules"),
@@ -473,72 +851,343 @@ impl MetricGenerator {
                     metric_value: MetricValueType::Text(metric_value.clone()),
                 });
 
-                if let Some(power) = socket.get_records_diff_power_microwatts() {
-                    let socket_power_microwatts = &power.value;
-
+                if let Some(energy) = socket.get_total_energy_microjoules_since_start() {
                     self.data.push(Metric {
-                        name: String::from("scaph_socket_power_microwatts"),
-                        metric_type: String::from("gauge"),
+                        name: String::from("scaph_socket_energy_since_start_microjoules"),
+                        metric_type: String::from("counter"),
                         ttl: 60.0,
-                        timestamp: power.timestamp,
+                        timestamp: energy.timestamp,
                         hostname: self.hostname.clone(),
                         state: String::from("ok"),
                         tags: vec!["scaphandre".to_string()],
                         attributes: attributes.clone(),
                         description: String::from(
-                            "Power measurement relative to a CPU socket, in microwatts",
+                            "Energy consumed by a CPU socket since scaphandre started, in microjoules.",
+                        ),
+                        metric_value: MetricValueType::IntUnsigned(
+                            socket.energy_since_start_microjoules,
                         ),
-                        metric_value: MetricValueType::Text(socket_power_microwatts.clone()),
                     });
                 }
-            }
-            for domain in socket.get_domains_passive() {
-                let records = domain.get_records_passive();
-                if !records.is_empty() {
-                    let metric = records.last().unwrap();
-                    let metric_value = metric.value.clone();
-                    let metric_timestamp = metric.timestamp;
 
-                    let mut attributes = HashMap::new();
-                    attributes.insert("domain_name".to_string(), domain.name.clone());
-                    attributes.insert("domain_id".to_string(), domain.id.to_string());
-                    attributes.insert("socket_id".to_string(), socket.id.to_string());
+                if let Some(power) = socket.get_records_diff_power_microwatts() {
+                    let smoothed = socket.get_smoothed_power_microwatts();
+                    if smoothed.is_some() {
+                        self.data.push(Metric {
+                            name: String::from("scaph_socket_power_raw_microwatts"),
+                            metric_type: String::from("gauge"),
+                            ttl: 60.0,
+                            timestamp: power.timestamp,
+                            hostname: self.hostname.clone(),
+                            state: String::from("ok"),
+                            tags: vec!["scaphandre".to_string()],
+                            attributes: attributes.clone(),
+                            description: String::from(
+                                "Power measurement relative to a CPU socket, before smoothing, in microwatts",
+                            ),
+                            metric_value: MetricValueType::Text(power.value.clone()),
+                        });
+                    }
+                    let power = smoothed.unwrap_or(power);
+                    let socket_power_microwatts = &power.value;
 
                     self.data.push(Metric {
-                        name: String::from("scaph_domain_energy_microjoules"),
-                        metric_type: String::from("counter"),
+                        name: String::from("scaph_socket_power_microwatts"),
+                        metric_type: String::from("gauge"),
                         ttl: 60.0,
+                        timestamp: power.timestamp,
                         hostname: self.hostname.clone(),
-                        timestamp: metric_timestamp,
                         state: String::from("ok"),
                         tags: vec!["scaphandre".to_string()],
                         attributes: attributes.clone(),
                         description: String::from(
-                            "Domain related energy measurement in microjoules.",
+                            "Power measurement relative to a CPU socket, in microwatts",
                         ),
-                        metric_value: MetricValueType::Text(metric_value.clone()),
+                        metric_value: MetricValueType::Text(socket_power_microwatts.clone()),
                     });
+                }
 
-                    if let Some(power) = domain.get_records_diff_power_microwatts() {
-                        let domain_power_microwatts = &power.value;
+                let extremes = socket.get_power_extremes();
+                for (name, value, description) in [
+                    (
+                        "scaph_socket_power_max_microwatts",
+                        extremes.get_max_microwatts(),
+                        "Highest power measured on a CPU socket since scaphandre started or the extremes were reset, in microwatts",
+                    ),
+                    (
+                        "scaph_socket_power_min_microwatts",
+                        extremes.get_min_microwatts(),
+                        "Lowest power measured on a CPU socket since scaphandre started or the extremes were reset, in microwatts",
+                    ),
+                ] {
+                    if let Some(value) = value {
                         self.data.push(Metric {
-                            name: String::from("scaph_domain_power_microwatts"),
+                            name: String::from(name),
                             metric_type: String::from("gauge"),
                             ttl: 60.0,
+                            timestamp: metric_timestamp,
                             hostname: self.hostname.clone(),
-                            timestamp: power.timestamp,
                             state: String::from("ok"),
                             tags: vec!["scaphandre".to_string()],
                             attributes: attributes.clone(),
-                            description: String::from(
-                                "Power measurement relative to a RAPL Domain, in microwatts",
-                            ),
-                            metric_value: MetricValueType::Text(domain_power_microwatts.clone()),
+                            description: String::from(description),
+                            metric_value: MetricValueType::IntUnsigned(value),
                         });
                     }
                 }
-            }
-        }
+
+                if let Some(power_limit) = socket.get_power_limit_microwatts() {
+                    self.data.push(Metric {
+                        name: String::from("scaph_socket_power_limit_microwatts"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: metric_timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from(
+                            "Active power limit of a CPU socket, enforced by RAPL, in microwatts",
+                        ),
+                        metric_value: MetricValueType::IntUnsigned(power_limit),
+                    });
+                }
+
+                if let Some(fraction) = socket.get_power_fraction_of_limit() {
+                    self.data.push(Metric {
+                        name: String::from("scaph_socket_power_fraction_of_limit"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: metric_timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from(
+                            "Power of a CPU socket divided by its active power limit, or by its maximum power limit (TDP) if none is active",
+                        ),
+                        metric_value: MetricValueType::FloatDouble(fraction),
+                    });
+                }
+
+                if let Some(resolution) = socket.get_energy_resolution_microjoules() {
+                    self.data.push(Metric {
+                        name: String::from("scaph_rapl_energy_resolution_microjoules"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: metric_timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from(
+                            "Energy of one increment of the energy counter of a CPU socket, in microjoules",
+                        ),
+                        metric_value: MetricValueType::FloatDouble(resolution),
+                    });
+                }
+
+                if let Some(throttled) = socket.throttled {
+                    self.data.push(Metric {
+                        name: String::from("scaph_cpu_throttled"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: metric_timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from(
+                            "1 if the CPU socket was throttled (thermal event or power clamped to its limit) during the last measurement step, 0 otherwise",
+                        ),
+                        metric_value: MetricValueType::IntUnsigned(throttled as u64),
+                    });
+                }
+            }
+            let ratios_timestamp = socket
+                .get_records_diff_power_microwatts()
+                .map(|power| power.timestamp)
+                .unwrap_or_default();
+            for (domain_name, ratio) in socket.get_domains_power_ratios() {
+                let mut attributes = HashMap::new();
+                attributes.insert("domain".to_string(), domain_name);
+                attributes.extend(socket.get_labels());
+                self.data.push(Metric {
+                    name: String::from("scaph_domain_power_ratio"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    hostname: self.hostname.clone(),
+                    timestamp: ratios_timestamp,
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Fraction of the power of a CPU socket consumed by a RAPL domain (1 for the package when it's the only domain)",
+                    ),
+                    metric_value: MetricValueType::FloatDouble(ratio),
+                });
+            }
+            for domain in socket.get_domains_passive() {
+                let mut attributes = HashMap::new();
+                attributes.insert("domain_name".to_string(), domain.name.clone());
+                attributes.insert("domain_id".to_string(), domain.id.to_string());
+                attributes.extend(socket.get_labels());
+                self.data.push(Metric {
+                    name: String::from("scaph_domain_read_errors_total"),
+                    metric_type: String::from("counter"),
+                    ttl: 60.0,
+                    hostname: self.hostname.clone(),
+                    timestamp: current_system_time_since_epoch(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Number of failed reads of the energy counter of a RAPL domain since the start",
+                    ),
+                    metric_value: MetricValueType::IntUnsigned(domain.get_read_errors()),
+                });
+
+                let records = domain.get_records_passive();
+                if !records.is_empty() {
+                    let metric = records.last().unwrap();
+                    let metric_value = metric.value.clone();
+                    let metric_timestamp = metric.timestamp;
+
+                    let mut attributes = HashMap::new();
+                    attributes.insert("domain_name".to_string(), domain.name.clone());
+                    attributes.insert("domain_id".to_string(), domain.id.to_string());
+                    attributes.extend(socket.get_labels());
+
+                    self.data.push(Metric {
+                        name: String::from("scaph_domain_energy_microjoules"),
+                        metric_type: String::from("counter"),
+                        ttl: 60.0,
+                        hostname: self.hostname.clone(),
+                        timestamp: metric_timestamp,
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from(
+                            "Domain related energy measurement in microjoules.",
+                        ),
+                        metric_value: MetricValueType::Text(metric_value.clone()),
+                    });
+
+                    if let Some(resolution) = domain.get_energy_resolution_microjoules() {
+                        self.data.push(Metric {
+                            name: String::from("scaph_rapl_energy_resolution_microjoules"),
+                            metric_type: String::from("gauge"),
+                            ttl: 60.0,
+                            hostname: self.hostname.clone(),
+                            timestamp: metric_timestamp,
+                            state: String::from("ok"),
+                            tags: vec!["scaphandre".to_string()],
+                            attributes: attributes.clone(),
+                            description: String::from(
+                                "Energy of one increment of the energy counter of a RAPL Domain, in microjoules",
+                            ),
+                            metric_value: MetricValueType::FloatDouble(resolution),
+                        });
+                    }
+
+                    if self.raw_energy {
+                        if let Some(raw) = domain.get_raw_energy_uj() {
+                            self.data.push(Metric {
+                                name: String::from("scaph_rapl_energy_uj_raw"),
+                                metric_type: String::from("counter"),
+                                ttl: 60.0,
+                                hostname: self.hostname.clone(),
+                                timestamp: raw.timestamp,
+                                state: String::from("ok"),
+                                tags: vec!["scaphandre".to_string()],
+                                attributes: attributes.clone(),
+                                description: String::from(
+                                    "Last value read from the energy counter of a RAPL domain, in microjoules, wraps not handled",
+                                ),
+                                metric_value: MetricValueType::Text(raw.value),
+                            });
+                        }
+                        if let Some(max_range) = domain.get_max_energy_range_uj() {
+                            self.data.push(Metric {
+                                name: String::from("scaph_rapl_max_energy_range_uj"),
+                                metric_type: String::from("gauge"),
+                                ttl: 60.0,
+                                hostname: self.hostname.clone(),
+                                timestamp: metric_timestamp,
+                                state: String::from("ok"),
+                                tags: vec!["scaphandre".to_string()],
+                                attributes: attributes.clone(),
+                                description: String::from(
+                                    "Value after which the energy counter of a RAPL domain wraps around, in microjoules",
+                                ),
+                                metric_value: MetricValueType::IntUnsigned(max_range),
+                            });
+                        }
+                    }
+
+                    if let Some(power) = domain.get_records_diff_power_microwatts() {
+                        let domain_power_microwatts = &power.value;
+                        self.data.push(Metric {
+                            name: String::from("scaph_domain_power_microwatts"),
+                            metric_type: String::from("gauge"),
+                            ttl: 60.0,
+                            hostname: self.hostname.clone(),
+                            timestamp: power.timestamp,
+                            state: String::from("ok"),
+                            tags: vec!["scaphandre".to_string()],
+                            attributes: attributes.clone(),
+                            description: String::from(
+                                "Power measurement relative to a RAPL Domain, in microwatts",
+                            ),
+                            metric_value: MetricValueType::Text(domain_power_microwatts.clone()),
+                        });
+                        if domain.is_igpu() {
+                            let mut attributes = HashMap::new();
+                            attributes.extend(socket.get_labels());
+                            self.data.push(Metric {
+                                name: String::from("scaph_igpu_power_microwatts"),
+                                metric_type: String::from("gauge"),
+                                ttl: 60.0,
+                                hostname: self.hostname.clone(),
+                                timestamp: power.timestamp,
+                                state: String::from("ok"),
+                                tags: vec!["scaphandre".to_string()],
+                                attributes,
+                                description: String::from(
+                                    "Power consumed by the uncore part of the CPU, including the integrated GPU, in microwatts",
+                                ),
+                                metric_value: MetricValueType::Text(power.value.clone()),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(temperatures) = self.topology.read_cpu_temperatures() {
+            let timestamp = current_system_time_since_epoch();
+            for temperature in temperatures {
+                let mut attributes = HashMap::new();
+                if let Some(socket_id) = temperature.socket_id {
+                    attributes.insert("socket_id".to_string(), socket_id.to_string());
+                }
+                attributes.insert("sensor".to_string(), temperature.sensor);
+                self.data.push(Metric {
+                    name: String::from("scaph_cpu_temperature_celsius"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Temperature of the CPU package (socket_id label) or of a CPU sensor, in celsius degrees",
+                    ),
+                    metric_value: MetricValueType::FloatDouble(temperature.celsius),
+                });
+            }
+        }
     }
 
     /// Generate system metrics.
@@ -603,6 +1252,21 @@ impl MetricGenerator {
                 metric_value: MetricValueType::IntUnsigned(metric_value),
             });
         }
+
+        if let Some(metric_value) = self.topology.read_nb_interrupts_total_count() {
+            self.data.push(Metric {
+                name: String::from("scaph_host_interrupts_total"),
+                metric_type: String::from("counter"),
+                ttl: 60.0,
+                timestamp: default_timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from("Number of interrupts serviced on the host since boot, from the intr line of /proc/stat. High interrupt rates drive uncore power up."),
+                metric_value: MetricValueType::IntUnsigned(metric_value),
+            });
+        }
     }
 
     /// If *self.watch_docker* is true and *self.docker_client* is Some
@@ -703,9 +1367,33 @@ impl MetricGenerator {
         }
         debug!("Before loop.");
 
+        let self_pid = std::process::id() as i32;
+        let kept = self.max_process_series.map(|max_process_series| {
+            let powers: Vec<(i32, f64)> = self
+                .topology
+                .proc_tracker
+                .get_alive_pids()
+                .into_iter()
+                .filter(|pid| !(self.topology.exclude_self && *pid == self_pid))
+                .filter_map(|pid| {
+                    let power = self
+                        .topology
+                        .get_process_power_consumption_microwatts(pid)?;
+                    Some((pid, power.value_f64()?))
+                })
+                .collect();
+            top_consumers(&powers, max_process_series)
+        });
+        let mut other = OtherProcessesPower::default();
+        let mut truncated = 0;
+        self.topology.proc_tracker.cache_process_labels();
         for pid in self.topology.proc_tracker.get_alive_pids() {
+            // scaphandre's own power is reported as scaph_self_power_microwatts instead
+            if self.topology.exclude_self && pid == self_pid {
+                continue;
+            }
+            let collapsed = matches!(&kept, Some(kept) if !kept.contains(&pid));
             let exe = self.topology.proc_tracker.get_process_name(pid);
-            let cmdline = self.topology.proc_tracker.get_process_cmdline(pid);
 
             let mut attributes = HashMap::new();
             debug!("Working on {}: {}", pid, exe);
@@ -730,23 +1418,95 @@ impl MetricGenerator {
                 }
             }
 
-            attributes.insert("pid".to_string(), pid.to_string());
-
-            attributes.insert("exe".to_string(), exe.clone());
+            // pid, ns_pid and the identity labels
+            for (key, value) in self
+                .topology
+                .proc_tracker
+                .get_process_labels(pid)
+                .into_iter()
+                .flatten()
+            {
+                let value = match key.as_str() {
+                    "cmdline" => utils::filter_cmdline(value),
+                    _ => value.clone(),
+                };
+                attributes.insert(key.clone(), value);
+            }
+            // the pods of the kubernetes API already are in the kubernetes_pod_* labels
+            if !attributes.contains_key("kubernetes_pod_name") {
+                attributes.extend(self.topology.proc_tracker.get_process_pod_labels(pid));
+            }
 
-            if let Some(cmdline_str) = cmdline {
-                attributes.insert("cmdline".to_string(), utils::filter_cmdline(&cmdline_str));
+            #[cfg(target_os = "linux")]
+            if self.qemu {
+                let cmdline = self.topology.proc_tracker.get_process_cmdline(pid);
+                if let Some(vmname) = cmdline.as_deref().and_then(utils::filter_qemu_cmdline) {
+                    attributes.insert("vmname".to_string(), vmname);
+                }
+            }
 
-                #[cfg(target_os = "linux")]
-                if self.qemu {
-                    if let Some(vmname) = utils::filter_qemu_cmdline(&cmdline_str) {
-                        attributes.insert("vmname".to_string(), vmname);
-                    }
+            if self.aggregate_by_tree && !collapsed {
+                if let Some(power) = self.topology.get_process_tree_power_microwatts(pid) {
+                    self.data.push(Metric {
+                        name: String::from("scaph_process_tree_power_consumption_microwatts"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: power.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from("Power consumption due to the process and all its descendants, in microwatts"),
+                        metric_value: MetricValueType::Text(power.value),
+                    });
                 }
             }
 
             let metric_name = String::from("scaph_process_power_consumption_microwatts");
-            if let Some(power) = self.topology.get_process_power_consumption_microwatts(pid) {
+            if collapsed {
+                if let Some(power) = self.topology.get_process_power_consumption_microwatts(pid) {
+                    truncated += 1;
+                    other.add(&power);
+                }
+                continue;
+            }
+            if let Some(power) = self.topology.get_process_power_over_min(pid, &mut other) {
+                if let Some((timestamp, microjoules)) = self
+                    .topology
+                    .get_process_energy_microjoules(pid)
+                    .and_then(|energy| Some((energy.timestamp, energy.value_u64()?)))
+                {
+                    self.data.push(Metric {
+                        name: String::from("scaph_process_energy_microjoules"),
+                        metric_type: String::from("counter"),
+                        ttl: 60.0,
+                        timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from(
+                            "Energy consumed by the process since scaphandre first saw it, in microjoules",
+                        ),
+                        metric_value: MetricValueType::IntUnsigned(microjoules),
+                    });
+                }
+                if let Some(age) = self.topology.proc_tracker.get_process_age(pid) {
+                    self.data.push(Metric {
+                        name: String::from("scaph_process_age_seconds"),
+                        metric_type: String::from("gauge"),
+                        ttl: 60.0,
+                        timestamp: power.timestamp,
+                        hostname: self.hostname.clone(),
+                        state: String::from("ok"),
+                        tags: vec!["scaphandre".to_string()],
+                        attributes: attributes.clone(),
+                        description: String::from(
+                            "Time the process has been running for, in seconds",
+                        ),
+                        metric_value: MetricValueType::FloatDouble(age.as_secs_f64()),
+                    });
+                }
                 self.data.push(Metric {
                     name: metric_name,
                     metric_type: String::from("gauge"),
@@ -761,47 +1521,348 @@ impl MetricGenerator {
                 });
             }
         }
+        if let Some(metric) = self.gen_other_processes_metric(
+            String::from("scaph_process_power_consumption_microwatts"),
+            &other,
+        ) {
+            self.data.push(metric);
+        }
+        if self.max_process_series.is_some() {
+            self.data.push(Metric {
+                name: String::from("scaph_series_truncated"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp: current_system_time_since_epoch(),
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from("Number of processes aggregated in the \"other\" series because they are not among the top consumers kept by --max-series, 0 when the limit isn't reached"),
+                metric_value: MetricValueType::IntUnsigned(truncated),
+            });
+        }
+    }
+
+    /// Returns the metric of the processes aggregated under
+    /// `--min-power-microwatts`, named `name`, if there are any.
+    fn gen_other_processes_metric(
+        &self,
+        name: String,
+        other: &OtherProcessesPower,
+    ) -> Option<Metric> {
+        let power = other.to_record()?;
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("pid"), String::from(OTHER_PROCESSES));
+        attributes.insert(String::from("exe"), String::from(OTHER_PROCESSES));
+        attributes.insert(String::from("processes"), other.processes.to_string());
+        Some(Metric {
+            name,
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: power.timestamp,
+            hostname: self.hostname.clone(),
+            state: String::from("ok"),
+            tags: vec!["scaphandre".to_string()],
+            attributes,
+            description: String::from("Power consumption due to the process, measured on at the topology level, in microwatts"),
+            metric_value: MetricValueType::Text(power.value),
+        })
+    }
+
+    /// Generate user specific metrics: the power of all the processes of each user.
+    fn gen_user_metrics(&mut self) {
+        for uid in self.topology.proc_tracker.get_alive_uids() {
+            if let Some(power) = self.topology.get_user_power_consumption_microwatts(uid) {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("uid"), uid.to_string());
+                self.data.push(Metric {
+                    name: String::from("scaph_user_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Power consumption due to all the processes of the user, in microwatts",
+                    ),
+                    metric_value: MetricValueType::Text(power.value),
+                });
+            }
+        }
+    }
+
+    /// Generate cgroup specific metrics, if cgroup accounting is enabled in the topology.
+    #[cfg(target_os = "linux")]
+    fn gen_cgroup_metrics(&mut self) {
+        let cgroups = match &self.topology.cgroup_tracker {
+            Some(cgroup_tracker) => cgroup_tracker.get_cgroups(),
+            None => return,
+        };
+        for cgroup in cgroups {
+            if let Some(power) = self
+                .topology
+                .get_cgroup_power_consumption_microwatts(&cgroup)
+            {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("cgroup"), cgroup);
+                self.data.push(Metric {
+                    name: String::from("scaph_cgroup_power_consumption_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from("Power consumption due to the tasks of the cgroup and its descendants, based on the CPU time accounted by the kernel, in microwatts"),
+                    metric_value: MetricValueType::Text(power.value),
+                });
+            }
+        }
+    }
+
+    /// Generate the power metrics of systemd services, if service accounting is enabled.
+    #[cfg(target_os = "linux")]
+    fn gen_service_metrics(&mut self) {
+        let services = match &self.topology.service_tracker {
+            Some(service_tracker) => service_tracker.get_services(),
+            None => return,
+        };
+        for unit in services {
+            if let Some(power) = self
+                .topology
+                .get_service_power_consumption_microwatts(&unit)
+            {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("unit"), unit);
+                self.data.push(Metric {
+                    name: String::from("scaph_service_power_microwatts"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: power.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Power consumption of the processes of a systemd service, in microwatts",
+                    ),
+                    metric_value: MetricValueType::Text(power.value),
+                });
+            }
+        }
+    }
+
+    /// Generate C-states residency metrics, if enabled in the topology.
+    #[cfg(target_os = "linux")]
+    fn gen_cstate_metrics(&mut self) {
+        let states = match &self.topology.cstate_tracker {
+            Some(cstate_tracker) => cstate_tracker.get_states(),
+            None => return,
+        };
+        let core_types = self.topology.get_core_types();
+        for (core, state) in states {
+            if let Some(residency) = self.topology.get_cstate_residency_percent(core, &state) {
+                let mut attributes = HashMap::new();
+                attributes.insert(String::from("core"), core.to_string());
+                if let Some(core_type) = core_types.get(&core) {
+                    attributes.insert(String::from("core_type"), core_type.to_string());
+                }
+                attributes.insert(String::from("state"), state);
+                self.data.push(Metric {
+                    name: String::from("scaph_cpu_cstate_residency_percent"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp: residency.timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes,
+                    description: String::from(
+                        "Share of the time spent by the core in the C-state, in percent",
+                    ),
+                    metric_value: MetricValueType::Text(residency.value),
+                });
+            }
+        }
+    }
+
+    /// Generate the energy per instruction metrics of the host and its sockets,
+    /// if instructions are counted by the topology.
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    fn gen_instructions_metrics(&mut self) {
+        let timestamp = current_system_time_since_epoch();
+        if let Some(picojoules) = self.topology.get_picojoules_per_instruction() {
+            self.data.push(Metric {
+                name: String::from("scaph_host_picojoules_per_instruction"),
+                metric_type: String::from("gauge"),
+                ttl: 60.0,
+                timestamp,
+                hostname: self.hostname.clone(),
+                state: String::from("ok"),
+                tags: vec!["scaphandre".to_string()],
+                attributes: HashMap::new(),
+                description: String::from(
+                    "Energy spent by the host per instruction retired by its CPUs, in picojoules",
+                ),
+                metric_value: MetricValueType::FloatDouble(picojoules),
+            });
+        }
+        for socket in &self.topology.sockets {
+            if let Some(picojoules) = self.topology.get_socket_picojoules_per_instruction(socket) {
+                self.data.push(Metric {
+                    name: String::from("scaph_socket_picojoules_per_instruction"),
+                    metric_type: String::from("gauge"),
+                    ttl: 60.0,
+                    timestamp,
+                    hostname: self.hostname.clone(),
+                    state: String::from("ok"),
+                    tags: vec!["scaphandre".to_string()],
+                    attributes: socket.get_labels().into_iter().collect(),
+                    description: String::from(
+                        "Energy spent by the socket per instruction retired by its cores, in picojoules",
+                    ),
+                    metric_value: MetricValueType::FloatDouble(picojoules),
+                });
+            }
+        }
     }
 
     /// Generate all metrics provided by Scaphandre agent.
     fn gen_all_metrics(&mut self) {
-        info!(
-            "{}: Get self metrics",
-            Utc::now().format("%Y-%m-%dT%H:%M:%S")
-        );
-        self.gen_self_metrics();
-        info!(
-            "{}: Get host metrics",
-            Utc::now().format("%Y-%m-%dT%H:%M:%S")
-        );
-        self.gen_host_metrics();
-        info!(
-            "{}: Get socket metrics",
-            Utc::now().format("%Y-%m-%dT%H:%M:%S")
-        );
-        self.gen_socket_metrics();
-        info!(
-            "{}: Get system metrics",
-            Utc::now().format("%Y-%m-%dT%H:%M:%S")
-        );
-        self.gen_system_metrics();
-        info!(
-            "{}: Get process metrics",
-            Utc::now().format("%Y-%m-%dT%H:%M:%S")
-        );
-        self.gen_process_metrics();
+        self.gen_metrics_of_families(|_| true);
+    }
+
+    /// Generates the metrics of the families (`self`, `host`, `socket` and
+    /// `process`) `keeps` returns true for, so that the metrics a scrape
+    /// leaves out are not even computed.
+    fn gen_metrics_of_families(&mut self, keeps: impl Fn(&str) -> bool) {
+        if keeps("self") {
+            info!(
+                "{}: Get self metrics",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S")
+            );
+            self.gen_self_metrics();
+        }
+        if keeps("host") {
+            info!(
+                "{}: Get host metrics",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S")
+            );
+            self.gen_host_metrics();
+        }
+        if keeps("socket") {
+            info!(
+                "{}: Get socket metrics",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S")
+            );
+            self.gen_socket_metrics();
+        }
+        if keeps("host") {
+            info!(
+                "{}: Get system metrics",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S")
+            );
+            self.gen_system_metrics();
+        }
+        if keeps("process") {
+            info!(
+                "{}: Get process metrics",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S")
+            );
+            self.gen_process_metrics();
+            if self.aggregate_by_user {
+                self.gen_user_metrics();
+            }
+            #[cfg(target_os = "linux")]
+            self.gen_cgroup_metrics();
+            #[cfg(target_os = "linux")]
+            self.gen_service_metrics();
+        }
+        #[cfg(target_os = "linux")]
+        if keeps("socket") {
+            self.gen_cstate_metrics();
+        }
+        // energy per instruction is given for the host and for each socket
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        if keeps("host") || keeps("socket") {
+            self.gen_instructions_metrics();
+        }
         debug!("self_metrics: {:#?}", self.data);
     }
 
     pub fn pop_metrics(&mut self) -> Vec<Metric> {
         let mut res = vec![];
+        let mut watts = vec![];
         while !&self.data.is_empty() {
-            res.push(self.data.pop().unwrap())
+            let mut metric = self.data.pop().unwrap();
+            merge_labels(&self.label_providers, &mut metric.attributes);
+            if self.watts {
+                watts.extend(metric.to_watts());
+            }
+            res.push(metric)
         }
+        // after all the others, so that series of a metric stay together
+        res.extend(watts);
         res
     }
 }
 
+/// Returns the pids of the `max` processes consuming the most, from the power
+/// of each process.
+fn top_consumers(powers: &[(i32, f64)], max: usize) -> HashSet<i32> {
+    let mut powers = powers.to_vec();
+    // highest power first, then lowest pid so that the selection is stable
+    powers.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    powers.into_iter().take(max).map(|(pid, _)| pid).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_consumers_kept() {
+        let powers = [(1, 10.0), (2, 500.0), (3, 20.0), (4, 20.0)];
+        assert_eq!(top_consumers(&powers, 2), HashSet::from([2, 3]));
+        assert_eq!(top_consumers(&powers, 10).len(), 4);
+        assert!(top_consumers(&powers, 0).is_empty());
+    }
+
+    #[test]
+    fn power_converted_to_watts() {
+        let metric = |name: &str, value: MetricValueType| Metric {
+            name: String::from(name),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            hostname: String::from("host"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes: HashMap::new(),
+            description: String::from("Power of the host, in microwatts"),
+            metric_value: value,
+            timestamp: Duration::from_secs(1),
+        };
+        let watts = metric(
+            "scaph_host_power_microwatts",
+            MetricValueType::Text(String::from("12500000")),
+        )
+        .to_watts()
+        .unwrap();
+        assert_eq!(watts.name, "scaph_host_power_watts");
+        assert_eq!(watts.description, "Power of the host, in watts");
+        assert_eq!(format!("{}", watts.metric_value), "12.5");
+        assert!(metric(
+            "scaph_host_energy_microjoules",
+            MetricValueType::IntUnsigned(1)
+        )
+        .to_watts()
+        .is_none());
+    }
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
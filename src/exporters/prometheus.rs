@@ -2,26 +2,101 @@
 //!
 //! `PrometheusExporter` implementation, expose metrics to
 //! a [Prometheus](https://prometheus.io/) server.
-use super::utils::get_hostname;
+use super::labels::{get_label_option, get_label_provider_option, get_label_providers};
+use super::utils::{
+    apply_topology_options, exit_dry_run, get_dry_run_option, get_hostname_option, get_jitter,
+    get_jitter_option, get_measure_interval, get_measure_interval_option,
+    get_perf_instructions_option, get_watts_option, gzip, jittered_step, prepare_dry_run,
+    resolve_hostname, set_perf_instructions, topology_options, validate_integer, validate_number,
+    validate_positive_integer, validate_unsigned_integer, wait_for_next_step,
+};
 use crate::current_system_time_since_epoch;
-use crate::exporters::{Exporter, MetricGenerator, MetricValueType};
-use crate::sensors::{Sensor, Topology};
+use crate::exporters::{Exporter, Metric, MetricGenerator, MetricValueType};
+use crate::sensors::baseline::BaselineMethod;
+#[cfg(target_os = "linux")]
+use crate::sensors::cgroups::DEFAULT_CGROUP_ROOT;
+#[cfg(target_os = "linux")]
+use crate::sensors::cstates::DEFAULT_CPU_SYSFS_ROOT;
+use crate::sensors::Sensor;
 use chrono::Utc;
 use clap::{Arg, ArgMatches};
+use hyper::header::{ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use isahc::RequestExt;
 use std::convert::Infallible;
 use std::fmt::Write as _;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
 use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
-    sync::{Arc, Mutex},
-    time::Duration,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
 };
 
 /// Default ipv4/ipv6 address to expose the service is any
 const DEFAULT_IP_ADDRESS: &str = "::";
 
+/// Path answering 200 as long as the exporter is running, for liveness probes.
+const HEALTH_PATH: &str = "/health";
+
+/// Path answering 200 unless the last metrics collections failed, for readiness probes.
+const READY_PATH: &str = "/ready";
+
+/// Default number of consecutive failed collections from which the exporter isn't ready.
+const DEFAULT_READY_MAX_FAILURES: u64 = 3;
+
+/// Content type of the answers in the OpenMetrics text format.
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Maximum number of characters of the label names and values of an OpenMetrics exemplar.
+const MAX_EXEMPLAR_LABELS_CHARS: usize = 128;
+
+/// Families of metrics that can be filtered with the query parameters of a
+/// scrape, with the prefixes of the names of their metrics.
+const METRIC_FAMILIES: [(&str, &[&str]); 4] = [
+    (
+        "host",
+        &[
+            "scaph_host_",
+            "scaph_source_",
+            "scaph_processes_",
+            "scaph_context_switches_",
+            "scaph_forks_",
+        ],
+    ),
+    (
+        "socket",
+        &[
+            "scaph_socket_",
+            "scaph_domain_",
+            "scaph_rapl_",
+            "scaph_cpu_",
+            "scaph_igpu_",
+        ],
+    ),
+    (
+        "process",
+        &[
+            "scaph_process_",
+            "scaph_series_",
+            "scaph_user_",
+            "scaph_cgroup_",
+            "scaph_service_",
+        ],
+    ),
+    (
+        "self",
+        &["scaph_self_", "scaph_collection_", "scaph_scrape_"],
+    ),
+];
+
+/// Family of the metrics matching none of METRIC_FAMILIES.
+const OTHER_METRIC_FAMILY: &str = "other";
+
 /// Exporter that exposes metrics to an HTTP endpoint
 /// matching the Prometheus.io metrics format.
 pub struct PrometheusExporter {
@@ -46,16 +121,192 @@ impl Exporter for PrometheusExporter {
             "{}: Starting Prometheus exporter",
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
         );
-        println!("Press CTRL-C to stop scaphandre");
+        let dry_run = parameters.is_present("dry_run");
+        if !dry_run {
+            println!("Press CTRL-C to stop scaphandre");
+        }
 
-        runner(
+        let mut metric_generator = MetricGenerator::new(
             (*self.sensor.get_topology()).unwrap(),
-            parameters.value_of("address").unwrap().to_string(),
-            parameters.value_of("port").unwrap().to_string(),
-            parameters.value_of("suffix").unwrap().to_string(),
+            resolve_hostname(&parameters),
             parameters.is_present("qemu"),
             parameters.is_present("containers"),
-            get_hostname(),
+        );
+        metric_generator.set_label_providers(get_label_providers(&parameters));
+        metric_generator.set_watts(parameters.is_present("watts"));
+        apply_topology_options(&mut metric_generator.topology, &parameters);
+        set_perf_instructions(&mut metric_generator.topology, &parameters);
+        metric_generator.set_aggregate_by_tree(parameters.is_present("aggregate_by_tree"));
+        metric_generator.set_aggregate_by_user(parameters.is_present("aggregate_by_user"));
+        metric_generator.set_raw_energy(parameters.is_present("raw_energy"));
+        // the value is checked by the validator of the option
+        metric_generator.set_max_process_series(parameters.value_of("max_series").map(|max| {
+            max.parse()
+                .expect("Wrong max_series value, should be a number of processes")
+        }));
+        #[cfg(target_os = "linux")]
+        if parameters.is_present("cgroup_accounting") {
+            let root = metric_generator
+                .topology
+                .get_sysfs_path(DEFAULT_CGROUP_ROOT);
+            if let Err(e) = metric_generator.topology.enable_cgroup_accounting(&root) {
+                warn!("{}", e);
+            }
+        }
+        if parameters.is_present("process_energy") {
+            metric_generator.topology.enable_process_energy();
+        }
+        #[cfg(target_os = "linux")]
+        if parameters.is_present("service_accounting") {
+            let root = metric_generator
+                .topology
+                .get_sysfs_path(DEFAULT_CGROUP_ROOT);
+            if let Err(e) = metric_generator.topology.enable_service_accounting(&root) {
+                warn!("{}", e);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if parameters.is_present("cstate_residency") {
+            let root = metric_generator
+                .topology
+                .get_sysfs_path(DEFAULT_CPU_SYSFS_ROOT);
+            if let Err(e) = metric_generator.topology.enable_cstate_residency(&root) {
+                warn!("{}", e);
+            }
+        }
+
+        // the value is checked by the validator of the option
+        let grace = parameters
+            .value_of("stale_series_grace")
+            .unwrap()
+            .parse()
+            .expect("Wrong stale_series_grace value, should be a number of seconds");
+        metric_generator
+            .topology
+            .proc_tracker
+            .set_stale_process_grace(Some(Duration::from_secs(grace)));
+
+        // the values are checked by the validators of the options
+        if let Some(window) = parameters
+            .value_of("idle_baseline_window")
+            .and_then(|window| window.parse::<u64>().ok())
+        {
+            let method = parameters
+                .value_of("idle_baseline_percentile")
+                .and_then(|percentile| percentile.parse().ok())
+                .map_or(BaselineMethod::Minimum, BaselineMethod::Percentile);
+            if let Err(e) = metric_generator
+                .topology
+                .enable_power_baseline(Duration::from_secs(window), method)
+            {
+                warn!("{}", e);
+            }
+        }
+
+        let power_histogram = parameters
+            .value_of("power_buckets")
+            .map(|buckets| PowerHistogram {
+                // the value is checked by the validator of the option
+                buckets: parse_buckets(buckets).expect("Wrong --power-buckets value"),
+                replace_process_series: parameters.is_present("histogram_only"),
+            });
+
+        // the value is checked by the validator of the option
+        let step = || {
+            Duration::from_secs(
+                parameters
+                    .value_of("step")
+                    .unwrap()
+                    .parse()
+                    .expect("Wrong step value, should be a number of seconds"),
+            )
+        };
+
+        let push_gateway = parameters.value_of("push_gateway").map(|url| PushGateway {
+            group_url: format!(
+                "{}/metrics/job/{}/instance/{}",
+                url.trim_end_matches('/'),
+                parameters.value_of("push_job").unwrap(),
+                parameters
+                    .value_of("push_instance")
+                    .map(String::from)
+                    .unwrap_or_else(|| resolve_hostname(&parameters)),
+            ),
+            delete_on_exit: parameters.is_present("push_delete_on_exit"),
+        });
+
+        if dry_run {
+            prepare_dry_run(&mut metric_generator.topology);
+            let body = build_metrics_body(
+                &PowerMetrics::new(metric_generator, power_histogram, false),
+                false,
+                &MetricsFilter::default(),
+            );
+            print!("{body}");
+            if let Some(path) = parameters.value_of("textfile_path") {
+                let result = write_textfile(Path::new(path), &body).map_err(|e| e.to_string());
+                exit_dry_run(path, result);
+            }
+            if let Some(push_gateway) = push_gateway {
+                exit_dry_run(
+                    &push_gateway.group_url,
+                    push_group(&push_gateway.group_url, body),
+                );
+            }
+            let port = parameters.value_of("port").unwrap();
+            let result = parameters
+                .values_of("address")
+                .unwrap()
+                .try_for_each(|address| check_listen_address(address, port));
+            exit_dry_run(&format!("listening on port {port}"), result);
+        }
+
+        if let Some(path) = parameters.value_of("textfile_path") {
+            textfile_writer(
+                PowerMetrics::new(metric_generator, power_histogram, false),
+                Path::new(path),
+                step(),
+                get_measure_interval(&parameters),
+            );
+            return;
+        }
+
+        if let Some(push_gateway) = push_gateway {
+            pusher(
+                PowerMetrics::new(metric_generator, power_histogram, false),
+                push_gateway,
+                step(),
+                get_measure_interval(&parameters),
+                get_jitter(&parameters),
+            );
+            return;
+        }
+
+        // the value is checked by the validator of the option
+        let ready_max_failures = parameters
+            .value_of("ready_max_failures")
+            .unwrap()
+            .parse()
+            .expect("Wrong ready_max_failures value, should be a number");
+        runner(
+            PowerMetrics::new(
+                metric_generator,
+                power_histogram,
+                parameters.is_present("openmetrics"),
+            )
+            .with_ready_max_failures(ready_max_failures),
+            parameters
+                .values_of("address")
+                .unwrap()
+                .map(String::from)
+                .collect(),
+            parameters.value_of("port").unwrap().to_string(),
+            parameters
+                .value_of("suffix")
+                .unwrap()
+                .trim_start_matches('/')
+                .to_string(),
+            get_measure_interval(&parameters),
         );
     }
     /// Returns options understood by the exporter.
@@ -63,26 +314,36 @@ impl Exporter for PrometheusExporter {
         let mut options = Vec::new();
         let arg = Arg::with_name("address")
             .default_value(DEFAULT_IP_ADDRESS)
-            .help("ipv6 or ipv4 address to expose the service to")
+            .help("ipv6 or ipv4 address to expose the service to, like 10.0.0.5 or [fd00::5]. Can be used multiple times to listen on several interfaces")
             .long("address")
+            .visible_alias("listen-address")
             .short("a")
             .required(false)
-            .takes_value(true);
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|address| match parse_listen_address(&address) {
+                Some(_) => Ok(()),
+                None => Err(format!("{address} is not a valid ip address")),
+            });
         options.push(arg);
 
         let arg = Arg::with_name("port")
             .default_value("8080")
             .help("TCP port number to expose the service")
             .long("port")
+            .visible_alias("listen-port")
             .short("p")
             .required(false)
-            .takes_value(true);
+            .takes_value(true)
+            .validator(|port| validate_integer::<u16>(&port, |_| true, "a TCP port number"));
         options.push(arg);
 
         let arg = Arg::with_name("suffix")
             .default_value("metrics")
-            .help("url suffix to access metrics")
+            .help("url path to access metrics, like metrics or /custom/metrics")
             .long("suffix")
+            .visible_alias("metrics-path")
             .short("s")
             .required(false)
             .takes_value(true);
@@ -126,6 +387,188 @@ impl Exporter for PrometheusExporter {
             .takes_value(true);
         options.push(arg);
 
+        let arg = Arg::with_name("process_energy")
+            .help("Expose the energy consumed by each process since scaphandre first saw it, as the scaph_process_energy_microjoules counter")
+            .long("process-energy")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("aggregate_by_tree")
+            .help("Also expose the power consumption of each process and all its descendants")
+            .long("aggregate-by-tree")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("aggregate_by_user")
+            .help("Also expose the power consumption of all the processes of each user (uid)")
+            .long("aggregate-by-user")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("power_buckets")
+            .help("Also expose a histogram of the power consumption of processes, with these comma separated bucket upper bounds, in microwatts (like 1000,10000,100000,1000000)")
+            .long("power-buckets")
+            .required(false)
+            .takes_value(true)
+            .validator(|buckets| parse_buckets(&buckets).map(|_| ()));
+        options.push(arg);
+
+        let arg = Arg::with_name("histogram_only")
+            .help("Expose the histogram of processes power instead of the series of each process (all the scaph_process_* metrics). Needs --power-buckets.")
+            .long("histogram-only")
+            .requires("power_buckets")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        // options live as long as the program, leaking the default value is fine
+        let default_ready_max_failures: &'static str =
+            Box::leak(DEFAULT_READY_MAX_FAILURES.to_string().into_boxed_str());
+        let arg = Arg::with_name("ready_max_failures")
+            .default_value(default_ready_max_failures)
+            .help("Number of consecutive failed metrics collections after which /ready answers 503 Service Unavailable")
+            .long("ready-max-failures")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_positive_integer);
+        options.push(arg);
+
+        let arg = Arg::with_name("openmetrics")
+            .help("Answer in the OpenMetrics text format to scrapers asking for it, with exemplars giving the pid, exe and cmdline of a process in each bucket of the processes power histogram (see --power-buckets)")
+            .long("openmetrics")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("push_gateway")
+            .help("Push metrics to this Prometheus Pushgateway (like http://pushgateway:9091) every step, instead of serving them over HTTP")
+            .long("push-gateway")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("textfile_path")
+            .help("Write metrics to this .prom file (like /var/lib/node_exporter/textfile/scaphandre.prom) every step, for the textfile collector of node_exporter, instead of serving them over HTTP")
+            .long("textfile-path")
+            .required(false)
+            .takes_value(true)
+            .conflicts_with("push_gateway");
+        options.push(arg);
+
+        let arg = Arg::with_name("step")
+            .default_value("5")
+            .help("Duration between two pushes to the Pushgateway, or two writes of the textfile, in seconds")
+            .long("step")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_positive_integer);
+        options.push(arg);
+
+        let arg = Arg::with_name("push_job")
+            .default_value("scaphandre")
+            .help("Job label of the group of metrics pushed to the Pushgateway")
+            .long("push-job")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("push_instance")
+            .help("Instance label of the group of metrics pushed to the Pushgateway. Defaults to the hostname.")
+            .long("push-instance")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("push_delete_on_exit")
+            .help("Delete the group of metrics from the Pushgateway when scaphandre is stopped (SIGINT or SIGTERM), so that they don't get stale")
+            .long("push-delete-on-exit")
+            .requires("push_gateway")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("max_series")
+            .help("Maximum number of processes with their own series. The others, consuming less, are aggregated in a series with pid=\"other\", and scaph_series_truncated tells how many.")
+            .long("max-series")
+            .required(false)
+            .takes_value(true)
+            .validator(|max| validate_integer::<usize>(&max, |_| true, "a number of processes"));
+        options.push(arg);
+
+        let arg = Arg::with_name("raw_energy")
+            .help("Also expose the raw values of the energy counters of the RAPL domains (scaph_rapl_energy_uj_raw), to check the computations of scaphandre.")
+            .long("raw-energy")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("stale_series_grace")
+            .default_value("0")
+            .help("Seconds after which the series of a process that is not seen anymore are removed from the metrics. By default they are removed as soon as a refresh doesn't see the process.")
+            .long("stale-series-grace")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_unsigned_integer);
+        options.push(arg);
+
+        let arg = Arg::with_name("idle_baseline_window")
+            .help("Estimate the idle power of the host over a rolling window of that many seconds, and also expose the power above it (active power)")
+            .long("idle-baseline-window")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_positive_integer);
+        options.push(arg);
+
+        let arg = Arg::with_name("idle_baseline_percentile")
+            .help("Percentile (between 0 and 100) of the power measured in the window used as idle power, instead of the minimum")
+            .long("idle-baseline-percentile")
+            .requires("idle_baseline_window")
+            .required(false)
+            .takes_value(true)
+            .validator(|percentile| {
+                validate_number(
+                    &percentile,
+                    |percentile| (0.0..=100.0).contains(&percentile),
+                    "a number between 0 and 100",
+                )
+            });
+        options.push(arg);
+
+        let arg = Arg::with_name("cstate_residency")
+            .help("Also expose the share of time spent by each core in each C-state (one series per core and C-state)")
+            .long("cstate-residency")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("cgroup_accounting")
+            .help("Also expose the power consumption of each cgroup, based on the CPU time accounted by the kernel (cpu.stat on cgroup v2, cpuacct.usage on cgroup v1)")
+            .long("cgroup-accounting")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("service_accounting")
+            .help("Also expose the power consumption of each systemd service, summing the power of the processes of its cgroup")
+            .long("service-accounting")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        options.push(get_measure_interval_option());
+        options.extend(topology_options());
+        options.push(get_hostname_option());
+
+        options.push(get_label_option());
+        options.push(get_label_provider_option());
+        options.push(get_watts_option());
+        options.push(get_perf_instructions_option());
+        options.push(get_dry_run_option());
+        options.push(get_jitter_option());
+
         options
     }
 }
@@ -135,60 +578,290 @@ impl Exporter for PrometheusExporter {
 struct PowerMetrics {
     last_request: Mutex<Duration>,
     metric_generator: Mutex<MetricGenerator>,
+    /// Time spent collecting metrics on the last scrape.
+    collection_duration: Mutex<Duration>,
+    /// Number of scrapes during which metrics collection failed.
+    scrape_errors: Mutex<u64>,
+    /// Histogram of processes power to expose, if asked.
+    power_histogram: Option<PowerHistogram>,
+    /// True to answer in the OpenMetrics format when the scraper accepts it.
+    openmetrics: bool,
+    /// Number of metrics collections that failed since the last successful one.
+    consecutive_failures: Mutex<u64>,
+    /// Number of consecutive failed collections from which the exporter isn't ready.
+    ready_max_failures: u64,
+}
+
+impl PowerMetrics {
+    /// Instantiates PowerMetrics for a MetricGenerator.
+    fn new(
+        metric_generator: MetricGenerator,
+        power_histogram: Option<PowerHistogram>,
+        openmetrics: bool,
+    ) -> PowerMetrics {
+        PowerMetrics {
+            last_request: Mutex::new(Duration::new(0, 0)),
+            metric_generator: Mutex::new(metric_generator),
+            collection_duration: Mutex::new(Duration::new(0, 0)),
+            scrape_errors: Mutex::new(0),
+            power_histogram,
+            openmetrics,
+            consecutive_failures: Mutex::new(0),
+            ready_max_failures: DEFAULT_READY_MAX_FAILURES,
+        }
+    }
+
+    /// Sets the number of consecutive failed collections from which /ready
+    /// answers that the exporter isn't ready.
+    fn with_ready_max_failures(mut self, ready_max_failures: u64) -> PowerMetrics {
+        self.ready_max_failures = ready_max_failures.max(1);
+        self
+    }
+
+    /// Returns true if the last metrics collections didn't all fail.
+    fn is_ready(&self) -> bool {
+        *self
+            .consecutive_failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            < self.ready_max_failures
+    }
+}
+
+/// Group of metrics to push to a Prometheus Pushgateway.
+struct PushGateway {
+    /// Url of the group, like `http://pushgateway:9091/metrics/job/scaphandre/instance/myhost`.
+    group_url: String,
+    /// True to delete the group when scaphandre is stopped.
+    delete_on_exit: bool,
+}
+
+/// Configuration of the histogram of processes power.
+struct PowerHistogram {
+    /// Upper bounds of the buckets, in microwatts, sorted.
+    buckets: Vec<f64>,
+    /// True to expose the histogram instead of one series per process.
+    replace_process_series: bool,
 }
 
 #[tokio::main]
 async fn runner(
-    topology: Topology,
-    address: String,
+    context: PowerMetrics,
+    addresses: Vec<String>,
     port: String,
     suffix: String,
-    qemu: bool,
-    watch_containers: bool,
-    hostname: String,
+    measure_interval: Option<Duration>,
 ) {
-    if let Ok(addr) = address.parse::<IpAddr>() {
-        if let Ok(port) = port.parse::<u16>() {
-            let socket_addr = SocketAddr::new(addr, port);
-
-            let power_metrics = PowerMetrics {
-                last_request: Mutex::new(Duration::new(0, 0)),
-                metric_generator: Mutex::new(MetricGenerator::new(
-                    topology,
-                    hostname.clone(),
-                    qemu,
-                    watch_containers,
-                )),
-            };
-            let context = Arc::new(power_metrics);
-            let make_svc = make_service_fn(move |_| {
-                let ctx = context.clone();
-                let sfx = suffix.clone();
-                async {
-                    Ok::<_, Infallible>(service_fn(move |req| {
-                        show_metrics(req, ctx.clone(), sfx.clone())
-                    }))
-                }
-            });
-            let server = Server::bind(&socket_addr);
-            let res = server.serve(make_svc);
-            let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-            let graceful = res.with_graceful_shutdown(async {
-                rx.await.ok();
-            });
+    // the values are checked by the validators of the options
+    let port = port
+        .parse::<u16>()
+        .unwrap_or_else(|_| panic!("{} is not a valid TCP port number", port));
+    let socket_addrs: Vec<SocketAddr> = addresses
+        .iter()
+        .map(|address| match parse_listen_address(address) {
+            Some(addr) => SocketAddr::new(addr, port),
+            None => panic!("{} is not a valid ip address", address),
+        })
+        .collect();
 
-            if let Err(e) = graceful.await {
-                error!("server error: {}", e);
+    let context = Arc::new(context);
+    if let Some(interval) = measure_interval.filter(|i| !i.is_zero()) {
+        // Measure between scrapes, the next scrape gets the consumption averaged since the previous one
+        let measure_context = context.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            measure_context
+                .metric_generator
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .topology
+                .refresh_intermediate();
+        });
+    }
+
+    let mut servers = vec![];
+    for socket_addr in socket_addrs {
+        // fail at startup rather than never answering scrapes
+        let server = match Server::try_bind(&socket_addr) {
+            Ok(server) => server,
+            Err(e) => {
+                error!("Couldn't listen on {}: {}", socket_addr, e);
+                std::process::exit(1);
             }
-            let _ = tx.send(());
-        } else {
-            panic!("{} is not a valid TCP port number", port);
+        };
+        let context = context.clone();
+        let suffix = suffix.clone();
+        let make_svc = make_service_fn(move |_| {
+            let ctx = context.clone();
+            let sfx = suffix.clone();
+            async {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    show_metrics(req, ctx.clone(), sfx.clone())
+                }))
+            }
+        });
+        servers.push(tokio::spawn(server.serve(make_svc)));
+    }
+    for server in servers {
+        match server.await {
+            Ok(Err(e)) => error!("server error: {}", e),
+            Err(e) => error!("server error: {}", e),
+            Ok(Ok(())) => {}
         }
-    } else {
-        panic!("{} is not a valid ip address", address);
     }
 }
 
+/// Parses an ipv4 or ipv6 address to listen on, accepting ipv6 literals in
+/// brackets, like `[::1]`.
+fn parse_listen_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim();
+    let address = address
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address);
+    address.parse().ok()
+}
+
+/// Tells if metrics could be served on `address` and `port`, by binding them
+/// and releasing them right away.
+fn check_listen_address(address: &str, port: &str) -> Result<(), String> {
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("{port} is not a valid TCP port number"))?;
+    let ip = parse_listen_address(address)
+        .ok_or_else(|| format!("{address} is not a valid ip address"))?;
+    let socket_addr = SocketAddr::new(ip, port);
+    std::net::TcpListener::bind(socket_addr)
+        .map(|_| ())
+        .map_err(|e| format!("couldn't listen on {socket_addr}: {e}"))
+}
+
+/// Pushes metrics to a Pushgateway every `step` (randomized by `jitter`),
+/// measuring every `measure_interval` meanwhile.
+fn pusher(
+    context: PowerMetrics,
+    push_gateway: PushGateway,
+    step: Duration,
+    measure_interval: Option<Duration>,
+    jitter: Option<f64>,
+) {
+    if push_gateway.delete_on_exit {
+        let group_url = push_gateway.group_url.clone();
+        thread::spawn(move || {
+            wait_for_termination();
+            if let Err(e) = delete_group(&group_url) {
+                error!("Couldn't delete metrics from the Pushgateway: {}", e);
+            }
+            std::process::exit(0);
+        });
+    }
+    println!("Pushing metrics to {}", push_gateway.group_url);
+    loop {
+        let body = build_metrics_body(&context, false, &MetricsFilter::default());
+        if let Err(e) = push_group(&push_gateway.group_url, body) {
+            error!("Couldn't push metrics to the Pushgateway: {}", e);
+        }
+        wait_for_next_step(
+            &mut context
+                .metric_generator
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .topology,
+            jittered_step(step, jitter),
+            measure_interval,
+        );
+    }
+}
+
+/// Writes metrics to the textfile at `path` every `step`, measuring every `measure_interval` meanwhile.
+fn textfile_writer(
+    context: PowerMetrics,
+    path: &Path,
+    step: Duration,
+    measure_interval: Option<Duration>,
+) {
+    println!("Writing metrics to {}", path.display());
+    loop {
+        let body = build_metrics_body(&context, false, &MetricsFilter::default());
+        if let Err(e) = write_textfile(path, &body) {
+            error!("Couldn't write metrics to {}: {}", path.display(), e);
+        }
+        wait_for_next_step(
+            &mut context
+                .metric_generator
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .topology,
+            step,
+            measure_interval,
+        );
+    }
+}
+
+/// Replaces the content of the file at `path` by `body`, writing a temporary
+/// file in the same folder first and renaming it, so that node_exporter never
+/// reads a half written file.
+fn write_textfile(path: &Path, body: &str) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+    fs::write(&temp_path, body)?;
+    if let Err(e) = fs::rename(&temp_path, path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Replaces the metrics of a group of the Pushgateway.
+fn push_group(group_url: &str, body: String) -> Result<(), String> {
+    let response = isahc::Request::put(group_url)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(body)
+        .map_err(|e| e.to_string())?
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Pushgateway answered {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Deletes a group of the Pushgateway.
+fn delete_group(group_url: &str) -> Result<(), String> {
+    let response = isahc::Request::delete(group_url)
+        .body(())
+        .map_err(|e| e.to_string())?
+        .send()
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Pushgateway answered {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Blocks until scaphandre receives SIGINT (CTRL-C), or SIGTERM on Unix.
+#[tokio::main(flavor = "current_thread")]
+async fn wait_for_termination() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = terminate.recv() => {},
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 /// Returns a well formatted Prometheus metric string.
 fn format_metric(key: &str, value: &str, labels: Option<&HashMap<String, String>>) -> String {
     let mut result = key.to_string();
@@ -204,6 +877,211 @@ fn format_metric(key: &str, value: &str, labels: Option<&HashMap<String, String>
     result
 }
 
+/// Parses comma separated histogram buckets upper bounds, which must be increasing.
+fn parse_buckets(buckets: &str) -> Result<Vec<f64>, String> {
+    let buckets = buckets
+        .split(',')
+        .map(|bucket| {
+            bucket
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| format!("'{bucket}' is not a number"))
+        })
+        .collect::<Result<Vec<f64>, String>>()?;
+    if buckets.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(String::from("buckets must be in increasing order"));
+    }
+    Ok(buckets)
+}
+
+/// Returns the Prometheus lines of a histogram of values: cumulative
+/// `_bucket` lines (including `+Inf`), `_sum` and `_count`.
+/// If the attributes of the observations are given (in the same order as
+/// `values`), each bucket gets an OpenMetrics exemplar: the highest value
+/// of the bucket, with its attributes.
+fn format_histogram(
+    name: &str,
+    values: &[f64],
+    buckets: &[f64],
+    labels: &HashMap<String, String>,
+    exemplars: Option<&[&HashMap<String, String>]>,
+) -> String {
+    let mut result = String::new();
+    let bucket_name = format!("{name}_bucket");
+    let mut bucket_labels = labels.clone();
+    let bounds = buckets
+        .iter()
+        .map(|bound| (*bound, bound.to_string()))
+        .chain([(f64::INFINITY, String::from("+Inf"))]);
+    let mut lower_bound = f64::NEG_INFINITY;
+    for (bound, le) in bounds {
+        let count = values.iter().filter(|v| **v <= bound).count();
+        bucket_labels.insert(String::from("le"), le);
+        let mut line = format_metric(&bucket_name, &count.to_string(), Some(&bucket_labels));
+        if let Some(exemplars) = exemplars {
+            let highest = values
+                .iter()
+                .zip(exemplars)
+                .filter(|(v, _)| **v > lower_bound && **v <= bound)
+                .max_by(|(a, _), (b, _)| a.total_cmp(b));
+            if let Some((value, attributes)) = highest {
+                line.pop();
+                let _ = writeln!(line, " # {} {}", format_exemplar_labels(attributes), value);
+            }
+        }
+        result.push_str(&line);
+        lower_bound = bound;
+    }
+    let labels = if labels.is_empty() {
+        None
+    } else {
+        Some(labels)
+    };
+    let sum: f64 = values.iter().sum();
+    result.push_str(&format_metric(
+        &format!("{name}_sum"),
+        &sum.to_string(),
+        labels,
+    ));
+    result.push_str(&format_metric(
+        &format!("{name}_count"),
+        &values.len().to_string(),
+        labels,
+    ));
+    result
+}
+
+/// Returns the labels of an OpenMetrics exemplar for a process: its pid, exe
+/// and cmdline, the cmdline being truncated to respect the limit of 128
+/// characters for the labels of an exemplar.
+fn format_exemplar_labels(attributes: &HashMap<String, String>) -> String {
+    let mut result = String::from("{");
+    let mut remaining = MAX_EXEMPLAR_LABELS_CHARS;
+    for key in ["pid", "exe", "cmdline"] {
+        if let Some(value) = attributes.get(key) {
+            if remaining <= key.len() {
+                break;
+            }
+            let value: String = value
+                .replace('\"', "_")
+                .chars()
+                .take(remaining - key.len())
+                .collect();
+            remaining -= key.len() + value.chars().count();
+            if result.len() > 1 {
+                result.push(',');
+            }
+            let _ = write!(result, "{key}=\"{value}\"");
+        }
+    }
+    result.push('}');
+    result
+}
+
+/// Returns the type of a metric family in the OpenMetrics format, and its name.
+/// OpenMetrics counters must end with `_total`, counters not following this
+/// rule are declared as unknown.
+fn openmetrics_family(metric_name: &str, metric_type: &str) -> (String, String) {
+    match (metric_type, metric_name.strip_suffix("_total")) {
+        ("counter", Some(family)) => (String::from(family), String::from("counter")),
+        ("counter", None) => (String::from(metric_name), String::from("unknown")),
+        _ => (String::from(metric_name), String::from(metric_type)),
+    }
+}
+
+/// Returns true if the scraper asks for the OpenMetrics text format.
+fn accepts_openmetrics(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/openmetrics-text"))
+}
+
+/// Returns true if the client accepts gzip compressed responses, that is if
+/// its `Accept-Encoding` header lists gzip (or *) without a zero quality.
+fn accepts_gzip(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or("").to_lowercase();
+            let refused = params.any(|param| {
+                param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+            (name == "gzip" || name == "*") && !refused
+        })
+}
+
+/// Returns the family of a metric, as given in the query parameters of a scrape.
+fn metric_family(metric_name: &str) -> &'static str {
+    METRIC_FAMILIES
+        .iter()
+        .find(|(_, prefixes)| {
+            prefixes
+                .iter()
+                .any(|prefix| metric_name.starts_with(prefix))
+        })
+        .map(|(family, _)| *family)
+        .unwrap_or(OTHER_METRIC_FAMILY)
+}
+
+/// Metric families asked for by a scrape, from the query parameters of
+/// `/metrics`: `include=host,socket` only returns these families, and
+/// `process=false` (or `exclude=process`) leaves a family out.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct MetricsFilter {
+    /// Families to return, all of them if None.
+    include: Option<Vec<String>>,
+    /// Families not to return.
+    exclude: Vec<String>,
+}
+
+impl MetricsFilter {
+    /// Parses the query string of a scrape. Unknown parameters are ignored.
+    fn from_query(query: Option<&str>) -> MetricsFilter {
+        let mut filter = MetricsFilter::default();
+        for param in query.unwrap_or("").split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = value.replace("%2C", ",").replace("%2c", ",");
+            let families = value
+                .split(',')
+                .map(str::trim)
+                .filter(|family| !family.is_empty())
+                .map(String::from);
+            match (key, value.as_str()) {
+                ("include", _) => filter.include.get_or_insert_with(Vec::new).extend(families),
+                ("exclude", _) => filter.exclude.extend(families),
+                (family, "false") => filter.exclude.push(family.to_string()),
+                (family, "true") => {
+                    if let Some(include) = &mut filter.include {
+                        include.push(family.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        filter
+    }
+
+    /// Returns true if the metric should be returned to the scraper.
+    fn keeps(&self, metric_name: &str) -> bool {
+        self.keeps_family(metric_family(metric_name))
+    }
+
+    /// Returns true if the metrics of `family` should be returned to the scraper.
+    fn keeps_family(&self, family: &str) -> bool {
+        let included = match &self.include {
+            Some(include) => include.iter().any(|f| f == family),
+            None => true,
+        };
+        included && !self.exclude.iter().any(|f| f == family)
+    }
+}
+
 /// Adds lines related to a metric in the body (String) of response.
 fn push_metric(
     mut body: String,
@@ -212,8 +1090,14 @@ fn push_metric(
     metric_name: String,
     metric_line: String,
     add_help: bool,
+    openmetrics: bool,
 ) -> String {
     if add_help {
+        let (metric_name, metric_type) = if openmetrics {
+            openmetrics_family(&metric_name, &metric_type)
+        } else {
+            (metric_name, metric_type)
+        };
         let _ = write!(body, "# HELP {metric_name} {help}");
         let _ = write!(body, "\n# TYPE {metric_name} {metric_type}\n");
     }
@@ -221,19 +1105,21 @@ fn push_metric(
     body
 }
 
-/// Handles requests and returns data formated for Prometheus.
-async fn show_metrics(
-    req: Request<Body>,
-    context: Arc<PowerMetrics>,
-    suffix: String,
-) -> Result<Response<Body>, Infallible> {
-    trace!("{}", req.uri());
+/// Refreshes the topology if needed, and returns the metrics kept by `filter` in the
+/// Prometheus text format, or in the OpenMetrics text format if `openmetrics` is true.
+fn build_metrics_body(context: &PowerMetrics, openmetrics: bool, filter: &MetricsFilter) -> String {
     let mut body = String::new();
-    if req.uri().path() == format!("/{}", &suffix) {
-        trace!("in metrics !");
-        let now = current_system_time_since_epoch();
-        let mut last_request = context.last_request.lock().unwrap();
-        let mut metric_generator = context.metric_generator.lock().unwrap();
+    let now = current_system_time_since_epoch();
+    let mut last_request = context
+        .last_request
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let mut metric_generator = context
+        .metric_generator
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let collection_start = Instant::now();
+    let collected = panic::catch_unwind(AssertUnwindSafe(|| {
         if now - (*last_request) > Duration::from_secs(2) {
             {
                 info!(
@@ -247,50 +1133,444 @@ async fn show_metrics(
                 metric_generator.topology.refresh();
             }
         }
-        *last_request = now;
 
         info!("{}: Refresh data", Utc::now().format("%Y-%m-%dT%H:%M:%S"));
 
-        metric_generator.gen_all_metrics();
+        metric_generator.gen_metrics_of_families(|family| filter.keeps_family(family));
+        metric_generator.pop_metrics()
+    }));
+    *last_request = now;
+    let mut collection_duration = context
+        .collection_duration
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    *collection_duration = collection_start.elapsed();
+    let mut scrape_errors = context
+        .scrape_errors
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let mut consecutive_failures = context
+        .consecutive_failures
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let metrics = match collected {
+        Ok(metrics) => {
+            *consecutive_failures = 0;
+            metrics
+        }
+        Err(_) => {
+            error!("Metrics collection failed, see error above.");
+            *scrape_errors += 1;
+            *consecutive_failures += 1;
+            vec![]
+        }
+    };
+    drop(consecutive_failures);
+    let metrics: Vec<Metric> = metrics
+        .into_iter()
+        .filter(|m| filter.keeps(&m.name))
+        .collect();
 
-        let mut metrics_pushed: Vec<String> = vec![];
+    let mut metrics_pushed: Vec<String> = vec![];
 
-        // Send all data
-        for msg in metric_generator.pop_metrics() {
-            let mut attributes: Option<&HashMap<String, String>> = None;
-            if !msg.attributes.is_empty() {
-                attributes = Some(&msg.attributes);
-            }
+    let metrics = if let Some(histogram) = context
+        .power_histogram
+        .as_ref()
+        .filter(|_| filter.keeps_family("process"))
+    {
+        let (processes, mut metrics): (Vec<_>, Vec<_>) = metrics
+            .into_iter()
+            .partition(|m| m.name == "scaph_process_power_consumption_microwatts");
+        // the processes aggregated under --min-power-microwatts aren't a single process
+        let single_processes: Vec<&Metric> = processes
+            .iter()
+            .filter(|m| !m.is_other_processes())
+            .collect();
+        let powers: Vec<f64> = single_processes
+            .iter()
+            .filter_map(|m| format!("{}", m.metric_value).parse::<f64>().ok())
+            .collect();
+        let labels = metric_generator.get_labels();
+        let exemplars: Vec<&HashMap<String, String>> =
+            single_processes.iter().map(|m| &m.attributes).collect();
+        body = push_metric(
+            body,
+            String::from("Distribution of the power consumption of processes, in microwatts"),
+            String::from("histogram"),
+            String::from("scaph_process_power_microwatts"),
+            format_histogram(
+                "scaph_process_power_microwatts",
+                &powers,
+                &histogram.buckets,
+                &labels,
+                Some(&exemplars)
+                    .filter(|_| openmetrics)
+                    .map(|e| e.as_slice()),
+            ),
+            true,
+            openmetrics,
+        );
+        if histogram.replace_process_series {
+            // every series of a single process goes, not only its power
+            metrics.retain(|m| !m.name.starts_with("scaph_process_"));
+        } else {
+            metrics.extend(processes);
+        }
+        metrics
+    } else {
+        metrics
+    };
 
-            let value = match msg.metric_value {
-                // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
-                // MetricValueType::Float(value) => event.set_metric_f(value),
-                MetricValueType::FloatDouble(value) => value.to_string(),
-                MetricValueType::IntUnsigned(value) => value.to_string(),
-                MetricValueType::Text(ref value) => value.to_string(),
-            };
+    // Send all data
+    for msg in metrics {
+        let mut attributes: Option<&HashMap<String, String>> = None;
+        if !msg.attributes.is_empty() {
+            attributes = Some(&msg.attributes);
+        }
 
-            let mut should_i_add_help = true;
+        let value = match msg.metric_value {
+            // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
+            // MetricValueType::Float(value) => event.set_metric_f(value),
+            MetricValueType::FloatDouble(value) => value.to_string(),
+            MetricValueType::IntUnsigned(value) => value.to_string(),
+            MetricValueType::Text(ref value) => value.to_string(),
+        };
 
-            if metrics_pushed.contains(&msg.name) {
-                should_i_add_help = false;
-            } else {
-                metrics_pushed.insert(0, msg.name.clone());
-            }
+        let mut should_i_add_help = true;
 
-            body = push_metric(
-                body,
-                msg.description.clone(),
-                msg.metric_type.clone(),
-                msg.name.clone(),
-                format_metric(&msg.name, &value, attributes),
-                should_i_add_help,
-            );
+        if metrics_pushed.contains(&msg.name) {
+            should_i_add_help = false;
+        } else {
+            metrics_pushed.insert(0, msg.name.clone());
+        }
+
+        body = push_metric(
+            body,
+            msg.description.clone(),
+            msg.metric_type.clone(),
+            msg.name.clone(),
+            format_metric(&msg.name, &value, attributes),
+            should_i_add_help,
+            openmetrics,
+        );
+    }
+
+    let labels = metric_generator.get_labels();
+    let labels = if labels.is_empty() {
+        None
+    } else {
+        Some(&labels)
+    };
+    if filter.keeps("scaph_collection_duration_seconds") {
+        body = push_metric(
+            body,
+            String::from(
+                "Time spent by scaphandre refreshing the topology and generating metrics, in seconds",
+            ),
+            String::from("gauge"),
+            String::from("scaph_collection_duration_seconds"),
+            format_metric(
+                "scaph_collection_duration_seconds",
+                &collection_duration.as_secs_f64().to_string(),
+                labels,
+            ),
+            true,
+            openmetrics,
+        );
+        body = push_metric(
+            body,
+            String::from("Number of scrapes during which metrics collection failed"),
+            String::from("counter"),
+            String::from("scaph_scrape_errors_total"),
+            format_metric(
+                "scaph_scrape_errors_total",
+                &scrape_errors.to_string(),
+                labels,
+            ),
+            true,
+            openmetrics,
+        );
+    }
+    if openmetrics {
+        body.push_str("# EOF\n");
+    }
+    body
+}
+
+/// Handles requests and returns data formated for Prometheus.
+async fn show_metrics(
+    req: Request<Body>,
+    context: Arc<PowerMetrics>,
+    suffix: String,
+) -> Result<Response<Body>, Infallible> {
+    trace!("{}", req.uri());
+    let mut body = String::new();
+    let is_metrics_path = req.uri().path() == format!("/{}", &suffix);
+    if !is_metrics_path {
+        match req.uri().path() {
+            HEALTH_PATH => return Ok(Response::new(Body::from("ok\n"))),
+            READY_PATH if context.is_ready() => return Ok(Response::new(Body::from("ready\n"))),
+            READY_PATH => {
+                return Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::from(format!(
+                        "not ready: the last {} metrics collections failed\n",
+                        context.ready_max_failures
+                    )))
+                    .unwrap())
+            }
+            _ => {}
         }
+    }
+    let openmetrics = is_metrics_path && context.openmetrics && accepts_openmetrics(&req);
+    if is_metrics_path {
+        trace!("in metrics !");
+        let filter = MetricsFilter::from_query(req.uri().query());
+        body = build_metrics_body(&context, openmetrics, &filter);
     } else {
         let _ = write!(body, "<a href=\"https://github.com/hubblo-org/scaphandre/\">Scaphandre's</a> prometheus exporter here. Metrics available on <a href=\"/{suffix}\">/{suffix}</a>");
     }
-    Ok(Response::new(body.into()))
+    let mut response = Response::builder();
+    if openmetrics {
+        response = response.header(CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE);
+    }
+    if is_metrics_path && accepts_gzip(&req) {
+        match gzip(body.as_bytes()) {
+            Ok(compressed) => {
+                return Ok(response
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(compressed.into())
+                    .unwrap());
+            }
+            Err(e) => warn!(
+                "Couldn't compress metrics, sending them uncompressed: {}",
+                e
+            ),
+        }
+    }
+    Ok(response.body(body.into()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::Topology;
+
+    #[test]
+    fn gzip_negotiated_from_accept_encoding() {
+        let request = |accept_encoding: Option<&str>| {
+            let mut builder = Request::builder().uri("/metrics");
+            if let Some(value) = accept_encoding {
+                builder = builder.header(ACCEPT_ENCODING, value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        assert!(accepts_gzip(&request(Some("gzip"))));
+        assert!(accepts_gzip(&request(Some("deflate, GZIP;q=0.8"))));
+        assert!(accepts_gzip(&request(Some("*"))));
+        assert!(!accepts_gzip(&request(Some("gzip;q=0"))));
+        assert!(!accepts_gzip(&request(Some("identity"))));
+        assert!(!accepts_gzip(&request(None)));
+    }
+
+    #[test]
+    fn ready_until_collections_fail() {
+        let context = Arc::new(
+            PowerMetrics::new(
+                MetricGenerator::new(Topology::default(), String::from("test"), false, false),
+                None,
+                false,
+            )
+            .with_ready_max_failures(2),
+        );
+        let status = |path: &str| {
+            let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let response = tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(show_metrics(
+                    request,
+                    context.clone(),
+                    String::from("metrics"),
+                ))
+                .unwrap();
+            response.status()
+        };
+        assert_eq!(status("/health"), StatusCode::OK);
+        assert_eq!(status("/ready"), StatusCode::OK);
+        *context.consecutive_failures.lock().unwrap() = 1;
+        assert_eq!(status("/ready"), StatusCode::OK);
+        *context.consecutive_failures.lock().unwrap() = 2;
+        assert_eq!(status("/ready"), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("/health"), StatusCode::OK);
+    }
+
+    #[test]
+    fn textfile_replaced_atomically() {
+        let dir = std::env::temp_dir().join(format!("scaph-textfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scaphandre.prom");
+        write_textfile(&path, "scaph_host_power_microwatts 1\n").unwrap();
+        write_textfile(&path, "scaph_host_power_microwatts 2\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "scaph_host_power_microwatts 2\n"
+        );
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn listen_addresses_parsed() {
+        assert_eq!(
+            parse_listen_address("10.0.0.5"),
+            Some(IpAddr::from([10, 0, 0, 5]))
+        );
+        assert_eq!(parse_listen_address("[::1]"), "::1".parse().ok());
+        assert_eq!(parse_listen_address("fd00::5"), "fd00::5".parse().ok());
+        assert_eq!(parse_listen_address("localhost"), None);
+    }
+
+    #[test]
+    fn parse_power_buckets() {
+        assert_eq!(parse_buckets("1000, 10000").unwrap(), vec![1000.0, 10000.0]);
+        assert!(parse_buckets("10000,1000").is_err());
+        assert!(parse_buckets("1000,a").is_err());
+    }
+
+    #[test]
+    fn invalid_options_rejected() {
+        let accepts = |args: &[&str]| {
+            clap::App::new("test")
+                .args(&PrometheusExporter::get_options())
+                .get_matches_from_safe([&["test"], args].concat())
+                .is_ok()
+        };
+        assert!(accepts(&[]));
+        assert!(accepts(&["--power-buckets", "1000,10000"]));
+        assert!(!accepts(&["--power-buckets", "10000,1000"]));
+        assert!(!accepts(&["--step", "0"]));
+        assert!(accepts(&["--address", "[::1]", "--address", "10.0.0.5"]));
+        assert!(!accepts(&["--address", "localhost"]));
+        assert!(!accepts(&["--port", "70000"]));
+        assert!(!accepts(&["--ready-max-failures", "0"]));
+        assert!(!accepts(&["--stale-series-grace", "-5"]));
+        assert!(accepts(&["--max-series", "0"]));
+        assert!(!accepts(&["--max-series", "many"]));
+        assert!(accepts(&[
+            "--idle-baseline-window",
+            "600",
+            "--idle-baseline-percentile",
+            "5"
+        ]));
+        assert!(!accepts(&["--idle-baseline-window", "ten"]));
+        assert!(!accepts(&[
+            "--idle-baseline-window",
+            "600",
+            "--idle-baseline-percentile",
+            "150"
+        ]));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = format_histogram(
+            "scaph_process_power_microwatts",
+            &[500.0, 1000.0, 5000.0, 20000.0],
+            &[1000.0, 10000.0],
+            &HashMap::new(),
+            None,
+        );
+        assert_eq!(
+            histogram,
+            "scaph_process_power_microwatts_bucket{le=\"1000\"} 2\n\
+             scaph_process_power_microwatts_bucket{le=\"10000\"} 3\n\
+             scaph_process_power_microwatts_bucket{le=\"+Inf\"} 4\n\
+             scaph_process_power_microwatts_sum 26500\n\
+             scaph_process_power_microwatts_count 4\n"
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_have_exemplars() {
+        let mut small = HashMap::new();
+        small.insert(String::from("pid"), String::from("12"));
+        small.insert(String::from("exe"), String::from("sshd"));
+        let mut big = HashMap::new();
+        big.insert(String::from("pid"), String::from("42"));
+        big.insert(String::from("exe"), String::from("make"));
+        big.insert(String::from("cmdline"), "make -j8 ".repeat(20));
+        let histogram = format_histogram(
+            "scaph_process_power_microwatts",
+            &[500.0, 20000.0],
+            &[1000.0, 10000.0],
+            &HashMap::new(),
+            Some(&[&small, &big]),
+        );
+        let lines: Vec<&str> = histogram.lines().collect();
+        assert_eq!(
+            lines[0],
+            "scaph_process_power_microwatts_bucket{le=\"1000\"} 1 # {pid=\"12\",exe=\"sshd\"} 500"
+        );
+        assert_eq!(
+            lines[1],
+            "scaph_process_power_microwatts_bucket{le=\"10000\"} 1"
+        );
+        let exemplar = lines[2].split(" # ").nth(1).unwrap();
+        assert!(exemplar.starts_with("{pid=\"42\",exe=\"make\",cmdline=\"make -j8"));
+        // labels names and values are limited to 128 characters
+        assert_eq!(exemplar.len(), 128 + "{=\"\",=\"\",=\"\"} 20000".len());
+    }
+
+    #[test]
+    fn metrics_filtered_from_query() {
+        let all = MetricsFilter::from_query(None);
+        assert!(all.keeps("scaph_process_power_consumption_microwatts"));
+        assert!(all.keeps("scaph_host_power_microwatts"));
+
+        let host_only = MetricsFilter::from_query(Some("include=host%2Csocket"));
+        assert!(host_only.keeps("scaph_host_power_microwatts"));
+        assert!(host_only.keeps("scaph_domain_power_microwatts"));
+        assert!(!host_only.keeps("scaph_process_power_consumption_microwatts"));
+        assert!(!host_only.keeps("scaph_self_version"));
+
+        let no_process = MetricsFilter::from_query(Some("process=false&self=false&foo=bar"));
+        assert!(no_process.keeps("scaph_socket_power_microwatts"));
+        assert!(!no_process.keeps("scaph_series_truncated"));
+        assert!(!no_process.keeps("scaph_scrape_errors_total"));
+        assert!(no_process.keeps_family("host"));
+        assert!(!no_process.keeps_family("process"));
+
+        assert_eq!(
+            MetricsFilter::from_query(Some("include=host&self=true&exclude=host")),
+            MetricsFilter {
+                include: Some(vec![String::from("host"), String::from("self")]),
+                exclude: vec![String::from("host")],
+            }
+        );
+    }
+
+    #[test]
+    fn openmetrics_counters_end_with_total() {
+        assert_eq!(
+            openmetrics_family("scaph_scrape_errors_total", "counter"),
+            (String::from("scaph_scrape_errors"), String::from("counter"))
+        );
+        assert_eq!(
+            openmetrics_family("scaph_host_energy_microjoules", "counter"),
+            (
+                String::from("scaph_host_energy_microjoules"),
+                String::from("unknown")
+            )
+        );
+        assert_eq!(
+            openmetrics_family("scaph_host_power_microwatts", "gauge"),
+            (
+                String::from("scaph_host_power_microwatts"),
+                String::from("gauge")
+            )
+        );
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
@@ -1,7 +1,16 @@
 use crate::exporters::Exporter;
 use crate::sensors::{utils::ProcessRecord, Sensor, Topology};
+use std::collections::HashMap;
+use std::path::Path;
 use std::{fs, io, thread, time};
 
+/// Folder where energy counters of each virtual machine are written, in a
+/// `${DOMAIN_NAME}` sub-folder, with the powercap layout expected by the
+/// powercap_rapl sensor in the guest: `intel-rapl:0/energy_uj`, in microjoules.
+/// The sub-folder of a virtual machine should be shared with it and mounted
+/// in `sensors::powercap_rapl::GUEST_POWERCAP_PATH`.
+pub const VMS_POWERCAP_PATH: &str = "/var/lib/libvirt/scaphandre";
+
 /// An Exporter that extracts power consumption data of running
 /// Qemu/KVM virtual machines on the host and store those data
 /// as folders and files that are supposed to be mounted on the
@@ -10,13 +19,15 @@ use std::{fs, io, thread, time};
 /// they would do it if they managed bare metal machines.
 pub struct QemuExporter {
     topology: Topology,
+    /// Last CPU time of each vCPU thread, in jiffies, by qemu pid and vCPU index.
+    vcpu_times: HashMap<(i32, u16), u64>,
 }
 
 impl Exporter for QemuExporter {
     /// Runs iteration() in a loop.
     fn run(&mut self, _parameters: clap::ArgMatches) {
         info!("Starting qemu exporter");
-        let path = "/var/lib/libvirt/scaphandre";
+        let path = VMS_POWERCAP_PATH;
         let cleaner_step = 120;
         let mut timer = time::Duration::from_secs(cleaner_step);
         loop {
@@ -29,6 +40,9 @@ impl Exporter for QemuExporter {
                 self.topology
                     .proc_tracker
                     .clean_terminated_process_records_vectors();
+                let procfs_root = self.topology.proc_tracker.get_procfs_root();
+                self.vcpu_times
+                    .retain(|(pid, _), _| procfs_root.join(pid.to_string()).exists());
                 timer = time::Duration::from_secs(cleaner_step);
             }
         }
@@ -45,6 +59,7 @@ impl QemuExporter {
         let some_topology = *sensor.get_topology();
         QemuExporter {
             topology: some_topology.unwrap(),
+            vcpu_times: HashMap::new(),
         }
     }
 
@@ -56,6 +71,13 @@ impl QemuExporter {
         let topo_stat_diff = self.topology.get_stats_diff();
         if let Some(topo_rec_uj) = topo_uj_diff {
             debug!("Got topo uj diff: {:?}", topo_rec_uj);
+            let host_uj = match topo_rec_uj.value_u64() {
+                Some(host_uj) => host_uj,
+                None => {
+                    warn!("Couldn't parse the host energy {}", topo_rec_uj.value);
+                    return;
+                }
+            };
             let proc_tracker = self.topology.get_proc_tracker();
             let processes = proc_tracker.get_alive_processes();
             let qemu_processes = QemuExporter::filter_qemu_vm_processes(&processes);
@@ -82,15 +104,42 @@ impl QemuExporter {
                         }
                         let tdiff = time_tdiff.total_time_jiffies();
                         trace!("Time_pdiff={} time_tdiff={}", time_pdiff.to_string(), tdiff);
-                        let ratio = time_pdiff / tdiff;
-                        trace!("Ratio is {}", ratio.to_string());
-                        let uj_to_add = ratio * topo_rec_uj.value.parse::<u64>().unwrap();
+                        let uj_to_add = energy_share_microjoules(time_pdiff, tdiff, host_uj);
                         trace!("Adding {} uJ", uj_to_add);
                         let complete_path = format!("{path}/{vm_name}/intel-rapl:0");
                         if let Ok(result) = QemuExporter::add_or_create(&complete_path, uj_to_add) {
                             trace!("{:?}", result);
                             debug!("Updated {}", complete_path);
                         }
+
+                        let pid = last.process.pid;
+                        let task_path = self
+                            .topology
+                            .proc_tracker
+                            .get_procfs_root()
+                            .join(pid.to_string())
+                            .join("task");
+                        let vcpus = QemuExporter::read_vcpu_times(&task_path);
+                        if vcpus.is_empty() {
+                            debug!(
+                                "No vCPU thread found for {}, only whole-VM energy is given",
+                                vm_name
+                            );
+                        }
+                        for (vcpu, jiffies) in vcpus {
+                            let previous = self.vcpu_times.insert((pid, vcpu), jiffies);
+                            if let Some(previous) = previous {
+                                let uj = energy_share_microjoules(
+                                    jiffies.saturating_sub(previous),
+                                    tdiff,
+                                    host_uj,
+                                );
+                                let vcpu_path = format!("{path}/{vm_name}/vcpus/{vcpu}");
+                                if let Err(e) = QemuExporter::add_or_create(&vcpu_path, uj) {
+                                    warn!("Couldn't update {}: {}", vcpu_path, e);
+                                }
+                            }
+                        }
                     }
                 }
             }
@@ -110,6 +159,30 @@ impl QemuExporter {
         String::from("")
     }
 
+    /// Reads the CPU time, in jiffies, of each vCPU thread found in the task
+    /// folder of a qemu process, by vCPU index. Threads running vCPUs are
+    /// named like `CPU 0/KVM`. Returns nothing if no thread is named this way.
+    fn read_vcpu_times(task_path: &Path) -> Vec<(u16, u64)> {
+        let mut vcpus = vec![];
+        if let Ok(threads) = fs::read_dir(task_path) {
+            for thread in threads.filter_map(|t| t.ok()) {
+                let comm = fs::read_to_string(thread.path().join("comm")).unwrap_or_default();
+                let vcpu = match parse_vcpu_index(&comm) {
+                    Some(vcpu) => vcpu,
+                    None => continue,
+                };
+                if let Some(jiffies) = fs::read_to_string(thread.path().join("stat"))
+                    .ok()
+                    .and_then(|stat| parse_stat_cpu_time(&stat))
+                {
+                    vcpus.push((vcpu, jiffies));
+                }
+            }
+        }
+        vcpus.sort_unstable();
+        vcpus
+    }
+
     /// Either creates an energy_uj file (as the ones managed by powercap kernel module)
     /// in 'path' and adds 'uj_value' to its numerical content, or simply performs the
     /// addition if the file exists.
@@ -154,6 +227,72 @@ impl QemuExporter {
     }
 }
 
+/// Returns the index of the vCPU run by a qemu thread named `comm`, like `CPU 3/KVM`.
+fn parse_vcpu_index(comm: &str) -> Option<u16> {
+    comm.trim()
+        .strip_prefix("CPU ")?
+        .strip_suffix("/KVM")?
+        .parse()
+        .ok()
+}
+
+/// Returns utime + stime, in jiffies, from the content of a /proc/PID/task/TID/stat file.
+fn parse_stat_cpu_time(stat: &str) -> Option<u64> {
+    // the command name, in parentheses, may hold spaces
+    let fields: Vec<&str> = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Returns the share of the host energy `host_uj` due to a virtual machine, or
+/// one of its vCPU threads, that ran `jiffies` out of the `host_jiffies` of the
+/// host CPU time.
+fn energy_share_microjoules(jiffies: u64, host_jiffies: u64, host_uj: u64) -> u64 {
+    if host_jiffies == 0 {
+        return 0;
+    }
+    (jiffies as f64 / host_jiffies as f64 * host_uj as f64) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn vcpu_threads_read_from_task_folder() {
+        assert_eq!(parse_vcpu_index("CPU 12/KVM\n"), Some(12));
+        assert_eq!(parse_vcpu_index("qemu-system-x86"), None);
+
+        let task_path = env::temp_dir().join(format!("scaph-vcpus-{}", std::process::id()));
+        for (tid, comm, utime, stime) in [
+            (100, "qemu-system-x86", 50, 50),
+            (101, "CPU 0/KVM", 300, 20),
+            (102, "CPU 1/KVM", 10, 5),
+        ] {
+            let thread = task_path.join(tid.to_string());
+            fs::create_dir_all(&thread).unwrap();
+            fs::write(thread.join("comm"), format!("{comm}\n")).unwrap();
+            fs::write(
+                thread.join("stat"),
+                format!("{tid} ({comm}) S 1 1 1 0 -1 4194560 0 0 0 0 {utime} {stime} 0 0 20 0 1 0"),
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            QemuExporter::read_vcpu_times(&task_path),
+            vec![(0, 320), (1, 15)]
+        );
+        assert_eq!(energy_share_microjoules(25, 100, 2_000_000), 500_000);
+        assert_eq!(energy_share_microjoules(25, 0, 2_000_000), 0);
+        fs::remove_dir_all(&task_path).unwrap();
+    }
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
@@ -0,0 +1,107 @@
+//! # Queue
+//!
+//! Bounded queue between the loop measuring power and a thread sending the
+//! measurements to a slow backend, so that a stalled backend doesn't delay the
+//! measurements. When the queue is full, the oldest snapshot is dropped to make
+//! room for the new one.
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+/// Queue of snapshots, holding at most `capacity` of them.
+#[derive(Debug)]
+pub struct SnapshotQueue<T> {
+    capacity: usize,
+    state: Mutex<QueueState<T>>,
+    /// Notified when a snapshot is pushed.
+    not_empty: Condvar,
+}
+
+#[derive(Debug)]
+struct QueueState<T> {
+    snapshots: VecDeque<T>,
+    /// Number of snapshots dropped since the start because the queue was full.
+    dropped: u64,
+}
+
+impl<T> SnapshotQueue<T> {
+    /// Instantiates SnapshotQueue, holding at least one snapshot.
+    pub fn new(capacity: usize) -> SnapshotQueue<T> {
+        SnapshotQueue {
+            capacity: capacity.max(1),
+            state: Mutex::new(QueueState {
+                snapshots: VecDeque::new(),
+                dropped: 0,
+            }),
+            not_empty: Condvar::new(),
+        }
+    }
+
+    /// Adds a snapshot to the queue, dropping the oldest one if it is full.
+    pub fn push(&self, snapshot: T) {
+        let mut state = self.state.lock().unwrap();
+        if state.snapshots.len() >= self.capacity {
+            state.snapshots.pop_front();
+            state.dropped += 1;
+            warn!(
+                "Snapshots queue is full, dropped the oldest one ({} dropped since the start).",
+                state.dropped
+            );
+        }
+        state.snapshots.push_back(snapshot);
+        self.not_empty.notify_one();
+    }
+
+    /// Waits for the queue to hold at least one snapshot, then takes all the
+    /// snapshots it holds, oldest first.
+    pub fn pop_all(&self) -> Vec<T> {
+        let mut state = self.state.lock().unwrap();
+        while state.snapshots.is_empty() {
+            state = self.not_empty.wait(state).unwrap();
+        }
+        state.snapshots.drain(..).collect()
+    }
+
+    /// Returns the number of snapshots dropped since the start because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn oldest_snapshots_dropped_when_full() {
+        let queue = SnapshotQueue::new(3);
+        for snapshot in 0..5 {
+            queue.push(snapshot);
+        }
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(queue.pop_all(), vec![2, 3, 4]);
+
+        let queue = Arc::new(queue);
+        let consumer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.pop_all())
+        };
+        queue.push(5);
+        assert_eq!(consumer.join().unwrap(), vec![5]);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
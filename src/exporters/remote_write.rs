@@ -0,0 +1,453 @@
+//! # RemoteWriteExporter
+//!
+//! `RemoteWriteExporter` implementation, pushes metrics to an endpoint
+//! implementing the [Prometheus remote write](https://prometheus.io/docs/concepts/remote_write_spec/)
+//! protocol, like Mimir, Thanos receive or Prometheus itself.
+use crate::exporters::labels::{get_label_option, get_label_provider_option, get_label_providers};
+use crate::exporters::utils::{
+    apply_topology_options, exit_dry_run, exit_with_error, format_dry_run_metrics,
+    get_dry_run_option, get_hostname_option, get_jitter, get_jitter_option, get_measure_interval,
+    get_measure_interval_option, get_perf_instructions_option, get_retry_options, get_secret,
+    get_watts_option, jittered_step, prepare_dry_run, resolve_hostname, set_perf_instructions,
+    topology_options, validate_positive_integer, wait_for_next_step, RetryPolicy,
+};
+use crate::exporters::*;
+use crate::sensors::Sensor;
+use chrono::Utc;
+use clap::Arg;
+use isahc::auth::{Authentication, Credentials};
+use isahc::config::Configurable;
+use isahc::http::StatusCode;
+use protobuf::CodedOutputStream;
+use std::time::Duration;
+
+/// Default job label of the series.
+const DEFAULT_JOB: &str = "scaphandre";
+
+/// Credentials sent along with each write request.
+#[derive(Debug, Clone, PartialEq)]
+enum RemoteWriteAuth {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+/// Error of a write request, transient if it may succeed when retried.
+#[derive(Debug)]
+struct RemoteWriteError {
+    message: String,
+    transient: bool,
+}
+
+impl fmt::Display for RemoteWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// An Exporter that pushes metrics to a Prometheus remote write endpoint.
+pub struct RemoteWriteExporter {
+    sensor: Box<dyn Sensor>,
+}
+
+impl Exporter for RemoteWriteExporter {
+    /// Runs the exporter, pushing metrics every step.
+    fn run(&mut self, parameters: ArgMatches) {
+        let endpoint = parameters.value_of("endpoint").unwrap().to_string();
+        // the value is checked by the validator of the option
+        let step = Duration::from_secs(
+            parameters
+                .value_of("step")
+                .unwrap()
+                .parse()
+                .expect("Wrong step value, should be a number of seconds"),
+        );
+        let measure_interval = get_measure_interval(&parameters);
+        let jitter = get_jitter(&parameters);
+        let auth = get_auth(&parameters);
+        let retry_policy = RetryPolicy::from_parameters(&parameters);
+        let hostname = resolve_hostname(&parameters);
+        let mut series_labels = HashMap::new();
+        series_labels.insert(
+            String::from("job"),
+            parameters.value_of("job").unwrap().to_string(),
+        );
+        series_labels.insert(String::from("instance"), hostname.clone());
+
+        let topology = self.sensor.get_topology().unwrap();
+        let mut metric_generator = MetricGenerator::new(
+            topology,
+            hostname,
+            parameters.is_present("qemu"),
+            parameters.is_present("containers"),
+        );
+        metric_generator.set_label_providers(get_label_providers(&parameters));
+        metric_generator.set_watts(parameters.is_present("watts"));
+        apply_topology_options(&mut metric_generator.topology, &parameters);
+        set_perf_instructions(&mut metric_generator.topology, &parameters);
+
+        let dry_run = parameters.is_present("dry_run");
+        if dry_run {
+            prepare_dry_run(&mut metric_generator.topology);
+        } else {
+            println!("Pushing metrics to {endpoint}");
+        }
+        loop {
+            metric_generator
+                .topology
+                .proc_tracker
+                .clean_terminated_process_records_vectors();
+            info!(
+                "{}: Refresh topology",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S")
+            );
+            metric_generator.topology.refresh();
+            metric_generator.gen_all_metrics();
+            let metrics = metric_generator.pop_metrics();
+            let body = snappy_compress(&encode_write_request(&metrics, &series_labels));
+            if dry_run {
+                println!("{}", format_dry_run_metrics(&metrics));
+            }
+            let result = retry_policy.run(
+                || send_write_request(&endpoint, auth.as_ref(), &body),
+                |e| e.transient,
+            );
+            if dry_run {
+                exit_dry_run(&endpoint, result.map_err(|e| e.to_string()));
+            }
+            if let Err(e) = result {
+                error!("Couldn't push metrics to {}: {}", endpoint, e);
+            }
+            wait_for_next_step(
+                &mut metric_generator.topology,
+                jittered_step(step, jitter),
+                measure_interval,
+            );
+        }
+    }
+
+    /// Returns options needed for that exporter.
+    fn get_options() -> Vec<clap::Arg<'static, 'static>> {
+        let mut options = Vec::new();
+        let arg = Arg::with_name("endpoint")
+            .help("Remote write endpoint to push metrics to, like http://mimir:9009/api/v1/push")
+            .long("endpoint")
+            .short("e")
+            .required(true)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("step")
+            .default_value("5")
+            .help("Duration between two pushes, in seconds")
+            .long("step")
+            .short("s")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_positive_integer);
+        options.push(arg);
+
+        let arg = Arg::with_name("job")
+            .default_value(DEFAULT_JOB)
+            .help("Value of the job label of all series")
+            .long("job")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("bearer_token")
+            .help("Bearer token sent with each request. Can also be given with the SCAPH_REMOTE_WRITE_BEARER_TOKEN environment variable")
+            .long("bearer-token")
+            .required(false)
+            .takes_value(true)
+            .conflicts_with("username");
+        options.push(arg);
+
+        let arg = Arg::with_name("bearer_token_file")
+            .help("File containing the bearer token, like a mounted Docker or Kubernetes secret. Read once at startup.")
+            .long("bearer-token-file")
+            .required(false)
+            .takes_value(true)
+            .conflicts_with_all(&["username", "bearer_token"]);
+        options.push(arg);
+
+        let arg = Arg::with_name("username")
+            .help("Username for basic authentication")
+            .long("username")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("password")
+            .help("Password for basic authentication. Can also be given with the SCAPH_REMOTE_WRITE_PASSWORD environment variable")
+            .long("password")
+            .required(false)
+            .takes_value(true)
+            .requires("username");
+        options.push(arg);
+
+        let arg = Arg::with_name("password_file")
+            .help("File containing the password for basic authentication, like a mounted Docker or Kubernetes secret. Read once at startup.")
+            .long("password-file")
+            .required(false)
+            .takes_value(true)
+            .requires("username")
+            .conflicts_with("password");
+        options.push(arg);
+
+        let arg = Arg::with_name("qemu")
+            .help("Apply labels to metrics of processes looking like a Qemu/KVM virtual machine")
+            .long("qemu")
+            .short("q")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("containers")
+            .help("Monitor and apply labels for processes running as containers")
+            .long("containers")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        options.push(get_measure_interval_option());
+        options.extend(topology_options());
+        options.push(get_hostname_option());
+        options.push(get_label_option());
+        options.push(get_label_provider_option());
+        options.push(get_watts_option());
+        options.push(get_perf_instructions_option());
+        options.extend(get_retry_options());
+        options.push(get_dry_run_option());
+        options.push(get_jitter_option());
+
+        options
+    }
+}
+
+impl RemoteWriteExporter {
+    /// Instantiates and returns a new RemoteWriteExporter
+    pub fn new(sensor: Box<dyn Sensor>) -> RemoteWriteExporter {
+        RemoteWriteExporter { sensor }
+    }
+}
+
+/// Returns the credentials given by the parameters or the environment, if any.
+fn get_auth(parameters: &ArgMatches) -> Option<RemoteWriteAuth> {
+    if let Some(username) = parameters.value_of("username") {
+        let password = get_secret(
+            parameters,
+            "password",
+            "password_file",
+            "SCAPH_REMOTE_WRITE_PASSWORD",
+        )
+        .unwrap_or_else(|e| exit_with_error(&e))
+        .unwrap_or_default();
+        return Some(RemoteWriteAuth::Basic {
+            username: username.to_string(),
+            password,
+        });
+    }
+    get_secret(
+        parameters,
+        "bearer_token",
+        "bearer_token_file",
+        "SCAPH_REMOTE_WRITE_BEARER_TOKEN",
+    )
+    .unwrap_or_else(|e| exit_with_error(&e))
+    .filter(|token| !token.is_empty())
+    .map(RemoteWriteAuth::Bearer)
+}
+
+/// Posts a snappy compressed WriteRequest to the remote write endpoint.
+fn send_write_request(
+    endpoint: &str,
+    auth: Option<&RemoteWriteAuth>,
+    body: &[u8],
+) -> Result<(), RemoteWriteError> {
+    let mut request = isahc::Request::post(endpoint)
+        .header("Content-Encoding", "snappy")
+        .header("Content-Type", "application/x-protobuf")
+        .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+    match auth {
+        Some(RemoteWriteAuth::Bearer(token)) => {
+            request = request.header("Authorization", format!("Bearer {token}"));
+        }
+        Some(RemoteWriteAuth::Basic { username, password }) => {
+            request = request
+                .authentication(Authentication::basic())
+                .credentials(Credentials::new(username.as_str(), password.as_str()));
+        }
+        None => {}
+    }
+    let response = request
+        .body(body.to_vec())
+        .map_err(|e| RemoteWriteError {
+            message: e.to_string(),
+            transient: false,
+        })
+        .and_then(|request| {
+            isahc::send(request).map_err(|e| RemoteWriteError {
+                message: e.to_string(),
+                transient: e.is_network() || e.is_timeout() || e.is_server(),
+            })
+        })?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(RemoteWriteError {
+            message: format!("endpoint answered {status}"),
+            transient: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        });
+    }
+    Ok(())
+}
+
+/// Returns the value of a metric as a float, or None if it isn't a number.
+fn metric_value(metric: &Metric) -> Option<f64> {
+    match &metric.metric_value {
+        MetricValueType::FloatDouble(value) => Some(*value),
+        MetricValueType::IntUnsigned(value) => Some(*value as f64),
+        MetricValueType::Text(value) => value.trim().parse().ok(),
+    }
+}
+
+/// Encodes metrics as a remote write WriteRequest protobuf message, with
+/// `series_labels` added to the labels of each series:
+///
+/// ```text
+/// message WriteRequest { repeated TimeSeries timeseries = 1; }
+/// message TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }
+/// message Label { string name = 1; string value = 2; }
+/// message Sample { double value = 1; int64 timestamp = 2; }
+/// ```
+fn encode_write_request(metrics: &[Metric], series_labels: &HashMap<String, String>) -> Vec<u8> {
+    let mut request = vec![];
+    let mut request_stream = CodedOutputStream::vec(&mut request);
+    for metric in metrics {
+        let value = match metric_value(metric) {
+            Some(value) => value,
+            None => continue,
+        };
+        let mut labels: Vec<(&str, &str)> = vec![("__name__", metric.name.as_str())];
+        // metric specific labels win over the labels of all series
+        for (name, value) in metric.attributes.iter().chain(series_labels.iter()) {
+            if !labels.iter().any(|(n, _)| *n == name.as_str()) {
+                labels.push((name.as_str(), value.as_str()));
+            }
+        }
+        // the labels of a series must be sorted by name
+        labels.sort_unstable();
+
+        let mut series = vec![];
+        let mut series_stream = CodedOutputStream::vec(&mut series);
+        for (name, value) in labels {
+            let mut label = vec![];
+            let mut label_stream = CodedOutputStream::vec(&mut label);
+            label_stream.write_string(1, name).unwrap();
+            label_stream.write_string(2, value).unwrap();
+            label_stream.flush().unwrap();
+            drop(label_stream);
+            series_stream.write_bytes(1, &label).unwrap();
+        }
+        let mut sample = vec![];
+        let mut sample_stream = CodedOutputStream::vec(&mut sample);
+        sample_stream.write_double(1, value).unwrap();
+        sample_stream
+            .write_int64(2, metric.timestamp.as_millis() as i64)
+            .unwrap();
+        sample_stream.flush().unwrap();
+        drop(sample_stream);
+        series_stream.write_bytes(2, &sample).unwrap();
+        series_stream.flush().unwrap();
+        drop(series_stream);
+        request_stream.write_bytes(1, &series).unwrap();
+    }
+    request_stream.flush().unwrap();
+    drop(request_stream);
+    request
+}
+
+/// Compresses `input` in the snappy block format expected by remote write endpoints.
+fn snappy_compress(input: &[u8]) -> Vec<u8> {
+    // only fails for inputs of more than 4GB
+    snap::raw::Encoder::new()
+        .compress_vec(input)
+        .expect("WriteRequest too large to be compressed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snappy_roundtrip() {
+        for input in [
+            b"".to_vec(),
+            b"abc".to_vec(),
+            b"scaph_host_power_microwatts scaph_host_power_microwatts".repeat(20),
+            (0..=255_u8).cycle().take(5000).collect(),
+        ] {
+            let compressed = snappy_compress(&input);
+            assert_eq!(
+                snap::raw::Decoder::new()
+                    .decompress_vec(&compressed)
+                    .unwrap(),
+                input
+            );
+        }
+        let repeated = b"aaaa".repeat(1000);
+        assert!(snappy_compress(&repeated).len() < 200);
+    }
+
+    #[test]
+    fn write_request_sorts_labels() {
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("socket_id"), String::from("0"));
+        let metrics = vec![Metric {
+            name: String::from("scaph_socket_power_microwatts"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            hostname: String::from("host"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes,
+            description: String::new(),
+            metric_value: MetricValueType::Text(String::from("1500")),
+            timestamp: Duration::from_millis(1_000),
+        }];
+        let mut series_labels = HashMap::new();
+        series_labels.insert(String::from("job"), String::from("scaphandre"));
+        let request = encode_write_request(&metrics, &series_labels);
+        let labels = [
+            &b"\x0a\x08__name__\x12\x1dscaph_socket_power_microwatts"[..],
+            b"\x0a\x03job\x12\x0ascaphandre",
+            b"\x0a\x09socket_id\x12\x010",
+        ];
+        let mut series = vec![];
+        for label in labels {
+            series.push(0x0a);
+            series.push(label.len() as u8);
+            series.extend_from_slice(label);
+        }
+        // value 1500.0 as a double, timestamp 1000 ms as a varint
+        series.extend_from_slice(b"\x12\x0c\x09");
+        series.extend_from_slice(&1500.0_f64.to_le_bytes());
+        series.extend_from_slice(b"\x10\xe8\x07");
+        let mut expected = vec![0x0a, series.len() as u8];
+        expected.extend_from_slice(&series);
+        assert_eq!(request, expected);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
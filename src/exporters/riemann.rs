@@ -2,18 +2,28 @@
 //!
 //! `RiemannExporter` implementation, sends metrics to a [Riemann](https://riemann.io/)
 //! server.
-use crate::exporters::utils::get_hostname;
+use crate::exporters::labels::{
+    get_label_option, get_label_provider_option, get_label_providers, merge_labels,
+};
+use crate::exporters::utils::{
+    apply_topology_options, exit_dry_run, format_dry_run_metrics, get_dry_run_option,
+    get_hostname_option, get_jitter, get_jitter_option, get_measure_interval,
+    get_measure_interval_option, get_perf_instructions_option, get_watts_option, jittered_step,
+    prepare_dry_run, resolve_hostname, set_perf_instructions, topology_options,
+    validate_positive_integer, wait_for_next_step,
+};
 use crate::exporters::*;
 use crate::sensors::Sensor;
 use chrono::Utc;
 use clap::Arg;
+use colored::*;
 use riemann_client::proto::Attribute;
 use riemann_client::proto::Event;
 use riemann_client::Client;
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::fs;
+use std::time::Duration;
 
 /// Riemann server default ipv4/ipv6 address
 const DEFAULT_IP_ADDRESS: &str = "localhost";
@@ -21,87 +31,163 @@ const DEFAULT_IP_ADDRESS: &str = "localhost";
 /// Riemann server default port
 const DEFAULT_PORT: &str = "5555";
 
+/// Default maximum number of events sent in a single message
+const DEFAULT_BATCH_SIZE: &str = "100";
+
 /// RiemannClient is a simple client implementation on top of the
 /// [rust-riemann_client](https://github.com/borntyping/rust-riemann_client) library.
 ///
 /// It allows to connect to a Riemann server and send metrics.
+/// If the connection breaks, it is established again, the same way.
 struct RiemannClient {
     client: Client,
+    address: String,
+    port: u16,
+    tls: Option<TlsFiles>,
+    /// Maximum number of events sent in a single message.
+    batch_size: usize,
+}
+
+/// Files needed to connect to a Riemann server using mTLS.
+struct TlsFiles {
+    ca_file: String,
+    cert_file: String,
+    key_file: String,
 }
 
 impl RiemannClient {
     /// Instanciate the Riemann client either with mTLS or using raw TCP.
-    fn new(parameters: &ArgMatches) -> RiemannClient {
+    /// Returns an error if the certificates or key can't be read, or if the
+    /// server can't be reached.
+    fn new(parameters: &ArgMatches) -> Result<RiemannClient, String> {
         let address = String::from(parameters.value_of("address").unwrap());
         let port = parameters
             .value_of("port")
             .unwrap()
             .parse::<u16>()
             .expect("Fail parsing port number");
-        let client: Client = if parameters.is_present("mtls") {
-            let cafile = parameters.value_of("cafile").unwrap();
-            let certfile = parameters.value_of("certfile").unwrap();
-            let keyfile = parameters.value_of("keyfile").unwrap();
-            Client::connect_tls(&address, port, cafile, certfile, keyfile)
-                .expect("Fail to connect to Riemann server using mTLS")
+        // the value is checked by the validator of the option
+        let batch_size = parameters
+            .value_of("batch_size")
+            .unwrap_or(DEFAULT_BATCH_SIZE)
+            .parse::<usize>()
+            .expect("Wrong batch_size value, should be a positive number of events");
+        let tls = if parameters.is_present("mtls") {
+            let tls = TlsFiles {
+                ca_file: String::from(parameters.value_of("cafile").unwrap()),
+                cert_file: String::from(parameters.value_of("certfile").unwrap()),
+                key_file: String::from(parameters.value_of("keyfile").unwrap()),
+            };
+            for (kind, path) in [
+                ("CA certificate", &tls.ca_file),
+                ("client certificate", &tls.cert_file),
+                ("client key", &tls.key_file),
+            ] {
+                if let Err(e) = fs::File::open(path) {
+                    return Err(format!("Can't read {kind} file {path}: {e}"));
+                }
+            }
+            Some(tls)
         } else {
-            Client::connect(&(address, port))
-                .expect("Fail to connect to Riemann server using raw TCP")
+            None
         };
-        RiemannClient { client }
+        let client = RiemannClient::connect(&address, port, tls.as_ref())?;
+        Ok(RiemannClient {
+            client,
+            address,
+            port,
+            tls,
+            batch_size,
+        })
     }
 
-    /// Send metrics to the server.
-    fn send_metric(&mut self, metric: &Metric) {
-        let mut event = Event::new();
-
-        let mut attributes: Vec<Attribute> = vec![];
-        for (key, value) in &metric.attributes {
-            let mut attribute = Attribute::new();
-            attribute.set_key(key.clone());
-            attribute.set_value(value.clone());
-            attributes.push(attribute);
+    /// Connects to the Riemann server, using mTLS if the TLS files are given.
+    fn connect(address: &str, port: u16, tls: Option<&TlsFiles>) -> Result<Client, String> {
+        match tls {
+            Some(tls) => {
+                Client::connect_tls(address, port, &tls.ca_file, &tls.cert_file, &tls.key_file)
+                    .map_err(|e| format!("Fail to connect to Riemann server using mTLS: {e}"))
+            }
+            None => Client::connect(&(address, port))
+                .map_err(|e| format!("Fail to connect to Riemann server using raw TCP: {e}")),
         }
+    }
 
-        event.set_time(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
-        );
-        event.set_ttl(metric.ttl);
-        event.set_host(metric.hostname.to_string());
-        event.set_service(metric.name.to_string());
-        event.set_state(metric.state.to_string());
-        event.set_tags(protobuf::RepeatedField::from_vec(metric.tags.clone()));
-        if !attributes.is_empty() {
-            event.set_attributes(protobuf::RepeatedField::from_vec(attributes));
-        }
-        event.set_description(metric.description.to_string());
-
-        match metric.metric_value {
-            // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
-            // MetricValueType::Float(value) => event.set_metric_f(value),
-            MetricValueType::FloatDouble(value) => event.set_metric_d(value),
-            MetricValueType::IntUnsigned(value) => event.set_metric_sint64(
-                i64::try_from(value).expect("Metric cannot be converted to signed integer."),
-            ),
-            MetricValueType::Text(ref value) => {
-                let value = value.replace(',', ".").replace('\n', "");
-                if value.contains('.') {
-                    event.set_metric_d(value.parse::<f64>().expect("Cannot parse metric value."));
-                } else {
-                    event.set_metric_sint64(
-                        value.parse::<i64>().expect("Cannot parse metric value."),
+    /// Connects again to the Riemann server, after the connection broke.
+    fn reconnect(&mut self) -> Result<(), String> {
+        self.client = RiemannClient::connect(&self.address, self.port, self.tls.as_ref())?;
+        Ok(())
+    }
+
+    /// Sends metrics to the server, batch_size events per message. A batch
+    /// failing to be sent, even after reconnecting, is logged and dropped, the
+    /// next ones are still sent. Returns the error of the last batch dropped.
+    fn send_metrics(&mut self, metrics: &[Metric]) -> Result<(), String> {
+        let mut result = Ok(());
+        for batch in metrics.chunks(self.batch_size) {
+            let events: Vec<Event> = batch.iter().map(metric_to_event).collect();
+            if let Err(e) = self.client.events(events.clone()) {
+                warn!("Fail to send metrics to Riemann ({}), reconnecting.", e);
+                let resent = self
+                    .reconnect()
+                    .and_then(|_| self.client.events(events).map_err(|e| e.to_string()));
+                if let Err(e) = resent {
+                    error!(
+                        "Fail to send a batch of {} metrics (from {}) to Riemann: {}",
+                        batch.len(),
+                        batch[0].name,
+                        e
                     );
+                    result = Err(e);
                 }
             }
         }
+        result
+    }
+}
+
+/// Returns the Riemann event of a metric.
+fn metric_to_event(metric: &Metric) -> Event {
+    let mut event = Event::new();
+
+    let mut attributes: Vec<Attribute> = vec![];
+    for (key, value) in &metric.attributes {
+        let mut attribute = Attribute::new();
+        attribute.set_key(key.clone());
+        attribute.set_value(value.clone());
+        attributes.push(attribute);
+    }
 
-        self.client
-            .event(event)
-            .expect("Fail to send metric to Riemann");
+    // the time of the measurement, not the time it is sent
+    event.set_time(metric.timestamp.as_secs() as i64);
+    event.set_time_micros(metric.timestamp.as_micros() as i64);
+    event.set_ttl(metric.ttl);
+    event.set_host(metric.hostname.to_string());
+    event.set_service(metric.name.to_string());
+    event.set_state(metric.state.to_string());
+    event.set_tags(protobuf::RepeatedField::from_vec(metric.tags.clone()));
+    if !attributes.is_empty() {
+        event.set_attributes(protobuf::RepeatedField::from_vec(attributes));
     }
+    event.set_description(metric.description.to_string());
+
+    match metric.metric_value {
+        // MetricValueType::IntSigned(value) => event.set_metric_sint64(value),
+        // MetricValueType::Float(value) => event.set_metric_f(value),
+        MetricValueType::FloatDouble(value) => event.set_metric_d(value),
+        MetricValueType::IntUnsigned(value) => event.set_metric_sint64(
+            i64::try_from(value).expect("Metric cannot be converted to signed integer."),
+        ),
+        MetricValueType::Text(ref value) => {
+            let value = value.replace(',', ".").replace('\n', "");
+            if value.contains('.') {
+                event.set_metric_d(value.parse::<f64>().expect("Cannot parse metric value."));
+            } else {
+                event.set_metric_sint64(value.parse::<i64>().expect("Cannot parse metric value."));
+            }
+        }
+    }
+    event
 }
 
 /// Exporter sends metrics to a Riemann server.
@@ -127,16 +213,28 @@ impl Exporter for RiemannExporter {
             .parse()
             .expect("Wrong dispatch_duration value, should be a number of seconds");
 
-        let hostname = get_hostname();
+        let measure_interval = get_measure_interval(&parameters);
+        let jitter = get_jitter(&parameters);
 
-        let mut rclient = RiemannClient::new(&parameters);
+        let hostname = resolve_hostname(&parameters);
+
+        let mut rclient = match RiemannClient::new(&parameters) {
+            Ok(rclient) => rclient,
+            Err(e) => {
+                eprintln!("{}", e.red());
+                std::process::exit(1);
+            }
+        };
 
         info!(
             "{}: Starting Riemann exporter",
             Utc::now().format("%Y-%m-%dT%H:%M:%S")
         );
-        println!("Press CTRL-C to stop scaphandre");
-        println!("Measurement step is: {dispatch_duration}s");
+        let dry_run = parameters.is_present("dry_run");
+        if !dry_run {
+            println!("Press CTRL-C to stop scaphandre");
+            println!("Measurement step is: {dispatch_duration}s");
+        }
 
         let topology = self.sensor.get_topology().unwrap();
         let mut metric_generator = MetricGenerator::new(
@@ -145,6 +243,13 @@ impl Exporter for RiemannExporter {
             parameters.is_present("qemu"),
             parameters.is_present("containers"),
         );
+        metric_generator.set_label_providers(get_label_providers(&parameters));
+        metric_generator.set_watts(parameters.is_present("watts"));
+        apply_topology_options(&mut metric_generator.topology, &parameters);
+        set_perf_instructions(&mut metric_generator.topology, &parameters);
+        if dry_run {
+            prepare_dry_run(&mut metric_generator.topology);
+        }
 
         loop {
             info!(
@@ -171,6 +276,7 @@ impl Exporter for RiemannExporter {
             metric_generator.gen_socket_metrics();
 
             let mut data = vec![];
+            let mut other = OtherProcessesPower::default();
             let processes_tracker = &metric_generator.topology.proc_tracker;
 
             for pid in processes_tracker.get_alive_pids() {
@@ -180,17 +286,21 @@ impl Exporter for RiemannExporter {
                 let mut attributes = HashMap::new();
                 attributes.insert("pid".to_string(), pid.to_string());
 
-                attributes.insert("exe".to_string(), exe.clone());
-
-                if let Some(cmdline_str) = cmdline {
-                    attributes.insert("cmdline".to_string(), cmdline_str.replace('\"', "\\\""));
+                for (key, value) in processes_tracker.get_process_identity_labels(pid) {
+                    let value = match key.as_str() {
+                        "cmdline" => value.replace('\"', "\\\""),
+                        _ => value,
+                    };
+                    attributes.insert(key, value);
+                }
+                attributes.extend(processes_tracker.get_process_pod_labels(pid));
 
-                    if parameters.is_present("qemu") {
-                        if let Some(vmname) = utils::filter_qemu_cmdline(&cmdline_str) {
-                            attributes.insert("vmname".to_string(), vmname);
-                        }
+                if parameters.is_present("qemu") {
+                    if let Some(vmname) = cmdline.as_deref().and_then(utils::filter_qemu_cmdline) {
+                        attributes.insert("vmname".to_string(), vmname);
                     }
                 }
+                merge_labels(&metric_generator.label_providers, &mut attributes);
 
                 // Here we define a metric name with pid + exe string suffix as riemann needs
                 // to differentiate services/metrics
@@ -200,13 +310,13 @@ impl Exporter for RiemannExporter {
                 );
                 if let Some(power) = metric_generator
                     .topology
-                    .get_process_power_consumption_microwatts(pid)
+                    .get_process_power_over_min(pid, &mut other)
                 {
                     data.push(Metric {
                         name: metric_name,
                         metric_type: String::from("gauge"),
                         ttl: 60.0,
-                        hostname: get_hostname(),
+                        hostname: metric_generator.hostname.clone(),
                         timestamp: power.timestamp,
                         state: String::from("ok"),
                         tags: vec!["scaphandre".to_string()],
@@ -216,16 +326,31 @@ impl Exporter for RiemannExporter {
                     });
                 }
             }
+            let other_name = format!(
+                "scaph_process_power_consumption_microwatts_{OTHER_PROCESSES}_{OTHER_PROCESSES}"
+            );
+            data.extend(metric_generator.gen_other_processes_metric(other_name, &other));
             // Send all data
             info!("{}: Send data", Utc::now().format("%Y-%m-%dT%H:%M:%S"));
-            for metric in metric_generator.pop_metrics() {
-                rclient.send_metric(&metric);
-            }
-            for metric in data {
-                rclient.send_metric(&metric);
+            let mut metrics = metric_generator.pop_metrics();
+            metrics.extend(data);
+            if dry_run {
+                println!("{}", format_dry_run_metrics(&metrics));
+                let destination = format!(
+                    "{}:{}",
+                    parameters.value_of("address").unwrap(),
+                    parameters.value_of("port").unwrap()
+                );
+                exit_dry_run(&destination, rclient.send_metrics(&metrics));
             }
+            // errors are logged, the next step is sent anyway
+            let _ = rclient.send_metrics(&metrics);
 
-            thread::sleep(Duration::new(dispatch_duration, 0));
+            wait_for_next_step(
+                &mut metric_generator.topology,
+                jittered_step(Duration::new(dispatch_duration, 0), jitter),
+                measure_interval,
+            );
         }
     }
 
@@ -270,6 +395,7 @@ impl Exporter for RiemannExporter {
         let arg = Arg::with_name("mtls")
             .help("Connect to a Riemann server using mTLS. Parameters address, ca, cert and key must be defined.")
             .long("mtls")
+            .alias("tls")
             .required(false)
             .takes_value(false)
             .requires_all(&["address","cafile", "certfile", "keyfile"]);
@@ -278,6 +404,7 @@ impl Exporter for RiemannExporter {
         let arg = Arg::with_name("cafile")
             .help("CA certificate file (.pem format)")
             .long("ca")
+            .alias("ca-file")
             .required(false)
             .takes_value(true)
             .display_order(1000)
@@ -287,6 +414,7 @@ impl Exporter for RiemannExporter {
         let arg = Arg::with_name("certfile")
             .help("Client certificate file (.pem format)")
             .long("cert")
+            .alias("cert-file")
             .required(false)
             .takes_value(true)
             .display_order(1001)
@@ -296,16 +424,68 @@ impl Exporter for RiemannExporter {
         let arg = Arg::with_name("keyfile")
             .help("Client RSA key")
             .long("key")
+            .alias("key-file")
             .required(false)
             .takes_value(true)
             .display_order(1001)
             .requires("mtls");
         options.push(arg);
 
+        let arg = Arg::with_name("batch_size")
+            .help("Maximum number of events sent to Riemann in a single message")
+            .long("batch-size")
+            .required(false)
+            .takes_value(true)
+            .default_value(DEFAULT_BATCH_SIZE)
+            .validator(validate_positive_integer);
+        options.push(arg);
+
+        options.push(get_measure_interval_option());
+        options.extend(topology_options());
+        options.push(get_hostname_option());
+
+        options.push(get_label_option());
+        options.push(get_label_provider_option());
+        options.push(get_watts_option());
+        options.push(get_perf_instructions_option());
+        options.push(get_dry_run_option());
+        options.push(get_jitter_option());
+
         options
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metric_converted_to_event() {
+        let mut attributes = HashMap::new();
+        attributes.insert(String::from("socket_id"), String::from("0"));
+        let mut metric = Metric {
+            name: String::from("scaph_socket_power_microwatts"),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: Duration::from_millis(1500),
+            hostname: String::from("host"),
+            state: String::from("ok"),
+            tags: vec![String::from("scaphandre")],
+            attributes,
+            description: String::new(),
+            metric_value: MetricValueType::Text(String::from("12000000\n")),
+        };
+        let event = metric_to_event(&metric);
+        assert_eq!(event.get_service(), "scaph_socket_power_microwatts");
+        assert_eq!(event.get_time(), 1);
+        assert_eq!(event.get_time_micros(), 1500000);
+        assert_eq!(event.get_metric_sint64(), 12000000);
+        assert_eq!(event.get_attributes()[0].get_key(), "socket_id");
+        metric.metric_value = MetricValueType::Text(String::from("0,25"));
+        assert_eq!(metric_to_event(&metric).get_metric_d(), 0.25);
+    }
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
@@ -0,0 +1,193 @@
+//! # Self metrics
+//!
+//! The self_metrics module gathers what scaphandre knows about itself (version,
+//! CPU usage, power, memory and the size of its buffers) in a [SelfMetrics]
+//! struct, so that every exporter reports the same `scaph_self_*` values
+//! whatever the format it sends them in.
+use crate::sensors::{
+    anomalies::ANOMALY_KINDS,
+    utils::{current_system_time_since_epoch, page_size, IProcess, IStatM},
+    Record, Topology,
+};
+use std::time::Duration;
+
+use super::utils::get_scaphandre_version;
+
+/// Memory used by scaphandre, in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfMemory {
+    /// Total program size.
+    pub total_program_size_bytes: u64,
+    /// Resident set size.
+    pub resident_set_size_bytes: u64,
+    /// Resident shared memory (i.e., backed by a file).
+    pub shared_resident_size_bytes: u64,
+}
+
+impl SelfMemory {
+    /// Converts the pages counted in `statm` to bytes, given the size of a page.
+    pub fn from_statm(statm: &IStatM, page_size: u64) -> SelfMemory {
+        SelfMemory {
+            total_program_size_bytes: statm.size * page_size,
+            resident_set_size_bytes: statm.resident * page_size,
+            shared_resident_size_bytes: statm.shared * page_size,
+        }
+    }
+}
+
+/// Number of stats and records stored for a socket and its domains.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketSelfMetrics {
+    /// Labels of the socket (socket_id, and package_id and die_id on packages
+    /// made of several dies).
+    pub labels: Vec<(String, String)>,
+    pub stats_nb: usize,
+    pub records_nb: usize,
+    /// Number of records stored for each domain, by domain name.
+    pub domains_records_nb: Vec<(String, usize)>,
+}
+
+/// What scaphandre measured about itself at the last refresh of the topology.
+#[derive(Debug, Clone)]
+pub struct SelfMetrics {
+    /// Time the metrics were collected, since the epoch.
+    pub timestamp: Duration,
+    pub version: String,
+    /// CPU consumed by scaphandre, in percent.
+    pub cpu_usage_percent: Option<Record>,
+    /// Power attributed to scaphandre, in microwatts.
+    pub power_microwatts: Option<Record>,
+    /// Memory used by scaphandre, if it could be read.
+    pub memory: Option<SelfMemory>,
+    /// Number of CPUStat traces stored for the host.
+    pub topo_stats_nb: usize,
+    /// Number of energy records stored for the host.
+    pub topo_records_nb: usize,
+    /// Number of processes monitored.
+    pub topo_procs_nb: usize,
+    /// Size in memory of all the records and stats stored.
+    pub buffers_size_bytes: usize,
+    /// Number of records and stats evicted to keep the buffers under their maximum size.
+    pub buffers_evictions: u64,
+    /// Number of implausible measurements detected since the start, by kind.
+    pub anomaly_counts: Vec<(&'static str, u64)>,
+    pub sockets: Vec<SocketSelfMetrics>,
+}
+
+impl SelfMetrics {
+    /// Collects the self metrics of scaphandre from `topology`.
+    pub fn collect(topology: &Topology) -> SelfMetrics {
+        #[cfg(target_os = "linux")]
+        let myself = IProcess::myself();
+        #[cfg(target_os = "windows")]
+        let myself = IProcess::myself(topology.get_proc_tracker());
+
+        let pid = match &myself {
+            Ok(myself) => myself.pid,
+            Err(_) => std::process::id() as i32,
+        };
+        let memory = match (myself.and_then(|p| p.statm()), page_size()) {
+            (Ok(statm), Ok(page_size)) if page_size > 0 => {
+                Some(SelfMemory::from_statm(&statm, page_size as u64))
+            }
+            _ => None,
+        };
+        SelfMetrics {
+            timestamp: current_system_time_since_epoch(),
+            version: get_scaphandre_version(),
+            cpu_usage_percent: topology.get_process_cpu_consumption_percentage(pid),
+            power_microwatts: topology.get_self_power_microwatts(),
+            memory,
+            topo_stats_nb: topology.stat_buffer.len(),
+            topo_records_nb: topology.record_buffer.len(),
+            topo_procs_nb: topology.proc_tracker.procs.len(),
+            buffers_size_bytes: topology.get_buffers_size_bytes(),
+            buffers_evictions: topology.get_buffers_evictions(),
+            anomaly_counts: ANOMALY_KINDS
+                .iter()
+                .map(|kind| (*kind, topology.anomaly_detector.get_count(kind)))
+                .collect(),
+            sockets: topology
+                .sockets
+                .iter()
+                .map(|socket| SocketSelfMetrics {
+                    labels: socket.get_labels(),
+                    stats_nb: socket.stat_buffer.len(),
+                    records_nb: socket.record_buffer.len(),
+                    domains_records_nb: socket
+                        .domains
+                        .iter()
+                        .map(|domain| (domain.name.clone(), domain.record_buffer.len()))
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the CPU consumed by scaphandre, in percent, as a float.
+    pub fn get_cpu_usage_percent_value(&self) -> Option<f64> {
+        self.cpu_usage_percent.as_ref()?.value_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::units::Unit;
+    use std::collections::HashMap;
+
+    #[test]
+    fn self_metrics_collected() {
+        let statm = IStatM {
+            size: 10,
+            resident: 4,
+            shared: 1,
+            text: 0,
+            lib: 0,
+            data: 0,
+            dt: 0,
+        };
+        assert_eq!(
+            SelfMemory::from_statm(&statm, 4096),
+            SelfMemory {
+                total_program_size_bytes: 40960,
+                resident_set_size_bytes: 16384,
+                shared_resident_size_bytes: 4096,
+            }
+        );
+
+        let mut topology = Topology::new();
+        topology.safe_add_socket(0, vec![], vec![], String::from(""), 1, HashMap::new());
+        topology.safe_add_domain_to_socket(0, 0, "dram", "", 1, HashMap::new());
+        topology.sockets[0].record_buffer.push(Record::new(
+            Duration::from_secs(1),
+            String::from("1000"),
+            Unit::MicroJoule,
+        ));
+        let metrics = SelfMetrics::collect(&topology);
+        assert_eq!(metrics.version, get_scaphandre_version());
+        assert!(metrics.memory.is_some());
+        assert_eq!(metrics.topo_records_nb, 0);
+        assert_eq!(metrics.anomaly_counts.len(), ANOMALY_KINDS.len());
+        assert_eq!(metrics.sockets.len(), 1);
+        assert_eq!(metrics.sockets[0].records_nb, 1);
+        assert_eq!(
+            metrics.sockets[0].domains_records_nb,
+            vec![(String::from("dram"), 0)]
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
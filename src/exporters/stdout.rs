@@ -5,15 +5,19 @@ use crate::sensors::{utils::IProcess, Sensor};
 use colored::*;
 use regex::Regex;
 use std::fmt::Write as _;
-use std::thread;
 use std::time::{Duration, Instant};
 
 /// An Exporter that displays power consumption data of the host
 /// and its processes on the standard output of the terminal.
 pub struct StdoutExporter {
     sensor: Box<dyn Sensor>,
+    /// Prints tab-separated values instead of the human readable table.
+    tsv: bool,
 }
 
+/// Header of the tab-separated values printed with --tsv.
+const TSV_HEADER: &str = "timestamp\tkind\tid\tname\tpower_microwatts";
+
 impl Exporter for StdoutExporter {
     /// Lanches runner()
     fn run(&mut self, parameters: ArgMatches) {
@@ -66,6 +70,17 @@ impl Exporter for StdoutExporter {
             .takes_value(false);
         options.push(arg);
 
+        let arg = Arg::with_name("tsv")
+            .help("Print tab-separated values (timestamp, kind, id, name and power in microwatts) after a header line, instead of the human readable table, for scripts.")
+            .long("tsv")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        options.push(utils::get_measure_interval_option());
+        options.extend(utils::topology_options());
+        options.push(utils::get_hostname_option());
+
         options
     }
 }
@@ -73,7 +88,7 @@ impl Exporter for StdoutExporter {
 impl StdoutExporter {
     /// Instantiates and returns a new StdoutExporter
     pub fn new(sensor: Box<dyn Sensor>) -> StdoutExporter {
-        StdoutExporter { sensor }
+        StdoutExporter { sensor, tsv: false }
     }
 
     /// Runs iteration() every 'step', during until 'timeout'
@@ -122,23 +137,34 @@ impl StdoutExporter {
         let topology = self.sensor.get_topology().unwrap();
         let mut metric_generator = MetricGenerator::new(
             topology,
-            utils::get_hostname(),
+            utils::resolve_hostname(&parameters),
             parameters.is_present("qemu"),
             parameters.is_present("containers"),
         );
+        utils::apply_topology_options(&mut metric_generator.topology, &parameters);
+
+        let step = Duration::new(step_duration, 0);
+        let measure_interval = utils::get_measure_interval(&parameters);
 
-        println!("Measurement step is: {step_duration}s");
+        self.tsv = parameters.is_present("tsv");
+        if self.tsv {
+            // keep stdout parseable
+            eprintln!("Measurement step is: {step_duration}s");
+            println!("{TSV_HEADER}");
+        } else {
+            println!("Measurement step is: {step_duration}s");
+        }
         if timeout_secs == 0 {
             loop {
                 self.iterate(&regex_filter, process_number, &mut metric_generator);
-                thread::sleep(Duration::new(step_duration, 0));
+                utils::wait_for_next_step(&mut metric_generator.topology, step, measure_interval);
             }
         } else {
             let now = Instant::now();
 
             while now.elapsed().as_secs() <= timeout_secs {
                 self.iterate(&regex_filter, process_number, &mut metric_generator);
-                thread::sleep(Duration::new(step_duration, 0));
+                utils::wait_for_next_step(&mut metric_generator.topology, step, measure_interval);
             }
         }
     }
@@ -154,7 +180,30 @@ impl StdoutExporter {
             .proc_tracker
             .clean_terminated_process_records_vectors();
         metric_generator.topology.refresh();
-        self.show_metrics(regex_filter, process_number, metric_generator);
+        if self.tsv {
+            self.show_tsv(regex_filter, process_number, metric_generator);
+        } else {
+            self.show_metrics(regex_filter, process_number, metric_generator);
+        }
+    }
+
+    /// Prints the power of the host, sockets, domains and selected processes
+    /// as tab-separated values.
+    fn show_tsv(
+        &self,
+        regex_filter: &Option<Regex>,
+        process_number: u16,
+        metric_generator: &mut MetricGenerator,
+    ) {
+        metric_generator.gen_all_metrics();
+        let metrics = metric_generator.pop_metrics();
+        let pids: Vec<i32> = select_consumers(regex_filter, process_number, metric_generator)
+            .iter()
+            .map(|(process, _)| process.pid)
+            .collect();
+        for row in get_tsv_rows(&metrics, &pids) {
+            println!("{row}");
+        }
     }
 
     fn show_metrics(
@@ -236,20 +285,12 @@ impl StdoutExporter {
             }
         }
 
-        let consumers: Vec<(IProcess, f64)>;
         if let Some(regex_filter) = regex_filter {
             println!("Processes filtered by '{}':", regex_filter.as_str());
-            consumers = metric_generator
-                .topology
-                .proc_tracker
-                .get_filtered_processes(regex_filter);
         } else {
             println!("Top {process_number} consumers:");
-            consumers = metric_generator
-                .topology
-                .proc_tracker
-                .get_top_consumers(process_number);
         }
+        let consumers = select_consumers(regex_filter, process_number, metric_generator);
 
         info!("consumers : {:?}", consumers);
         println!("Power\t\tPID\tExe");
@@ -260,28 +301,157 @@ impl StdoutExporter {
                 if let Some(process) = metrics.iter().find(|x| {
                     if x.name == "scaph_process_power_consumption_microwatts" {
                         let pid = x.attributes.get("pid").unwrap();
-                        pid.parse::<i32>().unwrap() == c.0.pid
+                        pid.parse::<i32>().ok() == Some(c.0.pid)
                     } else {
                         false
                     }
                 }) {
+                    // processes resolved to a pod with the kubelet are followed by it
+                    let pod = match (
+                        process.attributes.get("namespace"),
+                        process.attributes.get("pod"),
+                    ) {
+                        (Some(namespace), Some(pod)) => format!("\t{namespace}/{pod}"),
+                        _ => String::new(),
+                    };
                     println!(
-                        "{} W\t{}\t{:?}",
+                        "{} W\t{}\t{:?}{}",
                         format!("{}", process.metric_value).parse::<f32>().unwrap() / 1000000.0,
                         process.attributes.get("pid").unwrap(),
-                        process.attributes.get("exe").unwrap()
+                        process.attributes.get("exe").unwrap(),
+                        pod
                     );
                 }
             }
         }
+        if let Some(other) = metrics.iter().find(|x| x.is_other_processes()) {
+            println!(
+                "{} W\t{}\t({} processes under --min-power-microwatts)",
+                format!("{}", other.metric_value).parse::<f32>().unwrap() / 1000000.0,
+                OTHER_PROCESSES,
+                other.attributes.get("processes").unwrap()
+            );
+        }
         println!("------------------------------------------------------------\n");
     }
 }
 
+/// Returns the processes matching regex_filter if given, or the process_number
+/// processes consuming the most.
+fn select_consumers(
+    regex_filter: &Option<Regex>,
+    process_number: u16,
+    metric_generator: &MetricGenerator,
+) -> Vec<(IProcess, f64)> {
+    let proc_tracker = &metric_generator.topology.proc_tracker;
+    match regex_filter {
+        Some(regex_filter) => proc_tracker.get_filtered_processes(regex_filter),
+        None => proc_tracker.get_top_consumers(process_number),
+    }
+}
+
+/// Returns the rows matching TSV_HEADER of the host, sockets and domains power
+/// metrics, then of the processes in `pids` and of the aggregated small ones.
+fn get_tsv_rows(metrics: &[Metric], pids: &[i32]) -> Vec<String> {
+    let row = |metric: &Metric, kind: &str, id: &str, name: &str| {
+        format!(
+            "{:.3}\t{}\t{}\t{}\t{}",
+            metric.timestamp.as_secs_f64(),
+            kind,
+            id,
+            name.replace(['\t', '\n'], " "),
+            metric.metric_value
+        )
+    };
+    let attribute = |metric: &Metric, key: &str| -> String {
+        metric.attributes.get(key).cloned().unwrap_or_default()
+    };
+    let mut rows = vec![];
+    for metric in metrics {
+        match metric.name.as_str() {
+            "scaph_host_power_microwatts" => rows.push(row(metric, "host", "", "")),
+            "scaph_socket_power_microwatts" => {
+                rows.push(row(metric, "socket", &attribute(metric, "socket_id"), ""))
+            }
+            "scaph_domain_power_microwatts" => rows.push(row(
+                metric,
+                "domain",
+                &attribute(metric, "socket_id"),
+                &attribute(metric, "domain_name"),
+            )),
+            _ => {}
+        }
+    }
+    let processes: Vec<&Metric> = metrics
+        .iter()
+        .filter(|m| m.name == "scaph_process_power_consumption_microwatts")
+        .collect();
+    for pid in pids {
+        let pid = pid.to_string();
+        if let Some(process) = processes.iter().find(|m| attribute(m, "pid") == pid) {
+            rows.push(row(process, "process", &pid, &attribute(process, "exe")));
+        }
+    }
+    if let Some(other) = processes.iter().find(|m| m.is_other_processes()) {
+        rows.push(row(other, "process", OTHER_PROCESSES, OTHER_PROCESSES));
+    }
+    rows
+}
+
 #[cfg(test)]
 mod tests {
-    //#[test]
-    //fn get_cons_socket0() {}
+    use super::*;
+
+    #[test]
+    fn tsv_rows_of_metrics() {
+        let metric = |name: &str, attributes: &[(&str, &str)], microwatts: &str| Metric {
+            name: String::from(name),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            timestamp: Duration::from_millis(1500),
+            hostname: String::from("host"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes: attributes
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            description: String::new(),
+            metric_value: MetricValueType::Text(String::from(microwatts)),
+        };
+        let metrics = vec![
+            metric(
+                "scaph_process_power_consumption_microwatts",
+                &[("pid", "42"), ("exe", "my\tapp")],
+                "300",
+            ),
+            metric("scaph_host_power_microwatts", &[], "5000"),
+            metric(
+                "scaph_socket_power_microwatts",
+                &[("socket_id", "0")],
+                "4000",
+            ),
+            metric(
+                "scaph_domain_power_microwatts",
+                &[("socket_id", "0"), ("domain_name", "dram")],
+                "1000",
+            ),
+            metric(
+                "scaph_process_power_consumption_microwatts",
+                &[("pid", "43"), ("exe", "ignored")],
+                "200",
+            ),
+        ];
+        assert_eq!(
+            get_tsv_rows(&metrics, &[42]),
+            vec![
+                "1.500\thost\t\t\t5000",
+                "1.500\tsocket\t0\t\t4000",
+                "1.500\tdomain\t0\tdram\t1000",
+                "1.500\tprocess\t42\tmy app\t300",
+            ]
+        );
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
@@ -1,12 +1,31 @@
 //! # utils
 //!
 //! The utils module provides common functions used by the exporters.
-use clap::crate_version;
+use super::Metric;
+#[cfg(target_os = "linux")]
+use crate::sensors::cstates::DEFAULT_CPU_SYSFS_ROOT;
+use crate::sensors::{
+    attribution::{get_attribution_strategy, ATTRIBUTION_STRATEGIES},
+    utils::{AttributionBasis, ProcessIdentity},
+    Topology,
+};
+use clap::{crate_version, Arg, ArgMatches};
+use colored::*;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 #[cfg(feature = "containers")]
 use {
     docker_sync::Docker,
     k8s_sync::{errors::KubernetesError, kubernetes::Kubernetes},
 };
+#[cfg(feature = "flate2")]
+use {
+    flate2::{write::GzEncoder, Compression},
+    std::io::Write,
+};
 
 /// Returns a cmdline String filtered from potential characters that
 /// could break exporters output.
@@ -56,9 +75,951 @@ pub fn get_hostname() -> String {
     )
 }
 
+/// Returns the `--hostname` option, shared by the exporters reporting the
+/// name of the host.
+pub fn get_hostname_option() -> Arg<'static, 'static> {
+    Arg::with_name("hostname")
+        .help("Name of the host reported in metrics, instead of the system hostname (like the node name in Kubernetes, as the hostname of a container is a random id). Can also be set with the SCAPH_HOSTNAME environment variable.")
+        .long("hostname")
+        .required(false)
+        .takes_value(true)
+}
+
+/// Returns the hostname given with `--hostname` or the `SCAPH_HOSTNAME`
+/// environment variable, if any.
+pub fn get_hostname_override(parameters: &ArgMatches) -> Option<String> {
+    parameters
+        .value_of("hostname")
+        .map(String::from)
+        .or_else(|| std::env::var("SCAPH_HOSTNAME").ok())
+        .filter(|hostname| !hostname.is_empty())
+}
+
+/// Returns the hostname to report: the one given with `--hostname` or the
+/// `SCAPH_HOSTNAME` environment variable, or the system hostname.
+pub fn resolve_hostname(parameters: &ArgMatches) -> String {
+    get_hostname_override(parameters).unwrap_or_else(get_hostname)
+}
+
+/// Returns the secret given with the `name` option, read from the file given
+/// with the `file_name` option (like a Docker or Kubernetes secret), or given
+/// with the `env_var` environment variable, if any.
+///
+/// The file is read once, when called, and its trailing newline is ignored.
+/// Returns an error if the file can't be read.
+pub fn get_secret(
+    parameters: &ArgMatches,
+    name: &str,
+    file_name: &str,
+    env_var: &str,
+) -> Result<Option<String>, String> {
+    if let Some(secret) = parameters.value_of(name) {
+        return Ok(Some(secret.to_owned()));
+    }
+    if let Some(path) = parameters.value_of(file_name) {
+        return read_secret_file(path)
+            .map(Some)
+            .map_err(|e| format!("Couldn't read --{}: {}", file_name.replace('_', "-"), e));
+    }
+    Ok(std::env::var(env_var).ok())
+}
+
+/// Returns the content of the secret file at `path`, without its trailing newline.
+pub fn read_secret_file(path: &str) -> Result<String, String> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.trim_end_matches(&['\n', '\r'][..]).to_owned()),
+        Err(e) => Err(format!("{path}: {e}")),
+    }
+}
+
+/// Returns the `--measure-interval` option, shared by the exporters that measure
+/// more often than they export.
+pub fn get_measure_interval_option() -> Arg<'static, 'static> {
+    Arg::with_name("measure_interval")
+        .help("Interval between measurements, in milliseconds. Metrics are still exported every step, averaged since the previous export. Measures once per step by default.")
+        .long("measure-interval")
+        .required(false)
+        .takes_value(true)
+        .validator(validate_positive_integer)
+}
+
+/// Returns the measure interval given with `--measure-interval`, if any.
+pub fn get_measure_interval(parameters: &ArgMatches) -> Option<Duration> {
+    parameters.value_of("measure_interval").map(|interval| {
+        // the value is checked by the validator of the option
+        Duration::from_millis(
+            interval
+                .parse()
+                .expect("Wrong measure_interval value, should be a number of milliseconds"),
+        )
+    })
+}
+
+/// Returns the `--io-power-fraction` option, shared by the exporters reporting
+/// the power consumption of processes.
+fn get_io_attribution_option() -> Arg<'static, 'static> {
+    Arg::with_name("io_power_fraction")
+        .help("Fraction (between 0 and 1) of the DRAM domain power to attribute to processes according to their block IO (from /proc/PID/io) instead of their CPU time. Power is only attributed according to CPU time by default.")
+        .long("io-power-fraction")
+        .required(false)
+        .takes_value(true)
+        .validator(validate_fraction)
+}
+
+/// Checks that an option value is a number for which `is_valid` is true,
+/// `expected` describing the valid values in the usage error.
+pub fn validate_number(
+    value: &str,
+    is_valid: impl Fn(f64) -> bool,
+    expected: &str,
+) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(number) if is_valid(number) => Ok(()),
+        _ => Err(format!("should be {expected}, got {value}")),
+    }
+}
+
+/// Checks that an option value is an integer of type `T` for which `is_valid`
+/// is true, `expected` describing the valid values in the usage error.
+pub fn validate_integer<T: FromStr + Copy>(
+    value: &str,
+    is_valid: impl Fn(T) -> bool,
+    expected: &str,
+) -> Result<(), String> {
+    match value.trim().parse::<T>() {
+        Ok(number) if is_valid(number) => Ok(()),
+        _ => Err(format!("should be {expected}, got {value}")),
+    }
+}
+
+/// Checks that an option value is an integer greater than or equal to 0.
+pub fn validate_unsigned_integer(value: String) -> Result<(), String> {
+    validate_integer::<u64>(&value, |_| true, "a positive integer or 0")
+}
+
+/// Checks that an option value is an integer greater than 0.
+pub fn validate_positive_integer(value: String) -> Result<(), String> {
+    validate_integer::<u64>(&value, |number| number > 0, "a positive integer")
+}
+
+/// Checks that an option value is a number between 0 and 1.
+fn validate_fraction(value: String) -> Result<(), String> {
+    validate_number(
+        &value,
+        |number| (0.0..=1.0).contains(&number),
+        "a number between 0 and 1",
+    )
+}
+
+/// Enables IO attribution in the topology if `--io-power-fraction` is given.
+fn enable_io_attribution(topology: &mut Topology, parameters: &ArgMatches) {
+    // the value is checked by the validator of the option
+    if let Some(fraction) = parameters
+        .value_of("io_power_fraction")
+        .and_then(|fraction| fraction.parse::<f64>().ok())
+    {
+        #[cfg(target_os = "linux")]
+        if let Err(e) = topology.enable_io_attribution(fraction) {
+            warn!("IO attribution disabled: {}", e);
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (topology, fraction);
+            warn!("IO attribution is only available on GNU/Linux");
+        }
+    }
+}
+
+/// Returns the `--only-pids` option, shared by the exporters reporting
+/// the power consumption of processes.
+fn get_only_pids_option() -> Arg<'static, 'static> {
+    Arg::with_name("only_pids")
+        .help("Comma separated list of pids to track, like 1234,5678. Other processes are ignored, host and sockets metrics are unaffected. All processes are tracked by default.")
+        .long("only-pids")
+        .required(false)
+        .takes_value(true)
+        .validator(|pids| {
+            pids.split(',').try_for_each(|pid| {
+                validate_integer::<i32>(pid, |pid| pid > 0, "a comma separated list of pids")
+            })
+        })
+}
+
+/// Restricts the processes tracked by the topology to the ones given with `--only-pids`, if any.
+fn set_pid_allowlist(topology: &mut Topology, parameters: &ArgMatches) {
+    if let Some(pids) = parameters.value_of("only_pids") {
+        // the value is checked by the validator of the option
+        let pids = pids
+            .split(',')
+            .map(|pid| {
+                pid.trim()
+                    .parse()
+                    .expect("Wrong only_pids value, should be a comma separated list of pids")
+            })
+            .collect();
+        topology.proc_tracker.set_pid_allowlist(Some(pids));
+    }
+}
+
+/// Returns the `--pid-namespace` option, shared by the exporters reporting
+/// the power consumption of processes.
+fn get_pid_namespace_option() -> Arg<'static, 'static> {
+    Arg::with_name("pid_namespace")
+        .help("Pid, on the host, of a process whose PID namespace is measured, like the init process of a container. Only the processes of this namespace are tracked, with their pid in the namespace as the ns_pid label. Host and sockets metrics are unaffected.")
+        .long("pid-namespace")
+        .required(false)
+        .takes_value(true)
+        .validator(|pid| validate_integer::<i32>(&pid, |pid| pid > 0, "a pid"))
+}
+
+/// Restricts the processes tracked by the topology to the PID namespace given
+/// with `--pid-namespace`, if any.
+fn set_pid_namespace(topology: &mut Topology, parameters: &ArgMatches) {
+    if let Some(pid) = parameters.value_of("pid_namespace") {
+        // the value is checked by the validator of the option
+        let pid: i32 = pid
+            .trim()
+            .parse()
+            .expect("Wrong pid_namespace value, should be a pid");
+        #[cfg(target_os = "linux")]
+        if let Err(e) = topology.proc_tracker.set_pid_namespace(pid) {
+            warn!("Ignoring --pid-namespace: {}", e);
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (topology, pid);
+            warn!("PID namespaces are only available on GNU/Linux");
+        }
+    }
+}
+
+/// Returns the `--attribution-basis`, `--system-time-weight` and
+/// `--attribution-strategy` options, telling which CPU time of processes their
+/// power is attributed from, and how.
+fn get_attribution_basis_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("attribution_basis")
+            .help("CPU time of processes their power is attributed from: user (time spent in user mode), user+system (time spent in user and kernel mode) or weighted (user time plus the system time multiplied by --system-time-weight).")
+            .long("attribution-basis")
+            .required(false)
+            .takes_value(true)
+            .possible_values(&["user", "user+system", "weighted"])
+            .default_value("user+system"),
+        Arg::with_name("system_time_weight")
+            .help("Weight of the time spent in kernel mode relatively to user mode, with --attribution-basis weighted.")
+            .long("system-time-weight")
+            .required(false)
+            .takes_value(true)
+            .default_value("0.5")
+            .validator(|value| {
+                validate_number(
+                    &value,
+                    |weight| weight >= 0.0 && weight.is_finite(),
+                    "a positive number",
+                )
+            }),
+        Arg::with_name("attribution_strategy")
+            .help("How the power of the host is divided between processes: cpu-time (by their share of the CPU time) or active-power (only the power above the idle baseline of the host, by their share of the CPU time; the same as cpu-time without --idle-baseline-window).")
+            .long("attribution-strategy")
+            .required(false)
+            .takes_value(true)
+            .possible_values(&ATTRIBUTION_STRATEGIES)
+            .default_value("cpu-time"),
+        Arg::with_name("smt_aware")
+            .help("Weights the CPU time of processes by the sharing of their physical core with its other hyperthreads (SMT siblings), so that a process running alone on a core is attributed more than one running while the sibling is busy. Approximate: the time of a process is weighted as if spent on the CPU it last ran on.")
+            .long("smt-aware")
+            .required(false)
+            .takes_value(false),
+    ]
+}
+
+/// Sets the attribution basis and strategy of the topology from
+/// `--attribution-basis`, `--attribution-strategy` and `--smt-aware`.
+fn set_attribution_basis(topology: &mut Topology, parameters: &ArgMatches) {
+    let name = parameters
+        .value_of("attribution_basis")
+        .unwrap_or("user+system");
+    // values are checked by the validators and possible values of the options
+    let system_weight = parameters
+        .value_of("system_time_weight")
+        .and_then(|weight| weight.parse().ok())
+        .unwrap_or(0.5);
+    match AttributionBasis::from_name(name, system_weight) {
+        Ok(basis) => topology.set_attribution_basis(basis),
+        Err(e) => warn!("{}, keeping the default attribution basis", e),
+    }
+    let strategy = parameters
+        .value_of("attribution_strategy")
+        .unwrap_or("cpu-time");
+    match get_attribution_strategy(strategy) {
+        Ok(strategy) => topology.set_attribution_strategy(strategy),
+        Err(e) => warn!("{}, keeping the default attribution strategy", e),
+    }
+    if parameters.is_present("smt_aware") {
+        #[cfg(target_os = "linux")]
+        if let Err(e) =
+            topology.enable_smt_attribution(&topology.get_sysfs_path(DEFAULT_CPU_SYSFS_ROOT))
+        {
+            warn!("SMT aware attribution disabled: {}", e);
+        }
+        #[cfg(not(target_os = "linux"))]
+        warn!("SMT aware attribution is only available on GNU/Linux");
+    }
+}
+
+/// Returns the `--perf-instructions` option, enabling the energy per
+/// instruction metrics.
+pub fn get_perf_instructions_option() -> Arg<'static, 'static> {
+    Arg::with_name("perf_instructions")
+        .help("Counts the instructions retired by the CPUs with perf_event, to report the energy spent per instruction (scaph_host_picojoules_per_instruction and scaph_socket_picojoules_per_instruction). Needs CAP_PERFMON or kernel.perf_event_paranoid set to 0 or less.")
+        .long("perf-instructions")
+        .required(false)
+        .takes_value(false)
+}
+
+/// Enables the counting of instructions in the topology if `--perf-instructions` is given.
+pub fn set_perf_instructions(topology: &mut Topology, parameters: &ArgMatches) {
+    if !parameters.is_present("perf_instructions") {
+        return;
+    }
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    if let Err(e) = topology.enable_instructions_counter() {
+        warn!("Energy per instruction disabled: {}", e);
+    }
+    #[cfg(not(all(target_os = "linux", feature = "perf")))]
+    {
+        let _ = topology;
+        warn!("Energy per instruction is only available on GNU/Linux, with the perf feature");
+    }
+}
+
+/// Returns the `--kubelet-url` option, telling where to get the pods of the
+/// node from.
+fn get_kubelet_url_option() -> Arg<'static, 'static> {
+    Arg::with_name("kubelet_url")
+        .help("Url of the read-only API of the kubelet, to label processes running in Kubernetes pods with their namespace, pod and container. Implied by --containers. [default: http://127.0.0.1:10255]")
+        .long("kubelet-url")
+        .required(false)
+        .takes_value(true)
+}
+
+/// Resolves processes to their Kubernetes pod with the kubelet if `--containers`
+/// or `--kubelet-url` is given. Nothing is queried on hosts without pods.
+fn set_kubelet_pods(topology: &mut Topology, parameters: &ArgMatches) {
+    if !parameters.is_present("containers") && !parameters.is_present("kubelet_url") {
+        return;
+    }
+    #[cfg(all(target_os = "linux", feature = "kubelet"))]
+    topology.proc_tracker.enable_kubelet_pods(
+        parameters
+            .value_of("kubelet_url")
+            .unwrap_or(crate::sensors::kubelet::DEFAULT_KUBELET_URL),
+    );
+    #[cfg(not(all(target_os = "linux", feature = "kubelet")))]
+    let _ = topology;
+}
+
+/// Returns the `--process-identity`, `--cmdline-max-length` and
+/// `--process-identity-regex` options, telling which labels identify processes.
+fn get_process_identity_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("process_identity")
+            .help("Labels identifying processes, besides their pid: cmdline (exe label with the short name of the process, cmdline label with its command line), comm (only the short name as exe), exe (only the path of the executable as exe) or regex (only the first group captured by --process-identity-regex in the command line as exe). cmdline makes the most series.")
+            .long("process-identity")
+            .required(false)
+            .takes_value(true)
+            .possible_values(&["cmdline", "comm", "exe", "regex"])
+            .default_value("cmdline")
+            .requires_if("regex", "process_identity_regex"),
+        Arg::with_name("cmdline_max_length")
+            .help("Maximum number of characters of the cmdline label, with --process-identity cmdline. Not truncated by default.")
+            .long("cmdline-max-length")
+            .required(false)
+            .takes_value(true)
+            .validator(|length| {
+                length
+                    .parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|_| format!("should be a number of characters, got {length}"))
+            }),
+        Arg::with_name("process_identity_regex")
+            .help("Regex applied to the command line of processes with --process-identity regex, like 'java .*-jar ([^ ]+)'. Its first group (or the whole match without group) is the exe label, the short name of the process if it doesn't match.")
+            .long("process-identity-regex")
+            .required(false)
+            .takes_value(true)
+            .validator(|regex| {
+                regex::Regex::new(&regex)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }),
+    ]
+}
+
+/// Sets which labels identify processes from `--process-identity`.
+fn set_process_identity(topology: &mut Topology, parameters: &ArgMatches) {
+    // values are checked by the validators and possible values of the options
+    let name = parameters.value_of("process_identity").unwrap_or("cmdline");
+    let max_length = parameters
+        .value_of("cmdline_max_length")
+        .and_then(|length| length.parse().ok());
+    match ProcessIdentity::from_name(
+        name,
+        max_length,
+        parameters.value_of("process_identity_regex"),
+    ) {
+        Ok(identity) => topology.proc_tracker.set_process_identity(identity),
+        Err(e) => warn!("{}, keeping the default process identity", e),
+    }
+}
+
+/// Returns the `--exclude-self` option, shared by the exporters reporting the
+/// power consumption of the host.
+fn get_exclude_self_option() -> Arg<'static, 'static> {
+    Arg::with_name("exclude_self")
+        .help("Subtract the power attributed to scaphandre itself from the host power, and don't report scaphandre as a process. Its power is still reported as scaph_self_power_microwatts.")
+        .long("exclude-self")
+        .required(false)
+        .takes_value(false)
+}
+
+/// Excludes scaphandre's own power from the host power if `--exclude-self` is given.
+fn set_exclude_self(topology: &mut Topology, parameters: &ArgMatches) {
+    topology.set_exclude_self(parameters.is_present("exclude_self"));
+}
+
+/// Returns the `--max-buffers-size-kB` option, capping the memory used by the
+/// records and stats kept by the topology.
+fn get_buffers_max_size_option() -> Arg<'static, 'static> {
+    Arg::with_name("max_buffers_size_kb")
+        .help("Maximum size in memory of all the records and stats buffers (host, sockets, domains and processes together), in kB. The oldest entries are evicted once it is exceeded.")
+        .long("max-buffers-size-kB")
+        .required(false)
+        .takes_value(true)
+        .validator(|max_kbytes| {
+            validate_integer::<u32>(&max_kbytes, |max_kbytes| max_kbytes > 0, "a positive number of kB")
+        })
+}
+
+/// Caps the size of the buffers of the topology if `--max-buffers-size-kB` is given.
+fn set_buffers_max_size(topology: &mut Topology, parameters: &ArgMatches) {
+    // the value is checked by the validator of the option
+    if let Some(max_kbytes) = parameters
+        .value_of("max_buffers_size_kb")
+        .and_then(|max_kbytes| max_kbytes.parse().ok())
+    {
+        topology.set_buffers_max_kbytes(max_kbytes);
+    }
+}
+
+/// Returns the `--min-power-microwatts` option, aggregating the processes
+/// consuming less than a threshold to reduce the number of series.
+fn get_min_process_power_option() -> Arg<'static, 'static> {
+    Arg::with_name("min_power_microwatts")
+        .help("Aggregate the processes consuming less than this power, in microwatts, in a single process whose pid and exe are \"other\", instead of reporting them one by one.")
+        .long("min-power-microwatts")
+        .required(false)
+        .takes_value(true)
+        .validator(validate_unsigned_integer)
+}
+
+/// Sets the power under which processes are aggregated if `--min-power-microwatts` is given.
+fn set_min_process_power(topology: &mut Topology, parameters: &ArgMatches) {
+    // the value is checked by the validator of the option
+    if let Some(min_microwatts) = parameters
+        .value_of("min_power_microwatts")
+        .and_then(|min_microwatts| min_microwatts.parse().ok())
+    {
+        topology.set_min_process_power_microwatts(min_microwatts);
+    }
+}
+
+/// Returns the `--power-smoothing` option, smoothing the host and sockets
+/// power with a moving average.
+fn get_power_smoothing_option() -> Arg<'static, 'static> {
+    Arg::with_name("power_smoothing")
+        .help("Smooth the host and sockets power with an exponentially weighted moving average, the value (greater than 0, at most 1) being the weight of the last measurement. The raw power is still reported as scaph_host_power_raw_microwatts and scaph_socket_power_raw_microwatts.")
+        .long("power-smoothing")
+        .required(false)
+        .takes_value(true)
+        .validator(|value| {
+            validate_number(
+                &value,
+                |alpha| alpha > 0.0 && alpha <= 1.0,
+                "a number greater than 0 and at most 1",
+            )
+        })
+}
+
+/// Enables the smoothing of the host and sockets power if `--power-smoothing` is given.
+fn set_power_smoothing(topology: &mut Topology, parameters: &ArgMatches) {
+    // the value is checked by the validator of the option
+    if let Some(alpha) = parameters
+        .value_of("power_smoothing")
+        .and_then(|alpha| alpha.parse().ok())
+    {
+        if let Err(e) = topology.enable_power_smoothing(alpha) {
+            warn!("Power smoothing disabled: {}", e);
+        }
+    }
+}
+
+/// Returns the options tuning how the topology measures and attributes power
+/// to processes, shared by the exporters reporting the power of processes.
+pub fn topology_options() -> Vec<Arg<'static, 'static>> {
+    let mut options = vec![
+        get_io_attribution_option(),
+        get_only_pids_option(),
+        get_pid_namespace_option(),
+        get_exclude_self_option(),
+        get_buffers_max_size_option(),
+        get_power_smoothing_option(),
+        get_min_process_power_option(),
+        get_kubelet_url_option(),
+    ];
+    options.extend(get_attribution_basis_options());
+    options.extend(get_process_identity_options());
+    options
+}
+
+/// Applies to `topology` the options returned by [`topology_options`].
+pub fn apply_topology_options(topology: &mut Topology, parameters: &ArgMatches) {
+    enable_io_attribution(topology, parameters);
+    set_attribution_basis(topology, parameters);
+    set_pid_allowlist(topology, parameters);
+    set_pid_namespace(topology, parameters);
+    set_exclude_self(topology, parameters);
+    set_buffers_max_size(topology, parameters);
+    set_power_smoothing(topology, parameters);
+    set_min_process_power(topology, parameters);
+    set_process_identity(topology, parameters);
+    set_kubelet_pods(topology, parameters);
+}
+
+/// Returns the `--watts` option, giving power metrics in watts as well.
+pub fn get_watts_option() -> Arg<'static, 'static> {
+    Arg::with_name("watts")
+        .help("Along with each power metric in microwatts, like scaph_host_power_microwatts, send the same metric in watts as a float, like scaph_host_power_watts.")
+        .long("watts")
+        .required(false)
+        .takes_value(false)
+}
+
+/// Waits for `step`, refreshing the topology every `measure_interval` meanwhile
+/// with intermediate records, so that the next refresh gives the consumption
+/// averaged over the whole step.
+pub fn wait_for_next_step(
+    topology: &mut Topology,
+    step: Duration,
+    measure_interval: Option<Duration>,
+) {
+    match measure_interval {
+        Some(interval) if !interval.is_zero() && interval < step => {
+            let start = Instant::now();
+            while start.elapsed() + interval < step {
+                thread::sleep(interval);
+                topology.refresh_intermediate();
+            }
+            if let Some(remaining) = step.checked_sub(start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+        _ => thread::sleep(step),
+    }
+}
+
+/// Returns the `--jitter` option of the exporters sending metrics every step.
+pub fn get_jitter_option() -> Arg<'static, 'static> {
+    Arg::with_name("jitter")
+        .help("Randomizes each wait between two sends by up to this percentage of the step, more or less (like 10 for a 60s step waited between 54 and 66s), so that many agents started together don't send at the same time.")
+        .long("jitter")
+        .required(false)
+        .takes_value(true)
+        .validator(|value| {
+            validate_number(
+                &value,
+                |percent| (0.0..=100.0).contains(&percent),
+                "a percentage between 0 and 100",
+            )
+        })
+}
+
+/// Returns the jitter given with `--jitter`, as a fraction of the step, if any.
+pub fn get_jitter(parameters: &ArgMatches) -> Option<f64> {
+    // the value is checked by the validator of the option
+    let percent: f64 = parameters.value_of("jitter")?.parse().ok()?;
+    Some(percent / 100.0).filter(|jitter| *jitter > 0.0)
+}
+
+/// Returns `step` randomized by up to `jitter` times itself, more or less.
+pub fn jittered_step(step: Duration, jitter: Option<f64>) -> Duration {
+    match jitter {
+        Some(jitter) => jitter_step(step, jitter, rand::random::<f64>() * 2.0 - 1.0),
+        None => step,
+    }
+}
+
+/// Returns `step` changed by `offset` (between -1 and 1) times `jitter` times itself.
+fn jitter_step(step: Duration, jitter: f64, offset: f64) -> Duration {
+    step.mul_f64((1.0 + jitter * offset).max(0.0))
+}
+
+/// Time measured before the single iteration of `--dry-run`, so that it has
+/// power values to send.
+const DRY_RUN_MEASURE_MILLIS: u64 = 1000;
+
+/// Returns the `--dry-run` option of the exporters sending metrics.
+pub fn get_dry_run_option() -> Arg<'static, 'static> {
+    Arg::with_name("dry_run")
+        .help("Check the configuration and exit: measure once, print the metrics and send them once, exiting with a non-zero status if sending failed.")
+        .long("dry-run")
+        .required(false)
+        .takes_value(false)
+}
+
+/// Measures during a short while, so that the next refresh of the topology
+/// gives power values for the single iteration of `--dry-run`.
+pub fn prepare_dry_run(topology: &mut Topology) {
+    topology.refresh();
+    thread::sleep(Duration::from_millis(DRY_RUN_MEASURE_MILLIS));
+}
+
+/// Formats metrics as they would be sent by `--dry-run`, one per line, like
+/// `name{label="value"} value`, labels sorted by name.
+pub(super) fn format_dry_run_metrics(metrics: &[Metric]) -> String {
+    let mut lines = vec![];
+    for metric in metrics {
+        let mut labels: Vec<_> = metric.attributes.iter().collect();
+        labels.sort();
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(name, value)| format!("{name}=\"{value}\""))
+            .collect();
+        lines.push(format!(
+            "{}{{{}}} {}",
+            metric.name,
+            labels.join(","),
+            metric.metric_value
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Prints `message` as an error and exits with a status of 1, for the errors
+/// of the configuration that prevent scaphandre from starting.
+pub fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message.red());
+    std::process::exit(1);
+}
+
+/// Ends `--dry-run`, telling if sending to `destination` succeeded, and exits
+/// with a status of 0 if it did, 1 otherwise. `destination` is only displayed,
+/// like the url of the endpoint.
+pub fn exit_dry_run(destination: &str, result: Result<(), String>) -> ! {
+    match result {
+        Ok(()) => {
+            println!("{}", format!("Dry run: {destination}: OK").green());
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("{}", format!("Dry run: {destination}: {e}").red());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Delay before the first retry of a failed request.
+const INITIAL_RETRY_BACKOFF_MILLIS: u64 = 500;
+
+/// Tells how HTTP exporters retry requests failing because of transient errors
+/// (5xx answers, timeouts...), waiting twice as long before each new retry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of retries of a request, 0 to never retry.
+    pub max_retries: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Maximum delay between two retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(INITIAL_RETRY_BACKOFF_MILLIS),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the RetryPolicy matching the `--max-retries` and `--max-backoff` options.
+    pub fn from_parameters(parameters: &ArgMatches) -> RetryPolicy {
+        let mut policy = RetryPolicy::default();
+        // the values are checked by the validators of the options
+        if let Some(max_retries) = parameters.value_of("max_retries") {
+            policy.max_retries = max_retries
+                .parse()
+                .expect("Wrong max_retries value, should be a number");
+        }
+        if let Some(max_backoff) = parameters.value_of("max_backoff") {
+            policy.max_backoff = Duration::from_millis(
+                max_backoff
+                    .parse()
+                    .expect("Wrong max_backoff value, should be a number of milliseconds"),
+            );
+        }
+        policy
+    }
+
+    /// Returns the delay before the retry number `retry`, starting at 0.
+    fn get_backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(2_u32.saturating_pow(retry))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// Runs operation until it succeeds, fails with an error for which is_transient
+    /// returns false, or max_retries is reached. Returns the last result.
+    pub fn run<T, E: fmt::Display>(
+        &self,
+        mut operation: impl FnMut() -> Result<T, E>,
+        is_transient: impl Fn(&E) -> bool,
+    ) -> Result<T, E> {
+        let mut retry = 0;
+        loop {
+            match operation() {
+                Err(e) if retry < self.max_retries && is_transient(&e) => {
+                    let backoff = self.get_backoff(retry);
+                    warn!("Request failed: {}. Retrying in {:?}.", e, backoff);
+                    thread::sleep(backoff);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Returns the `--max-retries` and `--max-backoff` options of the HTTP exporters.
+pub fn get_retry_options() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("max_retries")
+            .help("Maximum number of retries of a request failing with a transient error (5xx, timeout). 0 disables retries. [default: 3]")
+            .long("max-retries")
+            .required(false)
+            .takes_value(true)
+            .validator(|value| {
+                validate_integer::<u32>(&value, |_| true, "a number of retries")
+            }),
+        Arg::with_name("max_backoff")
+            .help("Maximum delay between two retries of a request, in milliseconds. [default: 10000]")
+            .long("max-backoff")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_unsigned_integer),
+    ]
+}
+
+/// Compresses a payload with gzip, for HTTP exporters sending it with a
+/// `Content-Encoding: gzip` header.
+#[cfg(feature = "flate2")]
+pub fn gzip(payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload)?;
+    encoder.finish()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn retry_transient_errors_only() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        };
+        let mut attempts = 0;
+        let result: Result<u32, String> = policy.run(
+            || {
+                attempts += 1;
+                if attempts < 3 {
+                    Err(String::from("503"))
+                } else {
+                    Ok(attempts)
+                }
+            },
+            |e| e.starts_with('5'),
+        );
+        assert_eq!(result, Ok(3));
+
+        let mut attempts = 0;
+        let result: Result<u32, String> = policy.run(
+            || {
+                attempts += 1;
+                Err(String::from("401"))
+            },
+            |e| e.starts_with('5'),
+        );
+        assert_eq!(result, Err(String::from("401")));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn secret_read_from_file() {
+        let path = std::env::temp_dir().join(format!("scaph-secret-{}", std::process::id()));
+        fs::write(&path, "s3cr3t\n").unwrap();
+        assert_eq!(
+            read_secret_file(path.to_str().unwrap()),
+            Ok(String::from("s3cr3t"))
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(read_secret_file(path.to_str().unwrap()).is_err());
+
+        let matches = clap::App::new("test")
+            .arg(Arg::with_name("token").long("token").takes_value(true))
+            .arg(
+                Arg::with_name("token_file")
+                    .long("token-file")
+                    .takes_value(true),
+            )
+            .get_matches_from(["test", "--token-file", path.to_str().unwrap()]);
+        assert!(get_secret(&matches, "token", "token_file", "SCAPH_TEST_UNSET_TOKEN").is_err());
+    }
+
+    #[test]
+    fn invalid_options_rejected() {
+        let app = || {
+            clap::App::new("test")
+                .arg(get_io_attribution_option())
+                .args(&get_attribution_basis_options())
+                .arg(get_power_smoothing_option())
+                .args(&get_process_identity_options())
+                .arg(get_jitter_option())
+                .arg(get_measure_interval_option())
+                .args(&get_retry_options())
+                .arg(get_only_pids_option())
+                .arg(get_buffers_max_size_option())
+                .arg(get_min_process_power_option())
+                .arg(get_pid_namespace_option())
+        };
+        let accepts = |args: &[&str]| {
+            app()
+                .get_matches_from_safe([&["test"], args].concat())
+                .is_ok()
+        };
+        assert!(accepts(&["--io-power-fraction", "0.3"]));
+        for value in ["1.5", "-0.1", "half"] {
+            assert!(!accepts(&["--io-power-fraction", value]));
+        }
+        assert!(accepts(&[
+            "--attribution-basis",
+            "weighted",
+            "--system-time-weight",
+            "2"
+        ]));
+        assert!(!accepts(&["--system-time-weight", "-1"]));
+        assert!(!accepts(&["--attribution-basis", "system"]));
+        assert!(accepts(&["--power-smoothing", "1"]));
+        assert!(!accepts(&["--power-smoothing", "0"]));
+        assert!(accepts(&[
+            "--process-identity",
+            "regex",
+            "--process-identity-regex",
+            "java (.*)"
+        ]));
+        assert!(!accepts(&["--process-identity", "regex"]));
+        assert!(!accepts(&["--process-identity-regex", "java (.*"]));
+        assert!(!accepts(&["--cmdline-max-length", "-3"]));
+        assert!(accepts(&["--jitter", "10"]));
+        assert!(!accepts(&["--jitter", "150"]));
+        assert!(accepts(&["--measure-interval", "500"]));
+        for value in ["0", "1.5", "-10"] {
+            assert!(!accepts(&["--measure-interval", value]));
+        }
+        assert!(accepts(&["--max-retries", "0", "--max-backoff", "2000"]));
+        assert!(!accepts(&["--max-retries", "-1"]));
+        assert!(!accepts(&["--max-backoff", "10s"]));
+        assert!(accepts(&["--only-pids", "12, 345"]));
+        assert!(!accepts(&["--only-pids", "12,abc"]));
+        assert!(accepts(&["--max-buffers-size-kB", "512"]));
+        assert!(!accepts(&["--max-buffers-size-kB", "0"]));
+        assert!(accepts(&["--min-power-microwatts", "1000"]));
+        assert!(!accepts(&["--min-power-microwatts", "1mW"]));
+        assert!(accepts(&["--pid-namespace", "4242"]));
+        assert!(!accepts(&["--pid-namespace", "0"]));
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.get_backoff(0), Duration::from_millis(500));
+        assert_eq!(policy.get_backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.get_backoff(40), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn step_jittered() {
+        let step = Duration::from_secs(60);
+        assert_eq!(jitter_step(step, 0.1, -1.0), Duration::from_secs(54));
+        assert_eq!(jitter_step(step, 0.1, 1.0), Duration::from_secs(66));
+        assert_eq!(jitter_step(step, 0.1, 0.0), step);
+        assert_eq!(jittered_step(step, None), step);
+        for _ in 0..100 {
+            let waited = jittered_step(step, Some(0.1));
+            assert!(waited >= Duration::from_secs(54) && waited <= Duration::from_secs(66));
+        }
+    }
+
+    #[test]
+    fn dry_run_metrics_formatted() {
+        use super::super::MetricValueType;
+        let metric = |name: &str, labels: &[(&str, &str)]| Metric {
+            name: String::from(name),
+            metric_type: String::from("gauge"),
+            ttl: 60.0,
+            hostname: String::from("host"),
+            state: String::from("ok"),
+            tags: vec![],
+            attributes: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            description: String::new(),
+            metric_value: MetricValueType::IntUnsigned(1500),
+            timestamp: Duration::from_millis(1_000),
+        };
+        let metrics = [
+            metric(
+                "scaph_socket_power_microwatts",
+                &[("socket_id", "0"), ("die_id", "1")],
+            ),
+            metric("scaph_host_power_microwatts", &[]),
+        ];
+        assert_eq!(
+            format_dry_run_metrics(&metrics),
+            "scaph_socket_power_microwatts{die_id=\"1\",socket_id=\"0\"} 1500\nscaph_host_power_microwatts{} 1500"
+        );
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn gzip_roundtrip() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+        let payload = "scaph_host_power_microwatts{} 12000000\n".repeat(100);
+        let compressed = gzip(payload.as_bytes()).unwrap();
+        assert!(compressed.len() < payload.len());
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, payload);
+    }
+
     #[test]
     fn test_filter_qemu_cmdline_ok() {
         let cmdline = "file=/var/lib/libvirt/qemu/domain-1-fedora33/master-key.aes-object-Sguest=fedora33,debug-threads=on-name/usr/bin/qemu-system-x86_64";
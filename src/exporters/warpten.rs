@@ -1,9 +1,21 @@
+use crate::exporters::labels::{
+    get_label_option, get_label_provider_option, get_label_providers, LabelProvider,
+};
+use crate::exporters::queue::SnapshotQueue;
+use crate::exporters::self_metrics::SelfMetrics;
 use crate::exporters::*;
 use crate::sensors::{RecordGenerator, Sensor, Topology};
 use clap::Arg;
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
-use std::{env, thread};
-use utils::get_scaphandre_version;
+use utils::{
+    apply_topology_options, exit_dry_run, exit_with_error, get_dry_run_option, get_hostname_option,
+    get_hostname_override, get_jitter, get_jitter_option, get_measure_interval,
+    get_measure_interval_option, get_retry_options, get_secret, jittered_step, prepare_dry_run,
+    topology_options, validate_positive_integer, wait_for_next_step, RetryPolicy,
+};
+use warp10::Warp10Serializable;
 //use warp10::data::Format;
 
 /// An exporter that sends power consumption data of the host and its processes to
@@ -11,6 +23,19 @@ use utils::get_scaphandre_version;
 /// (contributions welcome to support websockets).
 pub struct Warp10Exporter {
     topology: Topology,
+    /// Providers of the labels attached to every metric sent.
+    label_providers: Vec<Box<dyn LabelProvider>>,
+    /// Tells if data is sent gzip compressed.
+    compress: bool,
+    /// How requests failing with transient errors are retried.
+    retry_policy: RetryPolicy,
+    /// Value of the `host` label attached to every metric sent, if the hostname is overridden.
+    hostname: Option<String>,
+    /// Tells if process metrics are named after the pid and exe of the process,
+    /// as before they got pid and exe labels.
+    legacy_process_names: bool,
+    /// Fraction of the step each wait is randomized by, if `--jitter` is given.
+    jitter: Option<f64>,
 }
 
 impl Exporter for Warp10Exporter {
@@ -19,19 +44,84 @@ impl Exporter for Warp10Exporter {
         let host = parameters.value_of("host").unwrap();
         let scheme = parameters.value_of("scheme").unwrap();
         let port = parameters.value_of("port").unwrap();
-        let write_token = if let Some(token) = parameters.value_of("write-token") {
-            token.to_owned()
-        } else {
-            match env::var("SCAPH_WARP10_WRITE_TOKEN") {
-                Ok(val) => val,
-                Err(_e) => panic!(
-                    "SCAPH_WARP10_WRITE_TOKEN not found in env, nor write-token flag was used."
+        let write_token = match get_secret(
+            &parameters,
+            "write-token",
+            "write-token-file",
+            "SCAPH_WARP10_WRITE_TOKEN",
+        ) {
+            Ok(Some(token)) => token,
+            Ok(None) => exit_with_error(
+                "SCAPH_WARP10_WRITE_TOKEN not found in env, nor write-token or write-token-file flag was used."
+            ),
+            Err(e) => exit_with_error(&e),
+        };
+        let read_token = if parameters.is_present("verify") {
+            match get_secret(
+                &parameters,
+                "read-token",
+                "read-token-file",
+                "SCAPH_WARP10_READ_TOKEN",
+            ) {
+                Ok(Some(token)) => Some(token),
+                Ok(None) => exit_with_error(
+                    "--verify was used but SCAPH_WARP10_READ_TOKEN was not found in env, nor read-token or read-token-file flag was used."
                 ),
+                Err(e) => exit_with_error(&e),
             }
+        } else {
+            None
         };
-        //let read_token = parameters.value_of("read-token");
-        let step = parameters.value_of("step").unwrap();
+        let step = Duration::new(parameters.value_of("step").unwrap().parse().unwrap(), 0);
+        let measure_interval = get_measure_interval(&parameters);
         let qemu = parameters.is_present("qemu");
+        self.compress = parameters.is_present("compress");
+        self.retry_policy = RetryPolicy::from_parameters(&parameters);
+        self.label_providers = get_label_providers(&parameters);
+        self.hostname = get_hostname_override(&parameters);
+        self.legacy_process_names = parameters.is_present("legacy-process-names");
+        self.jitter = get_jitter(&parameters);
+        apply_topology_options(&mut self.topology, &parameters);
+
+        if parameters.is_present("dry_run") {
+            let target = Warp10Target {
+                base_url: format!("{scheme}://{host}:{port}"),
+                write_token,
+                read_token,
+                compress: self.compress,
+                retry_policy: self.retry_policy.clone(),
+            };
+            prepare_dry_run(&mut self.topology);
+            let snapshot = self.collect(qemu);
+            for data in snapshot.data.iter().chain(&snapshot.process_data) {
+                println!("{}", data.warp10_serialize());
+            }
+            let result = target
+                .send(&snapshot)
+                .map(|_| ())
+                .map_err(|e| format!("{e:?}"));
+            exit_dry_run(&target.base_url, result);
+        }
+
+        if let Some(queue_size) = parameters.value_of("queue-size") {
+            let target = Warp10Target {
+                base_url: format!("{scheme}://{host}:{port}"),
+                write_token,
+                read_token,
+                compress: self.compress,
+                retry_policy: self.retry_policy.clone(),
+            };
+            self.run_with_queue(
+                target,
+                // the value is checked by the validator of the option
+                queue_size
+                    .parse()
+                    .expect("Wrong queue-size value, should be a positive number of steps"),
+                qemu,
+                step,
+                measure_interval,
+            );
+        }
 
         loop {
             match self.iteration(
@@ -39,13 +129,17 @@ impl Exporter for Warp10Exporter {
                 scheme,
                 port.parse::<u16>().unwrap(),
                 &write_token,
-                //read_token,
+                read_token.as_deref(),
                 qemu,
             ) {
                 Ok(res) => debug!("Result: {:?}", res),
                 Err(err) => error!("Failed ! {:?}", err),
             }
-            thread::sleep(Duration::new(step.parse::<u64>().unwrap(), 0));
+            wait_for_next_step(
+                &mut self.topology,
+                jittered_step(step, self.jitter),
+                measure_interval,
+            );
         }
     }
 
@@ -87,6 +181,14 @@ impl Exporter for Warp10Exporter {
             .takes_value(true);
         options.push(arg);
 
+        let arg = Arg::with_name("write-token-file")
+            .help("File containing the auth. token to write on Warp10, like a mounted Docker or Kubernetes secret. Read once at startup.")
+            .long("write-token-file")
+            .required(false)
+            .takes_value(true)
+            .conflicts_with("write-token");
+        options.push(arg);
+
         let arg = Arg::with_name("step")
             .default_value("30")
             .help("Time step between measurements, in seconds.")
@@ -104,6 +206,61 @@ impl Exporter for Warp10Exporter {
             .takes_value(false);
         options.push(arg);
 
+        let arg = Arg::with_name("read-token")
+            .help("Auth. token to read on Warp10, used by --verify")
+            .long("read-token")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("read-token-file")
+            .help("File containing the auth. token to read on Warp10, used by --verify. Read once at startup.")
+            .long("read-token-file")
+            .required(false)
+            .takes_value(true)
+            .conflicts_with("read-token");
+        options.push(arg);
+
+        let arg = Arg::with_name("verify")
+            .help("After each write, read back the last host power point and warn if it doesn't match what was sent.")
+            .long("verify")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("compress")
+            .help("Compress the data sent to Warp10 with gzip.")
+            .long("compress")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("legacy-process-names")
+            .help("Name process metrics scaph_process_power_consumption_microwats_<pid>_<exe>, as older versions did, instead of scaph_process_power_microwatts with pid and exe labels.")
+            .long("legacy-process-names")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("queue-size")
+            .help("Send data from a separate thread, so that a slow Warp10 doesn't delay measurements. At most this number of steps are kept while waiting to be sent, the oldest ones are dropped beyond.")
+            .long("queue-size")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_positive_integer);
+        options.push(arg);
+
+        options.extend(get_retry_options());
+        options.push(get_dry_run_option());
+        options.push(get_jitter_option());
+
+        options.push(get_measure_interval_option());
+        options.extend(topology_options());
+        options.push(get_hostname_option());
+
+        options.push(get_label_option());
+        options.push(get_label_provider_option());
+
         options
     }
 }
@@ -112,7 +269,15 @@ impl Warp10Exporter {
     /// Instantiates and returns a new Warp10Exporter
     pub fn new(mut sensor: Box<dyn Sensor>) -> Warp10Exporter {
         if let Some(topo) = *sensor.get_topology() {
-            Warp10Exporter { topology: topo }
+            Warp10Exporter {
+                topology: topo,
+                label_providers: vec![],
+                compress: false,
+                retry_policy: RetryPolicy::default(),
+                hostname: None,
+                legacy_process_names: false,
+                jitter: None,
+            }
         } else {
             error!("Could'nt generate the Topology.");
             panic!("Could'nt generate the Topology.");
@@ -128,11 +293,71 @@ impl Warp10Exporter {
         scheme: &str,
         port: u16,
         write_token: &str,
-        //read_token: Option<&str>,
+        read_token: Option<&str>,
         qemu: bool,
     ) -> Result<Vec<warp10::Warp10Response>, warp10::Error> {
-        let client = warp10::Client::new(&format!("{scheme}://{host}:{port}"))?;
-        let writer = client.get_writer(write_token.to_string());
+        let target = Warp10Target {
+            base_url: format!("{scheme}://{host}:{port}"),
+            write_token: write_token.to_string(),
+            read_token: read_token.map(String::from),
+            compress: self.compress,
+            retry_policy: self.retry_policy.clone(),
+        };
+        let snapshot = self.collect(qemu);
+        target.send(&snapshot)
+    }
+
+    /// Measures every step and pushes the data points to a queue, drained by a
+    /// thread sending them to target, all those waiting in the same requests.
+    fn run_with_queue(
+        &mut self,
+        target: Warp10Target,
+        queue_size: usize,
+        qemu: bool,
+        step: Duration,
+        measure_interval: Option<Duration>,
+    ) -> ! {
+        let queue: Arc<SnapshotQueue<Warp10Snapshot>> = Arc::new(SnapshotQueue::new(queue_size));
+        let sender_queue = Arc::clone(&queue);
+        thread::spawn(move || loop {
+            let mut snapshots = sender_queue.pop_all().into_iter();
+            if let Some(mut snapshot) = snapshots.next() {
+                for newer in snapshots {
+                    snapshot.merge(newer);
+                }
+                match target.send(&snapshot) {
+                    Ok(res) => debug!("Result: {:?}", res),
+                    Err(err) => error!("Failed ! {:?}", err),
+                }
+            }
+        });
+
+        loop {
+            let mut snapshot = self.collect(qemu);
+            let labels: Vec<warp10::Label> = snapshot
+                .labels
+                .iter()
+                .map(|(k, v)| warp10::Label::new(k, v))
+                .collect();
+            snapshot.data.push(warp10::Data::new(
+                time::OffsetDateTime::now_utc(),
+                None,
+                String::from("scaph_self_dropped_snapshots_total"),
+                labels,
+                warp10::Value::Long(queue.dropped() as i64),
+            ));
+            queue.push(snapshot);
+            wait_for_next_step(
+                &mut self.topology,
+                jittered_step(step, self.jitter),
+                measure_interval,
+            );
+        }
+    }
+
+    /// Refreshes the topology and returns the data points of the host, its
+    /// sockets, domains and processes.
+    fn collect(&mut self, qemu: bool) -> Warp10Snapshot {
         self.topology
             .proc_tracker
             .clean_terminated_process_records_vectors();
@@ -141,248 +366,513 @@ impl Warp10Exporter {
         self.topology.refresh();
 
         let records = self.topology.get_records_passive();
-        let scaphandre_version = get_scaphandre_version();
+        let self_metrics = SelfMetrics::collect(&self.topology);
 
-        let labels = vec![];
+        let mut custom_labels = HashMap::new();
+        if let Some(hostname) = &self.hostname {
+            custom_labels.insert(String::from("host"), hostname.clone());
+        }
+        labels::merge_labels(&self.label_providers, &mut custom_labels);
+        let labels: Vec<warp10::Label> = custom_labels
+            .iter()
+            .map(|(k, v)| warp10::Label::new(k, v))
+            .collect();
 
         let mut data = vec![warp10::Data::new(
             time::OffsetDateTime::now_utc(),
             None,
             String::from("scaph_self_version"),
             labels.clone(),
-            warp10::Value::Double(scaphandre_version.parse::<f64>().unwrap()),
+            warp10::Value::Double(self_metrics.version.parse::<f64>().unwrap()),
         )];
 
-        if let Some(metric_value) = self
-            .topology
-            .get_process_cpu_consumption_percentage(procfs::process::Process::myself().unwrap().pid)
-        {
+        let info = self.topology.get_host_info();
+        let mut info_labels = labels.clone();
+        info_labels.push(warp10::Label::new("cpu_model", &info.cpu_model));
+        info_labels.push(warp10::Label::new("kernel", &info.kernel));
+        info_labels.push(warp10::Label::new("hostname", &info.hostname));
+        info_labels.push(warp10::Label::new("sockets", &info.sockets.to_string()));
+        data.push(warp10::Data::new(
+            time::OffsetDateTime::now_utc(),
+            None,
+            String::from("scaph_host_info"),
+            info_labels,
+            warp10::Value::Long(1),
+        ));
+
+        if let Some(metric_value) = self_metrics.get_cpu_usage_percent_value() {
             data.push(warp10::Data::new(
                 time::OffsetDateTime::now_utc(),
                 None,
                 String::from("scaph_self_cpu_usage_percent"),
                 labels.clone(),
-                warp10::Value::Int(metric_value.value.parse::<i32>().unwrap()),
+                warp10::Value::Double(metric_value),
             ));
         }
 
-        if let Some(metric_value) = self
-            .topology
-            .get_process_cpu_consumption_percentage(procfs::process::Process::myself().unwrap().pid)
-        {
-            data.push(warp10::Data::new(
-                time::OffsetDateTime::now_utc(),
-                None,
-                String::from("scaph_self_cpu_usage_percent"),
-                labels.clone(),
-                warp10::Value::Int(metric_value.value.parse::<i32>().unwrap()),
-            ));
+        if let Some(memory) = &self_metrics.memory {
+            for (name, value) in [
+                (
+                    "scaph_self_mem_total_program_size",
+                    memory.total_program_size_bytes,
+                ),
+                (
+                    "scaph_self_mem_resident_set_size",
+                    memory.resident_set_size_bytes,
+                ),
+                (
+                    "scaph_self_mem_shared_resident_size",
+                    memory.shared_resident_size_bytes,
+                ),
+            ] {
+                data.push(warp10::Data::new(
+                    time::OffsetDateTime::now_utc(),
+                    None,
+                    String::from(name),
+                    labels.clone(),
+                    warp10::Value::Long(value as i64),
+                ));
+            }
         }
 
-        if let Ok(metric_value) = procfs::process::Process::myself().unwrap().statm() {
-            let value = metric_value.size * procfs::page_size().unwrap() as u64;
-            data.push(warp10::Data::new(
-                time::OffsetDateTime::now_utc(),
-                None,
-                String::from("scaph_self_mem_total_program_size"),
-                labels.clone(),
-                warp10::Value::Int(value as i32),
-            ));
-            let value = metric_value.resident * procfs::page_size().unwrap() as u64;
-            data.push(warp10::Data::new(
-                time::OffsetDateTime::now_utc(),
-                None,
-                String::from("scaph_self_mem_resident_set_size"),
-                labels.clone(),
-                warp10::Value::Int(value as i32),
-            ));
-            let value = metric_value.shared * procfs::page_size().unwrap() as u64;
+        for (name, value) in [
+            ("scaph_self_topo_stats_nb", self_metrics.topo_stats_nb),
+            ("scaph_self_topo_records_nb", self_metrics.topo_records_nb),
+            ("scaph_self_topo_procs_nb", self_metrics.topo_procs_nb),
+        ] {
             data.push(warp10::Data::new(
                 time::OffsetDateTime::now_utc(),
                 None,
-                String::from("scaph_self_mem_shared_resident_size"),
+                String::from(name),
                 labels.clone(),
                 warp10::Value::Int(value as i32),
             ));
         }
 
-        let metric_value = self.topology.stat_buffer.len();
-        data.push(warp10::Data::new(
-            time::OffsetDateTime::now_utc(),
-            None,
-            String::from("scaph_self_topo_stats_nb"),
-            labels.clone(),
-            warp10::Value::Int(metric_value as i32),
-        ));
-
-        let metric_value = self.topology.record_buffer.len();
-        data.push(warp10::Data::new(
-            time::OffsetDateTime::now_utc(),
-            None,
-            String::from("scaph_self_topo_records_nb"),
-            labels.clone(),
-            warp10::Value::Int(metric_value as i32),
-        ));
-
-        let metric_value = self.topology.proc_tracker.procs.len();
-        data.push(warp10::Data::new(
-            time::OffsetDateTime::now_utc(),
-            None,
-            String::from("scaph_self_topo_procs_nb"),
-            labels.clone(),
-            warp10::Value::Int(metric_value as i32),
-        ));
-
-        for socket in &self.topology.sockets {
+        for (socket, socket_self_metrics) in self.topology.sockets.iter().zip(&self_metrics.sockets)
+        {
             let mut metric_labels = labels.clone();
-            metric_labels.push(warp10::Label::new("socket_id", &socket.id.to_string()));
-            let metric_value = socket.stat_buffer.len();
+            for (name, value) in &socket_self_metrics.labels {
+                metric_labels.push(warp10::Label::new(name, value));
+            }
             data.push(warp10::Data::new(
                 time::OffsetDateTime::now_utc(),
                 None,
                 String::from("scaph_self_socket_stats_nb"),
                 metric_labels.clone(),
-                warp10::Value::Int(metric_value as i32),
+                warp10::Value::Int(socket_self_metrics.stats_nb as i32),
             ));
-            let metric_value = socket.record_buffer.len();
             data.push(warp10::Data::new(
                 time::OffsetDateTime::now_utc(),
                 None,
                 String::from("scaph_self_socket_records_nb"),
                 metric_labels.clone(),
-                warp10::Value::Int(metric_value as i32),
+                warp10::Value::Int(socket_self_metrics.records_nb as i32),
             ));
 
             let socket_records = socket.get_records_passive();
             if !socket_records.is_empty() {
-                let socket_energy_microjoules = &socket_records.last().unwrap().value;
-                if let Ok(metric_value) = socket_energy_microjoules.parse::<i64>() {
+                let socket_record = socket_records.last().unwrap();
+                if let Some(metric_value) = socket_record.value_u64() {
                     data.push(warp10::Data::new(
-                        time::OffsetDateTime::now_utc(),
+                        record_datetime(socket_record.timestamp),
                         None,
                         String::from("scaph_socket_energy_microjoules"),
                         metric_labels.clone(),
-                        warp10::Value::Long(metric_value),
+                        warp10::Value::Long(metric_value as i64),
                     ));
                 }
 
                 if let Some(metric_value) = socket.get_records_diff_power_microwatts() {
-                    data.push(warp10::Data::new(
-                        time::OffsetDateTime::now_utc(),
-                        None,
-                        String::from("scaph_socket_power_microwatts"),
-                        metric_labels.clone(),
-                        warp10::Value::Long(metric_value.value.parse::<i64>().unwrap()),
-                    ));
+                    if let Some(power) = metric_value.value_u64() {
+                        data.push(warp10::Data::new(
+                            record_datetime(metric_value.timestamp),
+                            None,
+                            String::from("scaph_socket_power_microwatts"),
+                            metric_labels.clone(),
+                            warp10::Value::Long(power as i64),
+                        ));
+                    }
                 }
             }
 
-            for domain in &socket.domains {
+            for (domain_name, ratio) in socket.get_domains_power_ratios() {
+                let mut metric_labels = metric_labels.clone();
+                metric_labels.push(warp10::Label::new("domain", &domain_name));
+                data.push(warp10::Data::new(
+                    time::OffsetDateTime::now_utc(),
+                    None,
+                    String::from("scaph_domain_power_ratio"),
+                    metric_labels,
+                    warp10::Value::Double(ratio),
+                ));
+            }
+
+            for (domain_name, records_nb) in &socket_self_metrics.domains_records_nb {
                 let mut metric_labels = labels.clone();
-                metric_labels.push(warp10::Label::new("rapl_domain_name", &domain.name));
-                let metric_value = domain.record_buffer.len();
+                metric_labels.push(warp10::Label::new("rapl_domain_name", domain_name));
                 data.push(warp10::Data::new(
                     time::OffsetDateTime::now_utc(),
                     None,
                     String::from("scaph_self_domain_records_nb"),
                     metric_labels.clone(),
-                    warp10::Value::Int(metric_value as i32),
+                    warp10::Value::Int(*records_nb as i32),
                 ));
             }
         }
 
+        let mut host_power_sent = None;
         if !records.is_empty() {
             let record = records.last().unwrap();
-            let metric_value = record.value.clone();
-
-            data.push(warp10::Data::new(
-                time::OffsetDateTime::now_utc(),
-                None,
-                String::from("scaph_host_energy_microjoules"),
-                labels.clone(),
-                warp10::Value::Long(metric_value.parse::<i64>().unwrap()),
-            ));
-
-            if let Some(metric_value) = self.topology.get_records_diff_power_microwatts() {
+            if let Some(energy) = record.value_u64() {
                 data.push(warp10::Data::new(
-                    time::OffsetDateTime::now_utc(),
+                    record_datetime(record.timestamp),
                     None,
-                    String::from("scaph_host_power_microwatts"),
+                    String::from("scaph_host_energy_microjoules"),
                     labels.clone(),
-                    warp10::Value::Long(metric_value.value.parse::<i64>().unwrap()),
+                    warp10::Value::Long(energy as i64),
                 ));
             }
-        }
 
-        let res = writer.post_sync(data)?;
-
-        let mut results = vec![res];
+            if let Some(metric_value) = self.topology.get_host_power_microwatts() {
+                if let Some(host_power) = metric_value.value_u64() {
+                    let host_power = host_power as i64;
+                    host_power_sent = Some(host_power);
+                    data.push(warp10::Data::new(
+                        record_datetime(metric_value.timestamp),
+                        None,
+                        String::from("scaph_host_power_microwatts"),
+                        labels.clone(),
+                        warp10::Value::Long(host_power),
+                    ));
+                }
+            }
+        }
 
         let mut process_data = vec![warp10::Data::new(
             time::OffsetDateTime::now_utc(),
             None,
             String::from("scaph_self_version"),
             labels.clone(),
-            warp10::Value::Double(scaphandre_version.parse::<f64>().unwrap()),
+            warp10::Value::Double(self_metrics.version.parse::<f64>().unwrap()),
         )];
 
+        self.topology.proc_tracker.cache_process_labels();
         let processes_tracker = &self.topology.proc_tracker;
+        let mut other = OtherProcessesPower::default();
         for pid in processes_tracker.get_alive_pids() {
-            let exe = processes_tracker.get_process_name(pid);
-            let cmdline = processes_tracker.get_process_cmdline(pid);
-
             let mut plabels = labels.clone();
-            plabels.push(warp10::Label::new("pid", &pid.to_string()));
-            plabels.push(warp10::Label::new("exe", &exe));
-            if let Some(cmdline_str) = cmdline {
-                if qemu {
-                    if let Some(vmname) = utils::filter_qemu_cmdline(&cmdline_str) {
-                        plabels.push(warp10::Label::new("vmname", &vmname));
+            let mut exe = "";
+            for (key, value) in processes_tracker
+                .get_process_labels(pid)
+                .into_iter()
+                .flatten()
+            {
+                if key == "exe" {
+                    exe = value;
+                }
+                if key == "cmdline" {
+                    if qemu {
+                        if let Some(vmname) = utils::filter_qemu_cmdline(value) {
+                            plabels.push(warp10::Label::new("vmname", &vmname));
+                        }
                     }
+                    plabels.push(warp10::Label::new(key, &value.replace('\"', "\\\"")));
+                } else {
+                    plabels.push(warp10::Label::new(key, value));
                 }
-                plabels.push(warp10::Label::new(
-                    "cmdline",
-                    &cmdline_str.replace('\"', "\\\""),
-                ));
             }
-            let metric_name = format!(
-                "{}_{}_{}",
-                "scaph_process_power_consumption_microwats", pid, exe
-            );
-            if let Some(power) = self.topology.get_process_power_consumption_microwatts(pid) {
-                process_data.push(warp10::Data::new(
-                    time::OffsetDateTime::now_utc(),
-                    None,
-                    metric_name,
-                    plabels,
-                    warp10::Value::Long(power.value.parse::<i64>().unwrap()),
-                ));
+            for (key, value) in processes_tracker.get_process_pod_labels(pid) {
+                plabels.push(warp10::Label::new(&key, &value));
+            }
+            let metric_name = process_metric_name(&pid.to_string(), exe, self.legacy_process_names);
+            if let Some(power) = self.topology.get_process_power_over_min(pid, &mut other) {
+                if let Some(value) = power.value_u64() {
+                    process_data.push(warp10::Data::new(
+                        record_datetime(power.timestamp),
+                        None,
+                        metric_name,
+                        plabels,
+                        warp10::Value::Long(value as i64),
+                    ));
+                }
+            }
+        }
+        if let Some(power) = other.to_record() {
+            let mut plabels = labels.clone();
+            plabels.push(warp10::Label::new("pid", OTHER_PROCESSES));
+            plabels.push(warp10::Label::new("exe", OTHER_PROCESSES));
+            plabels.push(warp10::Label::new(
+                "processes",
+                &other.processes.to_string(),
+            ));
+            process_data.push(warp10::Data::new(
+                record_datetime(power.timestamp),
+                None,
+                process_metric_name(OTHER_PROCESSES, OTHER_PROCESSES, self.legacy_process_names),
+                plabels,
+                warp10::Value::Long(other.microwatts as i64),
+            ));
+        }
+        Warp10Snapshot {
+            data,
+            process_data,
+            host_power: host_power_sent,
+            labels: custom_labels,
+        }
+    }
+}
+
+/// Data points measured at a step, to be sent to Warp10.
+#[derive(Debug, Clone)]
+struct Warp10Snapshot {
+    /// Points of the host, its sockets and domains.
+    data: Vec<warp10::Data>,
+    /// Points of the processes.
+    process_data: Vec<warp10::Data>,
+    /// Host power sent, read back to verify it was written.
+    host_power: Option<i64>,
+    /// Labels attached to every point.
+    labels: HashMap<String, String>,
+}
+
+impl Warp10Snapshot {
+    /// Adds the points of a more recent snapshot to this one, to send them in
+    /// the same requests.
+    fn merge(&mut self, newer: Warp10Snapshot) {
+        self.data.extend(newer.data);
+        self.process_data.extend(newer.process_data);
+        self.host_power = newer.host_power;
+        self.labels = newer.labels;
+    }
+}
+
+/// Warp10 instance the data is sent to, and how.
+#[derive(Debug, Clone)]
+struct Warp10Target {
+    base_url: String,
+    write_token: String,
+    /// Token to read back the host power sent, if it should be verified.
+    read_token: Option<String>,
+    compress: bool,
+    retry_policy: RetryPolicy,
+}
+
+impl Warp10Target {
+    /// Sends the points of a snapshot, then reads back the host power if a
+    /// read token is set.
+    fn send(
+        &self,
+        snapshot: &Warp10Snapshot,
+    ) -> Result<Vec<warp10::Warp10Response>, warp10::Error> {
+        let client = warp10::Client::new(&self.base_url)?;
+        let writer = client.get_writer(self.write_token.clone());
+        let mut results = vec![];
+        for data in [&snapshot.data, &snapshot.process_data] {
+            results.push(self.retry_policy.run(
+                || {
+                    post_data(
+                        &client,
+                        &writer,
+                        &self.write_token,
+                        data.clone(),
+                        self.compress,
+                    )
+                },
+                is_transient_error,
+            )?);
+        }
+
+        if let (Some(token), Some(sent)) = (&self.read_token, snapshot.host_power) {
+            match read_last_host_power(&self.base_url, token, &snapshot.labels) {
+                Ok(Some(read)) if read == sent => {
+                    debug!("Verified scaph_host_power_microwatts on Warp10: {}", read)
+                }
+                Ok(Some(read)) => warn!(
+                    "scaph_host_power_microwatts read back from Warp10 is {} but {} was sent",
+                    read, sent
+                ),
+                Ok(None) => warn!(
+                    "scaph_host_power_microwatts couldn't be read back from Warp10, {} was sent",
+                    sent
+                ),
+                Err(err) => warn!("Couldn't read back data from Warp10: {}", err),
             }
         }
-        let process_res = writer.post_sync(process_data)?;
-
-        //if let Some(token) = read_token {
-        //let reader = client.get_reader(token.to_owned());
-        //let parameters = warp10::data::ParameterSet::new(
-        //"scaph_host_power_microwatts{}".to_string(),
-        //Format::Text,
-        //None, None, None,
-        //Some(String::from("now")), Some(String::from("-10")),
-        //None, None, None
-        //);
-        //let response = reader.get_sync(parameters);
-        //match response {
-        //Ok(resp) => warn!("response is: {:?}", resp),
-        //Err(err) => panic!("error is: {:?}", err)
-        //}
-        //}
-
-        results.push(process_res);
 
         Ok(results)
     }
 }
 
+/// Sends data to Warp10 with writer, or in a gzip compressed request if compress is true.
+fn post_data(
+    client: &warp10::Client,
+    writer: &warp10::Writer,
+    write_token: &str,
+    data: Vec<warp10::Data>,
+    compress: bool,
+) -> Result<warp10::Warp10Response, warp10::Error> {
+    if !compress {
+        return writer.post_sync(data);
+    }
+    let body = data
+        .iter()
+        .map(|d| d.warp10_serialize())
+        .collect::<Vec<String>>()
+        .join("\n");
+    let mut request =
+        isahc::Request::post(client.update_uri()).body(utils::gzip(body.as_bytes())?)?;
+    warp10::Token::new(client, write_token.to_string()).set_headers(request.headers_mut());
+    request.headers_mut().insert(
+        isahc::http::header::CONTENT_ENCODING,
+        isahc::http::HeaderValue::from_static("gzip"),
+    );
+    let mut response = isahc::send(request)?;
+    let status = response.status();
+    let response =
+        warp10::Warp10Response::new(status, isahc::ReadResponseExt::text(&mut response)?);
+    if status == isahc::http::StatusCode::OK {
+        Ok(response)
+    } else {
+        Err(warp10::Error::api_error(response))
+    }
+}
+
+/// Tells if a request to Warp10 failed because of a transient error and should
+/// be retried: 5xx and 429 answers, network errors and timeouts. Other errors,
+/// like a 4xx answer to a wrong token, won't succeed when retried.
+fn is_transient_error(error: &warp10::Error) -> bool {
+    match error {
+        warp10::Error::ApiError(response) => {
+            response.status().is_server_error()
+                || response.status() == isahc::http::StatusCode::TOO_MANY_REQUESTS
+        }
+        warp10::Error::HttpBodyError(error) => {
+            error.is_network() || error.is_timeout() || error.is_server()
+        }
+        warp10::Error::IoError(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns the date of a measurement, from the timestamp of its record, so that
+/// points are dated when the sensor was read rather than when they are sent.
+fn record_datetime(timestamp: Duration) -> time::OffsetDateTime {
+    time::OffsetDateTime::from_unix_timestamp_nanos(timestamp.as_nanos() as i128)
+}
+
+/// Returns the name of the power metric of a process. Older versions put the
+/// pid and exe in the name, creating a class per process in Warp10: they are
+/// now labels of a single class, and the name is only kept if `legacy` is set.
+fn process_metric_name(pid: &str, exe: &str, legacy: bool) -> String {
+    if legacy {
+        format!("scaph_process_power_consumption_microwats_{pid}_{exe}")
+    } else {
+        String::from("scaph_process_power_microwatts")
+    }
+}
+
+/// Fetches the last point of the scaph_host_power_microwatts series matching labels
+/// from the Warp10 instance at base_url, and returns its value.
+fn read_last_host_power(
+    base_url: &str,
+    read_token: &str,
+    labels: &HashMap<String, String>,
+) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+    let mut sorted_labels: Vec<String> = labels.iter().map(|(k, v)| format!("{k}={v}")).collect();
+    sorted_labels.sort();
+    let selector = format!("scaph_host_power_microwatts{{{}}}", sorted_labels.join(","));
+    let request = isahc::Request::get(format!(
+        "{}/api/v0/fetch?selector={}&now=now&timespan=-1",
+        base_url,
+        encode_query_value(&selector)
+    ))
+    .header("X-Warp10-Token", read_token)
+    .body(())?;
+    let mut response = isahc::send(request)?;
+    if !response.status().is_success() {
+        return Err(format!("Warp10 answered {}", response.status()).into());
+    }
+    Ok(parse_last_fetched_value(&isahc::ReadResponseExt::text(
+        &mut response,
+    )?))
+}
+
+/// Returns the value of the last point of a response of the Warp10 fetch
+/// endpoint, in text format (`TS/LAT:LON/ELEV NAME{LABELS} VALUE` lines).
+fn parse_last_fetched_value(body: &str) -> Option<i64> {
+    body.lines()
+        .rev()
+        .find(|line| !line.trim().is_empty())
+        .and_then(|line| line.split_whitespace().last())
+        .and_then(|value| value.parse::<f64>().ok())
+        .map(|value| value as i64)
+}
+
+/// Percent-encodes a value to be used in the query string of an url.
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_dated_from_records() {
+        let date = record_datetime(Duration::from_millis(1_614_592_800_123));
+        assert_eq!(date.unix_timestamp(), 1_614_592_800);
+        assert_eq!(date.millisecond(), 123);
+    }
+
+    #[test]
+    fn parse_last_fetched_value_from_text() {
+        let body = "1614592800000000// scaph_host_power_microwatts{} 12000000\n=1614592830000000// 15000000\n";
+        assert_eq!(parse_last_fetched_value(body), Some(15000000));
+        assert_eq!(parse_last_fetched_value(""), None);
+    }
+
+    #[test]
+    fn transient_errors() {
+        let error =
+            |status| warp10::Error::api_error(warp10::Warp10Response::new(status, String::new()));
+        assert!(is_transient_error(&error(
+            isahc::http::StatusCode::SERVICE_UNAVAILABLE
+        )));
+        assert!(!is_transient_error(&error(
+            isahc::http::StatusCode::FORBIDDEN
+        )));
+    }
+
+    #[test]
+    fn process_metric_named_by_labels() {
+        assert_eq!(
+            process_metric_name("42", "/usr/bin/stress", false),
+            "scaph_process_power_microwatts"
+        );
+        assert_eq!(
+            process_metric_name("42", "/usr/bin/stress", true),
+            "scaph_process_power_consumption_microwats_42_/usr/bin/stress"
+        );
+    }
+
+    #[test]
+    fn encode_selector() {
+        assert_eq!(
+            encode_query_value("scaph_host_power_microwatts{dc=dc1}"),
+            "scaph_host_power_microwatts%7Bdc%3Ddc1%7D"
+        );
+    }
+}
+
 //  Copyright 2020 The scaphandre authors.
 //
 //  Licensed under the Apache License, Version 2.0 (the "License");
@@ -0,0 +1,542 @@
+//! # WebsocketExporter
+//!
+//! `WebsocketExporter` listens for WebSocket clients, like a dashboard in a
+//! browser, and pushes them at each step the report the json exporter writes,
+//! so that they are updated live without polling an http endpoint.
+use crate::exporters::json::{build_report, serialize_reports};
+use crate::exporters::labels::{get_label_option, get_label_provider_option, get_label_providers};
+use crate::exporters::utils::{
+    apply_topology_options, get_measure_interval, get_measure_interval_option, topology_options,
+    validate_integer, validate_positive_integer, validate_unsigned_integer, wait_for_next_step,
+};
+use crate::exporters::*;
+use crate::sensors::Sensor;
+use clap::Arg;
+use std::error::Error;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Message, WebSocket};
+
+/// Default ipv4/ipv6 address to listen on, all interfaces.
+const DEFAULT_IP_ADDRESS: &str = "::";
+/// Default TCP port to listen on.
+const DEFAULT_PORT: &str = "8081";
+/// Default maximum number of clients connected at the same time.
+const DEFAULT_MAX_CLIENTS: &str = "16";
+
+/// Maximum time a client may take to send its handshake, or to receive a report.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Maximum size of the messages received from a client.
+const MAX_RECEIVED_SIZE: usize = 8192;
+/// Maximum number of reports waiting to be sent to a client. A client further
+/// behind is dropped.
+const CLIENT_QUEUE_SIZE: usize = 4;
+/// Maximum time between two checks of the frames received from a client.
+const CLIENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An Exporter that pushes the power consumption of the host, its sockets and
+/// top consumers as JSON to WebSocket clients.
+pub struct WebsocketExporter {
+    sensor: Box<dyn Sensor>,
+}
+
+impl Exporter for WebsocketExporter {
+    /// Lanches runner()
+    fn run(&mut self, parameters: ArgMatches) {
+        self.runner(parameters);
+    }
+
+    /// Returns options needed for that exporter, as a HashMap
+    fn get_options() -> Vec<clap::Arg<'static, 'static>> {
+        let mut options = Vec::new();
+        let arg = Arg::with_name("timeout")
+            .help("Maximum time spent measuring, in seconds.")
+            .long("timeout")
+            .short("t")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("step_duration")
+            .default_value("2")
+            .help("Set measurement step duration in second.")
+            .long("step")
+            .short("s")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_unsigned_integer);
+        options.push(arg);
+
+        let arg = Arg::with_name("step_duration_nano")
+            .default_value("0")
+            .help("Set measurement step duration in nano second.")
+            .long("step_nano")
+            .short("n")
+            .required(false)
+            .takes_value(true)
+            .validator(|nanos| {
+                validate_integer::<u32>(&nanos, |_| true, "a number of nano seconds")
+            });
+        options.push(arg);
+
+        let arg = Arg::with_name("address")
+            .default_value(DEFAULT_IP_ADDRESS)
+            .help("ipv6 or ipv4 address to listen for WebSocket clients on, like 10.0.0.5 or [fd00::5].")
+            .long("address")
+            .short("a")
+            .required(false)
+            .takes_value(true)
+            .validator(|address| match parse_ip_address(&address) {
+                Some(_) => Ok(()),
+                None => Err(format!("{address} is not a valid ip address")),
+            });
+        options.push(arg);
+
+        let arg = Arg::with_name("port")
+            .default_value(DEFAULT_PORT)
+            .help("TCP port number to listen for WebSocket clients on.")
+            .long("port")
+            .short("p")
+            .required(false)
+            .takes_value(true)
+            .validator(|port| validate_integer::<u16>(&port, |_| true, "a TCP port number"));
+        options.push(arg);
+
+        let arg = Arg::with_name("max_clients")
+            .default_value(DEFAULT_MAX_CLIENTS)
+            .help(
+                "Maximum number of clients connected at the same time, the next ones are refused.",
+            )
+            .long("max-clients")
+            .required(false)
+            .takes_value(true)
+            .validator(validate_positive_integer);
+        options.push(arg);
+
+        let arg = Arg::with_name("max_top_consumers")
+            .default_value("10")
+            .help("Maximum number of processes to watch.")
+            .long("max-top-consumers")
+            .short("m")
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        let arg = Arg::with_name("containers")
+            .help("Monitor and apply labels for processes running as containers")
+            .long("containers")
+            .required(false)
+            .takes_value(false);
+        options.push(arg);
+
+        let arg = Arg::with_name("fields")
+            .help("Comma-separated list of the fields to keep in each report, like host,sockets to drop the consumers. All fields are kept by default.")
+            .long("fields")
+            .possible_values(&["host", "consumers", "sockets", "labels"])
+            .use_delimiter(true)
+            .required(false)
+            .takes_value(true);
+        options.push(arg);
+
+        options.push(get_measure_interval_option());
+        options.extend(topology_options());
+        options.push(utils::get_hostname_option());
+
+        options.push(get_label_option());
+        options.push(get_label_provider_option());
+
+        options
+    }
+}
+
+impl WebsocketExporter {
+    /// Instantiates and returns a new WebsocketExporter
+    pub fn new(sensor: Box<dyn Sensor>) -> WebsocketExporter {
+        WebsocketExporter { sensor }
+    }
+
+    /// Pushes a report to the connected clients every 'step', until 'timeout'
+    pub fn runner(&mut self, parameters: ArgMatches) {
+        let topology = self.sensor.get_topology().unwrap();
+        let mut metric_generator = MetricGenerator::new(
+            topology,
+            utils::resolve_hostname(&parameters),
+            false,
+            parameters.is_present("containers"),
+        );
+        metric_generator.set_label_providers(get_label_providers(&parameters));
+        apply_topology_options(&mut metric_generator.topology, &parameters);
+
+        // the values are checked by the validators of the options
+        let ip = parse_ip_address(parameters.value_of("address").unwrap())
+            .expect("Wrong address value, should be an ip address");
+        let port: u16 = parameters
+            .value_of("port")
+            .unwrap()
+            .parse()
+            .expect("Wrong port value, should be a TCP port number");
+        let max_clients: usize = parameters
+            .value_of("max_clients")
+            .unwrap()
+            .parse()
+            .expect("Wrong max_clients value, should be a positive number of clients");
+        let address = SocketAddr::new(ip, port);
+        let mut server = match WebsocketServer::bind(address, max_clients) {
+            Ok(server) => server,
+            Err(e) => {
+                error!(
+                    "Couldn't listen for WebSocket clients on {}: {}",
+                    address, e
+                );
+                std::process::exit(1);
+            }
+        };
+
+        // We have a default value of 2s so it is safe to unwrap the option
+        let step_duration: u64 = parameters
+            .value_of("step_duration")
+            .unwrap()
+            .parse()
+            .expect("Wrong step_duration value, should be a number of seconds");
+        let step_duration_nano: u32 = parameters
+            .value_of("step_duration_nano")
+            .unwrap()
+            .parse()
+            .expect("Wrong step_duration_nano value, should be a number of nano seconds");
+
+        let step = Duration::new(step_duration, step_duration_nano);
+        let measure_interval = get_measure_interval(&parameters);
+
+        info!(
+            "Measurement step is: {}s, listening for WebSocket clients on {}",
+            step_duration, server.address
+        );
+        if let Some(timeout) = parameters.value_of("timeout") {
+            let now = Instant::now();
+
+            let timeout_secs: u64 = timeout.parse().unwrap();
+            while now.elapsed().as_secs() <= timeout_secs {
+                self.iterate(&parameters, &mut metric_generator, &mut server);
+                wait_for_next_step(&mut metric_generator.topology, step, measure_interval);
+            }
+        } else {
+            loop {
+                self.iterate(&parameters, &mut metric_generator, &mut server);
+                wait_for_next_step(&mut metric_generator.topology, step, measure_interval);
+            }
+        }
+    }
+
+    fn iterate(
+        &mut self,
+        parameters: &ArgMatches,
+        metric_generator: &mut MetricGenerator,
+        server: &mut WebsocketServer,
+    ) {
+        metric_generator.topology.refresh();
+        match build_report(parameters, metric_generator) {
+            Some(report) => {
+                let fields: Option<Vec<&str>> = parameters
+                    .values_of("fields")
+                    .map(|fields| fields.collect());
+                server.broadcast(&serialize_reports(&report, fields.as_deref(), false));
+            }
+            None => {
+                info!("No data yet, didn't send report.");
+            }
+        }
+    }
+}
+
+/// WebSocket server sending text messages to all its clients.
+///
+/// Clients are accepted and served by their own threads, so that a slow or
+/// stalled client never delays the measurements: reports are queued for each
+/// client, which is dropped once its queue is full.
+struct WebsocketServer {
+    /// Address the server listens on.
+    address: SocketAddr,
+    clients: Arc<Mutex<Vec<ClientHandle>>>,
+}
+
+/// A connected client, served by its own thread.
+struct ClientHandle {
+    address: SocketAddr,
+    /// Queue of the reports to send, read by the thread of the client.
+    reports: SyncSender<String>,
+}
+
+impl WebsocketServer {
+    /// Listens on address, accepting clients from a dedicated thread.
+    fn bind(address: SocketAddr, max_clients: usize) -> io::Result<WebsocketServer> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let clients = Arc::new(Mutex::new(vec![]));
+        let accepted = clients.clone();
+        thread::Builder::new()
+            .name(String::from("websocket-accept"))
+            .spawn(move || accept_clients(listener, accepted, max_clients))?;
+        Ok(WebsocketServer { address, clients })
+    }
+
+    /// Queues text for all the clients, dropping those that disconnected or
+    /// whose queue is full.
+    fn broadcast(&mut self, text: &str) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|client| match client.reports.try_send(String::from(text)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "Dropped WebSocket client {}: it doesn't read the reports fast enough.",
+                    client.address
+                );
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// Accepts the clients connecting to listener, serving each of them from its
+/// own thread. Clients over max_clients are answered that the service is
+/// unavailable. Clients that left are only forgotten at the next broadcast.
+fn accept_clients(
+    listener: TcpListener,
+    clients: Arc<Mutex<Vec<ClientHandle>>>,
+    max_clients: usize,
+) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Couldn't accept WebSocket client: {}", err);
+                continue;
+            }
+        };
+        let address = match stream.peer_addr() {
+            Ok(address) => address,
+            Err(err) => {
+                warn!("Couldn't accept WebSocket client: {}", err);
+                continue;
+            }
+        };
+        let mut clients = clients.lock().unwrap();
+        if clients.len() >= max_clients {
+            warn!(
+                "Refused WebSocket client {}: {} clients are already connected.",
+                address, max_clients
+            );
+            let _ = stream.set_write_timeout(Some(CLIENT_TIMEOUT));
+            let _ = stream.write_all(
+                b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+            continue;
+        }
+        let (sender, receiver) = mpsc::sync_channel(CLIENT_QUEUE_SIZE);
+        let spawned = thread::Builder::new()
+            .name(format!("websocket-{address}"))
+            .spawn(move || serve_client(stream, address, receiver));
+        match spawned {
+            Ok(_) => clients.push(ClientHandle {
+                address,
+                reports: sender,
+            }),
+            Err(err) => warn!("Couldn't serve WebSocket client {}: {}", address, err),
+        }
+    }
+}
+
+/// Answers the handshake of a client, then sends it the reports queued for
+/// it, until it leaves or is dropped by the server.
+fn serve_client(stream: TcpStream, address: SocketAddr, reports: Receiver<String>) {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_RECEIVED_SIZE),
+        max_frame_size: Some(MAX_RECEIVED_SIZE),
+        // clients must mask their frames, see RFC 6455
+        accept_unmasked_frames: false,
+        ..WebSocketConfig::default()
+    };
+    let accepted = stream
+        .set_read_timeout(Some(CLIENT_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+        .map_err(|err| err.to_string())
+        .and_then(|()| {
+            tungstenite::accept_with_config(stream, Some(config)).map_err(|err| err.to_string())
+        });
+    let mut websocket = match accepted {
+        Ok(websocket) => websocket,
+        Err(err) => {
+            warn!("Couldn't accept WebSocket client {}: {}", address, err);
+            return;
+        }
+    };
+    info!("WebSocket client {} connected.", address);
+    if let Err(err) = send_reports(&mut websocket, &reports) {
+        info!("WebSocket client {} disconnected: {}", address, err);
+    }
+}
+
+/// Sends the reports to the client as they are queued, and reads the frames it
+/// sends meanwhile, pings being answered. Returns once the client closed the
+/// connection, sent an invalid frame or was dropped by the server.
+fn send_reports(
+    websocket: &mut WebSocket<TcpStream>,
+    reports: &Receiver<String>,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        match reports.recv_timeout(CLIENT_POLL_INTERVAL) {
+            Ok(report) => websocket.send(Message::Text(report))?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = websocket.close(None);
+                let _ = websocket.flush();
+                return Ok(());
+            }
+        }
+        websocket.get_mut().set_nonblocking(true)?;
+        let received = loop {
+            match websocket.read() {
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break Ok(())
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        websocket.get_mut().set_nonblocking(false)?;
+        received?;
+    }
+}
+
+/// Parses an ipv4 or ipv6 address to listen on, accepting ipv6 literals in
+/// brackets, like `[fd00::5]`.
+fn parse_ip_address(address: &str) -> Option<IpAddr> {
+    address
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Connects a WebSocket client to server.
+    fn connect(server: &WebsocketServer) -> WebSocket<TcpStream> {
+        let stream = TcpStream::connect(server.address).unwrap();
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        tungstenite::client(format!("ws://{}/", server.address), stream)
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn reports_pushed_to_clients() {
+        let mut server = WebsocketServer::bind("127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        let mut client = connect(&server);
+        let mut refused = TcpStream::connect(server.address).unwrap();
+        let mut answer = String::new();
+        refused.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.1 503"));
+
+        server.broadcast("{\"host\":{}}");
+        assert_eq!(
+            client.read().unwrap(),
+            Message::Text(String::from("{\"host\":{}}"))
+        );
+
+        // the client leaves
+        client.close(None).unwrap();
+        for _ in 0..50 {
+            server.broadcast("{}");
+            if server.clients.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(CLIENT_POLL_INTERVAL);
+        }
+        assert!(server.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn slow_clients_dropped() {
+        let mut server = WebsocketServer::bind("127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        // a client whose reports are never sent
+        let (sender, _receiver) = mpsc::sync_channel(CLIENT_QUEUE_SIZE);
+        server.clients.lock().unwrap().push(ClientHandle {
+            address: server.address,
+            reports: sender,
+        });
+        for _ in 0..CLIENT_QUEUE_SIZE {
+            server.broadcast("{}");
+        }
+        assert_eq!(server.clients.lock().unwrap().len(), 1);
+        server.broadcast("{}");
+        assert!(server.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unmasked_frames_rejected() {
+        let server = WebsocketServer::bind("127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        let mut stream = TcpStream::connect(server.address).unwrap();
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT * 2)).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+            .unwrap();
+        let mut received = vec![];
+        let mut buffer = [0; 1024];
+        while !received.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..n]);
+        }
+        assert!(received.starts_with(b"HTTP/1.1 101"));
+        // an unmasked text frame, as only a server may send
+        stream.write_all(&[0x81, 2, b'h', b'i']).unwrap();
+        // the server closes the connection instead of waiting for more
+        let closed = loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => break true,
+                Ok(_) => {}
+                Err(err) => break err.kind() != io::ErrorKind::WouldBlock,
+            }
+        };
+        assert!(closed);
+    }
+
+    #[test]
+    fn invalid_options_rejected() {
+        let accepts = |args: &[&str]| {
+            clap::App::new("test")
+                .args(&WebsocketExporter::get_options())
+                .get_matches_from_safe([&["test"], args].concat())
+                .is_ok()
+        };
+        assert!(accepts(&[]));
+        assert!(accepts(&["--address", "[fd00::5]", "--port", "9000"]));
+        assert!(!accepts(&["--address", "localhost"]));
+        assert!(!accepts(&["--port", "70000"]));
+        assert!(!accepts(&["--max-clients", "0"]));
+        assert!(!accepts(&["--step", "-1"]));
+        assert!(!accepts(&["--step_nano", "half"]));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,216 @@
+//! # WrapExporter
+//!
+//! `WrapExporter` launches a command, measures the power consumption of its
+//! process tree until it exits, and prints the energy it consumed, like
+//! `/usr/bin/time` does for durations.
+use crate::exporters::utils::{get_measure_interval, get_measure_interval_option};
+use crate::exporters::*;
+use crate::sensors::Sensor;
+use clap::Arg;
+use std::process::{Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Default interval between two measurements of the command.
+const DEFAULT_MEASURE_INTERVAL_MS: u64 = 1000;
+
+/// An Exporter that runs a command and reports the energy consumed by its
+/// process tree once it exits.
+pub struct WrapExporter {
+    sensor: Box<dyn Sensor>,
+}
+
+impl Exporter for WrapExporter {
+    /// Runs the command and prints its report.
+    fn run(&mut self, parameters: ArgMatches) {
+        let command: Vec<&str> = parameters.values_of("command").unwrap().collect();
+        let interval = get_measure_interval(&parameters)
+            .filter(|interval| !interval.is_zero())
+            .unwrap_or_else(|| Duration::from_millis(DEFAULT_MEASURE_INTERVAL_MS));
+
+        let report = self.measure(&command, interval);
+        #[cfg(feature = "json")]
+        let output = if parameters.is_present("json") {
+            report.to_json()
+        } else {
+            report.to_string()
+        };
+        #[cfg(not(feature = "json"))]
+        let output = report.to_string();
+        // the command may write on stdout, so the report goes to stderr, like /usr/bin/time does
+        eprintln!("{output}");
+
+        std::process::exit(report.status.and_then(|s| s.code()).unwrap_or(1));
+    }
+
+    /// Returns options needed for that exporter.
+    fn get_options() -> Vec<clap::Arg<'static, 'static>> {
+        let mut options = Vec::new();
+        let arg = Arg::with_name("command")
+            .help("Command to run and measure, with its arguments, after --")
+            .required(true)
+            .multiple(true)
+            .last(true);
+        options.push(arg);
+
+        #[cfg(feature = "json")]
+        {
+            let arg = Arg::with_name("json")
+                .help("Print the report as JSON.")
+                .long("json")
+                .required(false)
+                .takes_value(false);
+            options.push(arg);
+        }
+
+        options.push(get_measure_interval_option());
+
+        options
+    }
+}
+
+impl WrapExporter {
+    /// Instantiates and returns a new WrapExporter
+    pub fn new(sensor: Box<dyn Sensor>) -> WrapExporter {
+        WrapExporter { sensor }
+    }
+
+    /// Runs `command` and sums the energy consumed by its process tree, measured
+    /// every `interval`, until it exits.
+    fn measure(&mut self, command: &[&str], interval: Duration) -> WrapReport {
+        let mut topology = self.sensor.get_topology().unwrap();
+        topology.refresh();
+
+        let start = Instant::now();
+        let mut report = WrapReport {
+            command: command.join(" "),
+            status: None,
+            duration: Duration::ZERO,
+            energy_microjoules: 0.0,
+        };
+        let mut child = match Command::new(command[0]).args(&command[1..]).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Couldn't run {}: {}", command[0], e);
+                return report;
+            }
+        };
+        let pid = child.id() as i32;
+
+        let mut last_refresh = Instant::now();
+        loop {
+            thread::sleep(interval);
+            // measure before reaping the command, while its last CPU times can still be read
+            topology.refresh();
+            let elapsed = last_refresh.elapsed();
+            last_refresh = Instant::now();
+            if let Some(power) = topology.get_process_tree_power_microwatts(pid) {
+                if let Ok(microwatts) = power.value.parse::<f64>() {
+                    report.energy_microjoules += microwatts * elapsed.as_secs_f64();
+                }
+            }
+            topology
+                .proc_tracker
+                .clean_terminated_process_records_vectors();
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    report.status = Some(status);
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Couldn't wait for {}: {}", command[0], e);
+                    break;
+                }
+            }
+        }
+        report.duration = start.elapsed();
+        report
+    }
+}
+
+/// Energy consumed by a command wrapped by WrapExporter.
+struct WrapReport {
+    command: String,
+    /// Exit status of the command, None if it couldn't run.
+    status: Option<ExitStatus>,
+    duration: Duration,
+    energy_microjoules: f64,
+}
+
+impl WrapReport {
+    /// Returns the energy consumed by the command, in joules.
+    fn energy_joules(&self) -> f64 {
+        self.energy_microjoules / 1_000_000.0
+    }
+
+    /// Returns the average power of the command over its run, in watts.
+    fn average_power_watts(&self) -> f64 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds > 0.0 {
+            self.energy_joules() / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the report as a JSON object.
+    #[cfg(feature = "json")]
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "command": self.command,
+            "exit_code": self.status.and_then(|s| s.code()),
+            "duration_seconds": self.duration.as_secs_f64(),
+            "energy_joules": self.energy_joules(),
+            "average_power_watts": self.average_power_watts(),
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for WrapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => writeln!(f, "Command {} exited with {}", self.command, status)?,
+            None => writeln!(f, "Command {} couldn't run", self.command)?,
+        }
+        writeln!(f, "Duration: {:.3} s", self.duration.as_secs_f64())?;
+        writeln!(f, "Energy: {:.3} J", self.energy_joules())?;
+        write!(f, "Average power: {:.3} W", self.average_power_watts())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_computes_average_power() {
+        let report = WrapReport {
+            command: String::from("make"),
+            status: None,
+            duration: Duration::from_secs(4),
+            energy_microjoules: 10_000_000.0,
+        };
+        assert_eq!(report.energy_joules(), 10.0);
+        assert_eq!(report.average_power_watts(), 2.5);
+        assert_eq!(
+            report.to_string(),
+            "Command make couldn't run\nDuration: 4.000 s\nEnergy: 10.000 J\nAverage power: 2.500 W"
+        );
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
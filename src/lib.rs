@@ -15,17 +15,32 @@ use exporters::json::JSONExporter;
 use exporters::prometheus::PrometheusExporter;
 #[cfg(all(target_os = "linux", not(feature = "warpten")))]
 use exporters::qemu::QemuExporter;
+#[cfg(feature = "prometheus")]
+use exporters::remote_write::RemoteWriteExporter;
 #[cfg(feature = "riemann")]
 use exporters::riemann::RiemannExporter;
 #[cfg(feature = "warpten")]
 use exporters::warpten::Warp10Exporter;
-use exporters::{stdout::StdoutExporter, Exporter};
+#[cfg(feature = "websocket")]
+use exporters::websocket::WebsocketExporter;
+use exporters::{
+    bench::BenchExporter, stdout::StdoutExporter, utils::exit_with_error, wrap::WrapExporter,
+    Exporter,
+};
 #[cfg(target_os = "windows")]
 use sensors::msr_rapl::MsrRAPLSensor;
 #[cfg(target_os = "linux")]
-use sensors::powercap_rapl::PowercapRAPLSensor;
-use sensors::Sensor;
+use sensors::powercap_rapl::{PowercapRAPLSensor, GUEST_POWERCAP_PATH};
+#[cfg(feature = "redfish")]
+use sensors::redfish::RedfishSensor;
+#[cfg(feature = "replay")]
+use sensors::replay::ReplaySensor;
+#[cfg(feature = "smartplug")]
+use sensors::smartplug::{SmartPlugSensor, SmartPlugType};
+use sensors::{composite::CompositeSensor, diagnostics::format_report, Sensor};
 use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::path::Path;
 use std::time::{Duration, SystemTime};
 
 /// Helper function to get an argument from ArgMatches
@@ -36,33 +51,160 @@ fn get_argument(matches: &ArgMatches, arg: &'static str) -> String {
     panic!("Couldn't get argument {}", arg);
 }
 
-/// Helper function to get a Sensor instance from ArgMatches
-fn get_sensor(matches: &ArgMatches) -> Box<dyn Sensor> {
-    let sensor = match &get_argument(matches, "sensor")[..] {
-        #[cfg(target_os = "linux")]
-        "powercap_rapl" => PowercapRAPLSensor::new(
+/// Helper function to get a Sensor instance from ArgMatches: the sensor asked
+/// with `--sensor`, or the first one working on this host.
+/// If `--extra-sensor` is given, the sensor is combined with the extra ones,
+/// whose host level power is reported along.
+fn get_sensor(matches: &ArgMatches) -> Result<Box<dyn Sensor>, String> {
+    let sensor = match matches.value_of("sensor") {
+        Some(name) => build_sensor(name, matches),
+        None => get_default_sensor(matches)?,
+    };
+    match matches.values_of("extra-sensor") {
+        Some(names) => Ok(Box::new(CompositeSensor::new(
+            sensor,
+            names
+                .map(|name| (String::from(name), build_sensor(name, matches)))
+                .collect(),
+        ))),
+        None => Ok(sensor),
+    }
+}
+
+/// Probes the sensors that could work on this host, by order of preference,
+/// and returns the first one able to get measurements. Sensors querying a
+/// remote power meter are only tried if their url is given. Returns an error
+/// listing the sensors tried, and why they failed, if none works.
+pub fn get_default_sensor(matches: &ArgMatches) -> Result<Box<dyn Sensor>, String> {
+    #[cfg(target_os = "linux")]
+    let mut candidates = vec![("powercap_rapl", build_sensor("powercap_rapl", matches))];
+    // in a virtual machine, energy counters may be given by the qemu exporter of the hypervisor
+    #[cfg(target_os = "linux")]
+    if !matches.is_present("vm")
+        && !matches.is_present("snapshot-dir")
+        && Path::new(GUEST_POWERCAP_PATH).exists()
+    {
+        candidates.push(("powercap_rapl (vm)", build_powercap_sensor(matches, true)));
+    }
+    #[cfg(not(target_os = "linux"))]
+    let mut candidates = vec![("msr_rapl", build_sensor("msr_rapl", matches))];
+    #[cfg(feature = "redfish")]
+    if matches.is_present("redfish-endpoint") {
+        candidates.push(("redfish", build_sensor("redfish", matches)));
+    }
+    #[cfg(feature = "smartplug")]
+    if matches.is_present("smartplug-url") {
+        candidates.push(("smartplug", build_sensor("smartplug", matches)));
+    }
+
+    let mut failures = vec![];
+    for (name, sensor) in candidates {
+        match sensor.probe() {
+            Ok(()) => {
+                info!("Using sensor {}", name);
+                return Ok(sensor);
+            }
+            Err(e) => {
+                debug!("Sensor {} is not available: {}", name, e);
+                failures.push(format!("{name}: {e}"));
+            }
+        }
+    }
+    Err(format!(
+        "No sensor is able to get measurements on this host. Tried:\n- {}",
+        failures.join("\n- ")
+    ))
+}
+
+/// Runs the self-test of the sensor asked with `--sensor`, or of the default
+/// sensor of the platform, without starting any exporter. Prints the report
+/// and exits with an error code if a check failed.
+fn diagnose(matches: &ArgMatches) {
+    #[cfg(target_os = "linux")]
+    let default_sensor = "powercap_rapl";
+    #[cfg(not(target_os = "linux"))]
+    let default_sensor = "msr_rapl";
+    let sensor = build_sensor(
+        matches.value_of("sensor").unwrap_or(default_sensor),
+        matches,
+    );
+    let checks = sensor.self_test();
+    print!("{}", format_report(&checks));
+    if checks.iter().any(|check| !check.passed) {
+        std::process::exit(1);
+    }
+}
+
+/// Returns the sensor named `name`, configured from ArgMatches.
+fn build_sensor(name: &str, matches: &ArgMatches) -> Box<dyn Sensor> {
+    let sensor: Box<dyn Sensor> = match name {
+        #[cfg(feature = "redfish")]
+        "redfish" => Box::new(RedfishSensor::new(
+            &get_argument(matches, "redfish-endpoint"),
+            &get_argument(matches, "redfish-chassis"),
+            matches.value_of("redfish-username").map(String::from),
+            exporters::utils::get_secret(
+                matches,
+                "redfish-password",
+                "redfish-password-file",
+                "SCAPH_REDFISH_PASSWORD",
+            )
+            .unwrap_or_else(|e| exit_with_error(&e)),
+            matches.is_present("redfish-skip-tls-verify"),
             get_argument(matches, "sensor-buffer-per-socket-max-kB")
                 .parse()
                 .unwrap(),
-            get_argument(matches, "sensor-buffer-per-domain-max-kB")
-                .parse()
+        )),
+        #[cfg(feature = "smartplug")]
+        "smartplug" => Box::new(SmartPlugSensor::new(
+            &get_argument(matches, "smartplug-url"),
+            get_argument(matches, "smartplug-type")
+                .parse::<SmartPlugType>()
                 .unwrap(),
-            matches.is_present("vm"),
-        ),
-        #[cfg(target_os = "linux")]
-        _ => PowercapRAPLSensor::new(
             get_argument(matches, "sensor-buffer-per-socket-max-kB")
                 .parse()
                 .unwrap(),
-            get_argument(matches, "sensor-buffer-per-domain-max-kB")
+        )),
+        // the value of replay-speed is checked by the validator of the option
+        #[cfg(feature = "replay")]
+        "replay" => Box::new(ReplaySensor::new(
+            &get_argument(matches, "replay-file"),
+            get_argument(matches, "replay-speed")
+                .parse::<f64>()
+                .expect("Wrong replay-speed value, should be a positive number"),
+            matches.is_present("replay-loop"),
+            get_argument(matches, "sensor-buffer-per-socket-max-kB")
                 .parse()
                 .unwrap(),
-            matches.is_present("vm"),
-        ),
+        )),
+        #[cfg(target_os = "linux")]
+        _ => build_powercap_sensor(matches, matches.is_present("vm")),
         #[cfg(not(target_os = "linux"))]
-        _ => MsrRAPLSensor::new(),
+        _ => Box::new(MsrRAPLSensor::new()),
     };
-    Box::new(sensor)
+    sensor
+}
+
+/// Returns the powercap_rapl sensor, reading the energy counters given by the
+/// hypervisor if `virtual_machine` is true.
+#[cfg(target_os = "linux")]
+fn build_powercap_sensor(matches: &ArgMatches, virtual_machine: bool) -> Box<dyn Sensor> {
+    let mut sensor = PowercapRAPLSensor::new(
+        get_argument(matches, "sensor-buffer-per-socket-max-kB")
+            .parse()
+            .unwrap(),
+        get_argument(matches, "sensor-buffer-per-domain-max-kB")
+            .parse()
+            .unwrap(),
+        virtual_machine,
+    );
+    if matches.is_present("rapl-package-sum") {
+        sensor = sensor.with_package_sum();
+    }
+    match matches.value_of("snapshot-dir") {
+        Some(snapshot_dir) => Box::new(sensor.with_snapshot_dir(snapshot_dir)),
+        None => Box::new(sensor),
+    }
 }
 
 /// Matches the sensor and exporter name and options requested from the command line and
@@ -72,7 +214,15 @@ fn get_sensor(matches: &ArgMatches) -> Box<dyn Sensor> {
 pub fn run(matches: ArgMatches) {
     loggerv::init_with_verbosity(matches.occurrences_of("v")).unwrap();
 
-    let sensor_boxed = get_sensor(&matches);
+    if matches.subcommand_matches("diagnose").is_some() {
+        diagnose(&matches);
+        return;
+    }
+
+    let sensor_boxed = match get_sensor(&matches) {
+        Ok(sensor) => sensor,
+        Err(e) => exit_with_error(&e),
+    };
     let exporter_parameters;
 
     let mut header = true;
@@ -80,6 +230,26 @@ pub fn run(matches: ArgMatches) {
         header = false;
     }
 
+    // exporters behind a feature can't be a branch of the chain below
+    #[cfg(feature = "prometheus")]
+    if let Some(remote_write_exporter_parameters) = matches.subcommand_matches("remote-write") {
+        if header {
+            scaphandre_header("remote-write");
+        }
+        let mut exporter = RemoteWriteExporter::new(sensor_boxed);
+        exporter.run(remote_write_exporter_parameters.clone());
+        return;
+    }
+    #[cfg(feature = "websocket")]
+    if let Some(websocket_exporter_parameters) = matches.subcommand_matches("websocket") {
+        if header {
+            scaphandre_header("websocket");
+        }
+        let mut exporter = WebsocketExporter::new(sensor_boxed);
+        exporter.run(websocket_exporter_parameters.clone());
+        return;
+    }
+
     if let Some(stdout_exporter_parameters) = matches.subcommand_matches("stdout") {
         if header {
             scaphandre_header("stdout");
@@ -108,6 +278,14 @@ pub fn run(matches: ArgMatches) {
         exporter_parameters = prometheus_exporter_parameters.clone();
         let mut exporter = PrometheusExporter::new(sensor_boxed);
         exporter.run(exporter_parameters);
+    } else if let Some(bench_exporter_parameters) = matches.subcommand_matches("bench") {
+        exporter_parameters = bench_exporter_parameters.clone();
+        let mut exporter = BenchExporter::new(sensor_boxed);
+        exporter.run(exporter_parameters);
+    } else if let Some(wrap_exporter_parameters) = matches.subcommand_matches("wrap") {
+        exporter_parameters = wrap_exporter_parameters.clone();
+        let mut exporter = WrapExporter::new(sensor_boxed);
+        exporter.run(exporter_parameters);
     } else {
         #[cfg(target_os = "linux")]
         {
@@ -157,11 +335,29 @@ pub fn get_exporters_options() -> HashMap<String, Vec<clap::Arg<'static, 'static
         String::from("prometheus"),
         exporters::prometheus::PrometheusExporter::get_options(),
     );
+    #[cfg(feature = "prometheus")]
+    options.insert(
+        String::from("remote-write"),
+        exporters::remote_write::RemoteWriteExporter::get_options(),
+    );
     #[cfg(feature = "riemann")]
     options.insert(
         String::from("riemann"),
         exporters::riemann::RiemannExporter::get_options(),
     );
+    #[cfg(feature = "websocket")]
+    options.insert(
+        String::from("websocket"),
+        exporters::websocket::WebsocketExporter::get_options(),
+    );
+    options.insert(
+        String::from("bench"),
+        exporters::bench::BenchExporter::get_options(),
+    );
+    options.insert(
+        String::from("wrap"),
+        exporters::wrap::WrapExporter::get_options(),
+    );
     #[cfg(target_os = "linux")]
     options.insert(
         String::from("qemu"),
@@ -1,20 +1,31 @@
 //! Generic sensor and transmission agent for energy consumption related metrics.
 use clap::{crate_authors, crate_version, App, AppSettings, Arg, SubCommand};
-use scaphandre::{get_exporters_options, run};
+use scaphandre::{exporters::utils::validate_number, get_exporters_options, run};
 fn main() {
     #[cfg(target_os = "linux")]
-    let sensors = ["powercap_rapl"];
+    let sensors = [
+        "powercap_rapl",
+        #[cfg(feature = "redfish")]
+        "redfish",
+        #[cfg(feature = "smartplug")]
+        "smartplug",
+        #[cfg(feature = "replay")]
+        "replay",
+    ];
     #[cfg(target_os = "windows")]
-    let sensors = ["msr_rapl"];
+    let sensors = [
+        "msr_rapl",
+        #[cfg(feature = "redfish")]
+        "redfish",
+        #[cfg(feature = "smartplug")]
+        "smartplug",
+        #[cfg(feature = "replay")]
+        "replay",
+    ];
     let exporters_options = get_exporters_options();
     let exporters = exporters_options.keys();
     let exporters: Vec<&str> = exporters.into_iter().map(|x| x.as_str()).collect();
 
-    #[cfg(target_os = "linux")]
-    let sensor_default_value = String::from("powercap_rapl");
-    #[cfg(not(target_os = "linux"))]
-    let sensor_default_value = String::from("msr_rapl");
-
     let mut matches = App::new("scaphandre")
         .author(crate_authors!())
         .version(crate_version!())
@@ -37,13 +48,22 @@ fn main() {
         .arg(
             Arg::with_name("sensor")
                 .value_name("sensor")
-                .help("Sensor module to apply on the host to get energy consumption metrics.")
+                .help("Sensor module to apply on the host to get energy consumption metrics. By default, the first sensor able to get measurements on this host is used.")
                 .required(false)
                 .takes_value(true)
-                .default_value(&sensor_default_value)
                 .possible_values(&sensors)
                 .short("s")
                 .long("sensor")
+        ).arg(
+            Arg::with_name("extra-sensor")
+                .value_name("extra-sensor")
+                .help("Additional sensor whose measure of the host power is also reported, labeled with the name of the sensor, like a smart plug measuring the wall power. Can be used several times.")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&sensors)
+                .long("extra-sensor")
         ).arg(
             Arg::with_name("sensor-buffer-per-domain-max-kB")
                 .value_name("sensor-buffer-per-domain-max-kB")
@@ -65,17 +85,141 @@ fn main() {
                 .long("vm")
                 .required(false)
                 .takes_value(false)
+        ).arg(
+            Arg::with_name("rapl-package-sum")
+                .value_name("rapl-package-sum")
+                .help("Measure the host power as the sum of the RAPL packages, even if a psys zone, covering the whole platform, is available. Only used by the powercap_rapl sensor.")
+                .long("rapl-package-sum")
+                .required(false)
+                .takes_value(false)
+        ).arg(
+            Arg::with_name("snapshot-dir")
+                .value_name("snapshot-dir")
+                .help("Folder holding the sys and proc folders of another host, captured or mounted there (with sshfs for example), to measure from them instead of from this host. Only used by the powercap_rapl sensor.")
+                .long("snapshot-dir")
+                .required(false)
+                .takes_value(true)
         );
 
+    #[cfg(feature = "redfish")]
+    {
+        matches = matches
+            .arg(
+                Arg::with_name("redfish-endpoint")
+                    .value_name("redfish-endpoint")
+                    .help("Base url of the Redfish endpoint (BMC) to get power from, with the redfish sensor.")
+                    .long("redfish-endpoint")
+                    .required_ifs(&[("sensor", "redfish"), ("extra-sensor", "redfish")])
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("redfish-chassis")
+                    .value_name("redfish-chassis")
+                    .help("Id of the Redfish chassis to get power from.")
+                    .long("redfish-chassis")
+                    .required(false)
+                    .takes_value(true)
+                    .default_value("1")
+            ).arg(
+                Arg::with_name("redfish-username")
+                    .value_name("redfish-username")
+                    .help("Username to authenticate on the Redfish endpoint.")
+                    .long("redfish-username")
+                    .required(false)
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("redfish-password")
+                    .value_name("redfish-password")
+                    .help("Password to authenticate on the Redfish endpoint. SCAPH_REDFISH_PASSWORD env var can be used instead.")
+                    .long("redfish-password")
+                    .required(false)
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("redfish-password-file")
+                    .value_name("redfish-password-file")
+                    .help("File containing the password to authenticate on the Redfish endpoint, like a mounted Docker or Kubernetes secret. Read once at startup.")
+                    .long("redfish-password-file")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with("redfish-password")
+            ).arg(
+                Arg::with_name("redfish-skip-tls-verify")
+                    .value_name("redfish-skip-tls-verify")
+                    .help("Don't verify the TLS certificate of the Redfish endpoint.")
+                    .long("redfish-skip-tls-verify")
+                    .required(false)
+                    .takes_value(false)
+            );
+    }
+
+    #[cfg(feature = "smartplug")]
+    {
+        matches = matches
+            .arg(
+                Arg::with_name("smartplug-url")
+                    .value_name("smartplug-url")
+                    .help(
+                        "Base url of the smart plug to get power from, with the smartplug sensor.",
+                    )
+                    .long("smartplug-url")
+                    .required_ifs(&[("sensor", "smartplug"), ("extra-sensor", "smartplug")])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("smartplug-type")
+                    .value_name("smartplug-type")
+                    .help("Firmware of the smart plug.")
+                    .long("smartplug-type")
+                    .required(false)
+                    .takes_value(true)
+                    .possible_values(&["tasmota", "shelly", "shelly_gen2"])
+                    .default_value("tasmota"),
+            );
+    }
+
+    #[cfg(feature = "replay")]
+    {
+        matches = matches
+            .arg(
+                Arg::with_name("replay-file")
+                    .value_name("replay-file")
+                    .help("Capture of the json exporter (a JSON file or NDJSON frames, one per line) to replay the power of the host and its sockets from, with the replay sensor. The consumers of the capture are not replayed: the power is attributed to the local processes.")
+                    .long("replay-file")
+                    .required_ifs(&[("sensor", "replay"), ("extra-sensor", "replay")])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("replay-speed")
+                    .value_name("replay-speed")
+                    .help("Seconds of the capture replayed per second: 1 replays it in real time, 10 ten times faster.")
+                    .long("replay-speed")
+                    .required(false)
+                    .takes_value(true)
+                    .default_value("1")
+                    .validator(|speed| validate_number(&speed, |speed| speed > 0.0, "a positive number")),
+            )
+            .arg(
+                Arg::with_name("replay-loop")
+                    .value_name("replay-loop")
+                    .help("Start the capture over once its end is reached, instead of stopping to report power.")
+                    .long("replay-loop")
+                    .required(false)
+                    .takes_value(false),
+            );
+    }
+
     for exporter in exporters {
         let mut subcmd = SubCommand::with_name(exporter).about(
             match exporter {
                 "stdout" => "Stdout exporter allows you to output the power consumption data in the terminal",
                 "json" => "JSON exporter allows you to output the power consumption data in a json file",
                 "prometheus" => "Prometheus exporter exposes power consumption metrics on an http endpoint (/metrics is default) in prometheus accepted format",
+                "remote-write" => "Remote write exporter pushes power consumption metrics to a Prometheus remote write endpoint (Mimir, Thanos receive...)",
                 "riemann" => "Riemann exporter sends power consumption metrics to a Riemann server",
                 "qemu" => "Qemu exporter watches all Qemu/KVM virtual machines running on the host and exposes metrics of each of them in a dedicated folder",
                 "warp10" => "Warp10 exporter sends data to a Warp10 host, through HTTP",
+                "websocket" => "WebSocket exporter pushes power consumption data as json to WebSocket clients, like live dashboards",
+                "bench" => "Bench exporter measures the overhead of scaphandre (CPU time, memory, energy and time of each iteration) for a while, then prints a summary",
+                "wrap" => "Wrap exporter runs a command and prints the energy consumed by its processes once it exits",
                 _ => "Unknown exporter",
             }
         );
@@ -86,6 +230,9 @@ fn main() {
         }
         matches = matches.subcommand(subcmd);
     }
+    matches = matches.subcommand(SubCommand::with_name("diagnose").about(
+        "Checks that the sensor can measure power on this host, and tells how to fix it if it can't",
+    ));
     run(matches.get_matches());
 }
 
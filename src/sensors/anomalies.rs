@@ -0,0 +1,128 @@
+//! # Anomalies
+//!
+//! The anomalies module counts implausible measurements (an energy counter going
+//! backwards, processes consuming more than the host...) and logs them, at most
+//! once per interval for each kind, so that operators notice bad data without
+//! the logs being flooded.
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// An energy counter decreased and no counter range is known to explain it as a wrap.
+pub const NEGATIVE_ENERGY_DIFF: &str = "negative_energy_diff";
+/// The CPU time spent by the host, from /proc/stat, decreased.
+pub const NEGATIVE_STATS_DIFF: &str = "negative_stats_diff";
+/// The power attributed to processes exceeds the host power by more than the threshold.
+pub const PROCESSES_EXCEED_HOST: &str = "processes_exceed_host";
+/// The host power is zero while its CPUs were busy.
+pub const ZERO_HOST_POWER: &str = "zero_host_power";
+
+/// Kinds of anomalies detected.
+pub const ANOMALY_KINDS: [&str; 4] = [
+    NEGATIVE_ENERGY_DIFF,
+    NEGATIVE_STATS_DIFF,
+    PROCESSES_EXCEED_HOST,
+    ZERO_HOST_POWER,
+];
+
+/// Default ratio of the host power the power of processes may exceed before
+/// it is considered an anomaly: rounding makes their sum slightly off.
+pub const DEFAULT_PROCESSES_POWER_THRESHOLD: f64 = 0.1;
+
+/// Default minimum time between two logs of the same kind of anomaly.
+pub const DEFAULT_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counts anomalies by kind and logs them, rate limited.
+#[derive(Debug, Clone)]
+pub struct AnomalyDetector {
+    /// Ratio of the host power the power of processes may exceed.
+    pub processes_power_threshold: f64,
+    log_interval: Duration,
+    /// Number of anomalies detected since the start, by kind.
+    counts: HashMap<&'static str, u64>,
+    /// Time of the last log and number of anomalies not logged since, by kind.
+    last_logs: HashMap<&'static str, (Duration, u64)>,
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> Self {
+        AnomalyDetector::new(DEFAULT_PROCESSES_POWER_THRESHOLD, DEFAULT_LOG_INTERVAL)
+    }
+}
+
+impl AnomalyDetector {
+    /// Instantiates AnomalyDetector, logging each kind of anomaly at most once
+    /// per `log_interval`.
+    pub fn new(processes_power_threshold: f64, log_interval: Duration) -> AnomalyDetector {
+        AnomalyDetector {
+            processes_power_threshold,
+            log_interval,
+            counts: HashMap::new(),
+            last_logs: HashMap::new(),
+        }
+    }
+
+    /// Counts an anomaly of `kind` detected at `timestamp`, and logs it with
+    /// `message` unless the same kind was logged less than log_interval ago.
+    /// Returns true if it was logged.
+    pub fn report(&mut self, kind: &'static str, timestamp: Duration, message: &str) -> bool {
+        *self.counts.entry(kind).or_insert(0) += 1;
+        match self.last_logs.get_mut(kind) {
+            Some((last_log, suppressed)) if timestamp < *last_log + self.log_interval => {
+                *suppressed += 1;
+                false
+            }
+            Some((last_log, suppressed)) => {
+                warn!(
+                    "Anomaly {}: {} ({} more since the last log)",
+                    kind, message, suppressed
+                );
+                *last_log = timestamp;
+                *suppressed = 0;
+                true
+            }
+            None => {
+                warn!("Anomaly {}: {}", kind, message);
+                self.last_logs.insert(kind, (timestamp, 0));
+                true
+            }
+        }
+    }
+
+    /// Returns the number of anomalies of `kind` detected since the start.
+    pub fn get_count(&self, kind: &str) -> u64 {
+        self.counts.get(kind).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anomalies_logged_once_per_interval() {
+        let mut detector = AnomalyDetector::new(0.1, Duration::from_secs(60));
+        let start = Duration::from_secs(1000);
+        assert!(detector.report(ZERO_HOST_POWER, start, "host power is 0"));
+        assert!(!detector.report(ZERO_HOST_POWER, start + Duration::from_secs(10), ""));
+        // other kinds have their own interval
+        assert!(detector.report(NEGATIVE_STATS_DIFF, start, ""));
+        assert!(detector.report(ZERO_HOST_POWER, start + Duration::from_secs(60), ""));
+        assert_eq!(detector.get_count(ZERO_HOST_POWER), 3);
+        assert_eq!(detector.get_count(NEGATIVE_STATS_DIFF), 1);
+        assert_eq!(detector.get_count(PROCESSES_EXCEED_HOST), 0);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
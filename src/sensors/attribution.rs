@@ -0,0 +1,151 @@
+//! # Attribution
+//!
+//! Attribution strategies divide the power of the host between its processes.
+//! The topology gives a strategy what was measured for a process between the
+//! previous and the last refresh, and the strategy returns the power of the
+//! process, so that models can be tested alone and new ones plugged without
+//! touching the topology.
+use std::fmt;
+use std::sync::Arc;
+
+/// Names of the strategies that can be given to `--attribution-strategy`.
+pub const ATTRIBUTION_STRATEGIES: [&str; 2] = ["cpu-time", "active-power"];
+
+/// What was measured for a process and the host between the previous and the
+/// last refresh.
+#[derive(Debug, Clone, Default)]
+pub struct AttributionInput {
+    /// Power of the host, in microwatts.
+    pub host_power_microwatts: f64,
+    /// CPU time spent by the process, as counted by the attribution basis.
+    pub process_time_jiffies: f64,
+    /// CPU time spent by the host, as counted by the attribution basis.
+    pub host_time_jiffies: f64,
+    /// Power attributed according to the IO of processes rather than their CPU
+    /// time, in microwatts, if IO attribution is enabled.
+    pub io_power_microwatts: Option<f64>,
+    /// Share of the IO of the host done by the process, between 0 and 1.
+    pub io_share: f64,
+    /// Estimated idle power of the host, in microwatts, if the power baseline is enabled.
+    pub idle_power_microwatts: Option<f64>,
+}
+
+impl AttributionInput {
+    /// Returns the share of the CPU time of the host spent by the process, between 0 and 1.
+    pub fn get_cpu_share(&self) -> f64 {
+        if self.host_time_jiffies > 0.0 {
+            self.process_time_jiffies / self.host_time_jiffies
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Divides the power of the host between its processes.
+pub trait AttributionStrategy: fmt::Debug + Send + Sync {
+    /// Returns the name of the strategy.
+    fn name(&self) -> &str;
+
+    /// Returns the power attributed to a process, in microwatts.
+    fn attribute(&self, input: &AttributionInput) -> f64;
+}
+
+/// Divides the power of the host according to the share of its CPU time spent
+/// by each process. With IO attribution, the power attributed from IO is
+/// divided according to the share of IO instead. The default.
+#[derive(Debug, Clone, Default)]
+pub struct CpuTimeShare;
+
+impl AttributionStrategy for CpuTimeShare {
+    fn name(&self) -> &str {
+        "cpu-time"
+    }
+
+    fn attribute(&self, input: &AttributionInput) -> f64 {
+        let cpu_share = input.get_cpu_share();
+        match input.io_power_microwatts {
+            Some(io_power) => {
+                (input.host_power_microwatts - io_power).max(0.0) * cpu_share
+                    + io_power * input.io_share
+            }
+            None => input.host_power_microwatts * cpu_share,
+        }
+    }
+}
+
+/// Divides only the power of the host above its idle power, like CpuTimeShare,
+/// the idle power being a cost of the host rather than of its processes.
+/// Without a power baseline, it is the same as CpuTimeShare.
+#[derive(Debug, Clone, Default)]
+pub struct ActivePowerShare;
+
+impl AttributionStrategy for ActivePowerShare {
+    fn name(&self) -> &str {
+        "active-power"
+    }
+
+    fn attribute(&self, input: &AttributionInput) -> f64 {
+        let idle_power = input.idle_power_microwatts.unwrap_or(0.0);
+        let active = AttributionInput {
+            host_power_microwatts: (input.host_power_microwatts - idle_power).max(0.0),
+            ..input.clone()
+        };
+        CpuTimeShare.attribute(&active)
+    }
+}
+
+/// Returns the built-in strategy named `name`, as given to `--attribution-strategy`.
+pub fn get_attribution_strategy(name: &str) -> Result<Arc<dyn AttributionStrategy>, String> {
+    match name {
+        "cpu-time" => Ok(Arc::new(CpuTimeShare)),
+        "active-power" => Ok(Arc::new(ActivePowerShare)),
+        _ => Err(format!(
+            "Unknown attribution strategy {name}, should be one of {}",
+            ATTRIBUTION_STRATEGIES.join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_attributed_by_strategy() {
+        let mut input = AttributionInput {
+            host_power_microwatts: 10_000_000.0,
+            process_time_jiffies: 25.0,
+            host_time_jiffies: 100.0,
+            ..Default::default()
+        };
+        assert_eq!(CpuTimeShare.attribute(&input), 2_500_000.0);
+        // no baseline, all the power is active
+        assert_eq!(ActivePowerShare.attribute(&input), 2_500_000.0);
+        input.idle_power_microwatts = Some(6_000_000.0);
+        assert_eq!(ActivePowerShare.attribute(&input), 1_000_000.0);
+        input.io_power_microwatts = Some(2_000_000.0);
+        input.io_share = 0.5;
+        assert_eq!(CpuTimeShare.attribute(&input), 3_000_000.0);
+        input.host_time_jiffies = 0.0;
+        assert_eq!(CpuTimeShare.attribute(&input), 1_000_000.0);
+
+        for name in ATTRIBUTION_STRATEGIES {
+            assert_eq!(get_attribution_strategy(name).unwrap().name(), name);
+        }
+        assert!(get_attribution_strategy("energy").is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
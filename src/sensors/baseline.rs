@@ -0,0 +1,122 @@
+//! # Baseline
+//!
+//! The baseline module estimates the idle (baseline) power of the host from the
+//! power measured over a rolling window, so that the power induced by the
+//! workloads (active power) can be told apart from the power the host would
+//! consume anyway.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How the baseline power is computed from the samples of the window.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BaselineMethod {
+    /// Lowest power measured in the window.
+    Minimum,
+    /// Given percentile (between 0 and 100) of the power measured in the window,
+    /// less sensitive than the minimum to a single low measurement.
+    Percentile(f64),
+}
+
+/// Keeps the host power measured over a rolling window to estimate its baseline.
+#[derive(Debug, Clone)]
+pub struct PowerBaseline {
+    /// Duration of the window.
+    window: Duration,
+    method: BaselineMethod,
+    /// Timestamps and powers measured in the window, in microwatts, oldest first.
+    samples: VecDeque<(Duration, f64)>,
+}
+
+impl PowerBaseline {
+    /// Instantiates PowerBaseline. Returns an error if the window is zero or the
+    /// percentile is not between 0 and 100.
+    pub fn new(window: Duration, method: BaselineMethod) -> Result<PowerBaseline, String> {
+        if window.is_zero() {
+            return Err(String::from("Baseline window should not be zero"));
+        }
+        if let BaselineMethod::Percentile(percentile) = method {
+            if !(0.0..=100.0).contains(&percentile) {
+                return Err(format!(
+                    "Baseline percentile should be between 0 and 100, got {percentile}"
+                ));
+            }
+        }
+        Ok(PowerBaseline {
+            window,
+            method,
+            samples: VecDeque::new(),
+        })
+    }
+
+    /// Stores the power measured at `timestamp` and forgets the samples that
+    /// are now out of the window.
+    pub fn add_sample(&mut self, timestamp: Duration, microwatts: f64) {
+        self.samples.push_back((timestamp, microwatts));
+        if let Some(start) = timestamp.checked_sub(self.window) {
+            while let Some((oldest, _)) = self.samples.front() {
+                if *oldest >= start {
+                    break;
+                }
+                self.samples.pop_front();
+            }
+        }
+    }
+
+    /// Returns the baseline power over the window, in microwatts, or None if
+    /// nothing has been measured yet.
+    pub fn get_baseline_microwatts(&self) -> Option<f64> {
+        let mut powers: Vec<f64> = self.samples.iter().map(|(_, power)| *power).collect();
+        if powers.is_empty() {
+            return None;
+        }
+        powers.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        match self.method {
+            BaselineMethod::Minimum => powers.first().copied(),
+            BaselineMethod::Percentile(percentile) => {
+                // nearest-rank percentile
+                let rank = (percentile / 100.0 * powers.len() as f64).ceil() as usize;
+                powers.get(rank.max(1) - 1).copied()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baseline_follows_window() {
+        assert!(PowerBaseline::new(Duration::ZERO, BaselineMethod::Minimum).is_err());
+        assert!(
+            PowerBaseline::new(Duration::from_secs(1), BaselineMethod::Percentile(101.0)).is_err()
+        );
+
+        let mut minimum =
+            PowerBaseline::new(Duration::from_secs(10), BaselineMethod::Minimum).unwrap();
+        let mut percentile =
+            PowerBaseline::new(Duration::from_secs(10), BaselineMethod::Percentile(50.0)).unwrap();
+        assert_eq!(minimum.get_baseline_microwatts(), None);
+        for (second, power) in [(0, 10.0), (4, 40.0), (8, 20.0), (12, 30.0)] {
+            minimum.add_sample(Duration::from_secs(second), power);
+            percentile.add_sample(Duration::from_secs(second), power);
+        }
+        // the sample at 0s is out of the window
+        assert_eq!(minimum.get_baseline_microwatts(), Some(20.0));
+        assert_eq!(percentile.get_baseline_microwatts(), Some(30.0));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,357 @@
+//! # Cgroups
+//!
+//! The cgroups module keeps track of the CPU usage of control groups, as
+//! accounted by the kernel in the `cpu.stat` file of each cgroup (cgroup v2),
+//! or in its `cpuacct.usage` file on hosts still using cgroup v1 (like RHEL 7
+//! and 8).
+//! It is used to divide the power consumption between cgroups rather than
+//! between processes. It also maps systemd services to the processes of their
+//! cgroup, to sum the power consumption per service.
+use crate::sensors::utils::current_system_time_since_epoch;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default mount point of the cgroup v2 hierarchy, or of the cgroup v1 hierarchies.
+pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Folders of the cgroup v1 hierarchy holding the cpuacct controller,
+/// relative to the root, by order of preference.
+const CPUACCT_HIERARCHIES: [&str; 2] = ["cpuacct", "cpu,cpuacct"];
+
+/// Maximum number of records kept per cgroup.
+const MAX_RECORDS_PER_CGROUP: usize = 3;
+
+/// CPU usage of a cgroup at a given time.
+#[derive(Debug, Clone)]
+pub struct CgroupRecord {
+    pub timestamp: Duration,
+    /// Total CPU time consumed by the tasks of the cgroup (and its descendants), in microseconds.
+    pub usage_usec: u64,
+}
+
+/// Version of the cgroup hierarchy CPU usage is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    /// cpuacct controller of a cgroup v1 hierarchy: `cpuacct.usage`, in nanoseconds.
+    V1,
+    /// Unified hierarchy: `usage_usec` in `cpu.stat`, in microseconds.
+    V2,
+}
+
+/// Keeps track of the CPU usage of all the cgroups of a cgroup hierarchy.
+#[derive(Debug, Clone)]
+pub struct CgroupTracker {
+    /// Mount point of the cgroup v2 hierarchy, or of the cpuacct hierarchy on cgroup v1.
+    root: PathBuf,
+    version: CgroupVersion,
+    /// Records of each cgroup, newest first, by cgroup path relative to root (like `/system.slice`).
+    pub cgroups: HashMap<String, Vec<CgroupRecord>>,
+}
+
+impl CgroupTracker {
+    /// Instantiates CgroupTracker for the cgroup hierarchy mounted at `root`.
+    /// The version is detected at runtime: cgroup v2 if `root` is a unified
+    /// hierarchy, cgroup v1 if a cpuacct hierarchy is mounted below it.
+    /// Returns an error if neither is found.
+    pub fn new(root: &str) -> Result<CgroupTracker, String> {
+        let root = PathBuf::from(root);
+        if root.join("cgroup.controllers").exists() {
+            return Ok(CgroupTracker {
+                root,
+                version: CgroupVersion::V2,
+                cgroups: HashMap::new(),
+            });
+        }
+        match CPUACCT_HIERARCHIES
+            .iter()
+            .map(|hierarchy| root.join(hierarchy))
+            .find(|hierarchy| hierarchy.join("cpuacct.usage").exists())
+        {
+            Some(hierarchy) => Ok(CgroupTracker {
+                root: hierarchy,
+                version: CgroupVersion::V1,
+                cgroups: HashMap::new(),
+            }),
+            None => Err(format!(
+                "{} is neither a cgroup v2 hierarchy nor holds a cgroup v1 cpuacct hierarchy, cgroup CPU accounting is not available",
+                root.display()
+            )),
+        }
+    }
+
+    /// Returns the version of the cgroup hierarchy CPU usage is read from.
+    pub fn get_version(&self) -> CgroupVersion {
+        self.version
+    }
+
+    /// Reads the CPU usage of every cgroup of the hierarchy and stores it.
+    /// Cgroups that don't exist anymore are forgotten.
+    pub fn refresh(&mut self) {
+        let timestamp = current_system_time_since_epoch();
+        let mut usages = HashMap::new();
+        self.read_usages(&self.root, &mut usages);
+        self.cgroups.retain(|path, _| usages.contains_key(path));
+        for (path, usage_usec) in usages {
+            let records = self.cgroups.entry(path).or_default();
+            records.insert(
+                0,
+                CgroupRecord {
+                    timestamp,
+                    usage_usec,
+                },
+            );
+            records.truncate(MAX_RECORDS_PER_CGROUP);
+        }
+    }
+
+    /// Walks the hierarchy from `folder` and gets the CPU usage of each cgroup
+    /// below it, in microseconds.
+    fn read_usages(&self, folder: &Path, usages: &mut HashMap<String, u64>) {
+        if let Ok(entries) = fs::read_dir(folder) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if !path.is_dir() {
+                    continue;
+                }
+                let usage_usec = match self.version {
+                    CgroupVersion::V1 => read_cpuacct_usage_usec(&path),
+                    CgroupVersion::V2 => read_usage_usec(&path),
+                };
+                if let Some(usage_usec) = usage_usec {
+                    if let Ok(relative) = path.strip_prefix(&self.root) {
+                        usages.insert(format!("/{}", relative.display()), usage_usec);
+                    }
+                }
+                self.read_usages(&path, usages);
+            }
+        }
+    }
+
+    /// Returns the CPU time consumed by a cgroup between the last two records, in microseconds.
+    pub fn get_diff_usage_usec(&self, cgroup: &str) -> Option<u64> {
+        let records = self.cgroups.get(cgroup)?;
+        match (records.first(), records.get(1)) {
+            (Some(last), Some(previous)) => last.usage_usec.checked_sub(previous.usage_usec),
+            _ => None,
+        }
+    }
+
+    /// Returns the paths of the cgroups tracked.
+    pub fn get_cgroups(&self) -> Vec<String> {
+        self.cgroups.keys().cloned().collect()
+    }
+
+    /// Removes the record preceding the last one for each cgroup,
+    /// keeping at least two records per cgroup.
+    pub fn drop_previous_records(&mut self) {
+        for records in self.cgroups.values_mut() {
+            if records.len() > 2 {
+                records.remove(1);
+            }
+        }
+    }
+}
+
+/// Slice holding the cgroups of the systemd system services.
+const SYSTEM_SLICE: &str = "system.slice";
+
+/// Keeps track of the processes of each systemd service, from the cgroups of
+/// the system slice (like `system.slice/nginx.service`).
+#[derive(Debug, Clone)]
+pub struct ServiceTracker {
+    /// Folder of the system slice in the cgroup hierarchy.
+    system_slice: PathBuf,
+    /// Pids of the processes of each service, by unit name (like `nginx.service`).
+    pub services: HashMap<String, Vec<i32>>,
+}
+
+impl ServiceTracker {
+    /// Instantiates ServiceTracker for the cgroup hierarchy mounted at `root`.
+    /// Returns an error if it has no system slice, as on hosts without systemd.
+    pub fn new(root: &str) -> Result<ServiceTracker, String> {
+        let system_slice = PathBuf::from(root).join(SYSTEM_SLICE);
+        if !system_slice.is_dir() {
+            return Err(format!(
+                "No {} folder, power per systemd service is not available",
+                system_slice.display()
+            ));
+        }
+        Ok(ServiceTracker {
+            system_slice,
+            services: HashMap::new(),
+        })
+    }
+
+    /// Reads the processes of every service of the system slice, including the
+    /// ones in sub-cgroups of the service and in nested slices.
+    pub fn refresh(&mut self) {
+        let mut services = HashMap::new();
+        read_services(&self.system_slice, &mut services);
+        self.services = services;
+    }
+
+    /// Returns the unit names of the services tracked, sorted.
+    pub fn get_services(&self) -> Vec<String> {
+        let mut services: Vec<String> = self.services.keys().cloned().collect();
+        services.sort();
+        services
+    }
+}
+
+/// Walks a slice and gets the pids of each `.service` cgroup below it.
+fn read_services(slice: &Path, services: &mut HashMap<String, Vec<i32>>) {
+    if let Ok(entries) = fs::read_dir(slice) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".service") {
+                let mut pids = vec![];
+                read_procs(&path, &mut pids);
+                services.insert(name, pids);
+            } else if name.ends_with(".slice") {
+                read_services(&path, services);
+            }
+        }
+    }
+}
+
+/// Reads the pids in the cgroup.procs file of a cgroup and of its descendants.
+fn read_procs(cgroup: &Path, pids: &mut Vec<i32>) {
+    if let Ok(procs) = fs::read_to_string(cgroup.join("cgroup.procs")) {
+        pids.extend(
+            procs
+                .lines()
+                .filter_map(|pid| pid.trim().parse::<i32>().ok()),
+        );
+    }
+    if let Ok(entries) = fs::read_dir(cgroup) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            if entry.path().is_dir() {
+                read_procs(&entry.path(), pids);
+            }
+        }
+    }
+}
+
+/// Reads usage_usec in the cpu.stat file of a cgroup.
+fn read_usage_usec(cgroup: &Path) -> Option<u64> {
+    let stat = fs::read_to_string(cgroup.join("cpu.stat")).ok()?;
+    stat.lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Reads the cpuacct.usage file of a cgroup v1 cgroup, converted from
+/// nanoseconds to microseconds.
+fn read_cpuacct_usage_usec(cgroup: &Path) -> Option<u64> {
+    let usage_nsec: u64 = fs::read_to_string(cgroup.join("cpuacct.usage"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(usage_nsec / 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn cgroup_usages_tracked() {
+        let root = env::temp_dir().join(format!("scaph-cgroups-{}", std::process::id()));
+        let service = root.join("system.slice/nginx.service");
+        fs::create_dir_all(&service).unwrap();
+        fs::write(root.join("cgroup.controllers"), "cpu io memory\n").unwrap();
+        fs::write(
+            root.join("system.slice/cpu.stat"),
+            "usage_usec 1000\nuser_usec 600\n",
+        )
+        .unwrap();
+        fs::write(service.join("cpu.stat"), "usage_usec 500\nuser_usec 300\n").unwrap();
+
+        let mut tracker = CgroupTracker::new(root.to_str().unwrap()).unwrap();
+        tracker.refresh();
+        fs::write(service.join("cpu.stat"), "usage_usec 2500\nuser_usec 300\n").unwrap();
+        tracker.refresh();
+        assert_eq!(
+            tracker.get_diff_usage_usec("/system.slice/nginx.service"),
+            Some(2000)
+        );
+        assert_eq!(tracker.get_diff_usage_usec("/system.slice"), Some(0));
+
+        fs::remove_dir_all(&service).unwrap();
+        tracker.refresh();
+        assert_eq!(tracker.get_cgroups(), vec![String::from("/system.slice")]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cgroup_v1_usages_tracked() {
+        let root = env::temp_dir().join(format!("scaph-cgroups-v1-{}", std::process::id()));
+        let hierarchy = root.join("cpu,cpuacct");
+        let service = hierarchy.join("system.slice/nginx.service");
+        fs::create_dir_all(&service).unwrap();
+        assert!(CgroupTracker::new(root.to_str().unwrap()).is_err());
+        fs::write(hierarchy.join("cpuacct.usage"), "90000000\n").unwrap();
+        fs::write(service.join("cpuacct.usage"), "1000000\n").unwrap();
+
+        let mut tracker = CgroupTracker::new(root.to_str().unwrap()).unwrap();
+        assert_eq!(tracker.get_version(), CgroupVersion::V1);
+        tracker.refresh();
+        fs::write(service.join("cpuacct.usage"), "3500000\n").unwrap();
+        tracker.refresh();
+        assert_eq!(
+            tracker.get_diff_usage_usec("/system.slice/nginx.service"),
+            Some(2500)
+        );
+        assert_eq!(tracker.get_diff_usage_usec("/system.slice"), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn service_processes_read_from_system_slice() {
+        let root = env::temp_dir().join(format!("scaph-services-{}", std::process::id()));
+        let nginx = root.join("system.slice/nginx.service");
+        let getty = root.join("system.slice/system-getty.slice/getty@tty1.service");
+        fs::create_dir_all(nginx.join("worker")).unwrap();
+        fs::create_dir_all(&getty).unwrap();
+        fs::write(nginx.join("cgroup.procs"), "100\n").unwrap();
+        fs::write(nginx.join("worker/cgroup.procs"), "101\n102\n").unwrap();
+        fs::write(getty.join("cgroup.procs"), "200\n").unwrap();
+
+        let mut tracker = ServiceTracker::new(root.to_str().unwrap()).unwrap();
+        tracker.refresh();
+        assert_eq!(
+            tracker.get_services(),
+            vec![
+                String::from("getty@tty1.service"),
+                String::from("nginx.service")
+            ]
+        );
+        let mut pids = tracker.services.get("nginx.service").unwrap().clone();
+        pids.sort();
+        assert_eq!(pids, vec![100, 101, 102]);
+        assert!(ServiceTracker::new(nginx.to_str().unwrap()).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,151 @@
+//! # CompositeSensor
+//!
+//! Sensor merging the measurements of several sensors in a single topology: a
+//! primary sensor gives the sockets, domains and processes, while secondary
+//! sensors (a smart plug measuring the wall power, a BMC...) give host level
+//! power that is reported along, namespaced by the name of each sensor.
+use crate::sensors::{diagnostics::DiagnosticCheck, Sensor, Topology};
+use std::error::Error;
+
+/// A Sensor combining a primary sensor with secondary sources of host power.
+pub struct CompositeSensor {
+    /// Sensor giving the topology, used to attribute power to processes.
+    primary: Box<dyn Sensor>,
+    /// Sensors whose host level records are reported along, by source name.
+    sources: Vec<(String, Box<dyn Sensor>)>,
+}
+
+impl CompositeSensor {
+    /// Instantiates and returns a CompositeSensor, with `primary` giving the
+    /// topology and `sources` giving additional host level power, by name.
+    pub fn new(
+        primary: Box<dyn Sensor>,
+        sources: Vec<(String, Box<dyn Sensor>)>,
+    ) -> CompositeSensor {
+        CompositeSensor { primary, sources }
+    }
+}
+
+impl Sensor for CompositeSensor {
+    /// Returns the name of the primary sensor, followed by the names of the
+    /// secondary ones, like `powercap_rapl+redfish`.
+    fn get_name(&self) -> String {
+        std::iter::once(self.primary.get_name())
+            .chain(self.sources.iter().map(|(_, sensor)| sensor.get_name()))
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    /// Creates the topology of the primary sensor and attaches the topologies
+    /// of the secondary sensors to it, as sources.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let mut topology = self.primary.generate_topology()?;
+        for (name, sensor) in &self.sources {
+            topology.add_source(name, sensor.generate_topology()?);
+        }
+        Ok(topology)
+    }
+
+    /// Checks that the primary sensor and all the secondary ones can get measurements.
+    fn probe(&self) -> Result<(), String> {
+        self.primary.probe()?;
+        for (name, sensor) in &self.sources {
+            sensor.probe().map_err(|e| format!("{name}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Runs the self-test of the primary sensor and of the secondary ones,
+    /// whose checks are prefixed by the name of the sensor.
+    fn self_test(&self) -> Vec<DiagnosticCheck> {
+        let mut checks = self.primary.self_test();
+        for (name, sensor) in &self.sources {
+            checks.extend(sensor.self_test().into_iter().map(|mut check| {
+                check.name = format!("{name}: {}", check.name);
+                check
+            }));
+        }
+        checks
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::PowerMeter;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::{thread, time::Duration};
+
+    /// Power meter always measuring the same power.
+    #[derive(Debug)]
+    struct ConstantPowerMeter(f64);
+
+    impl PowerMeter for ConstantPowerMeter {
+        fn read_power_watts(&self) -> Result<f64, Box<dyn Error>> {
+            Ok(self.0)
+        }
+    }
+
+    /// Sensor giving a single socket measured by a ConstantPowerMeter.
+    struct ConstantSensor(f64);
+
+    impl Sensor for ConstantSensor {
+        fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+            let mut topology = Topology::default();
+            topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+            for socket in topology.get_sockets() {
+                socket.set_power_meter(Arc::new(ConstantPowerMeter(self.0)));
+            }
+            Ok(topology)
+        }
+
+        fn get_topology(&mut self) -> Box<Option<Topology>> {
+            Box::new(self.generate_topology().ok())
+        }
+    }
+
+    #[test]
+    fn sources_power_reported_by_name() {
+        let sensor = CompositeSensor::new(
+            Box::new(ConstantSensor(20.0)),
+            vec![(String::from("wall"), Box::new(ConstantSensor(50.0)))],
+        );
+        assert!(sensor.probe().is_ok());
+        let mut topology = sensor.generate_topology().unwrap();
+        for _ in 0..3 {
+            topology.refresh();
+            thread::sleep(Duration::from_millis(100));
+        }
+        let sources = topology.get_sources_power_microwatts();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].0, "wall");
+        let wall = sources[0].1.value.parse::<f64>().unwrap();
+        let host = topology.get_records_diff_power_microwatts().unwrap();
+        let host = host.value.parse::<f64>().unwrap();
+        assert!(wall > 2.0 * host);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
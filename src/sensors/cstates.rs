@@ -0,0 +1,173 @@
+//! # C-states
+//!
+//! The cstates module keeps track of the time spent by each CPU core in each
+//! idle state (C-state), as accounted by the cpuidle kernel subsystem in
+//! `/sys/devices/system/cpu/cpuN/cpuidle/stateM/time`. Residencies in deep
+//! C-states explain the power consumption of the package when it is idle.
+use crate::sensors::utils::current_system_time_since_epoch;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Default folder holding the cpuN folders in sysfs.
+pub const DEFAULT_CPU_SYSFS_ROOT: &str = "/sys/devices/system/cpu";
+
+/// Maximum number of records kept per core and state.
+const MAX_RECORDS_PER_STATE: usize = 3;
+
+/// Time spent by a core in a C-state, at a given time.
+#[derive(Debug, Clone)]
+pub struct CStateRecord {
+    pub timestamp: Duration,
+    /// Total time spent in the state since boot, in microseconds.
+    pub time_us: u64,
+}
+
+/// Keeps track of the time spent by each core in each C-state.
+#[derive(Debug, Clone)]
+pub struct CStateTracker {
+    /// Folder holding the cpuN folders.
+    root: PathBuf,
+    /// Records of each state of each core, newest first, by core id and state name (like `C6`).
+    pub states: HashMap<(u16, String), Vec<CStateRecord>>,
+}
+
+impl CStateTracker {
+    /// Instantiates CStateTracker for the cores described in `root`.
+    /// Returns an error if cpuidle is not available.
+    pub fn new(root: &str) -> Result<CStateTracker, String> {
+        let root = PathBuf::from(root);
+        if !root.join("cpu0/cpuidle").exists() {
+            return Err(format!(
+                "No cpuidle folder in {}, C-states residency is not available",
+                root.join("cpu0").display()
+            ));
+        }
+        Ok(CStateTracker {
+            root,
+            states: HashMap::new(),
+        })
+    }
+
+    /// Reads the time spent in each C-state by each core and stores it.
+    pub fn refresh(&mut self) {
+        let timestamp = current_system_time_since_epoch();
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Couldn't read {}: {}", self.root.display(), e);
+                return;
+            }
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+            let core = match name.to_str().and_then(|n| n.strip_prefix("cpu")) {
+                Some(id) => match id.parse::<u16>() {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            for (state, time_us) in read_states(&entry.path().join("cpuidle")) {
+                let records = self.states.entry((core, state)).or_default();
+                records.insert(0, CStateRecord { timestamp, time_us });
+                records.truncate(MAX_RECORDS_PER_STATE);
+            }
+        }
+    }
+
+    /// Returns the share of the time spent by a core in a C-state between the
+    /// last two records, in percent.
+    pub fn get_residency_percent(&self, core: u16, state: &str) -> Option<f64> {
+        let records = self.states.get(&(core, String::from(state)))?;
+        let (last, previous) = (records.first()?, records.get(1)?);
+        let elapsed_us = last.timestamp.checked_sub(previous.timestamp)?.as_micros();
+        if elapsed_us == 0 {
+            return None;
+        }
+        let time_us = last.time_us.checked_sub(previous.time_us)?;
+        Some((time_us as f64 * 100.0 / elapsed_us as f64).min(100.0))
+    }
+
+    /// Returns the core ids and C-states names tracked, sorted.
+    pub fn get_states(&self) -> Vec<(u16, String)> {
+        let mut states: Vec<(u16, String)> = self.states.keys().cloned().collect();
+        states.sort();
+        states
+    }
+
+    /// Removes the record preceding the last one for each state,
+    /// keeping at least two records per state.
+    pub fn drop_previous_records(&mut self) {
+        for records in self.states.values_mut() {
+            if records.len() > 2 {
+                records.remove(1);
+            }
+        }
+    }
+}
+
+/// Reads the name and time of each stateM folder in a cpuidle folder.
+fn read_states(cpuidle: &Path) -> Vec<(String, u64)> {
+    let mut states = vec![];
+    if let Ok(entries) = fs::read_dir(cpuidle) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !entry.file_name().to_string_lossy().starts_with("state") {
+                continue;
+            }
+            let name = fs::read_to_string(path.join("name"));
+            let time = fs::read_to_string(path.join("time"));
+            if let (Ok(name), Ok(time)) = (name, time) {
+                if let Ok(time_us) = time.trim().parse() {
+                    states.push((String::from(name.trim()), time_us));
+                }
+            }
+        }
+    }
+    states
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn cstate_residencies_computed() {
+        let root = env::temp_dir().join(format!("scaph-cstates-{}", std::process::id()));
+        let state = root.join("cpu0/cpuidle/state2");
+        fs::create_dir_all(&state).unwrap();
+        fs::create_dir_all(root.join("cpufreq")).unwrap();
+        fs::write(state.join("name"), "C6\n").unwrap();
+        fs::write(state.join("time"), "1000\n").unwrap();
+
+        let mut tracker = CStateTracker::new(root.to_str().unwrap()).unwrap();
+        tracker.refresh();
+        let records = tracker.states.get_mut(&(0, String::from("C6"))).unwrap();
+        // as if the previous record was taken one second earlier
+        records[0].timestamp -= Duration::from_secs(1);
+        fs::write(state.join("time"), "251000\n").unwrap();
+        tracker.refresh();
+        assert_eq!(tracker.get_states(), vec![(0, String::from("C6"))]);
+        let residency = tracker.get_residency_percent(0, "C6").unwrap();
+        assert!(residency > 24.0 && residency <= 25.0);
+        assert!(tracker.get_residency_percent(1, "C6").is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,82 @@
+//! # Diagnostics
+//!
+//! The diagnostics module holds the result of the self-test of a sensor: a list
+//! of checks (is the energy counter readable, does it increase...) that each say
+//! if they passed, what was found, and how to fix the problem if they failed.
+//! It is what `scaphandre diagnose` prints.
+use colored::*;
+
+/// Result of one check of a sensor self-test.
+#[derive(Debug, Clone)]
+pub struct DiagnosticCheck {
+    /// What is checked, like "energy counters readable".
+    pub name: String,
+    pub passed: bool,
+    /// What was found: the path read, the error met...
+    pub details: String,
+    /// How to fix the problem, if the check failed.
+    pub hint: Option<String>,
+}
+
+impl DiagnosticCheck {
+    /// Returns a check that passed.
+    pub fn pass(name: &str, details: &str) -> DiagnosticCheck {
+        DiagnosticCheck {
+            name: String::from(name),
+            passed: true,
+            details: String::from(details),
+            hint: None,
+        }
+    }
+
+    /// Returns a check that failed, with a remediation hint.
+    pub fn fail(name: &str, details: &str, hint: &str) -> DiagnosticCheck {
+        DiagnosticCheck {
+            name: String::from(name),
+            passed: false,
+            details: String::from(details),
+            hint: Some(String::from(hint)),
+        }
+    }
+}
+
+/// Returns the report of a self-test, one line per check followed by the hint
+/// of the checks that failed.
+pub fn format_report(checks: &[DiagnosticCheck]) -> String {
+    let mut report = String::new();
+    for check in checks {
+        let status = if check.passed {
+            "[OK]  ".green()
+        } else {
+            "[FAIL]".red()
+        };
+        report.push_str(&format!("{} {}: {}\n", status, check.name, check.details));
+        if let Some(hint) = &check.hint {
+            report.push_str(&format!("       hint: {hint}\n"));
+        }
+    }
+    let failures = checks.iter().filter(|c| !c.passed).count();
+    if failures == 0 {
+        report.push_str(&format!("{}\n", "All checks passed.".green()));
+    } else {
+        report.push_str(&format!(
+            "{}\n",
+            format!("{failures} check(s) failed, power will not be measured correctly.").red()
+        ));
+    }
+    report
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,114 @@
+//! # Hybrid
+//!
+//! The hybrid module tells apart the performance cores (P-cores) from the
+//! efficiency cores (E-cores) of hybrid CPUs, like Intel Alder Lake and later.
+//! The kernel exposes a PMU for each core type, listing its CPUs in
+//! `/sys/devices/cpu_core/cpus` and `/sys/devices/cpu_atom/cpus`. On other
+//! CPUs, only `/sys/devices/cpu` exists and no core type is known.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Default folder holding the PMU devices in sysfs.
+pub const DEFAULT_DEVICES_SYSFS_ROOT: &str = "/sys/devices";
+
+/// Type of a core of a hybrid CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CoreType {
+    /// Performance core (P-core), listed by the cpu_core PMU.
+    Performance,
+    /// Efficiency core (E-core), listed by the cpu_atom PMU.
+    Efficiency,
+}
+
+impl CoreType {
+    /// Returns the name of the core type, as used in labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CoreType::Performance => "performance",
+            CoreType::Efficiency => "efficiency",
+        }
+    }
+}
+
+impl fmt::Display for CoreType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Returns the type of each logical core, by core id, as listed by the PMU
+/// devices in `devices_root`. Returns an empty map if the CPU isn't hybrid.
+pub fn read_core_types(devices_root: &str) -> HashMap<u16, CoreType> {
+    let mut core_types = HashMap::new();
+    for (device, core_type) in [
+        ("cpu_core", CoreType::Performance),
+        ("cpu_atom", CoreType::Efficiency),
+    ] {
+        if let Ok(cpus) = fs::read_to_string(Path::new(devices_root).join(device).join("cpus")) {
+            for core in parse_cpu_list(&cpus) {
+                core_types.insert(core, core_type);
+            }
+        }
+    }
+    core_types
+}
+
+/// Parses a list of CPUs in the sysfs format, like `0-7,16`.
+fn parse_cpu_list(list: &str) -> Vec<u16> {
+    let mut cpus = vec![];
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let start = bounds.next().and_then(|b| b.parse::<u16>().ok());
+        let end = match bounds.next() {
+            Some(end) => end.parse::<u16>().ok(),
+            None => start,
+        };
+        if let (Some(start), Some(end)) = (start, end) {
+            cpus.extend(start..=end);
+        }
+    }
+    cpus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn core_types_from_pmu_devices() {
+        assert_eq!(parse_cpu_list("0-3,8\n"), vec![0, 1, 2, 3, 8]);
+        assert!(parse_cpu_list("").is_empty());
+
+        let root = env::temp_dir().join(format!("scaph-hybrid-{}", std::process::id()));
+        let root_str = root.to_str().unwrap();
+        fs::create_dir_all(root.join("cpu")).unwrap();
+        assert!(read_core_types(root_str).is_empty());
+        fs::create_dir_all(root.join("cpu_core")).unwrap();
+        fs::create_dir_all(root.join("cpu_atom")).unwrap();
+        fs::write(root.join("cpu_core/cpus"), "0-3\n").unwrap();
+        fs::write(root.join("cpu_atom/cpus"), "4-5\n").unwrap();
+        let core_types = read_core_types(root_str);
+        assert_eq!(core_types.len(), 6);
+        assert_eq!(core_types.get(&2), Some(&CoreType::Performance));
+        assert_eq!(core_types.get(&5), Some(&CoreType::Efficiency));
+        assert_eq!(CoreType::Efficiency.to_string(), "efficiency");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,150 @@
+//! # IO
+//!
+//! The io module keeps track of the block IO of processes, as accounted by the
+//! kernel in `/proc/PID/io`. It is used to attribute a part of the memory (DRAM
+//! domain) power to processes according to the bytes they read and wrote,
+//! rather than only according to their CPU time.
+use crate::sensors::utils::current_system_time_since_epoch;
+use procfs::process::Process;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+/// Maximum number of records kept per process.
+const MAX_RECORDS_PER_PROCESS: usize = 3;
+
+/// Block IO of a process at a given time.
+#[derive(Debug, Clone)]
+pub struct IoRecord {
+    pub timestamp: Duration,
+    /// Bytes read from and written to the storage layer by the process since it started.
+    pub bytes: u64,
+}
+
+/// Keeps track of the block IO of processes.
+#[derive(Debug, Clone)]
+pub struct IoTracker {
+    /// Fraction (between 0 and 1) of the DRAM domain power attributed according to block IO.
+    pub dram_power_fraction: f64,
+    /// Records of each process, newest first, by PID.
+    pub processes: HashMap<i32, Vec<IoRecord>>,
+}
+
+impl IoTracker {
+    /// Instantiates IoTracker, attributing `dram_power_fraction` of the DRAM power
+    /// according to block IO. Returns an error if the fraction is not between 0 and 1.
+    pub fn new(dram_power_fraction: f64) -> Result<IoTracker, String> {
+        if !(0.0..=1.0).contains(&dram_power_fraction) {
+            return Err(format!(
+                "IO power fraction should be between 0 and 1, got {dram_power_fraction}"
+            ));
+        }
+        Ok(IoTracker {
+            dram_power_fraction,
+            processes: HashMap::new(),
+        })
+    }
+
+    /// Reads the block IO of the processes `pids` in the procfs at
+    /// `procfs_root` and stores it. Processes not in `pids` are forgotten.
+    pub fn refresh(&mut self, procfs_root: &Path, pids: &[i32]) {
+        let alive: HashSet<&i32> = pids.iter().collect();
+        self.processes.retain(|pid, _| alive.contains(pid));
+        let timestamp = current_system_time_since_epoch();
+        for pid in pids {
+            if let Some(bytes) = read_io_bytes(procfs_root, *pid) {
+                self.add_record(*pid, IoRecord { timestamp, bytes });
+            }
+        }
+    }
+
+    /// Stores a record for a process.
+    fn add_record(&mut self, pid: i32, record: IoRecord) {
+        let records = self.processes.entry(pid).or_default();
+        records.insert(0, record);
+        records.truncate(MAX_RECORDS_PER_PROCESS);
+    }
+
+    /// Returns the bytes read and written by a process between the last two records.
+    pub fn get_diff_bytes(&self, pid: i32) -> Option<u64> {
+        let records = self.processes.get(&pid)?;
+        match (records.first(), records.get(1)) {
+            (Some(last), Some(previous)) => last.bytes.checked_sub(previous.bytes),
+            _ => None,
+        }
+    }
+
+    /// Returns the bytes read and written by all the processes tracked between
+    /// their last two records.
+    pub fn get_total_diff_bytes(&self) -> u64 {
+        self.processes
+            .keys()
+            .filter_map(|pid| self.get_diff_bytes(*pid))
+            .sum()
+    }
+
+    /// Returns the share (between 0 and 1) of the block IO done by a process
+    /// between the last two records. Returns None if no IO was done at all.
+    pub fn get_io_share(&self, pid: i32) -> Option<f64> {
+        let total = self.get_total_diff_bytes();
+        if total == 0 {
+            return None;
+        }
+        Some(self.get_diff_bytes(pid).unwrap_or(0) as f64 / total as f64)
+    }
+
+    /// Removes the record preceding the last one for each process,
+    /// keeping at least two records per process.
+    pub fn drop_previous_records(&mut self) {
+        for records in self.processes.values_mut() {
+            if records.len() > 2 {
+                records.remove(1);
+            }
+        }
+    }
+}
+
+/// Reads the bytes read and written by a process in /proc/PID/io.
+fn read_io_bytes(procfs_root: &Path, pid: i32) -> Option<u64> {
+    let io = Process::new_with_root(procfs_root.join(pid.to_string()))
+        .ok()?
+        .io()
+        .ok()?;
+    Some(io.read_bytes + io.write_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_shares_computed() {
+        assert!(IoTracker::new(1.5).is_err());
+        let mut tracker = IoTracker::new(0.5).unwrap();
+        let timestamp = current_system_time_since_epoch();
+        for (pid, bytes) in [(1, 1000), (2, 0), (1, 4000), (2, 1000)] {
+            tracker.add_record(pid, IoRecord { timestamp, bytes });
+        }
+        assert_eq!(tracker.get_diff_bytes(1), Some(3000));
+        assert_eq!(tracker.get_total_diff_bytes(), 4000);
+        assert_eq!(tracker.get_io_share(2), Some(0.25));
+        assert_eq!(tracker.get_io_share(3), Some(0.0));
+
+        tracker.refresh(Path::new("/proc"), &[]);
+        assert_eq!(tracker.get_io_share(1), None);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
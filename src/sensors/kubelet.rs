@@ -0,0 +1,258 @@
+//! # Kubelet
+//!
+//! Resolves the pod and container of processes running on a Kubernetes node,
+//! from the pod UID and container id found in their cgroup path, against the
+//! pods listed by the read-only API of the kubelet (`/pods`). Unlike the
+//! Kubernetes API, it needs no credentials, and the kubelet is only queried
+//! once a process is found in a pod, so nothing is sent on other hosts.
+use isahc::config::Configurable;
+use isahc::{HttpClient, ReadResponseExt};
+use serde_json::Value;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Default url of the read-only API of the kubelet.
+pub const DEFAULT_KUBELET_URL: &str = "http://127.0.0.1:10255";
+
+/// Timeout of the requests sent to the kubelet.
+const REQUEST_TIMEOUT_SECONDS: u64 = 2;
+
+/// Minimum time between two requests for the pods list.
+const PODS_REFRESH_SECONDS: u64 = 30;
+
+/// A pod running on the node, as listed by the kubelet.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct KubeletPod {
+    namespace: String,
+    name: String,
+    /// Name of each container of the pod, by container id (without the
+    /// `containerd://` like prefix).
+    containers: HashMap<String, String>,
+}
+
+/// Pods of the node, by UID, as listed by the kubelet.
+#[derive(Debug, Clone)]
+pub struct KubeletPods {
+    url: String,
+    pods: HashMap<String, KubeletPod>,
+    last_fetch: Option<Instant>,
+    /// True if a process was found in a pod missing from the list, so that the
+    /// list is fetched again.
+    missing_pod: Cell<bool>,
+}
+
+impl KubeletPods {
+    /// Instantiates KubeletPods, querying the kubelet at `url`, like
+    /// `http://127.0.0.1:10255`.
+    pub fn new(url: &str) -> KubeletPods {
+        KubeletPods {
+            url: String::from(url.trim_end_matches('/')),
+            pods: HashMap::new(),
+            last_fetch: None,
+            missing_pod: Cell::new(false),
+        }
+    }
+
+    /// Fetches the pods list again if a process was found in an unknown pod,
+    /// at most every PODS_REFRESH_SECONDS. A kubelet that can't be reached is
+    /// only logged at the debug level, the node may not run one.
+    pub fn refresh(&mut self) {
+        let fetched_recently = matches!(
+            self.last_fetch,
+            Some(t) if t.elapsed() < Duration::from_secs(PODS_REFRESH_SECONDS)
+        );
+        if !self.missing_pod.get() || fetched_recently {
+            return;
+        }
+        self.last_fetch = Some(Instant::now());
+        match self.fetch_pods() {
+            Ok(pods) => {
+                debug!("Found {} pods on the kubelet", pods.len());
+                self.pods = pods;
+                self.missing_pod.set(false);
+            }
+            Err(e) => debug!("Couldn't get the pods from the kubelet: {}", e),
+        }
+    }
+
+    /// Gets the pods list from the kubelet.
+    fn fetch_pods(&self) -> Result<HashMap<String, KubeletPod>, String> {
+        let client = HttpClient::builder()
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut response = client
+            .get(format!("{}/pods", self.url))
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("kubelet answered {}", response.status()));
+        }
+        parse_pods(&response.text().map_err(|e| e.to_string())?)
+    }
+
+    /// Returns the `namespace`, `pod` and `container` labels of process `pid`,
+    /// found in the procfs at `procfs_root`, or nothing if it doesn't run in a
+    /// known pod.
+    pub fn get_process_labels(&self, procfs_root: &Path, pid: i32) -> Vec<(String, String)> {
+        match fs::read_to_string(procfs_root.join(pid.to_string()).join("cgroup")) {
+            Ok(cgroups) => self.get_labels(&cgroups),
+            Err(_) => vec![],
+        }
+    }
+
+    /// Returns the labels of the pod and container of a process whose
+    /// `/proc/PID/cgroup` file reads `cgroups`.
+    fn get_labels(&self, cgroups: &str) -> Vec<(String, String)> {
+        let (uid, container_id) = match cgroups
+            .lines()
+            .filter_map(|line| line.splitn(3, ':').nth(2))
+            .find_map(parse_pod_cgroup)
+        {
+            Some(ids) => ids,
+            None => return vec![],
+        };
+        let pod = match self.pods.get(&uid) {
+            Some(pod) => pod,
+            None => {
+                self.missing_pod.set(true);
+                return vec![];
+            }
+        };
+        let mut labels = vec![
+            (String::from("namespace"), pod.namespace.clone()),
+            (String::from("pod"), pod.name.clone()),
+        ];
+        if let Some(name) = container_id.and_then(|id| pod.containers.get(&id)) {
+            labels.push((String::from("container"), name.clone()));
+        }
+        labels
+    }
+}
+
+/// Returns the UID of the pod and the id of the container a cgroup belongs to,
+/// from paths like `/kubepods/burstable/pod<uid>/<id>` (cgroupfs driver) or
+/// `/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod<uid>.slice/cri-containerd-<id>.scope`
+/// (systemd driver). Returns None for cgroups outside of pods.
+fn parse_pod_cgroup(path: &str) -> Option<(String, Option<String>)> {
+    if !path.contains("kubepods") {
+        return None;
+    }
+    let mut segments = path.split('/');
+    let uid = segments.find_map(|segment| {
+        let segment = segment.strip_suffix(".slice").unwrap_or(segment);
+        match segment.strip_prefix("pod") {
+            Some(uid) => Some(uid),
+            None => segment.rsplit_once("-pod").map(|(_, uid)| uid),
+        }
+    })?;
+    let container_id = segments
+        .next()
+        .map(|segment| segment.strip_suffix(".scope").unwrap_or(segment))
+        .map(|segment| segment.rsplit('-').next().unwrap_or(segment))
+        .filter(|id| !id.is_empty())
+        .map(String::from);
+    Some((uid.replace('_', "-"), container_id))
+}
+
+/// Parses the pods list answered by the `/pods` endpoint of the kubelet.
+fn parse_pods(body: &str) -> Result<HashMap<String, KubeletPod>, String> {
+    let list: Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    let items = list["items"]
+        .as_array()
+        .ok_or_else(|| String::from("no items in the pods list"))?;
+    let mut pods = HashMap::new();
+    for item in items {
+        let metadata = &item["metadata"];
+        let uid = match metadata["uid"].as_str() {
+            Some(uid) => uid,
+            None => continue,
+        };
+        let mut containers = HashMap::new();
+        for status in item["status"]["containerStatuses"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            if let (Some(name), Some(id)) =
+                (status["name"].as_str(), status["containerID"].as_str())
+            {
+                let id = id.split_once("://").map_or(id, |(_, id)| id);
+                containers.insert(String::from(id), String::from(name));
+            }
+        }
+        pods.insert(
+            String::from(uid),
+            KubeletPod {
+                namespace: String::from(metadata["namespace"].as_str().unwrap_or_default()),
+                name: String::from(metadata["name"].as_str().unwrap_or_default()),
+                containers,
+            },
+        );
+    }
+    Ok(pods)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pod_cgroups_parsed() {
+        assert_eq!(
+            parse_pod_cgroup("/kubepods/burstable/pod6b1c-22/0123abcd"),
+            Some((String::from("6b1c-22"), Some(String::from("0123abcd"))))
+        );
+        assert_eq!(
+            parse_pod_cgroup("/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod6b1c_22.slice/cri-containerd-0123abcd.scope"),
+            Some((String::from("6b1c-22"), Some(String::from("0123abcd"))))
+        );
+        assert_eq!(
+            parse_pod_cgroup("/kubepods/pod6b1c-22"),
+            Some((String::from("6b1c-22"), None))
+        );
+        assert_eq!(parse_pod_cgroup("/system.slice/sshd.service"), None);
+    }
+
+    #[test]
+    fn processes_resolved_to_pods() {
+        let body = r#"{"kind": "PodList", "items": [{
+            "metadata": {"name": "web-0", "namespace": "shop", "uid": "6b1c-22"},
+            "status": {"containerStatuses": [{"name": "nginx", "containerID": "containerd://0123abcd"}]}
+        }]}"#;
+        let mut kubelet = KubeletPods::new(DEFAULT_KUBELET_URL);
+        kubelet.pods = parse_pods(body).unwrap();
+        let labels = kubelet.get_labels("0::/kubepods/burstable/pod6b1c-22/0123abcd\n");
+        assert_eq!(
+            labels,
+            vec![
+                (String::from("namespace"), String::from("shop")),
+                (String::from("pod"), String::from("web-0")),
+                (String::from("container"), String::from("nginx")),
+            ]
+        );
+        assert!(!kubelet.missing_pod.get());
+        assert!(kubelet
+            .get_labels("0::/kubepods/burstable/pod99/4567\n")
+            .is_empty());
+        assert!(kubelet.missing_pod.get());
+        assert!(kubelet.get_labels("0::/user.slice\n").is_empty());
+        assert!(parse_pods("{}").is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
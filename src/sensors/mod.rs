@@ -3,28 +3,111 @@
 //! `Sensor` is the root for all sensors. It defines the [Sensor] trait
 //! needed to implement a sensor.
 
+pub mod anomalies;
+pub mod attribution;
+pub mod baseline;
+#[cfg(target_os = "linux")]
+pub mod cgroups;
+pub mod composite;
+#[cfg(target_os = "linux")]
+pub mod cstates;
+pub mod diagnostics;
+pub mod hybrid;
+#[cfg(target_os = "linux")]
+pub mod io;
+#[cfg(all(target_os = "linux", feature = "kubelet"))]
+pub mod kubelet;
 #[cfg(not(target_os = "linux"))]
 pub mod msr_rapl;
+#[cfg(target_os = "windows")]
+pub mod pdh;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+pub mod perf;
 #[cfg(target_os = "linux")]
 pub mod powercap_rapl;
+#[cfg(feature = "redfish")]
+pub mod redfish;
+#[cfg(feature = "replay")]
+pub mod replay;
+#[cfg(feature = "smartplug")]
+pub mod smartplug;
+#[cfg(target_os = "linux")]
+pub mod smt;
+pub mod snapshot;
 pub mod units;
 pub mod utils;
+use anomalies::{
+    AnomalyDetector, NEGATIVE_ENERGY_DIFF, NEGATIVE_STATS_DIFF, PROCESSES_EXCEED_HOST,
+    ZERO_HOST_POWER,
+};
+use attribution::{AttributionInput, AttributionStrategy, CpuTimeShare};
+use baseline::{BaselineMethod, PowerBaseline};
+#[cfg(target_os = "linux")]
+use cgroups::{CgroupTracker, ServiceTracker};
+#[cfg(target_os = "linux")]
+use cstates::CStateTracker;
+use diagnostics::DiagnosticCheck;
+use hybrid::CoreType;
+#[cfg(target_os = "linux")]
+use io::IoTracker;
+#[cfg(all(target_os = "linux", feature = "perf"))]
+use perf::{picojoules_per_instruction, InstructionsCounter};
 #[cfg(target_os = "linux")]
-use procfs::{process, CpuInfo, CpuTime, KernelStats};
-use std::collections::HashMap;
+use powercap_rapl::PowercapRAPLSensor;
+#[cfg(target_os = "linux")]
+use procfs::{CpuInfo, CpuTime};
+#[cfg(target_os = "linux")]
+use smt::SmtTracker;
+use snapshot::{
+    DomainSnapshot, HostSnapshot, ProcessSnapshot, RefreshObserver, RefreshObservers,
+    SocketSnapshot, TopologySnapshot,
+};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
-use std::mem::size_of_val;
+use std::fs;
+use std::mem::{size_of, size_of_val};
+#[cfg(target_os = "linux")]
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 #[cfg(not(target_os = "linux"))]
 use sysinfo::{ProcessorExt, System, SystemExt};
-use utils::{current_system_time_since_epoch, IProcess, ProcessTracker};
+use utils::{
+    current_system_time_since_epoch, AttributionBasis, Ewma, IProcess, PowerExtremes,
+    ProcessRecord, ProcessTracker,
+};
 
 // !!!!!!!!!!!!!!!!! Sensor !!!!!!!!!!!!!!!!!!!!!!!
 /// Sensor trait, the Sensor API.
 pub trait Sensor {
     fn get_topology(&mut self) -> Box<Option<Topology>>;
     fn generate_topology(&self) -> Result<Topology, Box<dyn Error>>;
+    /// Returns the name of the sensor, as given to `--sensor`.
+    fn get_name(&self) -> String {
+        String::from("unknown")
+    }
+    /// Checks that the sensor can get measurements on this host, and returns
+    /// the reason why if it can't. Used to select a sensor automatically.
+    fn probe(&self) -> Result<(), String> {
+        self.generate_topology()
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+    /// Runs the checks needed for the sensor to measure power on this host and
+    /// returns their results, with remediation hints for those that failed.
+    /// Sensors should override it to tell precisely what is wrong.
+    fn self_test(&self) -> Vec<DiagnosticCheck> {
+        vec![match self.probe() {
+            Ok(()) => DiagnosticCheck::pass("sensor available", "measurements can be read"),
+            Err(e) => DiagnosticCheck::fail(
+                "sensor available",
+                &e,
+                "check the documentation of the sensor for its requirements",
+            ),
+        }]
+    }
 }
 
 /// Defines methods for Record instances creation
@@ -39,6 +122,87 @@ pub trait RecordReader {
     fn read_record(&self) -> Result<Record, Box<dyn Error>>;
 }
 
+/// Folder where hwmon (hardware monitoring) sensors are exposed, in the sysfs
+/// of this host.
+const HWMON_PATH: &str = "/sys/class/hwmon";
+
+/// Names of the hwmon drivers giving CPU temperatures.
+const CPU_HWMON_NAMES: [&str; 3] = ["coretemp", "k10temp", "zenpower"];
+
+/// Ratio of the power limit from which a socket is considered clamped by RAPL.
+const POWER_LIMIT_CLAMPING_RATIO: f64 = 0.98;
+
+/// Name of the RAPL domain of the uncore part of Intel client CPUs,
+/// mostly consumed by their integrated GPU (GT).
+const IGPU_DOMAIN_NAME: &str = "uncore";
+
+/// Name of the RAPL domain of the memory.
+const DRAM_DOMAIN_NAME: &str = "dram";
+
+/// Name given to the whole socket (RAPL package domain) in power breakdowns.
+pub const PACKAGE_DOMAIN_NAME: &str = "package";
+
+/// Key of the sensor_data of sockets and domains giving the resolution of their
+/// energy counter: the energy of one increment, in microjoules.
+pub const ENERGY_RESOLUTION_KEY: &str = "energy_resolution_uj";
+
+/// Number of entries the global buffers size cap keeps at least in each buffer,
+/// as power and usage are computed from the last two.
+const MIN_ENTRIES_PER_BUFFER: usize = 2;
+
+/// A PowerMeter is a device or service measuring the instant power of the whole
+/// host, outside of the CPU (a BMC, a smart plug...). Sensors relying on such a
+/// meter attach it to a CPUSocket, whose energy records are then computed from
+/// the power readings.
+pub trait PowerMeter: fmt::Debug + Send + Sync {
+    /// Returns the instant power measured, in watts.
+    fn read_power_watts(&self) -> Result<f64, Box<dyn Error>>;
+}
+
+/// Reads the power measured by power_meter and returns a Record of the energy
+/// consumed since the first reading, in microjoules, `previous` being the last
+/// record computed. The power is considered constant since the previous record.
+fn power_meter_record(
+    power_meter: &dyn PowerMeter,
+    previous: Option<&Record>,
+) -> Result<Record, Box<dyn Error>> {
+    let watts = power_meter.read_power_watts()?;
+    let timestamp = current_system_time_since_epoch();
+    let microjoules = match previous {
+        Some(previous) => {
+            let elapsed = timestamp.saturating_sub(previous.timestamp).as_secs_f64();
+            previous.value.trim().parse::<u64>()? + (watts * elapsed * 1_000_000.0) as u64
+        }
+        None => 0,
+    };
+    Ok(Record::new(
+        timestamp,
+        microjoules.to_string(),
+        units::Unit::MicroJoule,
+    ))
+}
+
+/// Returns the energy counter resolution given in sensor_data, in microjoules.
+fn get_energy_resolution(sensor_data: &HashMap<String, String>) -> Option<f64> {
+    sensor_data
+        .get(ENERGY_RESOLUTION_KEY)
+        .and_then(|resolution| resolution.parse::<f64>().ok())
+        .filter(|resolution| *resolution > 0.0)
+}
+
+/// Static information about the host, to group measurements by hardware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostInfo {
+    /// Model of the CPUs, from /proc/cpuinfo.
+    pub cpu_model: String,
+    /// Version of the kernel.
+    pub kernel: String,
+    /// Hostname of the system, not overridden by --hostname.
+    pub hostname: String,
+    /// Number of CPU sockets.
+    pub sockets: usize,
+}
+
 // !!!!!!!!!!!!!!!!! Topology !!!!!!!!!!!!!!!!!!!!!!!
 /// Topology struct represents the whole CPUSocket architecture,
 /// from the electricity consumption point of view,
@@ -58,6 +222,69 @@ pub struct Topology {
     pub buffer_max_kbytes: u16,
     /// Sorted list of all domains names
     pub domains_names: Option<Vec<String>>,
+    /// True if the last records come from refresh_intermediate() and should be
+    /// replaced by the next ones.
+    intermediate_records: bool,
+    /// Estimates the idle power of the host, if enabled
+    pub power_baseline: Option<PowerBaseline>,
+    /// Topologies of secondary sensors giving host level power, by source name
+    pub sources: Vec<(String, Topology)>,
+    /// Energy counter of the whole platform (the RAPL psys domain), in
+    /// microjoules, giving the host energy instead of the sum of the sockets, if set
+    pub platform_counter_uj_path: Option<String>,
+    /// Meter of the power of the whole host, giving the host energy instead of
+    /// the sum of the sockets, if set
+    host_power_meter: Option<Arc<dyn PowerMeter>>,
+    /// True to subtract the power attributed to scaphandre itself from the host power
+    pub exclude_self: bool,
+    /// Maximum size in memory of all the buffers of the topology, its sockets,
+    /// domains and processes together, if set
+    pub buffers_max_kbytes: Option<u32>,
+    /// Number of buffer entries evicted to enforce buffers_max_kbytes
+    buffers_evictions: u64,
+    /// CPU time of processes their share of the power is computed from
+    pub attribution_basis: AttributionBasis,
+    /// Divides the power of the host between processes, from their CPU time
+    pub attribution_strategy: Arc<dyn AttributionStrategy>,
+    /// Power under which processes are aggregated in a single "other" bucket
+    /// by the exporters, if set
+    pub min_process_power_microwatts: Option<u64>,
+    /// Counts and logs implausible measurements
+    pub anomaly_detector: AnomalyDetector,
+    /// Moving average of the host power, if smoothing is enabled
+    host_power_smoothing: Option<Ewma>,
+    /// Lowest and highest host power measured since the start or the last reset
+    host_power_extremes: PowerExtremes,
+    /// Power of the processes computed since the last refresh, by pid
+    process_power_cache: RefCell<HashMap<i32, Option<Record>>>,
+    /// True to accumulate the energy attributed to each process
+    process_energy: bool,
+    /// Static information about the host, gathered on first use
+    host_info: Option<HostInfo>,
+    /// Called with a snapshot after each refresh
+    refresh_observers: RefreshObservers,
+    /// Sensor that generated the topology, if powercap_rapl, to follow sockets
+    /// and domains appearing or disappearing at runtime
+    #[cfg(target_os = "linux")]
+    pub powercap_sensor: Option<PowercapRAPLSensor>,
+    /// Keeps track of the CPU usage of cgroups, if cgroup accounting is enabled
+    #[cfg(target_os = "linux")]
+    pub cgroup_tracker: Option<CgroupTracker>,
+    /// Keeps track of the processes of systemd services, if service accounting is enabled
+    #[cfg(target_os = "linux")]
+    pub service_tracker: Option<ServiceTracker>,
+    /// Keeps track of the block IO of processes, if IO attribution is enabled
+    #[cfg(target_os = "linux")]
+    pub io_tracker: Option<IoTracker>,
+    /// Keeps track of the C-states residency of cores, if enabled
+    #[cfg(target_os = "linux")]
+    pub cstate_tracker: Option<CStateTracker>,
+    /// Keeps track of the busy time of SMT siblings, if SMT aware attribution is enabled
+    #[cfg(target_os = "linux")]
+    pub smt_tracker: Option<SmtTracker>,
+    /// Counts the instructions retired by the CPUs, if enabled
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub instructions_counter: Option<InstructionsCounter>,
     ///
     #[cfg(target_os = "windows")]
     #[allow(dead_code)]
@@ -69,6 +296,34 @@ impl RecordGenerator for Topology {
     /// and returns a clone of this record.
     ///
     fn refresh_record(&mut self) {
+        if self.is_energy_unavailable() {
+            return;
+        }
+        if let Some(path) = &self.platform_counter_uj_path {
+            match fs::read_to_string(path).map(|energy| energy.trim().parse::<u64>()) {
+                Ok(Ok(microjoules)) => {
+                    self.record_buffer.push(Record::new(
+                        current_system_time_since_epoch(),
+                        microjoules.to_string(),
+                        units::Unit::MicroJoule,
+                    ));
+                    self.clean_old_records();
+                }
+                Ok(Err(e)) => warn!("Couldn't parse the energy counter {}: {}", path, e),
+                Err(e) => warn!("Couldn't read the energy counter {}: {}", path, e),
+            }
+            return;
+        }
+        if let Some(power_meter) = &self.host_power_meter {
+            match power_meter_record(power_meter.as_ref(), self.record_buffer.last()) {
+                Ok(record) => {
+                    self.record_buffer.push(record);
+                    self.clean_old_records();
+                }
+                Err(e) => warn!("Couldn't read the host power meter: {}", e),
+            }
+            return;
+        }
         let mut value: u64 = 0;
         let mut last_timestamp = current_system_time_since_epoch();
         for s in self.get_sockets() {
@@ -162,6 +417,24 @@ impl Topology {
             record_buffer: vec![],
             buffer_max_kbytes: 1,
             domains_names: None,
+            intermediate_records: false,
+            power_baseline: None,
+            sources: vec![],
+            platform_counter_uj_path: None,
+            host_power_meter: None,
+            exclude_self: false,
+            buffers_max_kbytes: None,
+            buffers_evictions: 0,
+            attribution_basis: AttributionBasis::default(),
+            attribution_strategy: Arc::new(CpuTimeShare),
+            min_process_power_microwatts: None,
+            anomaly_detector: AnomalyDetector::default(),
+            host_power_smoothing: None,
+            host_power_extremes: PowerExtremes::default(),
+            process_power_cache: RefCell::new(HashMap::new()),
+            process_energy: false,
+            host_info: None,
+            refresh_observers: RefreshObservers::default(),
             sensor_data,
         }
     }
@@ -175,6 +448,32 @@ impl Topology {
             record_buffer: vec![],
             buffer_max_kbytes: 1,
             domains_names: None,
+            intermediate_records: false,
+            power_baseline: None,
+            sources: vec![],
+            platform_counter_uj_path: None,
+            host_power_meter: None,
+            exclude_self: false,
+            buffers_max_kbytes: None,
+            buffers_evictions: 0,
+            attribution_basis: AttributionBasis::default(),
+            attribution_strategy: Arc::new(CpuTimeShare),
+            min_process_power_microwatts: None,
+            anomaly_detector: AnomalyDetector::default(),
+            host_power_smoothing: None,
+            host_power_extremes: PowerExtremes::default(),
+            process_power_cache: RefCell::new(HashMap::new()),
+            process_energy: false,
+            host_info: None,
+            refresh_observers: RefreshObservers::default(),
+            powercap_sensor: None,
+            cgroup_tracker: None,
+            service_tracker: None,
+            io_tracker: None,
+            cstate_tracker: None,
+            smt_tracker: None,
+            #[cfg(feature = "perf")]
+            instructions_counter: None,
         }
     }
 
@@ -193,32 +492,42 @@ impl Topology {
     /// }
     /// ```
     pub fn generate_cpu_cores() -> Option<Vec<CPUCore>> {
-        let mut cores = vec![];
-
         #[cfg(target_os = "linux")]
+        return Topology::generate_cpu_cores_in(Path::new("/proc"));
+        #[cfg(not(target_os = "linux"))]
         {
-            let cpuinfo = CpuInfo::new().unwrap();
-            for id in 0..(cpuinfo.num_cores() - 1) {
-                let mut info = HashMap::new();
-                for (k, v) in cpuinfo.get_info(id).unwrap().iter() {
-                    info.insert(String::from(*k), String::from(*v));
+            let mut cores = vec![];
+            #[cfg(target_os = "windows")]
+            {
+                warn!("generate_cpu_info is not implemented yet on this OS.");
+                let sysinfo_system = System::new_all();
+                let sysinfo_cores = sysinfo_system.processors();
+                for (id, c) in (0_u16..).zip(sysinfo_cores.iter()) {
+                    let mut info = HashMap::new();
+                    info.insert(String::from("frequency"), c.frequency().to_string());
+                    info.insert(String::from("name"), c.name().to_string());
+                    info.insert(String::from("vendor_id"), c.vendor_id().to_string());
+                    info.insert(String::from("brand"), c.brand().to_string());
+                    cores.push(CPUCore::new(id, info));
                 }
-                cores.push(CPUCore::new(id as u16, info));
             }
+            Some(cores)
         }
-        #[cfg(target_os = "windows")]
-        {
-            warn!("generate_cpu_info is not implemented yet on this OS.");
-            let sysinfo_system = System::new_all();
-            let sysinfo_cores = sysinfo_system.processors();
-            for (id, c) in (0_u16..).zip(sysinfo_cores.iter()) {
-                let mut info = HashMap::new();
-                info.insert(String::from("frequency"), c.frequency().to_string());
-                info.insert(String::from("name"), c.name().to_string());
-                info.insert(String::from("vendor_id"), c.vendor_id().to_string());
-                info.insert(String::from("brand"), c.brand().to_string());
-                cores.push(CPUCore::new(id, info));
+    }
+
+    /// Parses the cpuinfo file of the procfs at `procfs_root`, like a snapshot
+    /// of `/proc` captured on another host, and creates instances of CPUCore.
+    #[cfg(target_os = "linux")]
+    pub fn generate_cpu_cores_in(procfs_root: &Path) -> Option<Vec<CPUCore>> {
+        let cpuinfo =
+            CpuInfo::from_reader(fs::File::open(procfs_root.join("cpuinfo")).ok()?).ok()?;
+        let mut cores = vec![];
+        for id in 0..cpuinfo.num_cores() {
+            let mut info = HashMap::new();
+            for (k, v) in cpuinfo.get_info(id).unwrap().iter() {
+                info.insert(String::from(*k), String::from(*v));
             }
+            cores.push(CPUCore::new(id as u16, info));
         }
         Some(cores)
     }
@@ -262,6 +571,112 @@ impl Topology {
         &self.sockets
     }
 
+    /// Returns what the host power is measured from: "psys" for the platform
+    /// domain of RAPL, covering the whole SoC, "meter" for a power meter of the
+    /// whole host, or "packages" for the sum of the sockets.
+    pub fn get_host_power_source(&self) -> &'static str {
+        if self.platform_counter_uj_path.is_some() {
+            "psys"
+        } else if self.host_power_meter.is_some() {
+            "meter"
+        } else {
+            "packages"
+        }
+    }
+
+    /// Attaches a meter of the power of the whole host. Host records are then
+    /// computed from the power it measures, instead of the sum of the sockets.
+    pub fn set_host_power_meter(&mut self, power_meter: Arc<dyn PowerMeter>) {
+        self.host_power_meter = Some(power_meter);
+    }
+
+    /// Returns the static information about the host: CPU model, kernel
+    /// version, hostname and number of sockets. It is gathered on the first
+    /// call only.
+    pub fn get_host_info(&mut self) -> HostInfo {
+        if self.host_info.is_none() {
+            self.host_info = Some(self.gather_host_info());
+        }
+        self.host_info.clone().unwrap()
+    }
+
+    /// Reads the static information about the host.
+    fn gather_host_info(&self) -> HostInfo {
+        let cpu_model = self
+            .sockets
+            .iter()
+            .flat_map(|socket| socket.cpu_cores.iter())
+            .find_map(|core| {
+                // "model name" in /proc/cpuinfo, "brand" from sysinfo on Windows
+                core.attributes
+                    .get("model name")
+                    .or_else(|| core.attributes.get("brand"))
+            })
+            .map(|model| model.trim().to_string())
+            .unwrap_or_default();
+        #[cfg(target_os = "linux")]
+        let kernel_file = |name: &str| {
+            let path = self
+                .proc_tracker
+                .get_procfs_root()
+                .join("sys/kernel")
+                .join(name);
+            fs::read_to_string(path).map(|content| content.trim().to_string())
+        };
+        #[cfg(target_os = "linux")]
+        let kernel = kernel_file("osrelease").unwrap_or_default();
+        #[cfg(not(target_os = "linux"))]
+        let kernel = System::new().kernel_version().unwrap_or_default();
+        // the hostname of the host the procfs was captured on, if not this one
+        #[cfg(target_os = "linux")]
+        let hostname = kernel_file("hostname").ok();
+        #[cfg(not(target_os = "linux"))]
+        let hostname = None;
+        HostInfo {
+            cpu_model,
+            kernel,
+            hostname: hostname.unwrap_or_else(|| {
+                hostname::get()
+                    .map(|h| h.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            }),
+            sockets: self.sockets.len(),
+        }
+    }
+
+    /// Returns the number of logical CPU cores (hardware threads) of the host.
+    pub fn logical_core_count(&self) -> usize {
+        self.sockets.iter().map(|s| s.cpu_cores.len()).sum()
+    }
+
+    /// Returns the number of physical CPU cores of the host, counting as one
+    /// the logical cores sharing a `core id` on the same socket (SMT siblings).
+    /// Cores without a `core id` attribute are counted as physical cores.
+    pub fn physical_core_count(&self) -> usize {
+        let mut physical_cores = HashSet::new();
+        for socket in &self.sockets {
+            for core in &socket.cpu_cores {
+                let physical_id = match core.attributes.get("core id") {
+                    Some(core_id) => core_id.clone(),
+                    None => format!("logical {}", core.id),
+                };
+                physical_cores.insert((socket.id, physical_id));
+            }
+        }
+        physical_cores.len()
+    }
+
+    /// Returns the id of the socket of each logical CPU core, by core id.
+    pub fn get_core_socket_map(&self) -> HashMap<u16, u16> {
+        let mut cores = HashMap::new();
+        for socket in &self.sockets {
+            for core in &socket.cpu_cores {
+                cores.insert(core.id, socket.id);
+            }
+        }
+        cores
+    }
+
     // Build a sorted list of all domains names from all sockets.
     fn build_domains_names(&mut self) {
         let mut names: HashMap<String, ()> = HashMap::new();
@@ -302,25 +717,36 @@ impl Topology {
     }
 
     /// Generates CPUCore instances for the host and adds them
-    /// to appropriate CPUSocket instance from self.sockets
-    pub fn add_cpu_cores(&mut self) {
-        if let Some(mut cores) = Topology::generate_cpu_cores() {
+    /// to appropriate CPUSocket instance from self.sockets, their dies and
+    /// types being read from the sysfs mounted at `sysfs_root`.
+    pub fn add_cpu_cores(&mut self, sysfs_root: &str) {
+        #[cfg(target_os = "linux")]
+        let cores = Topology::generate_cpu_cores_in(&self.proc_tracker.get_procfs_root());
+        #[cfg(not(target_os = "linux"))]
+        let cores = Topology::generate_cpu_cores();
+        if let Some(mut cores) = cores {
+            let sysfs_root = sysfs_root.trim_end_matches('/');
+            let core_types = hybrid::read_core_types(&format!("{sysfs_root}/devices"));
             while !cores.is_empty() {
-                let c = cores.pop().unwrap();
+                let mut c = cores.pop().unwrap();
+                c.core_type = core_types.get(&c.id).copied();
                 let socket_id = &c
                     .attributes
                     .get("physical id")
                     .unwrap()
                     .parse::<u16>()
                     .unwrap();
+                // on a package made of several dies, the core belongs to the socket of its die
+                let die_id = read_core_die_id(sysfs_root, c.id).unwrap_or(0);
                 let socket = self
                     .sockets
                     .iter_mut()
-                    .find(|x| &x.id == socket_id)
+                    .find(|x| {
+                        &x.get_package_id() == socket_id
+                            && (x.die_id.is_none() || x.die_id == Some(die_id))
+                    })
                     .expect("Trick: if you are running on a vm, do not forget to use --vm parameter invoking scaphandre at the command line");
-                if socket_id == &socket.id {
-                    socket.add_cpu_core(c);
-                }
+                socket.add_cpu_core(c);
             }
         } else {
             warn!("Couldn't retrieve any CPU Core from the topology. (generate_cpu_cores)");
@@ -331,11 +757,28 @@ impl Topology {
     /// and power consumption, CPU stats and cores power comsumption,
     /// CPU sockets stats and power consumption.
     pub fn refresh(&mut self) {
+        self.invalidate_process_power_cache();
+        #[cfg(target_os = "linux")]
+        if let Some(mut powercap_sensor) = self.powercap_sensor.take() {
+            powercap_sensor.update_topology(self);
+            self.powercap_sensor = Some(powercap_sensor);
+        }
+        if self.is_energy_unavailable() {
+            // zeros would be reported from the records of unreadable counters
+            self.clear_energy_records();
+        }
+        #[cfg(target_os = "linux")]
+        let cpu_sysfs_root = self.get_sysfs_path(cstates::DEFAULT_CPU_SYSFS_ROOT);
+        #[cfg(not(target_os = "linux"))]
+        let cpu_sysfs_root = String::from("/sys/devices/system/cpu");
         let sockets = &mut self.sockets;
         for s in sockets {
             // refresh each socket with new record
             s.refresh_record();
-            s.refresh_stats();
+            s.refresh_power_smoothing();
+            s.refresh_power_extremes();
+            s.refresh_stats(&self.proc_tracker);
+            s.refresh_throttling(&cpu_sysfs_root);
             let domains = s.get_domains();
             for d in domains {
                 d.refresh_record();
@@ -346,17 +789,786 @@ impl Topology {
             //}
         }
         self.refresh_procs();
+        for (_, source) in &mut self.sources {
+            source.refresh_energy();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cgroup_tracker) = &mut self.cgroup_tracker {
+            cgroup_tracker.refresh();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(service_tracker) = &mut self.service_tracker {
+            service_tracker.refresh();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(io_tracker) = &mut self.io_tracker {
+            io_tracker.refresh(
+                &self.proc_tracker.get_procfs_root(),
+                &self.proc_tracker.get_alive_pids(),
+            );
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cstate_tracker) = &mut self.cstate_tracker {
+            cstate_tracker.refresh();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(smt_tracker) = &mut self.smt_tracker {
+            smt_tracker.refresh(&self.proc_tracker);
+        }
+        #[cfg(all(target_os = "linux", feature = "perf"))]
+        if let Some(instructions_counter) = &mut self.instructions_counter {
+            instructions_counter.refresh();
+        }
         self.refresh_record();
         self.refresh_stats();
+        if self.power_baseline.is_some() {
+            if let Some(power) = self.get_records_diff_power_microwatts() {
+                if let (Some(power_baseline), Ok(microwatts)) =
+                    (&mut self.power_baseline, power.value.parse::<f64>())
+                {
+                    power_baseline.add_sample(power.timestamp, microwatts);
+                }
+            }
+        }
+        if let Some(power) = self.get_host_power_microwatts() {
+            if let (Some(smoothing), Ok(microwatts)) =
+                (&mut self.host_power_smoothing, power.value.parse::<f64>())
+            {
+                smoothing.update(microwatts);
+            }
+        }
+        if let Some(microwatts) = self.get_host_power_microwatts_value() {
+            self.host_power_extremes.update(microwatts);
+        }
+        if self.intermediate_records {
+            self.drop_previous_records();
+            self.intermediate_records = false;
+        }
+        if self.process_energy {
+            self.accumulate_processes_energy();
+        }
+        self.check_anomalies();
+        self.enforce_buffers_max_size();
+        if !self.refresh_observers.is_empty() {
+            self.refresh_observers.notify(&self.snapshot());
+        }
+    }
+
+    /// Registers an observer, called with a snapshot of the topology after each
+    /// refresh, after the observers already registered.
+    pub fn add_refresh_observer(&mut self, observer: Arc<dyn RefreshObserver>) {
+        self.refresh_observers.add(observer);
+    }
+
+    /// Adds the energy consumed by each process during the last measurement to
+    /// the energy it consumed since it was first seen.
+    fn accumulate_processes_energy(&mut self) {
+        let powers: Vec<(i32, Record)> = self
+            .proc_tracker
+            .get_alive_pids()
+            .into_iter()
+            .filter_map(|pid| Some((pid, self.get_process_power_consumption_microwatts(pid)?)))
+            .collect();
+        for (pid, power) in powers {
+            self.proc_tracker.add_process_energy(pid, &power);
+        }
+        self.proc_tracker
+            .finalize_exited_processes_energy(current_system_time_since_epoch());
+    }
+
+    /// Looks for implausible measurements in the last records and stats, and
+    /// reports them to the anomaly detector, which logs them rate limited.
+    fn check_anomalies(&mut self) {
+        let timestamp = current_system_time_since_epoch();
+        let mut anomalies = vec![];
+        for socket in &self.sockets {
+            // with a known range, a decrease is a wrap of the counter
+            let len = socket.record_buffer.len();
+            if len < 2 || socket.sensor_data.contains_key("max_energy_range_uj") {
+                continue;
+            }
+            if let (Some(last), Some(previous)) = (
+                socket.record_buffer[len - 1].value_u64(),
+                socket.record_buffer[len - 2].value_u64(),
+            ) {
+                if last < previous {
+                    anomalies.push((
+                        NEGATIVE_ENERGY_DIFF,
+                        format!(
+                            "energy counter of socket {} went from {} to {} microjoules",
+                            socket.id, previous, last
+                        ),
+                    ));
+                }
+            }
+        }
+        if let (Some(last), Some(previous)) = (self.stat_buffer.first(), self.stat_buffer.get(1)) {
+            let (last, previous) = (last.total_time_jiffies(), previous.total_time_jiffies());
+            if last < previous {
+                anomalies.push((
+                    NEGATIVE_STATS_DIFF,
+                    format!("CPU time of the host went from {previous} to {last} jiffies"),
+                ));
+            }
+        }
+        if let Some(host_power) = self.get_records_diff_power_microwatts_value() {
+            if host_power == 0 {
+                let busy = self
+                    .get_stats_diff()
+                    .map(|stats| stats.total_time_jiffies() > 0)
+                    .unwrap_or(false);
+                if busy {
+                    anomalies.push((
+                        ZERO_HOST_POWER,
+                        String::from("host power is 0 while its CPUs were busy"),
+                    ));
+                }
+            } else {
+                let processes_power: u64 = self
+                    .proc_tracker
+                    .get_alive_pids()
+                    .into_iter()
+                    .filter_map(|pid| self.get_process_power_consumption_microwatts_value(pid))
+                    .sum();
+                let max_power =
+                    host_power as f64 * (1.0 + self.anomaly_detector.processes_power_threshold);
+                if processes_power as f64 > max_power {
+                    anomalies.push((
+                        PROCESSES_EXCEED_HOST,
+                        format!(
+                            "processes are attributed {processes_power} microwatts, more than the {host_power} microwatts of the host"
+                        ),
+                    ));
+                }
+            }
+        }
+        for (kind, message) in anomalies {
+            self.anomaly_detector.report(kind, timestamp, &message);
+        }
+    }
+
+    /// Refreshes the topology like refresh(), but the new records only replace
+    /// the ones of the previous intermediate refresh, if any. Power and usage
+    /// computed after the next refresh() are then averaged since the refresh()
+    /// preceding the intermediate ones. This allows measuring more often than
+    /// exporting, without losing the energy consumed between two exports.
+    pub fn refresh_intermediate(&mut self) {
+        // refresh() already replaces the records of a previous intermediate refresh
+        self.refresh();
+        self.intermediate_records = true;
+    }
+
+    /// Removes the records and stats preceding the last ones, in the topology,
+    /// its sockets and domains, and for each process.
+    fn drop_previous_records(&mut self) {
+        self.invalidate_process_power_cache();
+        if self.record_buffer.len() > 1 {
+            self.record_buffer.remove(self.record_buffer.len() - 2);
+        }
+        if self.stat_buffer.len() > 1 {
+            self.stat_buffer.remove(1);
+        }
+        for s in &mut self.sockets {
+            s.drop_previous_records();
+        }
+        self.proc_tracker.drop_previous_records();
+        #[cfg(target_os = "linux")]
+        if let Some(cgroup_tracker) = &mut self.cgroup_tracker {
+            cgroup_tracker.drop_previous_records();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(io_tracker) = &mut self.io_tracker {
+            io_tracker.drop_previous_records();
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cstate_tracker) = &mut self.cstate_tracker {
+            cstate_tracker.drop_previous_records();
+        }
+    }
+
+    /// Attaches the topology of a secondary sensor, whose host level power is
+    /// then refreshed along and can be read with get_sources_power_microwatts().
+    pub fn add_source(&mut self, name: &str, source: Topology) {
+        self.sources.push((String::from(name), source));
+    }
+
+    /// Refreshes the energy records of the sockets, domains and of the topology
+    /// itself, but not the processes. Used for the topologies of secondary sources.
+    fn refresh_energy(&mut self) {
+        for s in &mut self.sockets {
+            s.refresh_record();
+            for d in s.get_domains() {
+                d.refresh_record();
+            }
+        }
+        self.refresh_record();
+    }
+
+    /// Returns the power measured by each secondary source, between last and
+    /// previous measurement, in microwatts, by source name.
+    pub fn get_sources_power_microwatts(&self) -> Vec<(String, Record)> {
+        self.sources
+            .iter()
+            .filter_map(|(name, source)| {
+                source
+                    .get_records_diff_power_microwatts()
+                    .map(|power| (name.clone(), power))
+            })
+            .collect()
+    }
+
+    /// Enables the estimation of the idle power of the host, computed with
+    /// `method` from the power measured over the last `window`, so that
+    /// get_idle_power_microwatts() and get_active_power_microwatts() can split
+    /// the host power. Returns an error if the window or method is invalid.
+    pub fn enable_power_baseline(
+        &mut self,
+        window: Duration,
+        method: BaselineMethod,
+    ) -> Result<(), String> {
+        self.power_baseline = Some(PowerBaseline::new(window, method)?);
+        Ok(())
+    }
+
+    /// Returns the power and energy of the host, its sockets, their domains
+    /// and the processes, as measured by the last refresh.
+    pub fn snapshot(&self) -> TopologySnapshot {
+        let sockets = self
+            .sockets
+            .iter()
+            .map(|socket| SocketSnapshot {
+                id: socket.id,
+                power_microwatts: socket.get_records_diff_power_microwatts_value(),
+                energy_microjoules: socket.record_buffer.last().and_then(Record::value_u64),
+                energy_since_start_microjoules: socket
+                    .get_total_energy_microjoules_since_start()
+                    .and_then(|r| r.value_u64()),
+                domains: socket
+                    .domains
+                    .iter()
+                    .map(|domain| DomainSnapshot {
+                        id: domain.id,
+                        name: domain.name.clone(),
+                        power_microwatts: domain.get_records_diff_power_microwatts_value(),
+                        energy_microjoules: domain.record_buffer.last().and_then(Record::value_u64),
+                    })
+                    .collect(),
+            })
+            .collect();
+        let processes = self
+            .proc_tracker
+            .get_alive_pids()
+            .into_iter()
+            .map(|pid| ProcessSnapshot {
+                pid,
+                exe: self.proc_tracker.get_process_name(pid),
+                cmdline: self.proc_tracker.get_process_cmdline(pid),
+                power_microwatts: self.get_process_power_consumption_microwatts_value(pid),
+            })
+            .collect();
+        TopologySnapshot {
+            timestamp: self
+                .record_buffer
+                .last()
+                .map(|r| r.timestamp.as_secs_f64())
+                .unwrap_or_default(),
+            host: HostSnapshot {
+                power_microwatts: self.get_host_power_microwatts_value(),
+                energy_microjoules: self.record_buffer.last().and_then(Record::value_u64),
+                energy_since_start_microjoules: self
+                    .get_total_energy_microjoules_since_start()
+                    .and_then(|r| r.value_u64()),
+            },
+            sockets,
+            processes,
+        }
+    }
+
+    /// Makes get_host_power_microwatts subtract the power attributed to
+    /// scaphandre itself, so that the agent overhead doesn't pollute measurements.
+    pub fn set_exclude_self(&mut self, exclude_self: bool) {
+        self.exclude_self = exclude_self;
+    }
+
+    /// Accumulates the energy attributed to each process at each refresh,
+    /// returned by get_process_energy_microjoules.
+    pub fn enable_process_energy(&mut self) {
+        self.process_energy = true;
+    }
+
+    /// Returns the energy attributed to a process since it was first seen, in
+    /// microjoules, if enable_process_energy was called. It is still returned
+    /// for a while once the process exited, and restarts from zero if the pid
+    /// is reused.
+    pub fn get_process_energy_microjoules(&self, pid: i32) -> Option<Record> {
+        let microjoules = self.proc_tracker.get_process_energy_microjoules(pid)?;
+        Some(Record::new(
+            current_system_time_since_epoch(),
+            microjoules.to_string(),
+            units::Unit::MicroJoule,
+        ))
+    }
+
+    /// Returns true if the energy counters can't be read because of their
+    /// permissions, in which case no power is reported.
+    pub fn is_energy_unavailable(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            matches!(&self.powercap_sensor, Some(sensor) if sensor.energy_unavailable)
+        }
+        #[cfg(target_os = "windows")]
+        false
+    }
+
+    /// Removes the energy records of the topology, its sockets and domains.
+    fn clear_energy_records(&mut self) {
+        self.record_buffer.clear();
+        for socket in &mut self.sockets {
+            socket.record_buffer.clear();
+            for domain in &mut socket.domains {
+                domain.record_buffer.clear();
+            }
+        }
+    }
+
+    /// Sets the CPU time of processes their share of the power is computed from.
+    pub fn set_attribution_basis(&mut self, basis: AttributionBasis) {
+        self.attribution_basis = basis;
+        self.invalidate_process_power_cache();
+    }
+
+    /// Sets the strategy dividing the power of the host between processes.
+    pub fn set_attribution_strategy(&mut self, strategy: Arc<dyn AttributionStrategy>) {
+        self.attribution_strategy = strategy;
+        self.invalidate_process_power_cache();
+    }
+
+    /// Sets the power under which processes are aggregated in a single "other"
+    /// bucket instead of being reported one by one.
+    pub fn set_min_process_power_microwatts(&mut self, min_microwatts: u64) {
+        self.min_process_power_microwatts = Some(min_microwatts);
+    }
+
+    /// Returns true if `power`, the power of a process, is under
+    /// min_process_power_microwatts and should be aggregated with the others.
+    pub fn is_under_min_process_power(&self, power: &Record) -> bool {
+        match (self.min_process_power_microwatts, power.value_f64()) {
+            (Some(min_microwatts), Some(microwatts)) => microwatts < min_microwatts as f64,
+            _ => false,
+        }
+    }
+
+    /// Returns the power of the process `pid`, in microwatts, unless it is
+    /// under min_process_power_microwatts: it is then added to `other`, so that
+    /// the total power of processes is still accounted for, and None is returned.
+    pub fn get_process_power_over_min(
+        &self,
+        pid: i32,
+        other: &mut OtherProcessesPower,
+    ) -> Option<Record> {
+        let power = self.get_process_power_consumption_microwatts(pid)?;
+        if self.is_under_min_process_power(&power) {
+            other.add(&power);
+            return None;
+        }
+        Some(power)
+    }
+
+    /// Smooths the host and sockets power with an exponentially weighted moving
+    /// average, `alpha` being the weight of the last measurement. The raw power
+    /// is still returned by get_host_power_microwatts.
+    pub fn enable_power_smoothing(&mut self, alpha: f64) -> Result<(), String> {
+        let smoothing = Ewma::new(alpha)?;
+        for socket in &mut self.sockets {
+            socket.power_smoothing = Some(smoothing.clone());
+        }
+        self.host_power_smoothing = Some(smoothing);
+        Ok(())
+    }
+
+    /// Returns the moving average of the host power, in microwatts, if
+    /// smoothing is enabled.
+    pub fn get_smoothed_host_power_microwatts(&self) -> Option<Record> {
+        let power = self.get_host_power_microwatts()?;
+        let smoothed = self.host_power_smoothing.as_ref()?.get()?;
+        Some(Record::new(
+            power.timestamp,
+            format!("{}", smoothed.round() as u64),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns the lowest and highest host power measured since the start, or
+    /// since the last call to reset_power_extremes.
+    pub fn get_host_power_extremes(&self) -> &PowerExtremes {
+        &self.host_power_extremes
+    }
+
+    /// Forgets the lowest and highest power measured so far for the host and
+    /// its sockets, to start a new reporting window.
+    pub fn reset_power_extremes(&mut self) {
+        self.host_power_extremes.reset();
+        for socket in &mut self.sockets {
+            socket.power_extremes.reset();
+        }
+    }
+
+    /// Caps the size in memory of all the buffers of the topology, its sockets,
+    /// domains and processes together. The oldest entries are evicted at each
+    /// refresh once the cap is exceeded.
+    pub fn set_buffers_max_kbytes(&mut self, max_kbytes: u32) {
+        self.buffers_max_kbytes = Some(max_kbytes);
+    }
+
+    /// Returns the size in memory of the records and stats stored by the
+    /// topology, its sockets, domains and processes, in bytes.
+    pub fn get_buffers_size_bytes(&self) -> usize {
+        let mut records = self.record_buffer.len();
+        let mut stats = self.stat_buffer.len();
+        for socket in &self.sockets {
+            records += socket.record_buffer.len();
+            stats += socket.stat_buffer.len();
+            for domain in &socket.domains {
+                records += domain.record_buffer.len();
+            }
+        }
+        let process_records: usize = self.proc_tracker.procs.iter().map(|p| p.len()).sum();
+        records * size_of::<Record>()
+            + stats * size_of::<CPUStat>()
+            + process_records * size_of::<ProcessRecord>()
+    }
+
+    /// Returns the number of buffer entries evicted since the start to keep the
+    /// buffers under buffers_max_kbytes.
+    pub fn get_buffers_evictions(&self) -> u64 {
+        self.buffers_evictions
+    }
+
+    /// Evicts the oldest entry of each buffer, round after round, until the
+    /// buffers fit in buffers_max_kbytes. The last entries of each buffer are
+    /// always kept. Returns the number of entries evicted.
+    fn enforce_buffers_max_size(&mut self) -> usize {
+        let max_bytes = match self.buffers_max_kbytes {
+            Some(max_kbytes) => max_kbytes as usize * 1000,
+            None => return 0,
+        };
+        let (record_size, stat_size, process_record_size) = (
+            size_of::<Record>(),
+            size_of::<CPUStat>(),
+            size_of::<ProcessRecord>(),
+        );
+        let mut size = self.get_buffers_size_bytes();
+        let mut evicted = 0;
+        while size > max_bytes {
+            let evicted_before = evicted;
+            // records are stored oldest first, stats and process records newest first
+            if self.record_buffer.len() > MIN_ENTRIES_PER_BUFFER {
+                self.record_buffer.remove(0);
+                size -= record_size;
+                evicted += 1;
+            }
+            if self.stat_buffer.len() > MIN_ENTRIES_PER_BUFFER {
+                self.stat_buffer.pop();
+                size -= stat_size;
+                evicted += 1;
+            }
+            for socket in &mut self.sockets {
+                if socket.record_buffer.len() > MIN_ENTRIES_PER_BUFFER {
+                    socket.record_buffer.remove(0);
+                    size -= record_size;
+                    evicted += 1;
+                }
+                if socket.stat_buffer.len() > MIN_ENTRIES_PER_BUFFER {
+                    socket.stat_buffer.pop();
+                    size -= stat_size;
+                    evicted += 1;
+                }
+                for domain in &mut socket.domains {
+                    if domain.record_buffer.len() > MIN_ENTRIES_PER_BUFFER {
+                        domain.record_buffer.remove(0);
+                        size -= record_size;
+                        evicted += 1;
+                    }
+                }
+            }
+            for records in &mut self.proc_tracker.procs {
+                if records.len() > MIN_ENTRIES_PER_BUFFER {
+                    records.pop();
+                    size -= process_record_size;
+                    evicted += 1;
+                }
+            }
+            if evicted == evicted_before {
+                warn!(
+                    "Buffers take {} bytes, more than the {} kB allowed, but only their last entries are left.",
+                    size,
+                    max_bytes / 1000
+                );
+                break;
+            }
+        }
+        if evicted > 0 {
+            debug!(
+                "Evicted {} buffer entries to fit in {} bytes.",
+                evicted, max_bytes
+            );
+        }
+        self.buffers_evictions += evicted as u64;
+        evicted
+    }
+
+    /// Returns the power attributed to the scaphandre process itself between
+    /// last and previous measurement, in microwatts.
+    pub fn get_self_power_microwatts(&self) -> Option<Record> {
+        self.get_process_power_consumption_microwatts(std::process::id() as i32)
+    }
+
+    /// Returns get_host_power_microwatts as an integer, in microwatts.
+    pub fn get_host_power_microwatts_value(&self) -> Option<u64> {
+        self.get_host_power_microwatts()?.value_u64()
+    }
+
+    /// Returns the power of the host between last and previous measurement, in
+    /// microwatts, without the power attributed to scaphandre itself if
+    /// exclude_self is set.
+    pub fn get_host_power_microwatts(&self) -> Option<Record> {
+        let power = self.get_records_diff_power_microwatts()?;
+        if !self.exclude_self {
+            return Some(power);
+        }
+        let self_power = self
+            .get_self_power_microwatts()
+            .and_then(|p| p.value.parse::<u64>().ok())
+            .unwrap_or(0);
+        let total = power.value.parse::<u64>().ok()?;
+        Some(Record::new(
+            power.timestamp,
+            total.saturating_sub(self_power).to_string(),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns the type of each logical core (P-core or E-core), by core id.
+    /// Returns an empty map if the CPU isn't hybrid.
+    pub fn get_core_types(&self) -> HashMap<u16, CoreType> {
+        let mut core_types = HashMap::new();
+        for socket in &self.sockets {
+            for core in &socket.cpu_cores {
+                if let Some(core_type) = core.core_type {
+                    core_types.insert(core.id, core_type);
+                }
+            }
+        }
+        core_types
+    }
+
+    /// Returns the power of the host between last and previous measurement,
+    /// divided by its number of logical cores, in microwatts.
+    pub fn get_power_per_core_microwatts(&self) -> Option<Record> {
+        let cores = self.logical_core_count();
+        if cores == 0 {
+            return None;
+        }
+        let power = self.get_records_diff_power_microwatts()?;
+        let total = power.value.parse::<u64>().ok()?;
+        Some(Record::new(
+            power.timestamp,
+            (total / cores as u64).to_string(),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns the estimated idle (baseline) power of the host, in microwatts.
+    /// Returns None if the power baseline is not enabled.
+    pub fn get_idle_power_microwatts(&self) -> Option<Record> {
+        let baseline = self.power_baseline.as_ref()?.get_baseline_microwatts()?;
+        Some(Record::new(
+            self.record_buffer.last()?.timestamp,
+            (baseline as u64).to_string(),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns the power of the host above its estimated idle power, between
+    /// last and previous measurement, in microwatts. Returns None if the power
+    /// baseline is not enabled.
+    pub fn get_active_power_microwatts(&self) -> Option<Record> {
+        let idle = self
+            .get_idle_power_microwatts()?
+            .value
+            .parse::<u64>()
+            .ok()?;
+        let power = self.get_records_diff_power_microwatts()?;
+        let total = power.value.parse::<u64>().ok()?;
+        Some(Record::new(
+            power.timestamp,
+            total.saturating_sub(idle).to_string(),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns `path`, a path under /sys like DEFAULT_CGROUP_ROOT, in the sysfs
+    /// the sensor reads, which is not the one of this host with --snapshot-dir.
+    #[cfg(target_os = "linux")]
+    pub fn get_sysfs_path(&self, path: &str) -> String {
+        let sysfs_root = match &self.powercap_sensor {
+            Some(sensor) => sensor.get_sysfs_root(),
+            None => powercap_rapl::DEFAULT_SYSFS_ROOT,
+        };
+        match path.strip_prefix(powercap_rapl::DEFAULT_SYSFS_ROOT) {
+            Some(relative) => format!("{}{}", sysfs_root.trim_end_matches('/'), relative),
+            None => String::from(path),
+        }
+    }
+
+    /// Enables the tracking of cgroups CPU usage, from the cgroup v2 hierarchy
+    /// mounted at `cgroup_root` or from the cgroup v1 cpuacct hierarchy below it,
+    /// so that power can be attributed to cgroups with
+    /// get_cgroup_power_consumption_microwatts(). Returns an error if the host
+    /// uses neither.
+    #[cfg(target_os = "linux")]
+    pub fn enable_cgroup_accounting(&mut self, cgroup_root: &str) -> Result<(), String> {
+        self.cgroup_tracker = Some(CgroupTracker::new(cgroup_root)?);
+        Ok(())
+    }
+
+    /// Enables the tracking of the processes of systemd services, from the system
+    /// slice of the cgroup hierarchy mounted at `cgroup_root`, so that power can
+    /// be summed per service with get_service_power_consumption_microwatts().
+    /// Returns an error if the host has no system slice.
+    #[cfg(target_os = "linux")]
+    pub fn enable_service_accounting(&mut self, cgroup_root: &str) -> Result<(), String> {
+        let mut service_tracker = ServiceTracker::new(cgroup_root)?;
+        service_tracker.refresh();
+        self.service_tracker = Some(service_tracker);
+        Ok(())
+    }
+
+    /// Enables the attribution of `dram_power_fraction` (between 0 and 1) of the
+    /// DRAM domain power to processes according to their block IO, the rest of the
+    /// host power being still attributed according to CPU time. Returns an error
+    /// if the fraction is not between 0 and 1.
+    #[cfg(target_os = "linux")]
+    pub fn enable_io_attribution(&mut self, dram_power_fraction: f64) -> Result<(), String> {
+        self.io_tracker = Some(IoTracker::new(dram_power_fraction)?);
+        self.invalidate_process_power_cache();
+        Ok(())
+    }
+
+    /// Enables the tracking of the time spent by each core in each C-state, read
+    /// in the cpuidle folders of the cores described in `cpu_sysfs_root`, so that
+    /// residencies can be read with get_cstate_residency_percent(). Returns an
+    /// error if cpuidle is not available.
+    #[cfg(target_os = "linux")]
+    pub fn enable_cstate_residency(&mut self, cpu_sysfs_root: &str) -> Result<(), String> {
+        self.cstate_tracker = Some(CStateTracker::new(cpu_sysfs_root)?);
+        Ok(())
+    }
+
+    /// Enables the weighting of the CPU time of processes by the sharing of their
+    /// physical core with its other SMT siblings, as described in `cpu_sysfs_root`,
+    /// so that a process running alone on a core is attributed more than one
+    /// running while its sibling is busy. Returns an error if SMT is not enabled.
+    #[cfg(target_os = "linux")]
+    pub fn enable_smt_attribution(&mut self, cpu_sysfs_root: &str) -> Result<(), String> {
+        self.smt_tracker = Some(SmtTracker::new(cpu_sysfs_root)?);
+        self.invalidate_process_power_cache();
+        Ok(())
+    }
+
+    /// Enables the counting of the instructions retired by the CPU cores of the
+    /// topology with perf_event, so that the energy spent per instruction can be
+    /// read with get_picojoules_per_instruction(). Returns an error if the
+    /// counters can't be opened.
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub fn enable_instructions_counter(&mut self) -> Result<(), String> {
+        let cpus: Vec<u16> = self
+            .sockets
+            .iter()
+            .flat_map(|socket| socket.cpu_cores.iter().map(|core| core.id))
+            .collect();
+        self.instructions_counter = Some(InstructionsCounter::new(&cpus)?);
+        Ok(())
+    }
+
+    /// Returns the energy spent by the host per instruction retired between the
+    /// last two refreshes, in picojoules. Returns None if instructions are not
+    /// counted.
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub fn get_picojoules_per_instruction(&self) -> Option<f64> {
+        let instructions = self
+            .instructions_counter
+            .as_ref()?
+            .get_total_instructions_per_second()?;
+        let power = self.get_records_diff_power_microwatts()?.value_f64()?;
+        picojoules_per_instruction(power, instructions)
+    }
+
+    /// Returns the energy spent by a socket per instruction retired by its
+    /// cores between the last two refreshes, in picojoules.
+    #[cfg(all(target_os = "linux", feature = "perf"))]
+    pub fn get_socket_picojoules_per_instruction(&self, socket: &CPUSocket) -> Option<f64> {
+        let cpus: Vec<u16> = socket.cpu_cores.iter().map(|core| core.id).collect();
+        let instructions = self
+            .instructions_counter
+            .as_ref()?
+            .get_instructions_per_second(&cpus)?;
+        let power = socket.get_records_diff_power_microwatts()?.value_f64()?;
+        picojoules_per_instruction(power, instructions)
+    }
+
+    /// Returns the share of the time spent by a core in a C-state (like `C6`)
+    /// between last and previous measurement, in percent. Returns None if
+    /// C-states residency tracking is not enabled.
+    #[cfg(target_os = "linux")]
+    pub fn get_cstate_residency_percent(&self, core: u16, state: &str) -> Option<Record> {
+        let cstate_tracker = self.cstate_tracker.as_ref()?;
+        let residency = cstate_tracker.get_residency_percent(core, state)?;
+        Some(Record::new(
+            cstate_tracker
+                .states
+                .get(&(core, String::from(state)))?
+                .first()?
+                .timestamp,
+            residency.to_string(),
+            units::Unit::Percentage,
+        ))
+    }
+
+    /// Returns the power of the DRAM domains of all sockets, between last and
+    /// previous measurement, in microwatts. Returns None if no socket has a DRAM domain.
+    pub fn get_dram_power_microwatts(&self) -> Option<f64> {
+        self.sockets
+            .iter()
+            .flat_map(|s| s.get_domains_passive())
+            .filter(|d| d.name == DRAM_DOMAIN_NAME)
+            .filter_map(|d| d.get_records_diff_power_microwatts())
+            .filter_map(|r| r.value.parse::<f64>().ok())
+            .fold(None, |total, power| Some(total.unwrap_or(0.0) + power))
+    }
+
+    /// Returns the part of the host power attributed according to block IO
+    /// rather than CPU time, in microwatts. Returns None if IO attribution is
+    /// disabled or no process did any IO since previous measurement.
+    #[cfg(target_os = "linux")]
+    fn get_io_attributed_power_microwatts(&self) -> Option<f64> {
+        let io_tracker = self.io_tracker.as_ref()?;
+        if io_tracker.get_total_diff_bytes() == 0 {
+            return None;
+        }
+        Some(self.get_dram_power_microwatts()? * io_tracker.dram_power_fraction)
     }
 
     /// Gets currently running processes (as procfs::Process instances) and stores
     /// them in self.proc_tracker
     fn refresh_procs(&mut self) {
+        self.proc_tracker.start_refresh();
         #[cfg(target_os = "linux")]
         {
             //current_procs is the up to date list of processus running on the host
-            if let Ok(procs) = process::all_processes() {
+            // (or of the allowed ones, to avoid reading all the others)
+            let current_procs = self.proc_tracker.read_processes();
+            if let Ok(procs) = current_procs {
                 info!("Before refresh procs init.");
                 procs
                     .iter()
@@ -378,11 +1590,13 @@ impl Topology {
             let pt = &mut self.proc_tracker;
             pt.sysinfo.refresh_processes();
             pt.sysinfo.refresh_cpu();
+            pt.refresh_cpu_usage();
             let current_procs = pt
                 .sysinfo
                 .processes()
                 .values()
                 .map(IProcess::from_windows_process)
+                .filter(|p| pt.is_pid_allowed(p.pid))
                 .collect::<Vec<_>>();
             for p in current_procs {
                 match pt.add_process_record(p) {
@@ -441,8 +1655,8 @@ impl Topology {
         if len > 2 {
             let last = self.record_buffer.last().unwrap();
             let previous = self.record_buffer.get(len - 2).unwrap();
-            let last_value = last.value.parse::<u64>().unwrap();
-            let previous_value = previous.value.parse::<u64>().unwrap();
+            let last_value = last.value_u64()?;
+            let previous_value = previous.value_u64()?;
             if previous_value <= last_value {
                 let diff = last_value - previous_value;
                 return Some(Record::new(last.timestamp, diff.to_string(), last.unit));
@@ -451,6 +1665,17 @@ impl Topology {
         None
     }
 
+    /// Returns the time actually elapsed between the last two records, which
+    /// get_records_diff_power_microwatts divides the energy consumed by.
+    pub fn get_records_diff_interval(&self) -> Option<Duration> {
+        records_diff_interval(&self.record_buffer)
+    }
+
+    /// Returns get_records_diff_power_microwatts as an integer, in microwatts.
+    pub fn get_records_diff_power_microwatts_value(&self) -> Option<u64> {
+        self.get_records_diff_power_microwatts()?.value_u64()
+    }
+
     /// Returns a Record instance containing the power consumed between
     /// last and previous measurement, in microwatts.
     pub fn get_records_diff_power_microwatts(&self) -> Option<Record> {
@@ -460,14 +1685,13 @@ impl Topology {
                 .record_buffer
                 .get(self.record_buffer.len() - 2)
                 .unwrap();
-            let last_microjoules = last_record.value.parse::<u64>().unwrap();
-            let previous_microjoules = previous_record.value.parse::<u64>().unwrap();
+            let last_microjoules = last_record.value_u64()?;
+            let previous_microjoules = previous_record.value_u64()?;
             if previous_microjoules > last_microjoules {
                 return None;
             }
             let microjoules = last_microjoules - previous_microjoules;
-            let time_diff =
-                last_record.timestamp.as_secs_f64() - previous_record.timestamp.as_secs_f64();
+            let time_diff = records_diff_interval(&self.record_buffer)?.as_secs_f64();
             let microwatts = microjoules as f64 / time_diff;
             return Some(Record::new(
                 last_record.timestamp,
@@ -478,6 +1702,39 @@ impl Topology {
         None
     }
 
+    /// Reads the CPU temperatures given by hwmon sensors (coretemp, k10temp...).
+    /// Returns None if there is no such sensor.
+    pub fn read_cpu_temperatures(&self) -> Option<Vec<CPUTemperature>> {
+        #[cfg(target_os = "linux")]
+        return read_cpu_temperatures_from(&self.get_sysfs_path(HWMON_PATH));
+        #[cfg(not(target_os = "linux"))]
+        read_cpu_temperatures_from(HWMON_PATH)
+    }
+
+    /// Returns the temperature of the package of a socket, in celsius degrees,
+    /// if hwmon gives one for this socket.
+    pub fn get_socket_temperature_celsius(&self, socket_id: u16) -> Option<f64> {
+        self.read_cpu_temperatures()?
+            .iter()
+            .find(|t| t.socket_id == Some(socket_id))
+            .map(|t| t.celsius)
+    }
+
+    /// Returns a Record instance containing the energy consumed by the whole host
+    /// since scaphandre started, in microjoules, as the sum of the per-socket totals.
+    pub fn get_total_energy_microjoules_since_start(&self) -> Option<Record> {
+        let mut total = 0;
+        let mut last_timestamp = None;
+        for s in self.get_sockets_passive() {
+            if let Some(record) = s.get_total_energy_microjoules_since_start() {
+                total += s.energy_since_start_microjoules;
+                last_timestamp = Some(record.timestamp);
+            }
+        }
+        last_timestamp
+            .map(|timestamp| Record::new(timestamp, total.to_string(), units::Unit::MicroJoule))
+    }
+
     /// Returns a CPUStat instance containing the difference between last
     /// and previous stats measurement (from stat_buffer), attribute by attribute.
     pub fn get_stats_diff(&self) -> Option<CPUStat> {
@@ -528,7 +1785,7 @@ impl Topology {
     pub fn read_stats(&self) -> Option<CPUStat> {
         #[cfg(target_os = "linux")]
         {
-            let kernelstats_or_not = KernelStats::new();
+            let kernelstats_or_not = self.proc_tracker.read_kernel_stats();
             if let Ok(res_cputime) = kernelstats_or_not {
                 return Some(CPUStat {
                     user: res_cputime.total.user,
@@ -551,7 +1808,7 @@ impl Topology {
     pub fn read_nb_process_total_count(&self) -> Option<u64> {
         #[cfg(target_os = "linux")]
         {
-            if let Ok(result) = KernelStats::new() {
+            if let Ok(result) = self.proc_tracker.read_kernel_stats() {
                 return Some(result.processes);
             }
         }
@@ -562,7 +1819,7 @@ impl Topology {
     pub fn read_nb_process_running_current(&self) -> Option<u32> {
         #[cfg(target_os = "linux")]
         {
-            if let Ok(result) = KernelStats::new() {
+            if let Ok(result) = self.proc_tracker.read_kernel_stats() {
                 if let Some(procs_running) = result.procs_running {
                     return Some(procs_running);
                 }
@@ -574,7 +1831,7 @@ impl Topology {
     pub fn read_nb_process_blocked_current(&self) -> Option<u32> {
         #[cfg(target_os = "linux")]
         {
-            if let Ok(result) = KernelStats::new() {
+            if let Ok(result) = self.proc_tracker.read_kernel_stats() {
                 if let Some(procs_blocked) = result.procs_blocked {
                     return Some(procs_blocked);
                 }
@@ -586,64 +1843,211 @@ impl Topology {
     pub fn read_nb_context_switches_total_count(&self) -> Option<u64> {
         #[cfg(target_os = "linux")]
         {
-            if let Ok(result) = KernelStats::new() {
+            if let Ok(result) = self.proc_tracker.read_kernel_stats() {
                 return Some(result.ctxt);
             }
         }
         None
     }
 
+    /// Returns the number of interrupts serviced since boot
+    pub fn read_nb_interrupts_total_count(&self) -> Option<u64> {
+        #[cfg(target_os = "linux")]
+        {
+            let stat = self.proc_tracker.get_procfs_root().join("stat");
+            if let Ok(content) = fs::read_to_string(stat) {
+                return parse_proc_stat_counter(&content, "intr");
+            }
+        }
+        None
+    }
+
+    /// Returns the power consumed by a process and all its descendants, in microwatts,
+    /// as the sum of their individual power consumptions. Returns None if none
+    /// of them has a power consumption measured yet.
+    pub fn get_process_tree_power_microwatts(&self, root_pid: i32) -> Option<Record> {
+        let mut pids = self.proc_tracker.get_descendants_pids(root_pid);
+        pids.push(root_pid);
+        self.get_processes_power_microwatts(pids)
+    }
+
+    /// Returns the power consumed by all the alive processes of the user `uid`,
+    /// in microwatts. Returns None if none of them has a power consumption measured yet.
+    pub fn get_user_power_consumption_microwatts(&self, uid: u32) -> Option<Record> {
+        self.get_processes_power_microwatts(self.proc_tracker.get_alive_pids_for_uid(uid))
+    }
+
+    /// Returns the sum of the power consumptions of processes, in microwatts,
+    /// with the timestamp of the most recent one.
+    fn get_processes_power_microwatts(&self, pids: Vec<i32>) -> Option<Record> {
+        let mut total = None;
+        for pid in pids {
+            if let Some(power) = self.get_process_power_consumption_microwatts(pid) {
+                if let Ok(value) = power.value.parse::<u64>() {
+                    let (sum, timestamp) = total.unwrap_or((0, power.timestamp));
+                    total = Some((sum + value, timestamp.max(power.timestamp)));
+                }
+            }
+        }
+        total
+            .map(|(sum, timestamp)| Record::new(timestamp, sum.to_string(), units::Unit::MicroWatt))
+    }
+
+    /// Returns the power consumed by the tasks of a cgroup (and its descendants)
+    /// between last and previous measurement, in microwatts. The host power is
+    /// divided according to the CPU time accounted by the kernel for the cgroup
+    /// (usage_usec, or cpuacct.usage on cgroup v1), which also covers the processes that lived between two
+    /// measurements. Returns None if cgroup accounting is not enabled.
+    #[cfg(target_os = "linux")]
+    pub fn get_cgroup_power_consumption_microwatts(&self, cgroup: &str) -> Option<Record> {
+        let cgroup_tracker = self.cgroup_tracker.as_ref()?;
+        let cgroup_usage_usec = cgroup_tracker.get_diff_usage_usec(cgroup)?;
+        let topo_stats_diff = self.get_stats_diff()?;
+        let ticks_per_second = procfs::ticks_per_second().ok()?;
+        let topo_usage_usec =
+            topo_stats_diff.total_time_jiffies() as f64 * 1_000_000.0 / ticks_per_second as f64;
+        if topo_usage_usec <= 0.0 {
+            return None;
+        }
+        let usage_percent = cgroup_usage_usec as f64 / topo_usage_usec;
+        let topo_conso = self.get_records_diff_power_microwatts()?;
+        let result = (topo_conso.value_f64()? * usage_percent) as u64;
+        Some(Record::new(
+            cgroup_tracker.cgroups.get(cgroup)?.first()?.timestamp,
+            result.to_string(),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns the power consumed by the processes of a systemd service (like
+    /// `nginx.service`) between last and previous measurement, in microwatts:
+    /// the sum of the power of the processes of its cgroup. Returns None if
+    /// service accounting is not enabled or if no process of the service has
+    /// been measured yet.
+    #[cfg(target_os = "linux")]
+    pub fn get_service_power_consumption_microwatts(&self, unit: &str) -> Option<Record> {
+        let pids = self.service_tracker.as_ref()?.services.get(unit)?;
+        let mut total = None;
+        let mut timestamp = None;
+        for pid in pids {
+            if let Some(power) = self.get_process_power_consumption_microwatts(*pid) {
+                if let Some(value) = power.value_u64() {
+                    total = Some(total.unwrap_or(0) + value);
+                    timestamp = Some(power.timestamp);
+                }
+            }
+        }
+        Some(Record::new(
+            timestamp?,
+            total?.to_string(),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns get_process_power_consumption_microwatts as an integer, in microwatts.
+    pub fn get_process_power_consumption_microwatts_value(&self, pid: i32) -> Option<u64> {
+        self.get_process_power_consumption_microwatts(pid)?
+            .value_u64()
+    }
+
     /// Returns the power consumed between last and previous measurement for a given process ID, in microwatts
+    /// It is computed once per refresh, exporters asking for it several times get the cached result.
     pub fn get_process_power_consumption_microwatts(&self, pid: i32) -> Option<Record> {
+        if let Some(power) = self.process_power_cache.borrow().get(&pid) {
+            return power.clone();
+        }
+        let power = self.compute_process_power_consumption_microwatts(pid);
+        self.process_power_cache
+            .borrow_mut()
+            .insert(pid, power.clone());
+        power
+    }
+
+    /// Empties the cache of the power of processes, to be done when the
+    /// records, stats or the way power is attributed change.
+    fn invalidate_process_power_cache(&self) {
+        self.process_power_cache.borrow_mut().clear();
+    }
+
+    /// Computes the power consumed between last and previous measurement for a given process ID, in microwatts,
+    /// with the attribution strategy of the topology.
+    fn compute_process_power_consumption_microwatts(&self, pid: i32) -> Option<Record> {
         let tracker = self.get_proc_tracker();
-        if let Some(recs) = tracker.find_records(pid) {
-            if recs.len() > 1 {
-                #[cfg(target_os = "linux")]
-                {
-                    let last = recs.first().unwrap();
-                    let previous = recs.get(1).unwrap();
-                    if let Some(topo_stats_diff) = self.get_stats_diff() {
-                        //trace!("Topology stats measured diff: {:?}", topo_stats_diff);
-                        let process_total_time =
-                            last.total_time_jiffies() - previous.total_time_jiffies();
-                        let topo_total_time = topo_stats_diff.total_time_jiffies();
-                        let usage_percent = process_total_time as f64 / topo_total_time as f64;
-                        let topo_conso = self.get_records_diff_power_microwatts();
-                        if let Some(val) = &topo_conso {
-                            //trace!("topo conso: {}", val);
-                            let val_f64 = val.value.parse::<f64>().unwrap();
-                            //trace!("val f64: {}", val_f64);
-                            let result = (val_f64 * usage_percent) as u64;
-                            //trace!("result: {}", result);
-                            return Some(Record::new(
-                                last.timestamp,
-                                result.to_string(),
-                                units::Unit::MicroWatt,
-                            ));
-                        }
-                    }
+        let recs = match tracker.find_records(pid) {
+            Some(recs) => recs,
+            None => {
+                trace!("Couldn't find records for PID: {}", pid);
+                return None;
+            }
+        };
+        if recs.len() < 2 {
+            return None;
+        }
+        let last = recs.first().unwrap();
+        let topo_conso = self.get_records_diff_power_microwatts()?;
+        let mut input = AttributionInput {
+            host_power_microwatts: topo_conso.value_f64()?,
+            idle_power_microwatts: self
+                .get_idle_power_microwatts()
+                .and_then(|power| power.value_f64()),
+            ..Default::default()
+        };
+        #[cfg(target_os = "linux")]
+        {
+            let previous = recs.get(1).unwrap();
+            let topo_stats_diff = self.get_stats_diff()?;
+            let basis = &self.attribution_basis;
+            let starttime =
+                |record: &ProcessRecord| record.process.stat.as_ref().map(|s| s.starttime);
+            input.process_time_jiffies = if starttime(last) == starttime(previous) {
+                last.attributed_time_jiffies(basis) - previous.attributed_time_jiffies(basis)
+            } else {
+                // the pid was reused, all the time of the new process was spent since the previous record
+                last.attributed_time_jiffies(basis)
+            };
+            input.host_time_jiffies = topo_stats_diff.attributed_time_jiffies(basis);
+            // the time spent alongside a busy SMT sibling costs less of the core, the
+            // time of the process being weighted as if spent on its last CPU only
+            if let Some(smt_tracker) = &self.smt_tracker {
+                let cpu = last.process.stat.as_ref().and_then(|s| s.processor);
+                if let Some(weight) = cpu.and_then(|cpu| smt_tracker.get_weight(cpu as u16)) {
+                    input.process_time_jiffies *= weight;
                 }
-                #[cfg(target_os = "windows")]
-                {
-                    let last = recs.first().unwrap();
-                    let process_cpu_percentage =
-                        tracker.get_cpu_usage_percentage(pid as usize, tracker.nb_cores);
-                    let topo_conso = self.get_records_diff_power_microwatts();
-                    if let Some(conso) = &topo_conso {
-                        let conso_f64 = conso.value.parse::<f64>().unwrap();
-                        let result = (conso_f64 * process_cpu_percentage as f64) / 100.0_f64;
-                        return Some(Record::new(
-                            last.timestamp,
-                            result.to_string(),
-                            units::Unit::MicroWatt,
-                        ));
-                    }
+                if let Some(factor) = smt_tracker.get_host_factor() {
+                    input.host_time_jiffies *= factor;
                 }
             }
-        } else {
-            trace!("Couldn't find records for PID: {}", pid);
+            // a process can't have used more than the host during the part of the interval it was alive
+            if let Some(alive) = tracker.get_alive_fraction(pid) {
+                input.process_time_jiffies = input
+                    .process_time_jiffies
+                    .min(input.host_time_jiffies * alive);
+            }
+            input.io_power_microwatts = self.get_io_attributed_power_microwatts();
+            input.io_share = self
+                .io_tracker
+                .as_ref()
+                .and_then(|t| t.get_io_share(pid))
+                .unwrap_or(0.0);
         }
-        None
+        #[cfg(target_os = "windows")]
+        {
+            input.process_time_jiffies =
+                tracker.get_cpu_usage_percentage(pid as usize, tracker.nb_cores) as f64;
+            input.host_time_jiffies = 100.0;
+        }
+        let result = self.attribution_strategy.attribute(&input) as u64;
+        Some(Record::new(
+            last.timestamp,
+            result.to_string(),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns get_process_cpu_consumption_percentage as a float.
+    pub fn get_process_cpu_consumption_percentage_value(&self, pid: i32) -> Option<f64> {
+        self.get_process_cpu_consumption_percentage(pid)?
+            .value_f64()
     }
 
     pub fn get_process_cpu_consumption_percentage(&self, pid: i32) -> Option<Record> {
@@ -677,8 +2081,15 @@ impl Topology {
 /// owning CPU cores (processor in /proc/cpuinfo).
 #[derive(Debug, Clone)]
 pub struct CPUSocket {
-    /// Numerical ID of the CPU socket (physical_id in /proc/cpuinfo)
+    /// Numerical ID of the CPU socket (physical_id in /proc/cpuinfo). On a
+    /// package made of several dies, each die has its own RAPL package zone,
+    /// and its own id: the index of the zone.
     pub id: u16,
+    /// Physical package (physical_id in /proc/cpuinfo) of the socket, if it
+    /// isn't its id, like on a package made of several dies
+    pub package_id: Option<u16>,
+    /// Die of the package measured, on a package made of several dies
+    pub die_id: Option<u16>,
     /// RAPL domains attached to the socket
     pub domains: Vec<Domain>,
     /// Text attributes linked to that socket, found in /proc/cpuinfo
@@ -693,9 +2104,24 @@ pub struct CPUSocket {
     pub cpu_cores: Vec<CPUCore>,
     /// Usage statistics records stored for this socket.
     pub stat_buffer: Vec<CPUStat>,
+    /// Energy consumed by the socket since scaphandre started, in microjoules,
+    /// accumulated from the difference between successive records.
+    pub energy_since_start_microjoules: u64,
     ///
     #[allow(dead_code)]
     sensor_data: HashMap<String, String>,
+    /// External power meter providing the records of this socket, if any.
+    power_meter: Option<Arc<dyn PowerMeter>>,
+    /// Tells if the socket was throttled during the last measurement step, either
+    /// by a thermal event or because its power was clamped to its limit by RAPL.
+    /// None if neither the throttle counter nor the power limit could be read.
+    pub throttled: Option<bool>,
+    /// Last value read from the package throttle counter
+    throttle_count: Option<u64>,
+    /// Moving average of the socket power, if smoothing is enabled
+    power_smoothing: Option<Ewma>,
+    /// Lowest and highest socket power measured since the start or the last reset
+    power_extremes: PowerExtremes,
 }
 
 impl RecordGenerator for CPUSocket {
@@ -703,8 +2129,13 @@ impl RecordGenerator for CPUSocket {
     /// Returns a clone of this Record instance.
     fn refresh_record(&mut self) {
         //if let Ok(record) = self.read_record_uj() {
-        if let Ok(record) = self.read_record() {
+        let record = match &self.power_meter {
+            Some(power_meter) => self.read_power_meter_record(power_meter.as_ref()),
+            None => self.read_record(),
+        };
+        if let Ok(record) = record {
             self.record_buffer.push(record);
+            self.accumulate_energy_since_start();
         }
 
         if !self.record_buffer.is_empty() {
@@ -757,29 +2188,258 @@ impl RecordGenerator for CPUSocket {
         }
         result
     }
-}
+}
+
+impl CPUSocket {
+    /// Creates and returns a CPUSocket instance with an empty buffer and no CPUCore owned yet.
+    fn new(
+        id: u16,
+        domains: Vec<Domain>,
+        attributes: Vec<Vec<HashMap<String, String>>>,
+        counter_uj_path: String,
+        buffer_max_kbytes: u16,
+        sensor_data: HashMap<String, String>,
+    ) -> CPUSocket {
+        CPUSocket {
+            id,
+            package_id: None,
+            die_id: None,
+            domains,
+            attributes,
+            counter_uj_path,
+            record_buffer: vec![], // buffer has to be empty first
+            buffer_max_kbytes,
+            cpu_cores: vec![], // cores are instantiated on a later step
+            stat_buffer: vec![],
+            energy_since_start_microjoules: 0,
+            sensor_data,
+            power_meter: None,
+            throttled: None,
+            throttle_count: None,
+            power_smoothing: None,
+            power_extremes: PowerExtremes::default(),
+        }
+    }
+
+    /// Returns the physical package (physical_id in /proc/cpuinfo) of the socket.
+    pub fn get_package_id(&self) -> u16 {
+        self.package_id.unwrap_or(self.id)
+    }
+
+    /// Returns the labels identifying the socket in metrics: socket_id, and on
+    /// a package made of several dies, package_id and die_id.
+    pub fn get_labels(&self) -> Vec<(String, String)> {
+        let mut labels = vec![(String::from("socket_id"), self.id.to_string())];
+        if let Some(die_id) = self.die_id {
+            labels.push((
+                String::from("package_id"),
+                self.get_package_id().to_string(),
+            ));
+            labels.push((String::from("die_id"), die_id.to_string()));
+        }
+        labels
+    }
+
+    /// Attaches an external power meter to the socket. Records are then
+    /// computed from the power it measures, instead of read by the sensor.
+    pub fn set_power_meter(&mut self, power_meter: Arc<dyn PowerMeter>) {
+        self.power_meter = Some(power_meter);
+    }
+
+    /// Reads the power measured by power_meter and returns a Record of the energy
+    /// consumed since the first reading, in microjoules.
+    fn read_power_meter_record(
+        &self,
+        power_meter: &dyn PowerMeter,
+    ) -> Result<Record, Box<dyn Error>> {
+        power_meter_record(power_meter, self.record_buffer.last())
+    }
+
+    /// Adds the energy consumed between the two last records to
+    /// energy_since_start_microjoules. If the counter wrapped around in between,
+    /// the maximum range of the counter (max_energy_range_uj in sensor_data)
+    /// is used to compute the delta. If this range is unknown, the delta is skipped.
+    fn accumulate_energy_since_start(&mut self) {
+        let len = self.record_buffer.len();
+        if len < 2 {
+            return;
+        }
+        let last = self.record_buffer[len - 1].value.trim().parse::<u64>();
+        let previous = self.record_buffer[len - 2].value.trim().parse::<u64>();
+        if let (Ok(last_microjoules), Ok(previous_microjoules)) = (last, previous) {
+            if last_microjoules >= previous_microjoules {
+                self.energy_since_start_microjoules += last_microjoules - previous_microjoules;
+            } else if let Some(max_range) = self
+                .sensor_data
+                .get("max_energy_range_uj")
+                .and_then(|v| v.trim().parse::<u64>().ok())
+            {
+                debug!(
+                    "socket {} energy counter wrapped around (max range: {})",
+                    self.id, max_range
+                );
+                self.energy_since_start_microjoules +=
+                    max_range.saturating_sub(previous_microjoules) + last_microjoules;
+            } else {
+                debug!(
+                    "previous_microjoules ({}) > last_microjoules ({}) and max range is unknown, skipping",
+                    previous_microjoules, last_microjoules
+                );
+            }
+        }
+    }
+
+    /// Returns a Record instance containing the energy consumed by the socket
+    /// since scaphandre started, in microjoules.
+    pub fn get_total_energy_microjoules_since_start(&self) -> Option<Record> {
+        self.record_buffer.last().map(|last| {
+            Record::new(
+                last.timestamp,
+                self.energy_since_start_microjoules.to_string(),
+                units::Unit::MicroJoule,
+            )
+        })
+    }
+
+    /// Returns the resolution of the energy counter of the socket, in microjoules,
+    /// if the sensor tells it.
+    pub fn get_energy_resolution_microjoules(&self) -> Option<f64> {
+        get_energy_resolution(&self.sensor_data)
+    }
+
+    /// Returns the active power limit of the socket, in microwatts, as read from
+    /// the file given as power_limit_file in sensor_data. Returns None if there
+    /// is no such limit or if it is disabled.
+    pub fn get_power_limit_microwatts(&self) -> Option<u64> {
+        if let Some(enabled_file) = self.sensor_data.get("power_limit_enabled_file") {
+            if let Ok(enabled) = fs::read_to_string(enabled_file) {
+                if enabled.trim() == "0" {
+                    return None;
+                }
+            }
+        }
+        let limit_file = self.sensor_data.get("power_limit_file")?;
+        match fs::read_to_string(limit_file) {
+            Ok(limit) => limit.trim().parse::<u64>().ok().filter(|l| *l > 0),
+            Err(_) => None,
+        }
+    }
+
+    /// Returns the maximum power limit of the socket, usually its TDP, in
+    /// microwatts, as read from the file given as power_max_file in sensor_data.
+    pub fn get_max_power_microwatts(&self) -> Option<u64> {
+        let max_file = self.sensor_data.get("power_max_file")?;
+        match fs::read_to_string(max_file) {
+            Ok(max) => max.trim().parse::<u64>().ok().filter(|m| *m > 0),
+            Err(_) => None,
+        }
+    }
+
+    /// Returns the power of the last measurement divided by the active power
+    /// limit of the socket, or by its maximum power limit if no limit is
+    /// active. Returns None if neither is known.
+    pub fn get_power_fraction_of_limit(&self) -> Option<f64> {
+        let limit = self
+            .get_power_limit_microwatts()
+            .or_else(|| self.get_max_power_microwatts())?;
+        let power = self.get_records_diff_power_microwatts_value()?;
+        Some(power as f64 / limit as f64)
+    }
+
+    /// Adds the power of the last measurement to the moving average of the
+    /// socket power, if smoothing is enabled.
+    fn refresh_power_smoothing(&mut self) {
+        if self.power_smoothing.is_some() {
+            if let Some(power) = self.get_records_diff_power_microwatts() {
+                if let (Some(smoothing), Ok(microwatts)) =
+                    (&mut self.power_smoothing, power.value.parse::<f64>())
+                {
+                    smoothing.update(microwatts);
+                }
+            }
+        }
+    }
+
+    /// Takes the power of the last measurement into account in the lowest and
+    /// highest power of the socket.
+    fn refresh_power_extremes(&mut self) {
+        if let Some(microwatts) = self.get_records_diff_power_microwatts_value() {
+            self.power_extremes.update(microwatts);
+        }
+    }
+
+    /// Returns the lowest and highest socket power measured since the start, or
+    /// since the last call to Topology::reset_power_extremes.
+    pub fn get_power_extremes(&self) -> &PowerExtremes {
+        &self.power_extremes
+    }
+
+    /// Returns the moving average of the socket power, in microwatts, if
+    /// smoothing is enabled.
+    pub fn get_smoothed_power_microwatts(&self) -> Option<Record> {
+        let power = self.get_records_diff_power_microwatts()?;
+        let smoothed = self.power_smoothing.as_ref()?.get()?;
+        Some(Record::new(
+            power.timestamp,
+            format!("{}", smoothed.round() as u64),
+            units::Unit::MicroWatt,
+        ))
+    }
+
+    /// Returns the fraction of the socket power consumed by each of its domains
+    /// (core, uncore, dram...), by domain name, for the last measurement. A
+    /// socket without any domain but the package gets 1.0 for PACKAGE_DOMAIN_NAME.
+    /// Domains outside of the package, like dram, may exceed 1.0.
+    pub fn get_domains_power_ratios(&self) -> Vec<(String, f64)> {
+        let socket_power = match self.get_records_diff_power_microwatts_value() {
+            Some(power) if power > 0 => power as f64,
+            _ => return vec![],
+        };
+        if self.domains.is_empty() {
+            return vec![(String::from(PACKAGE_DOMAIN_NAME), 1.0)];
+        }
+        self.domains
+            .iter()
+            .filter_map(|domain| {
+                let power = domain.get_records_diff_power_microwatts_value()?;
+                Some((domain.name.clone(), power as f64 / socket_power))
+            })
+            .collect()
+    }
 
-impl CPUSocket {
-    /// Creates and returns a CPUSocket instance with an empty buffer and no CPUCore owned yet.
-    fn new(
-        id: u16,
-        domains: Vec<Domain>,
-        attributes: Vec<Vec<HashMap<String, String>>>,
-        counter_uj_path: String,
-        buffer_max_kbytes: u16,
-        sensor_data: HashMap<String, String>,
-    ) -> CPUSocket {
-        CPUSocket {
-            id,
-            domains,
-            attributes,
-            counter_uj_path,
-            record_buffer: vec![], // buffer has to be empty first
-            buffer_max_kbytes,
-            cpu_cores: vec![], // cores are instantiated on a later step
-            stat_buffer: vec![],
-            sensor_data,
+    /// Updates self.throttled. The socket is considered throttled if the package
+    /// throttle counter (of its first core) increased since the previous call, or
+    /// if its power reached POWER_LIMIT_CLAMPING_RATIO of its power limit.
+    /// `cpu_sysfs_root` is the sysfs directory of the CPUs, like
+    /// /sys/devices/system/cpu.
+    fn refresh_throttling(&mut self, cpu_sysfs_root: &str) {
+        let mut thermal_throttled = None;
+        if let Some(core) = self.cpu_cores.first() {
+            let counter_file = format!(
+                "{}/cpu{}/thermal_throttle/package_throttle_count",
+                cpu_sysfs_root, core.id
+            );
+            if let Ok(Ok(count)) = fs::read_to_string(counter_file).map(|c| c.trim().parse::<u64>())
+            {
+                thermal_throttled = self.throttle_count.map(|previous| count > previous);
+                self.throttle_count = Some(count);
+            }
         }
+        let clamped = match (
+            self.get_power_limit_microwatts(),
+            self.get_records_diff_power_microwatts(),
+        ) {
+            (Some(limit), Some(power)) => power
+                .value
+                .parse::<f64>()
+                .ok()
+                .map(|p| p >= limit as f64 * POWER_LIMIT_CLAMPING_RATIO),
+            _ => None,
+        };
+        self.throttled = match (thermal_throttled, clamped) {
+            (None, None) => None,
+            (t, c) => Some(t.unwrap_or(false) || c.unwrap_or(false)),
+        };
     }
 
     /// Adds a new Domain instance to the domains vector if and only if it doesn't exist in the vector already.
@@ -814,13 +2474,29 @@ impl CPUSocket {
         self.cpu_cores.push(core);
     }
 
-    /// Generates a new CPUStat object storing current usage statistics of the socket
-    /// and stores it in the stat_buffer.
-    pub fn refresh_stats(&mut self) {
+    /// Generates a new CPUStat object storing current usage statistics of the socket,
+    /// read from the procfs of `proc_tracker`, and stores it in the stat_buffer.
+    pub fn refresh_stats(&mut self, proc_tracker: &ProcessTracker) {
         if !self.stat_buffer.is_empty() {
             self.clean_old_stats();
         }
-        self.stat_buffer.insert(0, self.read_stats().unwrap());
+        self.stat_buffer
+            .insert(0, self.read_stats(proc_tracker).unwrap());
+    }
+
+    /// Removes the record and stats preceding the last ones, for the socket and its domains.
+    fn drop_previous_records(&mut self) {
+        if self.record_buffer.len() > 1 {
+            self.record_buffer.remove(self.record_buffer.len() - 2);
+        }
+        if self.stat_buffer.len() > 1 {
+            self.stat_buffer.remove(1);
+        }
+        for d in &mut self.domains {
+            if d.record_buffer.len() > 1 {
+                d.record_buffer.remove(d.record_buffer.len() - 2);
+            }
+        }
     }
 
     /// Checks the size in memory of stats_buffer and deletes as many CPUStat
@@ -867,7 +2543,7 @@ impl CPUSocket {
 
     /// Combines stats from all CPU cores owned byu the socket and returns
     /// a CpuStat struct containing stats for the whole socket.
-    pub fn read_stats(&self) -> Option<CPUStat> {
+    pub fn read_stats(&self, proc_tracker: &ProcessTracker) -> Option<CPUStat> {
         let mut stats = CPUStat {
             user: 0,
             nice: 0,
@@ -881,7 +2557,7 @@ impl CPUSocket {
             steal: Some(0),
         };
         for c in &self.cpu_cores {
-            let c_stats = c.read_stats().unwrap();
+            let c_stats = c.read_stats(proc_tracker).unwrap();
             stats.user += c_stats.user;
             stats.nice += c_stats.nice;
             stats.system += c_stats.system;
@@ -942,6 +2618,17 @@ impl CPUSocket {
         None
     }
 
+    /// Returns the time actually elapsed between the last two records, which
+    /// get_records_diff_power_microwatts divides the energy consumed by.
+    pub fn get_records_diff_interval(&self) -> Option<Duration> {
+        records_diff_interval(&self.record_buffer)
+    }
+
+    /// Returns get_records_diff_power_microwatts as an integer, in microwatts.
+    pub fn get_records_diff_power_microwatts_value(&self) -> Option<u64> {
+        self.get_records_diff_power_microwatts()?.value_u64()
+    }
+
     /// Returns a Record instance containing the power consumed between last
     /// and previous measurement, for this CPU socket
     pub fn get_records_diff_power_microwatts(&self) -> Option<Record> {
@@ -971,8 +2658,7 @@ impl CPUSocket {
                         previous_microjoules, last_microjoules
                     );
                 }
-                let time_diff =
-                    last_record.timestamp.as_secs_f64() - previous_record.timestamp.as_secs_f64();
+                let time_diff = records_diff_interval(&self.record_buffer)?.as_secs_f64();
                 let microwatts = microjoules as f64 / time_diff;
                 debug!("l866: microwatts: {}", microwatts);
                 return Some(Record::new(
@@ -997,24 +2683,33 @@ impl CPUSocket {
 pub struct CPUCore {
     pub id: u16,
     pub attributes: HashMap<String, String>,
+    /// Type of the core on hybrid CPUs (P-core or E-core), None on other CPUs.
+    pub core_type: Option<CoreType>,
 }
 
 impl CPUCore {
     /// Instantiates CPUCore and returns the instance.
     pub fn new(id: u16, attributes: HashMap<String, String>) -> CPUCore {
-        CPUCore { id, attributes }
+        CPUCore {
+            id,
+            attributes,
+            core_type: None,
+        }
     }
 
-    /// Reads content from /proc/stat and extracts the stats of the CPU core
-    fn read_stats(&self) -> Option<CPUStat> {
+    /// Reads content from the stat file of the procfs of `proc_tracker` and
+    /// extracts the stats of the CPU core
+    fn read_stats(&self, proc_tracker: &ProcessTracker) -> Option<CPUStat> {
         #[cfg(target_os = "linux")]
         {
-            if let Ok(mut kernelstats) = KernelStats::new() {
+            if let Ok(mut kernelstats) = proc_tracker.read_kernel_stats() {
                 return Some(CPUStat::from_procfs_cputime(
                     kernelstats.cpu_time.remove(self.id as usize),
                 ));
             }
         }
+        #[cfg(not(target_os = "linux"))]
+        let _ = proc_tracker;
         None
     }
 }
@@ -1037,14 +2732,28 @@ pub struct Domain {
     ///
     #[allow(dead_code)]
     sensor_data: HashMap<String, String>,
+    /// Number of successful reads of the energy counter since the start.
+    reads: u64,
+    /// Number of failed reads of the energy counter since the start.
+    read_errors: u64,
 }
 impl RecordGenerator for Domain {
     /// Computes a measurement of energy comsumption for this CPU domain,
     /// stores a copy in self.record_buffer and returns it.
     fn refresh_record(&mut self) {
         //if let Ok(record) = self.read_record_uj() {
-        if let Ok(record) = self.read_record() {
-            self.record_buffer.push(record);
+        match self.read_record() {
+            Ok(record) => {
+                self.reads += 1;
+                self.record_buffer.push(record);
+            }
+            Err(e) => {
+                self.read_errors += 1;
+                debug!(
+                    "Couldn't read the energy counter of domain {}: {}",
+                    self.name, e
+                );
+            }
         }
 
         if !self.record_buffer.is_empty() {
@@ -1085,6 +2794,12 @@ impl RecordGenerator for Domain {
     }
 }
 impl Domain {
+    /// Returns the resolution of the energy counter of the domain, in microjoules,
+    /// if the sensor tells it.
+    pub fn get_energy_resolution_microjoules(&self) -> Option<f64> {
+        get_energy_resolution(&self.sensor_data)
+    }
+
     /// Instanciates Domain and returns the instance
     fn new(
         id: u16,
@@ -1100,9 +2815,57 @@ impl Domain {
             record_buffer: vec![],
             buffer_max_kbytes,
             sensor_data,
+            reads: 0,
+            read_errors: 0,
         }
     }
 
+    /// Returns the number of successful reads of the energy counter of the
+    /// domain since the start.
+    pub fn get_reads(&self) -> u64 {
+        self.reads
+    }
+
+    /// Returns the number of failed reads of the energy counter of the domain
+    /// since the start, telling a flaky counter or missing permissions.
+    pub fn get_read_errors(&self) -> u64 {
+        self.read_errors
+    }
+
+    /// Returns the last value read from the energy counter of the domain
+    /// (energy_uj with powercap), as is: wraps of the counter are not handled,
+    /// so that the computations of scaphandre can be checked against it.
+    pub fn get_raw_energy_uj(&self) -> Option<Record> {
+        let last = self.record_buffer.last()?;
+        Some(Record::new(
+            last.timestamp,
+            last.value.trim().to_string(),
+            units::Unit::MicroJoule,
+        ))
+    }
+
+    /// Returns the value after which the energy counter of the domain wraps
+    /// around, in microjoules, if the sensor tells it.
+    pub fn get_max_energy_range_uj(&self) -> Option<u64> {
+        self.sensor_data.get("max_energy_range_uj")?.parse().ok()
+    }
+
+    /// Returns true if this is the uncore domain, including the integrated GPU.
+    pub fn is_igpu(&self) -> bool {
+        self.name == IGPU_DOMAIN_NAME
+    }
+
+    /// Returns the time actually elapsed between the last two records, which
+    /// get_records_diff_power_microwatts divides the energy consumed by.
+    pub fn get_records_diff_interval(&self) -> Option<Duration> {
+        records_diff_interval(&self.record_buffer)
+    }
+
+    /// Returns get_records_diff_power_microwatts as an integer, in microwatts.
+    pub fn get_records_diff_power_microwatts_value(&self) -> Option<u64> {
+        self.get_records_diff_power_microwatts()?.value_u64()
+    }
+
     /// Returns a Record instance containing the power consumed between
     /// last and previous measurement, in microwatts.
     pub fn get_records_diff_power_microwatts(&self) -> Option<Record> {
@@ -1120,8 +2883,7 @@ impl Domain {
                     return None;
                 }
                 let microjoules = last_microjoules - previous_microjoules;
-                let time_diff =
-                    last_record.timestamp.as_secs_f64() - previous_record.timestamp.as_secs_f64();
+                let time_diff = records_diff_interval(&self.record_buffer)?.as_secs_f64();
                 let microwatts = microjoules as f64 / time_diff;
                 return Some(Record::new(
                     last_record.timestamp,
@@ -1139,7 +2901,94 @@ impl fmt::Display for Domain {
     }
 }
 
+/// Returns the time elapsed between the timestamps of the last two records of
+/// `records`, or None if there is no such pair or if the time didn't increase
+/// (as when the clock of the system was set back).
+fn records_diff_interval(records: &[Record]) -> Option<Duration> {
+    let len = records.len();
+    if len < 2 {
+        return None;
+    }
+    records[len - 1]
+        .timestamp
+        .checked_sub(records[len - 2].timestamp)
+        .filter(|interval| !interval.is_zero())
+}
+
 // !!!!!!!!!!!!!!!!! Record !!!!!!!!!!!!!!!!!!!!!!!
+/// CPUTemperature is a temperature given by a hwmon sensor of the CPU.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CPUTemperature {
+    /// Socket this temperature is related to, if the sensor tells it
+    /// (coretemp gives a "Package id N" label). None otherwise.
+    pub socket_id: Option<u16>,
+    /// Name of the sensor, as the hwmon driver name and the sensor label, like "coretemp Package id 0".
+    pub sensor: String,
+    /// Temperature, in celsius degrees.
+    pub celsius: f64,
+}
+
+/// Reads CPU temperatures from the hwmon devices found in hwmon_path. Package
+/// level temperatures are mapped to their socket. If the driver doesn't give
+/// package temperatures (like k10temp), all its temperatures are returned in a
+/// flat list, without socket. Returns None if there is no CPU hwmon device.
+fn read_cpu_temperatures_from(hwmon_path: &str) -> Option<Vec<CPUTemperature>> {
+    let mut devices: Vec<_> = fs::read_dir(hwmon_path)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    devices.sort();
+    let mut packages = vec![];
+    let mut others = vec![];
+    for device in devices {
+        let name = match fs::read_to_string(device.join("name")) {
+            Ok(name) => String::from(name.trim()),
+            Err(_) => continue,
+        };
+        if !CPU_HWMON_NAMES.contains(&name.as_str()) {
+            continue;
+        }
+        let mut inputs: Vec<_> = match fs::read_dir(&device) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|e| String::from(e.file_name().to_string_lossy()))
+                .filter(|f| f.starts_with("temp") && f.ends_with("_input"))
+                .collect(),
+            Err(_) => continue,
+        };
+        inputs.sort();
+        for input in inputs {
+            let celsius =
+                match fs::read_to_string(device.join(&input)).map(|v| v.trim().parse::<f64>()) {
+                    Ok(Ok(millidegrees)) => millidegrees / 1000.0,
+                    _ => continue,
+                };
+            let label = fs::read_to_string(device.join(input.replace("_input", "_label")))
+                .map(|l| String::from(l.trim()))
+                .unwrap_or_else(|_| input.replace("_input", ""));
+            let socket_id = label
+                .strip_prefix("Package id ")
+                .and_then(|id| id.parse::<u16>().ok());
+            let temperature = CPUTemperature {
+                socket_id,
+                sensor: format!("{name} {label}"),
+                celsius,
+            };
+            match socket_id {
+                Some(_) => packages.push(temperature),
+                None => others.push(temperature),
+            }
+        }
+    }
+    if !packages.is_empty() {
+        Some(packages)
+    } else if !others.is_empty() {
+        Some(others)
+    } else {
+        None
+    }
+}
+
 /// Record struct represents an electricity consumption measurement
 /// tied to a domain.
 #[derive(Debug, Clone)]
@@ -1158,6 +3007,17 @@ impl Record {
             unit,
         }
     }
+
+    /// Returns the value as an integer, in the unit of the record, or None if
+    /// it isn't one (like a negative or fractional value).
+    pub fn value_u64(&self) -> Option<u64> {
+        self.value.trim().parse().ok()
+    }
+
+    /// Returns the value as a float, in the unit of the record, or None if it isn't a number.
+    pub fn value_f64(&self) -> Option<f64> {
+        self.value.trim().parse().ok()
+    }
 }
 
 impl fmt::Display for Record {
@@ -1172,6 +3032,38 @@ impl fmt::Display for Record {
     }
 }
 
+/// Power of the processes under Topology::min_process_power_microwatts,
+/// aggregated so that the total power of processes is still accounted for.
+#[derive(Debug, Clone, Default)]
+pub struct OtherProcessesPower {
+    pub microwatts: u64,
+    /// Number of processes aggregated.
+    pub processes: usize,
+    /// Timestamp of the most recent power aggregated.
+    pub timestamp: Duration,
+}
+
+impl OtherProcessesPower {
+    /// Adds the power of a process.
+    pub fn add(&mut self, power: &Record) {
+        self.microwatts += power.value_f64().unwrap_or(0.0) as u64;
+        self.processes += 1;
+        self.timestamp = self.timestamp.max(power.timestamp);
+    }
+
+    /// Returns the power of all the processes aggregated, or None if there is none.
+    pub fn to_record(&self) -> Option<Record> {
+        if self.processes == 0 {
+            return None;
+        }
+        Some(Record::new(
+            self.timestamp,
+            self.microwatts.to_string(),
+            units::Unit::MicroWatt,
+        ))
+    }
+}
+
 #[derive(Debug)]
 pub struct CPUStat {
     user: u64,
@@ -1225,6 +3117,19 @@ impl CPUStat {
     }
 }
 
+impl CPUStat {
+    /// Returns the CPU time spent by the host, as counted by `basis`: time spent
+    /// in user mode (including nice and guest time), plus the weighted time
+    /// spent in kernel mode.
+    pub fn attributed_time_jiffies(&self, basis: &AttributionBasis) -> f64 {
+        let user = self.user
+            + self.nice
+            + self.guest_nice.unwrap_or_default()
+            + self.guest.unwrap_or_default();
+        user as f64 + self.system as f64 * basis.system_weight()
+    }
+}
+
 impl Clone for CPUStat {
     /// Returns a copy of CPUStat instance
     fn clone(&self) -> CPUStat {
@@ -1243,6 +3148,32 @@ impl Clone for CPUStat {
     }
 }
 
+/// Returns the die of the package logical CPU core `core_id` is on, if the
+/// kernel tells it in the sysfs mounted at `sysfs_root`.
+fn read_core_die_id(sysfs_root: &str, core_id: u16) -> Option<u16> {
+    fs::read_to_string(format!(
+        "{sysfs_root}/devices/system/cpu/cpu{core_id}/topology/die_id"
+    ))
+    .ok()?
+    .trim()
+    .parse()
+    .ok()
+}
+
+/// Returns the first value of the line of /proc/stat starting with `name`, like
+/// the total of the `intr` line, that is followed by the count of each interrupt.
+#[cfg(target_os = "linux")]
+fn parse_proc_stat_counter(content: &str, name: &str) -> Option<u64> {
+    content.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? == name {
+            fields.next()?.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1279,10 +3210,10 @@ mod tests {
         let mut sensor = powercap_rapl::PowercapRAPLSensor::new(8, 8, false);
         #[cfg(not(target_os = "linux"))]
         let mut sensor = msr_rapl::MsrRAPLSensor::new();
-        let mut topo = (*sensor.get_topology()).unwrap();
-        for s in topo.get_sockets() {
-            for c in s.get_cores() {
-                println!("{:?}", c.read_stats());
+        let topo = (*sensor.get_topology()).unwrap();
+        for s in topo.get_sockets_passive() {
+            for c in s.get_cores_passive() {
+                println!("{:?}", c.read_stats(&topo.proc_tracker));
             }
         }
     }
@@ -1293,10 +3224,655 @@ mod tests {
         let mut sensor = powercap_rapl::PowercapRAPLSensor::new(8, 8, false);
         #[cfg(not(target_os = "linux"))]
         let mut sensor = msr_rapl::MsrRAPLSensor::new();
-        let mut topo = (*sensor.get_topology()).unwrap();
-        for s in topo.get_sockets() {
-            println!("{:?}", s.read_stats());
+        let topo = (*sensor.get_topology()).unwrap();
+        for s in topo.get_sockets_passive() {
+            println!("{:?}", s.read_stats(&topo.proc_tracker));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn socket_stats_read_from_snapshot() {
+        let procfs_root =
+            std::env::temp_dir().join(format!("scaphandre_test_snapshot_{}", std::process::id()));
+        fs::create_dir_all(procfs_root.join("sys/kernel")).unwrap();
+        fs::write(
+            procfs_root.join("stat"),
+            "cpu  40 0 12 400 0 0 0 0 0 0\ncpu0 10 0 2 100 0 0 0 0 0 0\ncpu1 30 0 10 300 0 0 0 0 0 0\nctxt 42\nbtime 1600000000\nprocesses 7\n",
+        )
+        .unwrap();
+        fs::write(procfs_root.join("sys/kernel/osrelease"), "6.1.0-remote\n").unwrap();
+        fs::write(procfs_root.join("sys/kernel/hostname"), "remote\n").unwrap();
+        let mut topology = Topology::default();
+        topology
+            .proc_tracker
+            .set_procfs_root(procfs_root.to_str().unwrap());
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        let socket = &mut topology.get_sockets()[0];
+        socket.add_cpu_core(CPUCore::new(0, HashMap::new()));
+        socket.add_cpu_core(CPUCore::new(1, HashMap::new()));
+        let stats = topology.get_sockets_passive()[0]
+            .read_stats(&topology.proc_tracker)
+            .unwrap();
+        assert_eq!((stats.user, stats.system, stats.idle), (40, 12, 400));
+        let info = topology.get_host_info();
+        assert_eq!(
+            (info.kernel.as_str(), info.hostname.as_str()),
+            ("6.1.0-remote", "remote")
+        );
+        fs::remove_dir_all(&procfs_root).unwrap();
+    }
+
+    #[test]
+    fn socket_energy_since_start_handles_wrap() {
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(String::from("max_energy_range_uj"), String::from("1000"));
+        let mut socket = CPUSocket::new(0, vec![], vec![], String::from(""), 1, sensor_data);
+        for value in ["100", "400\n", "900", "150"] {
+            socket.record_buffer.push(Record::new(
+                current_system_time_since_epoch(),
+                String::from(value),
+                units::Unit::MicroJoule,
+            ));
+            socket.accumulate_energy_since_start();
+        }
+        // 300 + 500 + (1000 - 900 + 150)
+        assert_eq!(socket.energy_since_start_microjoules, 1050);
+        assert_eq!(
+            socket
+                .get_total_energy_microjoules_since_start()
+                .unwrap()
+                .value,
+            "1050"
+        );
+    }
+
+    #[test]
+    fn socket_power_averaged_over_intermediate_records() {
+        let mut socket = CPUSocket::new(0, vec![], vec![], String::from(""), 1, HashMap::new());
+        let start = current_system_time_since_epoch() - Duration::from_secs(10);
+        for (secs, value) in [(0, "0"), (1, "1000000"), (2, "5000000"), (10, "10000000")] {
+            socket.record_buffer.push(Record::new(
+                start + Duration::from_secs(secs),
+                String::from(value),
+                units::Unit::MicroJoule,
+            ));
+            if secs > 1 {
+                socket.drop_previous_records();
+            }
+        }
+        assert_eq!(socket.record_buffer.len(), 2);
+        // 10 J over 10 s
+        assert_eq!(
+            socket.get_records_diff_power_microwatts().unwrap().value,
+            "1000000"
+        );
+    }
+
+    #[derive(Debug)]
+    struct ConstantPowerMeter {}
+
+    impl PowerMeter for ConstantPowerMeter {
+        fn read_power_watts(&self) -> Result<f64, Box<dyn Error>> {
+            Ok(2.0)
+        }
+    }
+
+    #[test]
+    fn socket_records_from_power_meter() {
+        let mut socket = CPUSocket::new(0, vec![], vec![], String::from(""), 1, HashMap::new());
+        socket.set_power_meter(Arc::new(ConstantPowerMeter {}));
+        socket.record_buffer.push(Record::new(
+            current_system_time_since_epoch() - Duration::from_secs(10),
+            String::from("1000"),
+            units::Unit::MicroJoule,
+        ));
+        socket.refresh_record();
+        let energy = socket.record_buffer[1].value.parse::<u64>().unwrap();
+        // 2 watts during (at least) 10 seconds
+        assert!((20_001_000..20_100_000).contains(&energy));
+    }
+
+    #[test]
+    fn host_records_from_power_meter() {
+        let mut topology = Topology::default();
+        assert_eq!(topology.get_host_power_source(), "packages");
+        topology.set_host_power_meter(Arc::new(ConstantPowerMeter {}));
+        assert_eq!(topology.get_host_power_source(), "meter");
+        topology.record_buffer.push(Record::new(
+            current_system_time_since_epoch() - Duration::from_secs(10),
+            String::from("0"),
+            units::Unit::MicroJoule,
+        ));
+        // the sockets, without records, are not summed up
+        topology.refresh_record();
+        let energy = topology.record_buffer[1].value.parse::<u64>().unwrap();
+        assert!((20_000_000..20_100_000).contains(&energy));
+    }
+
+    #[test]
+    fn cpu_temperatures_from_hwmon() {
+        let dir =
+            std::env::temp_dir().join(format!("scaphandre_test_hwmon_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let coretemp = dir.join("hwmon1");
+        let other = dir.join("hwmon0");
+        fs::create_dir_all(&coretemp).unwrap();
+        fs::create_dir_all(&other).unwrap();
+        fs::write(other.join("name"), "acpitz\n").unwrap();
+        fs::write(other.join("temp1_input"), "27800\n").unwrap();
+        fs::write(coretemp.join("name"), "coretemp\n").unwrap();
+        fs::write(coretemp.join("temp1_label"), "Package id 0\n").unwrap();
+        fs::write(coretemp.join("temp1_input"), "45000\n").unwrap();
+        fs::write(coretemp.join("temp2_label"), "Core 0\n").unwrap();
+        fs::write(coretemp.join("temp2_input"), "43000\n").unwrap();
+        let temperatures = read_cpu_temperatures_from(dir.to_str().unwrap()).unwrap();
+        assert_eq!(
+            temperatures,
+            vec![CPUTemperature {
+                socket_id: Some(0),
+                sensor: String::from("coretemp Package id 0"),
+                celsius: 45.0,
+            }]
+        );
+
+        fs::write(coretemp.join("name"), "k10temp\n").unwrap();
+        fs::write(coretemp.join("temp1_label"), "Tctl\n").unwrap();
+        let temperatures = read_cpu_temperatures_from(dir.to_str().unwrap()).unwrap();
+        assert_eq!(temperatures.len(), 2);
+        assert!(temperatures.iter().all(|t| t.socket_id.is_none()));
+
+        assert_eq!(read_cpu_temperatures_from("/nonexistent/hwmon"), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn socket_throttled_when_power_clamped() {
+        let dir = std::env::temp_dir().join(format!(
+            "scaphandre_test_power_limit_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("constraint_0_power_limit_uw"), "10000000\n").unwrap();
+        fs::write(dir.join("enabled"), "1\n").unwrap();
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(
+            String::from("power_limit_file"),
+            String::from(dir.join("constraint_0_power_limit_uw").to_str().unwrap()),
+        );
+        sensor_data.insert(
+            String::from("power_limit_enabled_file"),
+            String::from(dir.join("enabled").to_str().unwrap()),
+        );
+        let mut socket = CPUSocket::new(0, vec![], vec![], String::from(""), 1, sensor_data);
+        assert_eq!(socket.get_power_limit_microwatts(), Some(10_000_000));
+
+        let now = current_system_time_since_epoch();
+        for (timestamp, value) in [(now - Duration::from_secs(1), "0"), (now, "9900000")] {
+            socket.record_buffer.push(Record::new(
+                timestamp,
+                String::from(value),
+                units::Unit::MicroJoule,
+            ));
+        }
+        socket.refresh_throttling(dir.to_str().unwrap());
+        assert_eq!(socket.throttled, Some(true));
+
+        fs::write(dir.join("enabled"), "0\n").unwrap();
+        assert_eq!(socket.get_power_limit_microwatts(), None);
+        socket.refresh_throttling(dir.to_str().unwrap());
+        assert_eq!(socket.throttled, None);
+    }
+
+    #[test]
+    fn socket_power_fraction_of_limit() {
+        let dir = std::env::temp_dir().join(format!(
+            "scaphandre_test_power_fraction_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("constraint_0_power_limit_uw"), "8000000\n").unwrap();
+        fs::write(dir.join("constraint_0_max_power_uw"), "20000000\n").unwrap();
+        fs::write(dir.join("enabled"), "1\n").unwrap();
+        let mut sensor_data = HashMap::new();
+        for (key, file) in [
+            ("power_limit_file", "constraint_0_power_limit_uw"),
+            ("power_max_file", "constraint_0_max_power_uw"),
+            ("power_limit_enabled_file", "enabled"),
+        ] {
+            sensor_data.insert(
+                String::from(key),
+                String::from(dir.join(file).to_str().unwrap()),
+            );
+        }
+        let mut socket = CPUSocket::new(0, vec![], vec![], String::from(""), 1, sensor_data);
+        assert_eq!(socket.get_power_fraction_of_limit(), None);
+
+        let now = current_system_time_since_epoch();
+        for (timestamp, value) in [(now - Duration::from_secs(1), "0"), (now, "4000000")] {
+            socket.record_buffer.push(Record::new(
+                timestamp,
+                String::from(value),
+                units::Unit::MicroJoule,
+            ));
+        }
+        assert_eq!(socket.get_power_fraction_of_limit(), Some(0.5));
+        // no active limit, relative to the maximum one
+        fs::write(dir.join("enabled"), "0\n").unwrap();
+        assert_eq!(socket.get_power_fraction_of_limit(), Some(0.2));
+        fs::remove_file(dir.join("constraint_0_max_power_uw")).unwrap();
+        assert_eq!(socket.get_power_fraction_of_limit(), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn record_typed_values() {
+        let record = Record::new(
+            Duration::ZERO,
+            String::from("1500\n"),
+            units::Unit::MicroWatt,
+        );
+        assert_eq!(record.value_u64(), Some(1500));
+        assert_eq!(record.value_f64(), Some(1500.0));
+        let record = Record::new(
+            Duration::ZERO,
+            String::from("0.25"),
+            units::Unit::Percentage,
+        );
+        assert_eq!(record.value_u64(), None);
+        assert_eq!(record.value_f64(), Some(0.25));
+    }
+
+    #[test]
+    fn snapshot_gathers_sockets_and_domains() {
+        let mut topology = Topology::default();
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        let now = current_system_time_since_epoch();
+        let socket = &mut topology.get_sockets()[0];
+        socket.domains.push(Domain::new(
+            1,
+            String::from("dram"),
+            String::new(),
+            1,
+            HashMap::new(),
+        ));
+        for (timestamp, value) in [(now - Duration::from_secs(1), "1000"), (now, "3000")] {
+            socket.record_buffer.push(Record::new(
+                timestamp,
+                String::from(value),
+                units::Unit::MicroJoule,
+            ));
+        }
+        topology.record_buffer = topology.get_sockets()[0].record_buffer.clone();
+
+        let snapshot = topology.snapshot();
+        assert_eq!(snapshot.timestamp, now.as_secs_f64());
+        assert_eq!(snapshot.host.energy_microjoules, Some(3000));
+        assert_eq!(snapshot.host.power_microwatts, Some(2000));
+        assert_eq!(snapshot.sockets.len(), 1);
+        assert_eq!(snapshot.sockets[0].energy_microjoules, Some(3000));
+        let domain = &snapshot.sockets[0].domains[0];
+        assert_eq!((domain.id, domain.name.as_str()), (1, "dram"));
+        assert_eq!(domain.power_microwatts, None);
+    }
+
+    #[test]
+    fn observers_notified_after_refresh() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let mut topology = Topology::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let calls = calls.clone();
+            topology.add_refresh_observer(Arc::new(move |snapshot: &TopologySnapshot| {
+                assert!(snapshot.sockets.is_empty());
+                calls.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        topology.refresh();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        // clones keep the observers
+        topology.clone().refresh();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn core_counts_from_attributes() {
+        let mut topology = Topology::default();
+        for socket_id in 0..2 {
+            topology.safe_add_socket(socket_id, vec![], vec![], String::new(), 1, HashMap::new());
+        }
+        // two sockets of two physical cores with two hardware threads each
+        for id in 0..8_u16 {
+            let mut attributes = HashMap::new();
+            attributes.insert(String::from("core id"), ((id / 2) % 2).to_string());
+            let socket = &mut topology.get_sockets()[(id / 4) as usize];
+            socket.add_cpu_core(CPUCore::new(id, attributes));
+        }
+        assert_eq!(topology.logical_core_count(), 8);
+        assert_eq!(topology.physical_core_count(), 4);
+        let cores = topology.get_core_socket_map();
+        assert_eq!(cores.get(&3), Some(&0));
+        assert_eq!(cores.get(&4), Some(&1));
+        assert!(topology.get_power_per_core_microwatts().is_none());
+    }
+
+    #[test]
+    fn cpu_time_attributed_by_basis() {
+        let stat = CPUStat {
+            user: 60,
+            nice: 10,
+            system: 40,
+            idle: 100,
+            irq: None,
+            iowait: None,
+            softirq: None,
+            steal: None,
+            guest: None,
+            guest_nice: None,
+        };
+        let basis = AttributionBasis::default();
+        assert_eq!(
+            stat.attributed_time_jiffies(&basis),
+            stat.total_time_jiffies() as f64
+        );
+        assert_eq!(
+            stat.attributed_time_jiffies(&AttributionBasis::UserTime),
+            70.0
+        );
+        let weighted = AttributionBasis::from_name("weighted", 0.5).unwrap();
+        assert_eq!(weighted.as_str(), "weighted");
+        assert_eq!(stat.attributed_time_jiffies(&weighted), 90.0);
+        assert!(AttributionBasis::from_name("weighted", -1.0).is_err());
+        assert!(AttributionBasis::from_name("stime", 1.0).is_err());
+    }
+
+    #[test]
+    fn socket_power_smoothed_with_ewma() {
+        let mut ewma = Ewma::new(0.5).unwrap();
+        assert_eq!(ewma.get(), None);
+        assert_eq!(ewma.update(10.0), 10.0);
+        assert_eq!(ewma.update(20.0), 15.0);
+        assert!(Ewma::new(0.0).is_err());
+        assert!(Ewma::new(1.5).is_err());
+
+        let mut topology = Topology::default();
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        assert!(topology.enable_power_smoothing(2.0).is_err());
+        topology.enable_power_smoothing(0.25).unwrap();
+        let socket = &mut topology.get_sockets()[0];
+        // 1 then 5 joules consumed during each second
+        for (secs, microjoules) in [(0, "0"), (1, "1000000"), (2, "6000000")] {
+            socket.record_buffer.push(Record::new(
+                Duration::from_secs(secs),
+                String::from(microjoules),
+                units::Unit::MicroJoule,
+            ));
+            socket.refresh_power_smoothing();
+        }
+        let raw = socket.get_records_diff_power_microwatts_value().unwrap();
+        assert_eq!(raw, 5000000);
+        let smoothed = socket.get_smoothed_power_microwatts().unwrap();
+        assert_eq!(smoothed.value, "2000000");
+    }
+
+    #[test]
+    fn power_computed_over_the_actual_interval() {
+        let mut topology = Topology::default();
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        let socket = &mut topology.get_sockets()[0];
+        // refreshed 2.5 seconds apart instead of 1
+        for (millis, microjoules) in [(1000, "0"), (3500, "5000000")] {
+            socket.record_buffer.push(Record::new(
+                Duration::from_millis(millis),
+                String::from(microjoules),
+                units::Unit::MicroJoule,
+            ));
+        }
+        assert_eq!(
+            socket.get_records_diff_interval(),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(
+            socket.get_records_diff_power_microwatts_value(),
+            Some(2000000)
+        );
+        // no power rather than an infinite one if the clock didn't move forward
+        socket.record_buffer.push(Record::new(
+            Duration::from_millis(3000),
+            String::from("6000000"),
+            units::Unit::MicroJoule,
+        ));
+        assert_eq!(socket.get_records_diff_interval(), None);
+        assert!(socket.get_records_diff_power_microwatts().is_none());
+    }
+
+    #[test]
+    fn power_extremes_tracked_until_reset() {
+        let mut extremes = PowerExtremes::default();
+        assert_eq!(extremes.get_max_microwatts(), None);
+        for microwatts in [20, 10, 30] {
+            extremes.update(microwatts);
+        }
+        assert_eq!(extremes.get_min_microwatts(), Some(10));
+        assert_eq!(extremes.get_max_microwatts(), Some(30));
+
+        let mut topology = Topology::default();
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        let socket = &mut topology.get_sockets()[0];
+        // 1, 5 then 2 joules consumed during each second
+        for (secs, microjoules) in [(0, "0"), (1, "1000000"), (2, "6000000"), (3, "8000000")] {
+            socket.record_buffer.push(Record::new(
+                Duration::from_secs(secs),
+                String::from(microjoules),
+                units::Unit::MicroJoule,
+            ));
+            socket.refresh_power_extremes();
+        }
+        assert_eq!(
+            socket.get_power_extremes().get_min_microwatts(),
+            Some(1000000)
+        );
+        assert_eq!(
+            socket.get_power_extremes().get_max_microwatts(),
+            Some(5000000)
+        );
+        topology.host_power_extremes.update(5000000);
+        topology.reset_power_extremes();
+        assert_eq!(
+            topology.get_host_power_extremes(),
+            &PowerExtremes::default()
+        );
+        assert_eq!(
+            topology.sockets[0]
+                .get_power_extremes()
+                .get_max_microwatts(),
+            None
+        );
+    }
+
+    #[test]
+    fn host_info_gathered_once() {
+        let mut topology = Topology::default();
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            String::from("model name"),
+            String::from("Intel(R) Core(TM) i7-8665U CPU @ 1.90GHz "),
+        );
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        topology.sockets[0]
+            .cpu_cores
+            .push(CPUCore::new(0, attributes));
+        let info = topology.get_host_info();
+        assert_eq!(info.cpu_model, "Intel(R) Core(TM) i7-8665U CPU @ 1.90GHz");
+        assert_eq!(info.sockets, 1);
+        topology.safe_add_socket(1, vec![], vec![], String::new(), 1, HashMap::new());
+        assert_eq!(topology.get_host_info(), info);
+    }
+
+    #[test]
+    fn domains_power_ratios_of_socket() {
+        let energy = |secs, microjoules: &str| {
+            Record::new(
+                Duration::from_secs(secs),
+                String::from(microjoules),
+                units::Unit::MicroJoule,
+            )
+        };
+        let mut topology = Topology::default();
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        let socket = &mut topology.get_sockets()[0];
+        assert!(socket.get_domains_power_ratios().is_empty());
+        socket.record_buffer = vec![energy(0, "0"), energy(1, "4000000")];
+        assert_eq!(
+            socket.get_domains_power_ratios(),
+            vec![(String::from(PACKAGE_DOMAIN_NAME), 1.0)]
+        );
+        for (id, name, microjoules) in [(0, "core", "3000000"), (1, "dram", "1000000")] {
+            let mut domain = Domain::new(id, String::from(name), String::new(), 1, HashMap::new());
+            domain.record_buffer = vec![energy(0, "0"), energy(1, microjoules)];
+            socket.domains.push(domain);
+        }
+        assert_eq!(
+            socket.get_domains_power_ratios(),
+            vec![(String::from("core"), 0.75), (String::from("dram"), 0.25)]
+        );
+    }
+
+    #[test]
+    fn small_processes_aggregated_under_min_power() {
+        let mut topology = Topology::default();
+        let power = |microwatts: &str, secs| {
+            Record::new(
+                Duration::from_secs(secs),
+                String::from(microwatts),
+                units::Unit::MicroWatt,
+            )
+        };
+        assert!(!topology.is_under_min_process_power(&power("10", 1)));
+        topology.set_min_process_power_microwatts(1000);
+        let mut other = OtherProcessesPower::default();
+        assert!(other.to_record().is_none());
+        for (microwatts, secs) in [("10", 2), ("999", 1), ("1000", 3)] {
+            let power = power(microwatts, secs);
+            if topology.is_under_min_process_power(&power) {
+                other.add(&power);
+            }
+        }
+        assert_eq!(other.processes, 2);
+        let record = other.to_record().unwrap();
+        assert_eq!(record.value, "1009");
+        assert_eq!(record.timestamp, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn process_power_cached_until_refresh() {
+        let mut topology = Topology::default();
+        let energy = |secs, microjoules: &str| {
+            Record::new(
+                Duration::from_secs(secs),
+                String::from(microjoules),
+                units::Unit::MicroJoule,
+            )
+        };
+        topology.record_buffer.push(energy(0, "0"));
+        topology.record_buffer.push(energy(1, "1000000"));
+        for _ in 0..2 {
+            topology.refresh_procs();
+            topology.refresh_stats();
+            // keep the CPU busy so that scaphandre gets a share of the power
+            let start = std::time::Instant::now();
+            while start.elapsed() < Duration::from_millis(100) {}
+        }
+        let pid = std::process::id() as i32;
+        let power = topology.get_process_power_consumption_microwatts_value(pid);
+        assert!(power.is_some());
+        // the host consumes 10 times more, but the power of processes was computed already
+        topology.record_buffer.push(energy(2, "11000000"));
+        assert_eq!(
+            topology.get_process_power_consumption_microwatts_value(pid),
+            power
+        );
+        topology.invalidate_process_power_cache();
+        assert!(topology.get_process_power_consumption_microwatts_value(pid) > power);
+    }
+
+    #[test]
+    fn negative_energy_diff_counted_as_anomaly() {
+        let mut topology = Topology::default();
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        let now = current_system_time_since_epoch();
+        for value in ["3000", "1000"] {
+            topology.get_sockets()[0].record_buffer.push(Record::new(
+                now,
+                String::from(value),
+                units::Unit::MicroJoule,
+            ));
+        }
+        topology.check_anomalies();
+        assert_eq!(topology.anomaly_detector.get_count(NEGATIVE_ENERGY_DIFF), 1);
+
+        // a wrap of a counter whose range is known is not an anomaly
+        topology.get_sockets()[0]
+            .sensor_data
+            .insert(String::from("max_energy_range_uj"), String::from("4000"));
+        topology.check_anomalies();
+        assert_eq!(topology.anomaly_detector.get_count(NEGATIVE_ENERGY_DIFF), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn counters_parsed_from_proc_stat() {
+        let content =
+            "cpu  10 0 20 300 0 0 0 0 0 0\nintr 123456 42 0 7\nctxt 98765\nbtime 1600000000\n";
+        assert_eq!(parse_proc_stat_counter(content, "intr"), Some(123456));
+        assert_eq!(parse_proc_stat_counter(content, "ctxt"), Some(98765));
+        assert_eq!(parse_proc_stat_counter(content, "softirq"), None);
+    }
+
+    #[test]
+    fn core_die_read_from_sysfs_root() {
+        let sysfs_root =
+            std::env::temp_dir().join(format!("scaphandre_test_die_id_{}", std::process::id()));
+        let topology = sysfs_root.join("devices/system/cpu/cpu3/topology");
+        fs::create_dir_all(&topology).unwrap();
+        fs::write(topology.join("die_id"), "1\n").unwrap();
+        let root = sysfs_root.to_str().unwrap();
+        assert_eq!(read_core_die_id(root, 3), Some(1));
+        assert_eq!(read_core_die_id(root, 4), None);
+        fs::remove_dir_all(&sysfs_root).unwrap();
+    }
+
+    #[test]
+    fn buffers_evicted_oldest_first_at_cap() {
+        let mut topology = Topology::default();
+        topology.safe_add_socket(0, vec![], vec![], String::new(), 1, HashMap::new());
+        let now = current_system_time_since_epoch();
+        let socket = &mut topology.get_sockets()[0];
+        for second in 0..100 {
+            socket.record_buffer.push(Record::new(
+                now + Duration::from_secs(second),
+                second.to_string(),
+                units::Unit::MicroJoule,
+            ));
         }
+        let size = topology.get_buffers_size_bytes();
+        assert_eq!(size, 100 * size_of::<Record>());
+        // no cap, nothing evicted
+        assert_eq!(topology.enforce_buffers_max_size(), 0);
+
+        topology.set_buffers_max_kbytes(1);
+        let evicted = topology.enforce_buffers_max_size();
+        assert!(evicted > 0);
+        assert_eq!(topology.get_buffers_evictions(), evicted as u64);
+        assert!(topology.get_buffers_size_bytes() <= 1000);
+        let records = &topology.get_sockets_passive()[0].record_buffer;
+        assert_eq!(records.len(), 100 - evicted);
+        assert_eq!(records.last().unwrap().value, "99");
+
+        // the last records are kept even if they don't fit
+        topology.set_buffers_max_kbytes(0);
+        topology.enforce_buffers_max_size();
+        assert_eq!(topology.get_sockets_passive()[0].record_buffer.len(), 2);
     }
 }
 
@@ -1,5 +1,7 @@
 use crate::sensors::utils::current_system_time_since_epoch;
-use crate::sensors::{CPUSocket, Domain, Record, RecordReader, Sensor, Topology};
+use crate::sensors::{
+    CPUSocket, Domain, Record, RecordReader, Sensor, Topology, ENERGY_RESOLUTION_KEY,
+};
 use std::collections::HashMap;
 use std::error::Error;
 use std::mem::size_of;
@@ -283,12 +285,21 @@ impl RecordReader for Domain {
 }
 
 impl Sensor for MsrRAPLSensor {
+    fn get_name(&self) -> String {
+        String::from("msr_rapl")
+    }
+
     fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
         let mut sensor_data = HashMap::new();
         sensor_data.insert(String::from("DRIVER_NAME"), self.driver_name.clone());
         sensor_data.insert(String::from("ENERGY_UNIT"), self.energy_unit.to_string());
         sensor_data.insert(String::from("POWER_UNIT"), self.power_unit.to_string());
         sensor_data.insert(String::from("TIME_UNIT"), self.time_unit.to_string());
+        // energy units are in joules
+        sensor_data.insert(
+            String::from(ENERGY_RESOLUTION_KEY),
+            (self.energy_unit * 1000000.0).to_string(),
+        );
 
         let mut topology = Topology::new(sensor_data.clone());
         let mut sys = System::new_all();
@@ -300,6 +311,16 @@ impl Sensor for MsrRAPLSensor {
         Ok(topology)
     }
 
+    /// Checks that the scaphandre driver can be opened.
+    fn probe(&self) -> Result<(), String> {
+        unsafe {
+            let device = get_handle(&self.driver_name)
+                .map_err(|e| format!("couldn't open driver {}: {e}", self.driver_name))?;
+            close_handle(device);
+        }
+        Ok(())
+    }
+
     fn get_topology(&mut self) -> Box<Option<Topology>> {
         let topology = self.generate_topology().ok();
         if topology.is_none() {
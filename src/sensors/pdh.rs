@@ -0,0 +1,241 @@
+//! # PDH
+//!
+//! Reads the CPU time of processes on Windows from the performance counters
+//! (PDH), procfs not being available there. The share of the busy CPU time
+//! used by each process gives its share of the host power, like the jiffies
+//! read from procfs on Linux.
+use std::collections::HashMap;
+use std::mem::size_of;
+use windows::Win32::Foundation::PWSTR;
+use windows::Win32::System::Performance::{
+    PdhAddEnglishCounterW, PdhCloseQuery, PdhCollectQueryData, PdhGetFormattedCounterArrayW,
+    PdhOpenQueryW, PDH_FMT_COUNTERVALUE_ITEM_W, PDH_FMT_DOUBLE,
+};
+
+/// Status returned by the PDH functions on success.
+const PDH_OK: i32 = 0;
+/// Status returned by PdhGetFormattedCounterArrayW when the buffer is too small.
+const PDH_MORE_DATA: i32 = 0x800007D2_u32 as i32;
+/// Status of a counter value that can be used, new or not since the last collection.
+const PDH_CSTATUS_VALID_DATA: u32 = 0;
+const PDH_CSTATUS_NEW_DATA: u32 = 1;
+
+/// Time spent by each process on the CPUs, 100 meaning a whole core.
+const PROCESSOR_TIME_COUNTER: &str = "\\Process(*)\\% Processor Time";
+/// Pid of each process instance.
+const PROCESS_ID_COUNTER: &str = "\\Process(*)\\ID Process";
+/// Process counters instance summing all the processes.
+const TOTAL_INSTANCE: &str = "_Total";
+/// Process counters instance of the idle time.
+const IDLE_INSTANCE: &str = "Idle";
+
+/// CPU usage of the processes between the last two collections.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessesCpuUsage {
+    /// Processor time used by each process, by pid, in % of a core.
+    pub processes: HashMap<i32, f64>,
+    /// Processor time used by all the processes but the idle one, in % of a core.
+    pub busy: f64,
+}
+
+impl ProcessesCpuUsage {
+    /// Returns the share of the busy CPU time used by process `pid`, in %.
+    pub fn get_share_percentage(&self, pid: i32) -> Option<f64> {
+        let usage = self.processes.get(&pid)?;
+        if self.busy > 0.0 {
+            Some((usage / self.busy * 100.0).min(100.0))
+        } else {
+            Some(0.0)
+        }
+    }
+}
+
+/// PDH query reading the processor time and pid of every process.
+#[derive(Debug)]
+pub struct PdhProcessCounters {
+    query: isize,
+    processor_time: isize,
+    process_id: isize,
+}
+
+impl PdhProcessCounters {
+    /// Opens the PDH query and adds the process counters to it.
+    pub fn new() -> Result<PdhProcessCounters, String> {
+        let mut counters = PdhProcessCounters {
+            query: 0,
+            processor_time: 0,
+            process_id: 0,
+        };
+        unsafe {
+            check(
+                PdhOpenQueryW(PWSTR::default(), 0, &mut counters.query),
+                "PdhOpenQueryW",
+            )?;
+            // from now on, the query is closed when counters is dropped
+            check(
+                PdhAddEnglishCounterW(
+                    counters.query,
+                    PROCESSOR_TIME_COUNTER,
+                    0,
+                    &mut counters.processor_time,
+                ),
+                PROCESSOR_TIME_COUNTER,
+            )?;
+            check(
+                PdhAddEnglishCounterW(
+                    counters.query,
+                    PROCESS_ID_COUNTER,
+                    0,
+                    &mut counters.process_id,
+                ),
+                PROCESS_ID_COUNTER,
+            )?;
+            // rates need two collections, the first one gives the reference
+            check(PdhCollectQueryData(counters.query), "PdhCollectQueryData")?;
+        }
+        Ok(counters)
+    }
+
+    /// Collects the counters and returns the CPU usage of the processes since
+    /// the previous collection.
+    pub fn collect(&self) -> Result<ProcessesCpuUsage, String> {
+        let (pids, times) = unsafe {
+            check(PdhCollectQueryData(self.query), "PdhCollectQueryData")?;
+            (
+                read_counter_array(self.process_id)?,
+                read_counter_array(self.processor_time)?,
+            )
+        };
+        let mut usage = ProcessesCpuUsage::default();
+        let mut total = 0.0;
+        let mut idle = 0.0;
+        for (instance, time) in times {
+            match instance.as_str() {
+                TOTAL_INSTANCE => total = time,
+                IDLE_INSTANCE => idle = time,
+                _ => {
+                    if let Some(pid) = pids.get(&instance) {
+                        usage.processes.insert(*pid as i32, time);
+                    }
+                }
+            }
+        }
+        usage.busy = (total - idle).max(0.0);
+        Ok(usage)
+    }
+}
+
+impl Drop for PdhProcessCounters {
+    fn drop(&mut self) {
+        if self.query != 0 {
+            unsafe {
+                PdhCloseQuery(self.query);
+            }
+        }
+    }
+}
+
+/// Returns an error naming `what` if `status` isn't a success.
+fn check(status: i32, what: &str) -> Result<(), String> {
+    if status == PDH_OK {
+        Ok(())
+    } else {
+        Err(format!("{what} failed with status {:#x}", status as u32))
+    }
+}
+
+/// Returns the value of each instance of `counter`, by instance name, skipping
+/// the instances whose value isn't valid.
+///
+/// # Safety
+///
+/// Unsafe code due to direct calls to Windows API.
+unsafe fn read_counter_array(counter: isize) -> Result<HashMap<String, f64>, String> {
+    let mut buffer_size = 0u32;
+    let mut count = 0u32;
+    let status = PdhGetFormattedCounterArrayW(
+        counter,
+        PDH_FMT_DOUBLE,
+        &mut buffer_size,
+        &mut count,
+        std::ptr::null_mut(),
+    );
+    if status != PDH_MORE_DATA {
+        check(status, "PdhGetFormattedCounterArrayW")?;
+        return Ok(HashMap::new());
+    }
+    // the buffer holds the items followed by their names
+    let item_size = size_of::<PDH_FMT_COUNTERVALUE_ITEM_W>();
+    let mut items: Vec<PDH_FMT_COUNTERVALUE_ITEM_W> =
+        Vec::with_capacity(buffer_size as usize / item_size + 1);
+    check(
+        PdhGetFormattedCounterArrayW(
+            counter,
+            PDH_FMT_DOUBLE,
+            &mut buffer_size,
+            &mut count,
+            items.as_mut_ptr(),
+        ),
+        "PdhGetFormattedCounterArrayW",
+    )?;
+    items.set_len(count as usize);
+    let mut values = HashMap::new();
+    for item in &items {
+        let status = item.FmtValue.CStatus;
+        if status == PDH_CSTATUS_VALID_DATA || status == PDH_CSTATUS_NEW_DATA {
+            values.insert(
+                wide_to_string(item.szName.0),
+                item.FmtValue.Anonymous.doubleValue,
+            );
+        }
+    }
+    Ok(values)
+}
+
+/// Returns the string of a null terminated UTF-16 pointer.
+///
+/// # Safety
+///
+/// `ptr` must point to a null terminated string, or be null.
+unsafe fn wide_to_string(ptr: *const u16) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    String::from_utf16_lossy(std::slice::from_raw_parts(ptr, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_of_busy_cpu_time() {
+        let mut usage = ProcessesCpuUsage {
+            processes: HashMap::new(),
+            busy: 150.0,
+        };
+        usage.processes.insert(42, 75.0);
+        assert_eq!(usage.get_share_percentage(42), Some(50.0));
+        assert_eq!(usage.get_share_percentage(43), None);
+        usage.busy = 0.0;
+        assert_eq!(usage.get_share_percentage(42), Some(0.0));
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,230 @@
+//! # Perf
+//!
+//! The perf module counts the instructions retired by each CPU with the
+//! `instructions` hardware counter of perf_event, to give the energy spent per
+//! instruction with the power measured by RAPL. Counting the instructions of
+//! all the processes of a CPU needs CAP_PERFMON (or CAP_SYS_ADMIN), or
+//! `kernel.perf_event_paranoid` set to 0 or less. The counter is often not
+//! available in virtual machines.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// perf_event type of the generalized hardware events.
+const PERF_TYPE_HARDWARE: u32 = 0;
+/// Hardware event counting retired instructions.
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+/// `exclude_hv` bit of the flags of perf_event_attr: the hypervisor isn't counted.
+const PERF_ATTR_FLAG_EXCLUDE_HV: u64 = 1 << 6;
+
+/// Attributes of a perf event, as `struct perf_event_attr` of
+/// `linux/perf_event.h` (version 5, 112 bytes).
+#[repr(C)]
+#[derive(Debug, Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+/// Opens a counter of the instructions retired by `cpu`, whatever the process.
+fn open_instructions_counter(cpu: u16) -> Result<File, String> {
+    let attr = PerfEventAttr {
+        type_: PERF_TYPE_HARDWARE,
+        size: std::mem::size_of::<PerfEventAttr>() as u32,
+        config: PERF_COUNT_HW_INSTRUCTIONS,
+        flags: PERF_ATTR_FLAG_EXCLUDE_HV,
+        ..Default::default()
+    };
+    // pid -1 and a cpu: all the processes running on this cpu
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &attr as *const PerfEventAttr,
+            -1 as libc::pid_t,
+            cpu as libc::c_int,
+            -1 as libc::c_int,
+            0 as libc::c_ulong,
+        )
+    };
+    if fd < 0 {
+        return Err(format!(
+            "couldn't open the instructions counter of CPU {cpu}: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(unsafe { File::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Reads the value of a counter opened by open_instructions_counter.
+fn read_counter(mut counter: &File) -> io::Result<u64> {
+    let mut value = [0; 8];
+    counter.read_exact(&mut value)?;
+    Ok(u64::from_ne_bytes(value))
+}
+
+/// Counts the instructions retired by CPUs between two refreshes.
+#[derive(Debug, Clone)]
+pub struct InstructionsCounter {
+    /// Counter of each CPU.
+    counters: Vec<(u16, Arc<File>)>,
+    /// Value of the counter of each CPU at the last refresh.
+    last_counts: HashMap<u16, u64>,
+    last_refresh: Option<Instant>,
+    /// Instructions retired per second by each CPU between the last two refreshes.
+    rates: HashMap<u16, f64>,
+}
+
+impl InstructionsCounter {
+    /// Opens the instructions counters of `cpus`. Returns an error if one of
+    /// them can't be opened, as when perf events are not permitted.
+    pub fn new(cpus: &[u16]) -> Result<InstructionsCounter, String> {
+        if cpus.is_empty() {
+            return Err(String::from("no CPU to count the instructions of"));
+        }
+        let mut counters = vec![];
+        for cpu in cpus {
+            let counter = open_instructions_counter(*cpu).map_err(|e| {
+                format!(
+                    "{e} (it needs CAP_PERFMON, or kernel.perf_event_paranoid set to 0 or less)"
+                )
+            })?;
+            counters.push((*cpu, Arc::new(counter)));
+        }
+        Ok(InstructionsCounter {
+            counters,
+            last_counts: HashMap::new(),
+            last_refresh: None,
+            rates: HashMap::new(),
+        })
+    }
+
+    /// Reads the counters and updates the instructions rates of the CPUs.
+    pub fn refresh(&mut self) {
+        let now = Instant::now();
+        let mut counts = HashMap::new();
+        for (cpu, counter) in &self.counters {
+            match read_counter(counter) {
+                Ok(count) => {
+                    counts.insert(*cpu, count);
+                }
+                Err(e) => warn!(
+                    "Couldn't read the instructions counter of CPU {}: {}",
+                    cpu, e
+                ),
+            }
+        }
+        if let Some(last_refresh) = self.last_refresh {
+            self.rates = compute_rates(
+                &self.last_counts,
+                &counts,
+                now.duration_since(last_refresh).as_secs_f64(),
+            );
+        }
+        self.last_counts = counts;
+        self.last_refresh = Some(now);
+    }
+
+    /// Returns the instructions retired per second by `cpus` between the last
+    /// two refreshes, or None if none of them is counted yet.
+    pub fn get_instructions_per_second(&self, cpus: &[u16]) -> Option<f64> {
+        let rates: Vec<f64> = cpus
+            .iter()
+            .filter_map(|cpu| self.rates.get(cpu).copied())
+            .collect();
+        if rates.is_empty() {
+            return None;
+        }
+        Some(rates.iter().sum())
+    }
+
+    /// Returns the instructions retired per second by all the counted CPUs.
+    pub fn get_total_instructions_per_second(&self) -> Option<f64> {
+        let cpus: Vec<u16> = self.counters.iter().map(|(cpu, _)| *cpu).collect();
+        self.get_instructions_per_second(&cpus)
+    }
+}
+
+/// Returns the instructions retired per second by each CPU, from the counts
+/// read `seconds` apart. A counter that went backwards is skipped.
+fn compute_rates(
+    previous: &HashMap<u16, u64>,
+    last: &HashMap<u16, u64>,
+    seconds: f64,
+) -> HashMap<u16, f64> {
+    if seconds <= 0.0 {
+        return HashMap::new();
+    }
+    last.iter()
+        .filter_map(|(cpu, count)| {
+            let diff = count.checked_sub(*previous.get(cpu)?)?;
+            Some((*cpu, diff as f64 / seconds))
+        })
+        .collect()
+}
+
+/// Returns the energy spent per instruction, in picojoules, from the power of
+/// the CPUs in microwatts and the instructions they retired per second.
+pub fn picojoules_per_instruction(
+    power_microwatts: f64,
+    instructions_per_second: f64,
+) -> Option<f64> {
+    if instructions_per_second <= 0.0 {
+        return None;
+    }
+    // 1 µW = 1e6 pJ/s
+    Some(power_microwatts * 1e6 / instructions_per_second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn energy_per_instruction_computed() {
+        assert_eq!(std::mem::size_of::<PerfEventAttr>(), 112);
+        let previous = HashMap::from([(0, 1_000), (1, 5_000)]);
+        let last = HashMap::from([(0, 3_000), (1, 4_000), (2, 10)]);
+        // cpu 1 went backwards and cpu 2 wasn't counted before
+        assert_eq!(
+            compute_rates(&previous, &last, 2.0),
+            HashMap::from([(0, 1_000.0)])
+        );
+        // 10 W for 2e10 instructions per second: 500 pJ per instruction
+        assert_eq!(picojoules_per_instruction(10_000_000.0, 2e10), Some(500.0));
+        assert_eq!(picojoules_per_instruction(10_000_000.0, 0.0), None);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -1,19 +1,57 @@
+use crate::sensors::diagnostics::DiagnosticCheck;
 use crate::sensors::units::Unit::MicroJoule;
 use crate::sensors::utils::current_system_time_since_epoch;
-use crate::sensors::{CPUSocket, Domain, Record, RecordReader, Sensor, Topology};
+use crate::sensors::{
+    CPUSocket, Domain, Record, RecordReader, Sensor, Topology, ENERGY_RESOLUTION_KEY,
+};
 use procfs::{modules, KernelModule};
 use regex::Regex;
 use std::collections::HashMap;
 use std::error::Error;
-use std::{env, fs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, io, thread};
+
+/// Folder where a virtual machine finds the energy counters computed for it by
+/// the qemu exporter running on the hypervisor, in the same layout as powercap:
+/// `intel-rapl:0/energy_uj`. This is where the folder shared by the hypervisor
+/// should be mounted in the guest. It can be changed with the
+/// SCAPHANDRE_POWERCAP_PATH environment variable.
+pub const GUEST_POWERCAP_PATH: &str = "/var/scaphandre";
+
+/// Default root of sysfs, holding the powercap folder in `class/powercap`.
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys";
+
+/// Name of the RAPL zone measuring the whole platform (SoC, and on some
+/// laptops more of the device) rather than a package.
+const PSYS_ZONE_NAME: &str = "psys";
+
+/// Resolution of the energy counters of powercap, in microjoules: the driver
+/// converts the RAPL energy units to microjoules in energy_uj.
+const POWERCAP_ENERGY_RESOLUTION_UJ: &str = "1";
+
+/// Time waited between the two reads of the energy counters when checking
+/// that they increase, in the self-test.
+const SELF_TEST_INTERVAL: Duration = Duration::from_millis(250);
 
 /// This is a Sensor type that relies on powercap and rapl linux modules
 /// to collect energy consumption from CPU sockets and RAPL domains
+#[derive(Debug, Clone)]
 pub struct PowercapRAPLSensor {
     base_path: String,
+    /// Sysfs the topology of the cores is read from.
+    sysfs_root: String,
     buffer_per_socket_max_kbytes: u16,
     buffer_per_domain_max_kbytes: u16,
     virtual_machine: bool,
+    /// True if the energy counters can't be read because they are only readable
+    /// by root: no power is reported then, rather than zeros.
+    pub energy_unavailable: bool,
+    /// Procfs processes are read from, if not /proc.
+    procfs_root: Option<String>,
+    /// True to measure the host as the sum of the packages even when a psys
+    /// zone is available.
+    package_sum: bool,
 }
 
 impl PowercapRAPLSensor {
@@ -23,9 +61,9 @@ impl PowercapRAPLSensor {
         buffer_per_domain_max_kbytes: u16,
         virtual_machine: bool,
     ) -> PowercapRAPLSensor {
-        let mut powercap_path = String::from("/sys/class/powercap");
+        let mut powercap_path = powercap_path_in(DEFAULT_SYSFS_ROOT);
         if virtual_machine {
-            powercap_path = String::from("/var/scaphandre");
+            powercap_path = String::from(GUEST_POWERCAP_PATH);
             if let Ok(val) = env::var("SCAPHANDRE_POWERCAP_PATH") {
                 powercap_path = val;
             }
@@ -35,15 +73,78 @@ impl PowercapRAPLSensor {
 
         PowercapRAPLSensor {
             base_path: powercap_path,
+            sysfs_root: String::from(DEFAULT_SYSFS_ROOT),
             buffer_per_socket_max_kbytes,
             buffer_per_domain_max_kbytes,
             virtual_machine,
+            energy_unavailable: false,
+            procfs_root: None,
+            package_sum: false,
         }
     }
 
+    /// Reads the powercap folder under `sysfs_root` (like a fixture folder
+    /// holding `class/powercap/intel-rapl:0/energy_uj`) instead of `/sys`, as
+    /// well as the dies and types of the cores.
+    pub fn with_sysfs_root(mut self, sysfs_root: &str) -> PowercapRAPLSensor {
+        self.base_path = powercap_path_in(sysfs_root);
+        self.sysfs_root = String::from(sysfs_root);
+        self
+    }
+
+    /// Returns the root of the sysfs the topology is read from.
+    pub fn get_sysfs_root(&self) -> &str {
+        &self.sysfs_root
+    }
+
+    /// Reads processes and kernel stats in `procfs_root` (like a snapshot of
+    /// `/proc` captured on another host) instead of `/proc`.
+    pub fn with_procfs_root(mut self, procfs_root: &str) -> PowercapRAPLSensor {
+        self.procfs_root = Some(String::from(procfs_root));
+        self
+    }
+
+    /// Reads the `sys` and `proc` folders of `snapshot_dir`, a snapshot of the
+    /// sysfs and procfs of a host captured or mounted there (with sshfs for
+    /// example), instead of the ones of this host.
+    pub fn with_snapshot_dir(self, snapshot_dir: &str) -> PowercapRAPLSensor {
+        let snapshot_dir = snapshot_dir.trim_end_matches('/');
+        self.with_sysfs_root(&format!("{snapshot_dir}/sys"))
+            .with_procfs_root(&format!("{snapshot_dir}/proc"))
+    }
+
+    /// Measures the host power as the sum of the packages, even on hosts
+    /// exposing a psys zone.
+    pub fn with_package_sum(mut self) -> PowercapRAPLSensor {
+        self.package_sum = true;
+        self
+    }
+
+    /// Returns true if the zone `intel-rapl:<zone_id>` is the psys zone.
+    fn is_psys_zone(&self, zone_id: u16) -> bool {
+        fs::read_to_string(format!("{}/intel-rapl:{}/name", self.base_path, zone_id))
+            .map(|name| name.trim() == PSYS_ZONE_NAME)
+            .unwrap_or(false)
+    }
+
+    /// Returns the path of the energy counter of the psys zone, if there is one.
+    fn find_psys_counter(&self) -> Option<String> {
+        let re_zone = Regex::new(r"^intel-rapl:(\d+)$").unwrap();
+        fs::read_dir(&self.base_path)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_str()?.to_string();
+                re_zone.captures(&name)?[1].parse::<u16>().ok()
+            })
+            .find(|zone_id| self.is_psys_zone(*zone_id))
+            .map(|zone_id| format!("{}/intel-rapl:{}/energy_uj", self.base_path, zone_id))
+    }
+
     /// Checks if intel_rapl modules are present and activated.
     pub fn check_module() -> Result<String, String> {
-        let modules = modules().unwrap();
+        let modules =
+            modules().map_err(|e| format!("Couldn't read the kernel modules loaded: {e}"))?;
         let rapl_modules = modules
             .iter()
             .filter(|(_, v)| {
@@ -63,6 +164,260 @@ impl PowercapRAPLSensor {
             ))
         }
     }
+
+    /// Adds to the topology the domains found in the powercap folder, and their
+    /// sockets, if not already there. Returns false if no domain folder was found.
+    fn add_domains(&self, topo: &mut Topology) -> Result<bool, io::Error> {
+        let re_domain = Regex::new(r"^.*/intel-rapl:\d+:\d+$").unwrap();
+        let mut re_domain_matched = false;
+        for folder in fs::read_dir(&self.base_path)? {
+            let folder_name = String::from(folder?.path().to_str().unwrap());
+            // let's catch domain folders
+            if re_domain.is_match(&folder_name) {
+                re_domain_matched = true;
+                // let's get the second number of the intel-rapl:X:X string
+                let mut splitted = folder_name.split(':');
+                let _ = splitted.next();
+                let socket_id = String::from(splitted.next().unwrap()).parse().unwrap();
+                let domain_id = String::from(splitted.next().unwrap()).parse().unwrap();
+                // the psys zone isn't a socket, its domains aren't measured
+                if self.is_psys_zone(socket_id) {
+                    continue;
+                }
+                self.add_socket(topo, socket_id);
+                let mut sensor_data_for_domain = HashMap::new();
+                sensor_data_for_domain.insert(
+                    String::from(ENERGY_RESOLUTION_KEY),
+                    String::from(POWERCAP_ENERGY_RESOLUTION_UJ),
+                );
+                sensor_data_for_domain.insert(
+                    String::from("source_file"),
+                    format!(
+                        "{}/intel-rapl:{}:{}/energy_uj",
+                        self.base_path, socket_id, domain_id
+                    ),
+                );
+                self.add_max_energy_range(
+                    &mut sensor_data_for_domain,
+                    &format!("{socket_id}:{domain_id}"),
+                );
+                if let Ok(domain_name) = &fs::read_to_string(format!("{folder_name}/name")) {
+                    topo.safe_add_domain_to_socket(
+                        socket_id,
+                        domain_id,
+                        domain_name.trim(),
+                        &format!(
+                            "{}/intel-rapl:{}:{}/energy_uj",
+                            self.base_path, socket_id, domain_id
+                        ),
+                        self.buffer_per_domain_max_kbytes,
+                        sensor_data_for_domain,
+                    );
+                }
+            }
+        }
+        Ok(re_domain_matched)
+    }
+
+    /// Adds to the topology the sockets found in the powercap folder, if not already there.
+    fn add_sockets(&self, topo: &mut Topology) -> Result<(), io::Error> {
+        let re_socket = Regex::new(r"^.*/intel-rapl:\d+$").unwrap();
+        for folder in fs::read_dir(&self.base_path)? {
+            let folder_name = String::from(folder?.path().to_str().unwrap());
+            if re_socket.is_match(&folder_name) {
+                let mut splitted = folder_name.split(':');
+                let _ = splitted.next();
+                let socket_id = String::from(splitted.next().unwrap()).parse().unwrap();
+                if !self.is_psys_zone(socket_id) {
+                    self.add_socket(topo, socket_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a socket to the topology, if not already there.
+    fn add_socket(&self, topo: &mut Topology, socket_id: u16) {
+        let mut sensor_data_for_socket = HashMap::new();
+        sensor_data_for_socket.insert(
+            String::from(ENERGY_RESOLUTION_KEY),
+            String::from(POWERCAP_ENERGY_RESOLUTION_UJ),
+        );
+        sensor_data_for_socket.insert(
+            String::from("source_file"),
+            format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
+        );
+        self.add_max_energy_range(&mut sensor_data_for_socket, &socket_id.to_string());
+        self.add_power_limit_files(&mut sensor_data_for_socket, socket_id);
+        topo.safe_add_socket(
+            socket_id,
+            vec![],
+            vec![],
+            format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
+            self.buffer_per_socket_max_kbytes,
+            sensor_data_for_socket,
+        );
+        // the zones of the dies of a package are named package-P-die-D
+        if let Ok(name) =
+            fs::read_to_string(format!("{}/intel-rapl:{}/name", self.base_path, socket_id))
+        {
+            if let Some((package_id, die_id)) = parse_package_zone_name(name.trim()) {
+                if let Some(socket) = topo.sockets.iter_mut().find(|s| s.id == socket_id) {
+                    if die_id.is_some() {
+                        socket.package_id = Some(package_id);
+                        socket.die_id = die_id;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Updates the sockets and domains of the topology to match the powercap
+    /// folder, as they may disappear or appear at runtime (CPU hotplug, driver
+    /// reload). Sockets and domains whose energy counter disappeared are dropped
+    /// with their records, new ones are added.
+    pub fn update_topology(&mut self, topo: &mut Topology) {
+        let sockets_nb = topo.sockets.len();
+        topo.sockets.retain(|s| {
+            let exists = Path::new(&s.counter_uj_path).exists();
+            if !exists {
+                warn!("Socket {} disappeared, dropping it.", s.id);
+            }
+            exists
+        });
+        let mut domains_changed = topo.sockets.len() != sockets_nb;
+        for socket in &mut topo.sockets {
+            let (socket_id, domains_nb) = (socket.id, socket.domains.len());
+            socket.domains.retain(|d| {
+                let exists = Path::new(&d.counter_uj_path).exists();
+                if !exists {
+                    warn!(
+                        "Domain {} of socket {} disappeared, dropping it.",
+                        d.name, socket_id
+                    );
+                }
+                exists
+            });
+            domains_changed |= socket.domains.len() != domains_nb;
+        }
+        if domains_changed {
+            topo.build_domains_names();
+        }
+        let added = match self.add_domains(topo) {
+            Ok(true) => Ok(()),
+            Ok(false) => self.add_sockets(topo),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = added {
+            warn!("Couldn't read {}: {}", self.base_path, e);
+        }
+        self.check_energy_permissions(topo);
+    }
+
+    /// Updates energy_unavailable, telling if the energy counters of the
+    /// sockets and domains can't be read because of their permissions. The
+    /// error is logged once, when the counters become unreadable.
+    fn check_energy_permissions(&mut self, topo: &Topology) {
+        let denied = topo
+            .sockets
+            .iter()
+            .flat_map(|s| {
+                std::iter::once(&s.counter_uj_path)
+                    .chain(s.domains.iter().map(|d| &d.counter_uj_path))
+            })
+            .find(|path| {
+                matches!(fs::File::open(path), Err(e) if e.kind() == io::ErrorKind::PermissionDenied)
+            });
+        match denied {
+            Some(path) if !self.energy_unavailable => {
+                error!(
+                    "Permission denied reading {}: no power will be reported until it can be read. {}. To allow a non-root user, give it read access with a udev rule or run scaphandre with the CAP_DAC_READ_SEARCH capability (setcap cap_dac_read_search+ep).",
+                    path,
+                    energy_read_hint(&io::Error::from(io::ErrorKind::PermissionDenied))
+                );
+                self.energy_unavailable = true;
+            }
+            None if self.energy_unavailable => {
+                info!("Energy counters are readable again, reporting power.");
+                self.energy_unavailable = false;
+            }
+            _ => {}
+        }
+    }
+
+    /// Reads max_energy_range_uj of a zone (like `0` for a socket or `0:1` for
+    /// one of its domains), if available, and stores it in its sensor_data, so
+    /// counter wraps can be handled when accumulating energy.
+    fn add_max_energy_range(&self, sensor_data: &mut HashMap<String, String>, zone: &str) {
+        if let Ok(max_range) = fs::read_to_string(format!(
+            "{}/intel-rapl:{}/max_energy_range_uj",
+            self.base_path, zone
+        )) {
+            sensor_data.insert(
+                String::from("max_energy_range_uj"),
+                String::from(max_range.trim()),
+            );
+        }
+    }
+
+    /// Stores in sensor_data the paths of the files giving the long term power
+    /// limit of a socket, whether this limit is enabled, and the maximum value
+    /// it can be set to, usually the TDP.
+    fn add_power_limit_files(&self, sensor_data: &mut HashMap<String, String>, socket_id: u16) {
+        sensor_data.insert(
+            String::from("power_limit_file"),
+            format!(
+                "{}/intel-rapl:{}/constraint_0_power_limit_uw",
+                self.base_path, socket_id
+            ),
+        );
+        sensor_data.insert(
+            String::from("power_limit_enabled_file"),
+            format!("{}/intel-rapl:{}/enabled", self.base_path, socket_id),
+        );
+        sensor_data.insert(
+            String::from("power_max_file"),
+            format!(
+                "{}/intel-rapl:{}/constraint_0_max_power_uw",
+                self.base_path, socket_id
+            ),
+        );
+    }
+}
+
+/// Returns the package and die of a RAPL package zone named `package-P`, or
+/// `package-P-die-D` on a package made of several dies.
+fn parse_package_zone_name(name: &str) -> Option<(u16, Option<u16>)> {
+    let ids = name.strip_prefix("package-")?;
+    match ids.split_once("-die-") {
+        Some((package, die)) => Some((package.parse().ok()?, Some(die.parse().ok()?))),
+        None => Some((ids.parse().ok()?, None)),
+    }
+}
+
+/// Reads the energy counter of a zone, in microjoules.
+fn read_energy_uj(zone: &Path) -> io::Result<u64> {
+    let energy = fs::read_to_string(zone.join("energy_uj"))?;
+    energy
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{e}")))
+}
+
+/// Returns the hint given when an energy counter can't be read.
+fn energy_read_hint(error: &io::Error) -> &'static str {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => {
+            "energy_uj files are only readable by root since 2020 (CVE-2020-8694): \
+             run scaphandre as root, or give its user read access to them (chmod, udev rule)"
+        }
+        _ => "check that the RAPL modules are loaded and the CPU supports RAPL",
+    }
+}
+
+/// Returns the path of the powercap folder in the sysfs mounted at `sysfs_root`.
+fn powercap_path_in(sysfs_root: &str) -> String {
+    format!("{}/class/powercap", sysfs_root.trim_end_matches('/'))
 }
 
 impl RecordReader for Topology {
@@ -102,88 +457,180 @@ impl RecordReader for Domain {
 }
 
 impl Sensor for PowercapRAPLSensor {
-    /// Creates a Topology instance.
-    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
-        let modules_state = PowercapRAPLSensor::check_module();
-        if modules_state.is_err() && !self.virtual_machine {
-            warn!("Couldn't find intel_rapl modules.");
-        }
-        let mut topo = Topology::new();
+    fn get_name(&self) -> String {
+        String::from("powercap_rapl")
+    }
+
+    /// Checks that a socket energy counter can be read in the powercap folder.
+    /// Since 2020, energy_uj files are only readable by root on most distributions.
+    fn probe(&self) -> Result<(), String> {
         let re_socket = Regex::new(r"^.*/intel-rapl:\d+$").unwrap();
-        let re_domain = Regex::new(r"^.*/intel-rapl:\d+:\d+$").unwrap();
-        let mut re_domain_matched = false;
-        for folder in fs::read_dir(&self.base_path).unwrap() {
-            let folder_name = String::from(folder.unwrap().path().to_str().unwrap());
-            // let's catch domain folders
-            if re_domain.is_match(&folder_name) {
-                re_domain_matched = true;
-                // let's get the second number of the intel-rapl:X:X string
-                let mut splitted = folder_name.split(':');
-                let _ = splitted.next();
-                let socket_id = String::from(splitted.next().unwrap()).parse().unwrap();
-                let domain_id = String::from(splitted.next().unwrap()).parse().unwrap();
-                let mut sensor_data_for_socket = HashMap::new();
-                sensor_data_for_socket.insert(
-                    String::from("source_file"),
-                    format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
-                );
-                topo.safe_add_socket(
-                    socket_id,
-                    vec![],
-                    vec![],
-                    format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
-                    self.buffer_per_socket_max_kbytes,
-                    sensor_data_for_socket,
-                );
-                let mut sensor_data_for_domain = HashMap::new();
-                sensor_data_for_domain.insert(
-                    String::from("source_file"),
+        let socket_folder = fs::read_dir(&self.base_path)
+            .map_err(|e| format!("couldn't read {}: {e}", self.base_path))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| re_socket.is_match(&path.to_string_lossy()))
+            .ok_or_else(|| format!("no intel-rapl socket domain found in {}", self.base_path))?;
+        let energy_file = socket_folder.join("energy_uj");
+        fs::read_to_string(&energy_file)
+            .map(|_| ())
+            .map_err(|e| format!("couldn't read {}: {e}", energy_file.display()))
+    }
+
+    /// Checks that the powercap folder is readable, that it holds RAPL zones,
+    /// that their energy counters can be read and that they increase.
+    fn self_test(&self) -> Vec<DiagnosticCheck> {
+        let mut checks = vec![];
+        let entries = match fs::read_dir(&self.base_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                let hint = if self.virtual_machine {
                     format!(
-                        "{}/intel-rapl:{}:{}/energy_uj",
-                        self.base_path, socket_id, domain_id
-                    ),
-                );
-                if let Ok(domain_name) = &fs::read_to_string(format!("{folder_name}/name")) {
-                    topo.safe_add_domain_to_socket(
-                        socket_id,
-                        domain_id,
-                        domain_name.trim(),
-                        &format!(
-                            "{}/intel-rapl:{}:{}/energy_uj",
-                            self.base_path, socket_id, domain_id
-                        ),
-                        self.buffer_per_domain_max_kbytes,
-                        sensor_data_for_domain,
-                    );
+                        "mount the folder exposed by the qemu exporter of the hypervisor in \
+                         {GUEST_POWERCAP_PATH}, or set SCAPHANDRE_POWERCAP_PATH to where it is mounted"
+                    )
+                } else {
+                    String::from(
+                        "load the RAPL modules (modprobe intel_rapl_common, or intel_rapl on \
+                         kernels older than 5.0), or use another sensor",
+                    )
+                };
+                checks.push(DiagnosticCheck::fail(
+                    "powercap folder readable",
+                    &format!("couldn't read {}: {e}", self.base_path),
+                    &hint,
+                ));
+                return checks;
+            }
+        };
+        checks.push(DiagnosticCheck::pass(
+            "powercap folder readable",
+            &self.base_path,
+        ));
+
+        if !self.virtual_machine && self.base_path == powercap_path_in(DEFAULT_SYSFS_ROOT) {
+            checks.push(match PowercapRAPLSensor::check_module() {
+                Ok(found) => DiagnosticCheck::pass("RAPL kernel modules", &found),
+                Err(e) => DiagnosticCheck::fail(
+                    "RAPL kernel modules",
+                    &e,
+                    "load them with modprobe intel_rapl_common (or intel_rapl on kernels older than 5.0)",
+                ),
+            });
+        }
+
+        let re_socket = Regex::new(r"^intel-rapl:\d+$").unwrap();
+        let re_domain = Regex::new(r"^intel-rapl:\d+:\d+$").unwrap();
+        let (mut sockets, mut domains): (Vec<PathBuf>, Vec<PathBuf>) = (vec![], vec![]);
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if re_socket.is_match(&name) {
+                sockets.push(entry.path());
+            } else if re_domain.is_match(&name) {
+                domains.push(entry.path());
+            }
+        }
+        sockets.sort();
+        if sockets.is_empty() {
+            checks.push(DiagnosticCheck::fail(
+                "RAPL zones present",
+                &format!("no intel-rapl:N folder in {}", self.base_path),
+                "the CPU may not support RAPL, or RAPL is disabled in the firmware",
+            ));
+            return checks;
+        }
+        checks.push(DiagnosticCheck::pass(
+            "RAPL zones present",
+            &format!("{} socket(s), {} domain(s)", sockets.len(), domains.len()),
+        ));
+
+        let mut first_reads = vec![];
+        let mut read_failure = None;
+        for zone in sockets.iter().chain(domains.iter()) {
+            match read_energy_uj(zone) {
+                Ok(energy) => {
+                    if sockets.contains(zone) {
+                        first_reads.push((zone, energy));
+                    }
+                }
+                Err(e) => {
+                    if read_failure.is_none() {
+                        read_failure = Some((zone.join("energy_uj"), e));
+                    }
                 }
             }
         }
-        if !re_domain_matched {
+        match read_failure {
+            Some((file, e)) => checks.push(DiagnosticCheck::fail(
+                "energy counters readable",
+                &format!("couldn't read {}: {e}", file.display()),
+                energy_read_hint(&e),
+            )),
+            None => checks.push(DiagnosticCheck::pass(
+                "energy counters readable",
+                "all energy_uj files could be read",
+            )),
+        }
+        if first_reads.is_empty() {
+            return checks;
+        }
+
+        thread::sleep(SELF_TEST_INTERVAL);
+        let increasing = first_reads.iter().any(|(zone, first)| {
+            // a counter that wrapped around also changed
+            matches!(read_energy_uj(zone), Ok(second) if second != *first)
+        });
+        checks.push(if increasing {
+            DiagnosticCheck::pass(
+                "energy counters increase",
+                &format!("counters changed in {}ms", SELF_TEST_INTERVAL.as_millis()),
+            )
+        } else {
+            DiagnosticCheck::fail(
+                "energy counters increase",
+                &format!(
+                    "no socket counter changed in {}ms",
+                    SELF_TEST_INTERVAL.as_millis()
+                ),
+                if self.virtual_machine {
+                    "check that the qemu exporter is running on the hypervisor"
+                } else {
+                    "RAPL may be disabled: check the enabled file of the intel-rapl:N folders"
+                },
+            )
+        });
+        checks
+    }
+
+    /// Creates a Topology instance.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        // in a virtual machine, energy counters are given by the hypervisor, not by rapl modules,
+        // and under a custom sysfs root they don't come from the modules of this host either
+        let host_powercap = self.base_path == powercap_path_in(DEFAULT_SYSFS_ROOT);
+        if !self.virtual_machine && host_powercap && PowercapRAPLSensor::check_module().is_err() {
+            warn!("Couldn't find intel_rapl modules.");
+        }
+        let mut topo = Topology::new();
+        if !self.add_domains(&mut topo)? {
             warn!("Couldn't find domain folders from powercap. Fallback on socket folders.");
             warn!("Scaphandre will not be able to provide per-domain data.");
-            for folder in fs::read_dir(&self.base_path).unwrap() {
-                let folder_name = String::from(folder.unwrap().path().to_str().unwrap());
-                if re_socket.is_match(&folder_name) {
-                    let mut splitted = folder_name.split(':');
-                    let _ = splitted.next();
-                    let socket_id = String::from(splitted.next().unwrap()).parse().unwrap();
-                    let mut sensor_data_for_socket = HashMap::new();
-                    sensor_data_for_socket.insert(
-                        String::from("source_file"),
-                        format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
-                    );
-                    topo.safe_add_socket(
-                        socket_id,
-                        vec![],
-                        vec![],
-                        format!("{}/intel-rapl:{}/energy_uj", self.base_path, socket_id),
-                        self.buffer_per_socket_max_kbytes,
-                        sensor_data_for_socket,
-                    )
-                }
+            self.add_sockets(&mut topo)?;
+        }
+        // the cores are read from the procfs too
+        if let Some(procfs_root) = &self.procfs_root {
+            topo.proc_tracker.set_procfs_root(procfs_root);
+        }
+        topo.add_cpu_cores(&self.sysfs_root);
+        if !self.package_sum {
+            topo.platform_counter_uj_path = self.find_psys_counter();
+            if let Some(path) = &topo.platform_counter_uj_path {
+                info!(
+                    "Measuring the host power from the psys zone {}, covering the whole platform.",
+                    path
+                );
             }
         }
-        topo.add_cpu_cores();
+        topo.powercap_sensor = Some(self.clone());
         Ok(topo)
     }
 
@@ -214,6 +661,272 @@ mod tests {
             type_of(topology)
         )
     }
+
+    #[test]
+    fn self_test_reports_failed_checks() {
+        let root = env::temp_dir().join(format!("scaph-selftest-{}", std::process::id()));
+        let powercap = root.join("class/powercap");
+        fs::create_dir_all(powercap.join("intel-rapl:0")).unwrap();
+        fs::create_dir_all(powercap.join("intel-rapl:0:0")).unwrap();
+        fs::write(powercap.join("intel-rapl:0/energy_uj"), "1000").unwrap();
+        let sensor = PowercapRAPLSensor::new(1, 1, false).with_sysfs_root(root.to_str().unwrap());
+
+        let checks = sensor.self_test();
+        let names: Vec<&str> = checks.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "powercap folder readable",
+                "RAPL zones present",
+                "energy counters readable",
+                "energy counters increase"
+            ]
+        );
+        assert!(checks[0].passed && checks[1].passed);
+        assert!(checks[2].details.contains("intel-rapl:0:0"));
+        // the fixture counter never changes
+        assert!(!checks[2].passed && !checks[3].passed);
+        assert!(checks[3].hint.is_some());
+
+        fs::remove_dir_all(powercap.join("intel-rapl:0")).unwrap();
+        let checks = sensor.self_test();
+        assert_eq!(checks.len(), 2);
+        assert!(!checks[1].passed);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn probe_reports_missing_energy_counter() {
+        let base_path = env::temp_dir().join(format!("scaph-probe-{}", std::process::id()));
+        let socket_path = base_path.join("intel-rapl:0");
+        fs::create_dir_all(&socket_path).unwrap();
+        let sensor = PowercapRAPLSensor {
+            base_path: base_path.to_string_lossy().to_string(),
+            sysfs_root: String::from(DEFAULT_SYSFS_ROOT),
+            buffer_per_socket_max_kbytes: 1,
+            buffer_per_domain_max_kbytes: 1,
+            virtual_machine: true,
+            energy_unavailable: false,
+            procfs_root: None,
+            package_sum: false,
+        };
+        assert!(sensor.probe().unwrap_err().contains("energy_uj"));
+        fs::write(socket_path.join("energy_uj"), "42\n").unwrap();
+        assert!(sensor.probe().is_ok());
+        fs::remove_dir_all(&base_path).unwrap();
+    }
+
+    #[test]
+    fn vanishing_domain_dropped_without_panic() {
+        let base_path = env::temp_dir().join(format!("scaph-hotplug-{}", std::process::id()));
+        let domain_path = base_path.join("intel-rapl:0:0");
+        fs::create_dir_all(base_path.join("intel-rapl:0")).unwrap();
+        fs::create_dir_all(&domain_path).unwrap();
+        fs::write(base_path.join("intel-rapl:0/energy_uj"), "1000").unwrap();
+        fs::write(domain_path.join("name"), "dram\n").unwrap();
+        fs::write(domain_path.join("energy_uj"), "100").unwrap();
+        let sensor = PowercapRAPLSensor {
+            base_path: base_path.to_string_lossy().to_string(),
+            sysfs_root: String::from(DEFAULT_SYSFS_ROOT),
+            buffer_per_socket_max_kbytes: 1,
+            buffer_per_domain_max_kbytes: 1,
+            virtual_machine: true,
+            energy_unavailable: false,
+            procfs_root: None,
+            package_sum: false,
+        };
+        let mut topology = sensor.generate_topology().unwrap();
+        topology.refresh();
+        assert_eq!(topology.sockets[0].domains.len(), 1);
+
+        // the driver is unloaded: the domain counter can't be read anymore
+        fs::remove_dir_all(&domain_path).unwrap();
+        topology.refresh();
+        assert!(topology.sockets[0].domains.is_empty());
+        assert_eq!(topology.domains_names, Some(vec![]));
+        assert!(topology.get_records_diff_power_microwatts().is_some());
+
+        // and reloaded
+        fs::create_dir_all(&domain_path).unwrap();
+        fs::write(domain_path.join("name"), "dram\n").unwrap();
+        fs::write(domain_path.join("energy_uj"), "200").unwrap();
+        topology.refresh();
+        assert_eq!(topology.sockets[0].domains[0].name, "dram");
+        fs::remove_dir_all(&base_path).unwrap();
+    }
+
+    #[test]
+    fn counter_wrap_handled_from_sysfs_fixture() {
+        let sysfs_root = env::temp_dir().join(format!("scaph-sysfs-{}", std::process::id()));
+        let socket_path = sysfs_root.join("class/powercap/intel-rapl:0");
+        fs::create_dir_all(&socket_path).unwrap();
+        fs::write(socket_path.join("max_energy_range_uj"), "1000\n").unwrap();
+        fs::write(socket_path.join("energy_uj"), "800\n").unwrap();
+        let sensor =
+            PowercapRAPLSensor::new(1, 1, false).with_sysfs_root(sysfs_root.to_str().unwrap());
+        assert!(sensor.probe().is_ok());
+        let mut topology = sensor.generate_topology().unwrap();
+        topology.refresh();
+        // the counter wraps around max_energy_range_uj
+        fs::write(socket_path.join("energy_uj"), "300\n").unwrap();
+        topology.refresh();
+        let socket = &topology.get_sockets_passive()[0];
+        assert_eq!(socket.energy_since_start_microjoules, 500);
+        assert_eq!(socket.get_energy_resolution_microjoules(), Some(1.0));
+        fs::remove_dir_all(&sysfs_root).unwrap();
+    }
+
+    #[test]
+    fn raw_domain_counter_exposed() {
+        let sysfs_root = env::temp_dir().join(format!("scaph-raw-{}", std::process::id()));
+        let powercap = sysfs_root.join("class/powercap");
+        let dram_path = powercap.join("intel-rapl:0:0");
+        fs::create_dir_all(powercap.join("intel-rapl:0")).unwrap();
+        fs::create_dir_all(&dram_path).unwrap();
+        fs::write(powercap.join("intel-rapl:0/energy_uj"), "1000\n").unwrap();
+        fs::write(dram_path.join("name"), "dram\n").unwrap();
+        fs::write(dram_path.join("energy_uj"), "4200\n").unwrap();
+        fs::write(dram_path.join("max_energy_range_uj"), "65532610987\n").unwrap();
+        let sensor =
+            PowercapRAPLSensor::new(1, 1, false).with_sysfs_root(sysfs_root.to_str().unwrap());
+        let mut topology = sensor.generate_topology().unwrap();
+        let domain = &topology.sockets[0].domains[0];
+        assert!(domain.get_raw_energy_uj().is_none());
+        assert_eq!(domain.get_max_energy_range_uj(), Some(65532610987));
+        topology.refresh();
+        let raw = topology.sockets[0].domains[0].get_raw_energy_uj().unwrap();
+        assert_eq!(raw.value, "4200");
+        fs::remove_dir_all(&sysfs_root).unwrap();
+    }
+
+    #[test]
+    fn domain_read_errors_counted() {
+        let sysfs_root = env::temp_dir().join(format!("scaph-read-errors-{}", std::process::id()));
+        let powercap = sysfs_root.join("class/powercap");
+        let dram_path = powercap.join("intel-rapl:0:0");
+        fs::create_dir_all(powercap.join("intel-rapl:0")).unwrap();
+        fs::create_dir_all(&dram_path).unwrap();
+        fs::write(powercap.join("intel-rapl:0/energy_uj"), "1000\n").unwrap();
+        fs::write(dram_path.join("name"), "dram\n").unwrap();
+        fs::write(dram_path.join("energy_uj"), "4200\n").unwrap();
+        let sensor =
+            PowercapRAPLSensor::new(1, 1, false).with_sysfs_root(sysfs_root.to_str().unwrap());
+        let mut topology = sensor.generate_topology().unwrap();
+        topology.refresh();
+        // still there, so the domain is kept, but unreadable
+        fs::remove_file(dram_path.join("energy_uj")).unwrap();
+        fs::create_dir(dram_path.join("energy_uj")).unwrap();
+        topology.refresh();
+        topology.refresh();
+        let domain = &topology.sockets[0].domains[0];
+        assert_eq!(domain.get_reads(), 1);
+        assert_eq!(domain.get_read_errors(), 2);
+        fs::remove_dir_all(&sysfs_root).unwrap();
+    }
+
+    #[test]
+    fn dies_of_a_package_kept_apart() {
+        let sysfs_root = env::temp_dir().join(format!("scaph-dies-{}", std::process::id()));
+        let powercap = sysfs_root.join("class/powercap");
+        // one package made of two dies, each with its own package and dram zones
+        for (zone, energy) in [(0, "1000"), (1, "5000")] {
+            let zone_path = powercap.join(format!("intel-rapl:{zone}"));
+            let dram_path = powercap.join(format!("intel-rapl:{zone}:0"));
+            fs::create_dir_all(&zone_path).unwrap();
+            fs::create_dir_all(&dram_path).unwrap();
+            fs::write(zone_path.join("name"), format!("package-0-die-{zone}\n")).unwrap();
+            fs::write(zone_path.join("energy_uj"), energy).unwrap();
+            fs::write(dram_path.join("name"), "dram\n").unwrap();
+            fs::write(dram_path.join("energy_uj"), energy).unwrap();
+        }
+        let sensor =
+            PowercapRAPLSensor::new(1, 1, false).with_sysfs_root(sysfs_root.to_str().unwrap());
+        let mut topology = sensor.generate_topology().unwrap();
+        topology.refresh();
+        let mut sockets = topology.get_sockets_passive().clone();
+        sockets.sort_by_key(|s| s.id);
+        assert_eq!(sockets.len(), 2);
+        for (socket, (die_id, energy)) in sockets.iter().zip([(0, "1000"), (1, "5000")]) {
+            assert_eq!((socket.get_package_id(), socket.die_id), (0, Some(die_id)));
+            assert_eq!(socket.record_buffer.last().unwrap().value.trim(), energy);
+            assert_eq!(socket.domains.len(), 1);
+            assert_eq!(
+                socket.domains[0].record_buffer.last().unwrap().value.trim(),
+                energy
+            );
+        }
+        assert_eq!(
+            sockets[1].get_labels(),
+            vec![
+                (String::from("socket_id"), String::from("1")),
+                (String::from("package_id"), String::from("0")),
+                (String::from("die_id"), String::from("1"))
+            ]
+        );
+        assert_eq!(parse_package_zone_name("package-1"), Some((1, None)));
+        assert_eq!(parse_package_zone_name("psys"), None);
+        fs::remove_dir_all(&sysfs_root).unwrap();
+    }
+
+    #[test]
+    fn psys_zone_measures_the_host() {
+        let sysfs_root = env::temp_dir().join(format!("scaph-psys-{}", std::process::id()));
+        let powercap = sysfs_root.join("class/powercap");
+        for (zone, name, energy) in [("0", "package-0", "1000"), ("0:0", "core", "500")] {
+            let zone_path = powercap.join(format!("intel-rapl:{zone}"));
+            fs::create_dir_all(&zone_path).unwrap();
+            fs::write(zone_path.join("name"), format!("{name}\n")).unwrap();
+            fs::write(zone_path.join("energy_uj"), energy).unwrap();
+        }
+        let psys_path = powercap.join("intel-rapl:1");
+        fs::create_dir_all(&psys_path).unwrap();
+        fs::write(psys_path.join("name"), "psys\n").unwrap();
+        fs::write(psys_path.join("energy_uj"), "9000\n").unwrap();
+
+        let sensor =
+            PowercapRAPLSensor::new(1, 1, false).with_sysfs_root(sysfs_root.to_str().unwrap());
+        let mut topology = sensor.generate_topology().unwrap();
+        assert_eq!(topology.get_host_power_source(), "psys");
+        topology.refresh();
+        assert_eq!(topology.sockets.len(), 1);
+        assert_eq!(topology.record_buffer.last().unwrap().value, "9000");
+
+        let mut topology = sensor.with_package_sum().generate_topology().unwrap();
+        assert_eq!(topology.get_host_power_source(), "packages");
+        topology.refresh();
+        assert_eq!(topology.sockets.len(), 1);
+        assert_eq!(topology.record_buffer.last().unwrap().value, "1000");
+        fs::remove_dir_all(&sysfs_root).unwrap();
+    }
+
+    #[test]
+    fn guest_topology_generated_from_qemu_exporter_files() {
+        let base_path = env::temp_dir().join(format!("scaph-guest-{}", std::process::id()));
+        // layout written by the qemu exporter for each virtual machine
+        fs::create_dir_all(base_path.join("intel-rapl:0")).unwrap();
+        fs::create_dir_all(base_path.join("intel-rapl:0:0")).unwrap();
+        fs::write(base_path.join("intel-rapl:0/energy_uj"), "1000").unwrap();
+        let sensor = PowercapRAPLSensor {
+            base_path: base_path.to_string_lossy().to_string(),
+            sysfs_root: String::from(DEFAULT_SYSFS_ROOT),
+            buffer_per_socket_max_kbytes: 1,
+            buffer_per_domain_max_kbytes: 1,
+            virtual_machine: true,
+            energy_unavailable: false,
+            procfs_root: None,
+            package_sum: false,
+        };
+        assert!(sensor.probe().is_ok());
+        let mut topology = sensor.generate_topology().unwrap();
+        assert_eq!(topology.get_sockets_passive().len(), 1);
+        assert!(topology.get_sockets_passive()[0]
+            .get_domains_passive()
+            .is_empty());
+        topology.refresh();
+        let records = &topology.get_sockets_passive()[0].record_buffer;
+        assert_eq!(records.last().unwrap().value.trim(), "1000");
+        fs::remove_dir_all(&base_path).unwrap();
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.
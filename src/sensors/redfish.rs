@@ -0,0 +1,197 @@
+//! # RedfishSensor
+//!
+//! Sensor getting the power consumption of the whole host from its BMC,
+//! through the [Redfish](https://www.dmtf.org/standards/redfish) API.
+use crate::sensors::{PowerMeter, Sensor, Topology};
+use isahc::auth::{Authentication, Credentials};
+use isahc::config::{Configurable, SslOption};
+use isahc::{HttpClient, ReadResponseExt};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Timeout of the requests sent to the Redfish endpoint.
+const REQUEST_TIMEOUT_SECONDS: u64 = 5;
+
+/// This is a Sensor type that polls the `/redfish/v1/Chassis/<id>/Power` resource
+/// of a Redfish endpoint for `PowerConsumedWatts`. It gives the wall power of
+/// the server, as measured by its power supplies, as a host level record.
+pub struct RedfishSensor {
+    endpoint: String,
+    chassis_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    skip_tls_verify: bool,
+    buffer_per_socket_max_kbytes: u16,
+}
+
+impl RedfishSensor {
+    /// Instantiates and returns an instance of RedfishSensor.
+    /// `endpoint` is the base url of the BMC, like `https://bmc.example.com`.
+    pub fn new(
+        endpoint: &str,
+        chassis_id: &str,
+        username: Option<String>,
+        password: Option<String>,
+        skip_tls_verify: bool,
+        buffer_per_socket_max_kbytes: u16,
+    ) -> RedfishSensor {
+        RedfishSensor {
+            endpoint: String::from(endpoint.trim_end_matches('/')),
+            chassis_id: String::from(chassis_id),
+            username,
+            password,
+            skip_tls_verify,
+            buffer_per_socket_max_kbytes,
+        }
+    }
+
+    /// Returns the url of the Power resource of the chassis.
+    fn get_power_url(&self) -> String {
+        format!(
+            "{}/redfish/v1/Chassis/{}/Power",
+            self.endpoint, self.chassis_id
+        )
+    }
+
+    /// Creates the power meter querying the Redfish endpoint.
+    fn build_power_meter(&self) -> Result<RedfishPowerMeter, Box<dyn Error>> {
+        let mut builder =
+            HttpClient::builder().timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS));
+        if let Some(username) = &self.username {
+            builder =
+                builder
+                    .authentication(Authentication::basic())
+                    .credentials(Credentials::new(
+                        username.as_str(),
+                        self.password.clone().unwrap_or_default(),
+                    ));
+        }
+        if self.skip_tls_verify {
+            warn!("TLS certificate of the Redfish endpoint won't be verified.");
+            builder = builder.ssl_options(
+                SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+            );
+        }
+        Ok(RedfishPowerMeter {
+            client: builder.build()?,
+            url: self.get_power_url(),
+        })
+    }
+}
+
+impl Sensor for RedfishSensor {
+    fn get_name(&self) -> String {
+        String::from("redfish")
+    }
+
+    /// Creates a Topology instance, with a single socket holding the power
+    /// measured by the Redfish endpoint.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let power_meter = self.build_power_meter()?;
+        info!("Redfish power resource is: {}", power_meter.url);
+
+        let mut topo = Topology::default();
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(String::from("source_url"), power_meter.url.clone());
+        topo.safe_add_socket(
+            0,
+            vec![],
+            vec![],
+            String::from(""),
+            self.buffer_per_socket_max_kbytes,
+            sensor_data,
+        );
+        let power_meter = Arc::new(power_meter);
+        for socket in topo.get_sockets() {
+            socket.set_power_meter(power_meter.clone());
+        }
+        Ok(topo)
+    }
+
+    /// Checks that the Redfish endpoint gives the power consumed.
+    fn probe(&self) -> Result<(), String> {
+        self.build_power_meter()
+            .and_then(|power_meter| power_meter.read_power_watts())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+/// Reads PowerConsumedWatts from the Power resource of a Redfish chassis.
+#[derive(Debug)]
+struct RedfishPowerMeter {
+    client: HttpClient,
+    url: String,
+}
+
+impl PowerMeter for RedfishPowerMeter {
+    fn read_power_watts(&self) -> Result<f64, Box<dyn Error>> {
+        let mut response = self.client.get(&self.url)?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Redfish endpoint {} answered {}",
+                self.url,
+                response.status()
+            )
+            .into());
+        }
+        parse_power_consumed_watts(&response.text()?)
+    }
+}
+
+/// Returns the first PowerConsumedWatts found in the PowerControl array
+/// of a Redfish Power resource.
+fn parse_power_consumed_watts(body: &str) -> Result<f64, Box<dyn Error>> {
+    let power: serde_json::Value = serde_json::from_str(body)?;
+    power["PowerControl"]
+        .as_array()
+        .and_then(|controls| {
+            controls
+                .iter()
+                .find_map(|control| control["PowerConsumedWatts"].as_f64())
+        })
+        .ok_or_else(|| "No PowerConsumedWatts found in Redfish Power resource".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_power_consumed_watts_from_power_control() {
+        let body = r#"{
+            "@odata.id": "/redfish/v1/Chassis/1/Power",
+            "PowerControl": [
+                {"MemberId": "0", "PowerConsumedWatts": null},
+                {"MemberId": "1", "PowerConsumedWatts": 245.5, "PowerCapacityWatts": 800}
+            ]
+        }"#;
+        assert_eq!(parse_power_consumed_watts(body).unwrap(), 245.5);
+        assert!(parse_power_consumed_watts(r#"{"PowerControl": []}"#).is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -0,0 +1,327 @@
+//! # ReplaySensor
+//!
+//! Sensor replaying the power of the host and its sockets from reports captured
+//! with the json exporter (a JSON file, or NDJSON frames saved one per line),
+//! as if they were measured live. The capture is played at its own pace, or
+//! faster with a speed factor, so that exporters can be developed and tested
+//! without the hardware the capture was made on.
+use crate::sensors::{PowerMeter, Sensor, Topology};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Power of the host and its sockets in a captured report.
+#[derive(Debug, Clone, PartialEq)]
+struct ReplaySnapshot {
+    /// Time of the report, in seconds since the epoch.
+    timestamp: f64,
+    /// Power of the host, in microwatts.
+    host_microwatts: f64,
+    /// Power of each socket, in microwatts, by socket id.
+    sockets: Vec<(u16, f64)>,
+}
+
+/// Parses a report of the json exporter. Returns None for the other objects
+/// of a capture, like the energy window reports.
+fn parse_snapshot(report: &serde_json::Value) -> Option<ReplaySnapshot> {
+    let host = report.get("host")?;
+    let sockets = match report.get("sockets").and_then(|s| s.as_array()) {
+        Some(sockets) => sockets
+            .iter()
+            .filter_map(|socket| {
+                Some((
+                    socket.get("id")?.as_u64()? as u16,
+                    socket.get("consumption")?.as_f64()?,
+                ))
+            })
+            .collect(),
+        None => vec![],
+    };
+    Some(ReplaySnapshot {
+        timestamp: host.get("timestamp")?.as_f64()?,
+        host_microwatts: host.get("consumption")?.as_f64()?,
+        sockets,
+    })
+}
+
+/// Parses a capture, either a JSON array of reports as written to a file by
+/// the json exporter, or one report per line (NDJSON). Snapshots are sorted
+/// by time.
+fn parse_capture(capture: &str) -> Result<Vec<ReplaySnapshot>, Box<dyn Error>> {
+    let reports: Vec<serde_json::Value> = if capture.trim_start().starts_with('[') {
+        serde_json::from_str(capture)?
+    } else {
+        capture
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?
+    };
+    let mut snapshots: Vec<ReplaySnapshot> = reports.iter().filter_map(parse_snapshot).collect();
+    if snapshots.is_empty() {
+        return Err("No report with the power of the host found in the capture".into());
+    }
+    snapshots.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+    Ok(snapshots)
+}
+
+/// Snapshots of a capture, replayed from the time the timeline was created.
+#[derive(Debug)]
+struct ReplayTimeline {
+    snapshots: Vec<ReplaySnapshot>,
+    /// Seconds of the capture replayed per second.
+    speed: f64,
+    /// Tells to start over once the end of the capture is reached.
+    looped: bool,
+    start: Instant,
+}
+
+impl ReplayTimeline {
+    /// Returns the snapshot replayed `elapsed` after the start: the last one
+    /// captured before that time, the capture being sped up by `speed`.
+    /// Returns None once the capture is over, unless it is looped.
+    fn snapshot_at(&self, elapsed: Duration) -> Option<&ReplaySnapshot> {
+        let first = self.snapshots.first()?.timestamp;
+        let duration = self.snapshots.last()?.timestamp - first;
+        let mut offset = elapsed.as_secs_f64() * self.speed;
+        if offset > duration {
+            if !self.looped {
+                return None;
+            }
+            if duration > 0.0 {
+                offset %= duration;
+            }
+        }
+        self.snapshots
+            .iter()
+            .take_while(|snapshot| snapshot.timestamp - first <= offset)
+            .last()
+    }
+
+    /// Returns the snapshot replayed now.
+    fn current(&self) -> Option<&ReplaySnapshot> {
+        self.snapshot_at(self.start.elapsed())
+    }
+}
+
+/// Gives the power of a socket, or of the whole host, from a timeline.
+#[derive(Debug)]
+struct ReplayPowerMeter {
+    timeline: Arc<ReplayTimeline>,
+    /// Socket whose power is replayed, or None for the host.
+    socket_id: Option<u16>,
+}
+
+impl PowerMeter for ReplayPowerMeter {
+    fn read_power_watts(&self) -> Result<f64, Box<dyn Error>> {
+        let snapshot = self
+            .timeline
+            .current()
+            .ok_or("The replayed capture is over")?;
+        let microwatts = match self.socket_id {
+            Some(id) => snapshot
+                .sockets
+                .iter()
+                .find(|(socket_id, _)| *socket_id == id)
+                .map(|(_, power)| *power)
+                .ok_or_else(|| format!("Socket {id} is missing from the replayed report"))?,
+            None => snapshot.host_microwatts,
+        };
+        Ok(microwatts / 1_000_000.0)
+    }
+}
+
+/// This is a Sensor type that replays the power of the host and its sockets
+/// from a capture of the json exporter. The consumers of the capture are not
+/// replayed: processes are still the ones of the local host, the replayed
+/// power being attributed to them.
+pub struct ReplaySensor {
+    path: String,
+    speed: f64,
+    looped: bool,
+    buffer_per_socket_max_kbytes: u16,
+}
+
+impl ReplaySensor {
+    /// Instantiates and returns an instance of ReplaySensor, replaying the
+    /// capture at `path` `speed` times faster than it was captured, and
+    /// starting over at its end if `looped` is true.
+    pub fn new(
+        path: &str,
+        speed: f64,
+        looped: bool,
+        buffer_per_socket_max_kbytes: u16,
+    ) -> ReplaySensor {
+        ReplaySensor {
+            path: String::from(path),
+            speed,
+            looped,
+            buffer_per_socket_max_kbytes,
+        }
+    }
+
+    /// Reads and parses the capture.
+    fn read_capture(&self) -> Result<Vec<ReplaySnapshot>, Box<dyn Error>> {
+        let capture = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Couldn't read the capture {}: {e}", self.path))?;
+        parse_capture(&capture)
+    }
+}
+
+impl Sensor for ReplaySensor {
+    fn get_name(&self) -> String {
+        String::from("replay")
+    }
+
+    /// Creates a Topology instance with the sockets of the first report of the
+    /// capture, or a single socket holding the host power if it has none. The
+    /// host power is the one of the capture, not the sum of the sockets.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let snapshots = self.read_capture()?;
+        info!(
+            "Replaying {} reports from {} at speed {}",
+            snapshots.len(),
+            self.path,
+            self.speed
+        );
+        let socket_ids: Vec<Option<u16>> = if snapshots[0].sockets.is_empty() {
+            vec![None]
+        } else {
+            snapshots[0]
+                .sockets
+                .iter()
+                .map(|(id, _)| Some(*id))
+                .collect()
+        };
+        let timeline = Arc::new(ReplayTimeline {
+            snapshots,
+            speed: self.speed,
+            looped: self.looped,
+            start: Instant::now(),
+        });
+
+        let mut topo = Topology::default();
+        for socket_id in &socket_ids {
+            let mut sensor_data = HashMap::new();
+            sensor_data.insert(String::from("source_file"), self.path.clone());
+            topo.safe_add_socket(
+                socket_id.unwrap_or(0),
+                vec![],
+                vec![],
+                String::from(""),
+                self.buffer_per_socket_max_kbytes,
+                sensor_data,
+            );
+        }
+        for socket in topo.get_sockets() {
+            let socket_id = socket_ids
+                .iter()
+                .find(|id| id.unwrap_or(0) == socket.id)
+                .copied()
+                .flatten();
+            socket.set_power_meter(Arc::new(ReplayPowerMeter {
+                timeline: timeline.clone(),
+                socket_id,
+            }));
+        }
+        if socket_ids != [None] {
+            topo.set_host_power_meter(Arc::new(ReplayPowerMeter {
+                timeline,
+                socket_id: None,
+            }));
+        }
+        Ok(topo)
+    }
+
+    /// Checks that the capture holds reports to replay.
+    fn probe(&self) -> Result<(), String> {
+        self.read_capture().map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPTURE: &str = r#"{"host":{"consumption":30000000.0,"consumption_unit":"microwatts","timestamp":1000.0},"consumers":[],"sockets":[{"id":0,"consumption":20000000.0,"consumption_unit":"microwatts","energy":null,"energy_unit":"microjoules","domains":[],"timestamp":1000.0}]}
+{"start":990.0,"end":1000.0,"partial":true,"host":null,"sockets":[],"consumers":[]}
+{"host":{"consumption":50000000.0,"consumption_unit":"microwatts","timestamp":1010.0},"consumers":[],"sockets":[{"id":0,"consumption":40000000.0,"consumption_unit":"microwatts","energy":null,"energy_unit":"microjoules","domains":[],"timestamp":1010.0}]}
+"#;
+
+    #[test]
+    fn capture_replayed_at_speed() {
+        let snapshots = parse_capture(CAPTURE).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[1].sockets, vec![(0, 40000000.0)]);
+        let array = format!("[{}]", CAPTURE.lines().collect::<Vec<_>>().join(","));
+        assert_eq!(parse_capture(&array).unwrap(), snapshots);
+        assert!(parse_capture("").is_err());
+
+        let timeline = ReplayTimeline {
+            snapshots,
+            speed: 2.0,
+            looped: false,
+            start: Instant::now(),
+        };
+        let power_at = |seconds| {
+            timeline
+                .snapshot_at(Duration::from_secs(seconds))
+                .map(|snapshot| snapshot.host_microwatts)
+        };
+        assert_eq!(power_at(0), Some(30000000.0));
+        assert_eq!(power_at(4), Some(30000000.0));
+        // at twice the speed, the second report is reached after 5 seconds
+        assert_eq!(power_at(5), Some(50000000.0));
+        assert_eq!(power_at(6), None);
+
+        let looped = ReplayTimeline {
+            looped: true,
+            ..timeline
+        };
+        assert_eq!(
+            looped
+                .snapshot_at(Duration::from_secs(7))
+                .map(|snapshot| snapshot.host_microwatts),
+            Some(30000000.0)
+        );
+    }
+
+    #[test]
+    fn host_power_replayed_from_capture() {
+        let path = std::env::temp_dir().join(format!(
+            "scaphandre_test_replay_{}.json",
+            std::process::id()
+        ));
+        fs::write(&path, CAPTURE).unwrap();
+        let sensor = ReplaySensor::new(path.to_str().unwrap(), 1.0, false, 1);
+        let topology = sensor.generate_topology().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(topology.sockets.len(), 1);
+        assert_eq!(topology.get_host_power_source(), "meter");
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
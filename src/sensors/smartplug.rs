@@ -0,0 +1,251 @@
+//! # SmartPlugSensor
+//!
+//! Sensor getting the power consumption of the whole machine from a smart plug
+//! (Tasmota or Shelly) it is plugged into, through the HTTP API of the plug.
+use crate::sensors::{PowerMeter, Sensor, Topology};
+use isahc::config::Configurable;
+use isahc::{HttpClient, ReadResponseExt};
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Timeout of the requests sent to the smart plug.
+const REQUEST_TIMEOUT_SECONDS: u64 = 5;
+
+/// Smart plug firmwares supported by SmartPlugSensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SmartPlugType {
+    /// Plugs running [Tasmota](https://tasmota.github.io), queried with `Status 8`.
+    Tasmota,
+    /// First generation Shelly plugs, queried on `/meter/0`.
+    Shelly,
+    /// Second generation (and later) Shelly plugs, queried on `/rpc/Switch.GetStatus`.
+    ShellyGen2,
+}
+
+impl SmartPlugType {
+    /// Returns the path to query on the plug to get its meter status.
+    fn get_status_path(&self) -> &'static str {
+        match self {
+            SmartPlugType::Tasmota => "/cm?cmnd=Status%208",
+            SmartPlugType::Shelly => "/meter/0",
+            SmartPlugType::ShellyGen2 => "/rpc/Switch.GetStatus?id=0",
+        }
+    }
+}
+
+impl FromStr for SmartPlugType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tasmota" => Ok(SmartPlugType::Tasmota),
+            "shelly" => Ok(SmartPlugType::Shelly),
+            "shelly_gen2" => Ok(SmartPlugType::ShellyGen2),
+            _ => Err(format!(
+                "Unknown smart plug type '{s}', should be tasmota, shelly or shelly_gen2"
+            )),
+        }
+    }
+}
+
+/// A reading of the meter of a smart plug.
+#[derive(Debug, PartialEq)]
+struct SmartPlugReading {
+    /// Instant power, in watts.
+    power_watts: f64,
+    /// Energy counter of the plug, in watt-hours, if it reports one.
+    energy_watthours: Option<f64>,
+}
+
+/// This is a Sensor type that polls a smart plug for the instant power
+/// of the machine plugged into it, and gives it as a host level record.
+pub struct SmartPlugSensor {
+    url: String,
+    plug_type: SmartPlugType,
+    buffer_per_socket_max_kbytes: u16,
+}
+
+impl SmartPlugSensor {
+    /// Instantiates and returns an instance of SmartPlugSensor.
+    /// `url` is the base url of the plug, like `http://192.168.1.42`.
+    pub fn new(
+        url: &str,
+        plug_type: SmartPlugType,
+        buffer_per_socket_max_kbytes: u16,
+    ) -> SmartPlugSensor {
+        SmartPlugSensor {
+            url: String::from(url.trim_end_matches('/')),
+            plug_type,
+            buffer_per_socket_max_kbytes,
+        }
+    }
+
+    /// Creates the power meter querying the smart plug.
+    fn build_power_meter(&self) -> Result<SmartPlugPowerMeter, Box<dyn Error>> {
+        Ok(SmartPlugPowerMeter {
+            client: HttpClient::builder()
+                .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+                .build()?,
+            url: format!("{}{}", self.url, self.plug_type.get_status_path()),
+            plug_type: self.plug_type,
+        })
+    }
+}
+
+impl Sensor for SmartPlugSensor {
+    fn get_name(&self) -> String {
+        String::from("smartplug")
+    }
+
+    /// Creates a Topology instance, with a single socket holding the power
+    /// measured by the smart plug.
+    fn generate_topology(&self) -> Result<Topology, Box<dyn Error>> {
+        let power_meter = self.build_power_meter()?;
+        info!("Smart plug status url is: {}", power_meter.url);
+
+        let mut topo = Topology::default();
+        let mut sensor_data = HashMap::new();
+        sensor_data.insert(String::from("source_url"), power_meter.url.clone());
+        topo.safe_add_socket(
+            0,
+            vec![],
+            vec![],
+            String::from(""),
+            self.buffer_per_socket_max_kbytes,
+            sensor_data,
+        );
+        let power_meter = Arc::new(power_meter);
+        for socket in topo.get_sockets() {
+            socket.set_power_meter(power_meter.clone());
+        }
+        Ok(topo)
+    }
+
+    /// Checks that the smart plug gives its power.
+    fn probe(&self) -> Result<(), String> {
+        self.build_power_meter()
+            .and_then(|power_meter| power_meter.read_power_watts())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Instanciates Topology object if not existing and returns it
+    fn get_topology(&mut self) -> Box<Option<Topology>> {
+        let topology = self.generate_topology().ok();
+        if topology.is_none() {
+            panic!("Couldn't generate the topology !");
+        }
+        Box::new(topology)
+    }
+}
+
+/// Reads the meter status of a smart plug.
+#[derive(Debug)]
+struct SmartPlugPowerMeter {
+    client: HttpClient,
+    url: String,
+    plug_type: SmartPlugType,
+}
+
+impl PowerMeter for SmartPlugPowerMeter {
+    /// Returns the instant power of the plug. The energy counter of plugs has a
+    /// resolution of one watt-hour at best, too coarse to compute the power at
+    /// each measurement step, so it is only logged.
+    fn read_power_watts(&self) -> Result<f64, Box<dyn Error>> {
+        let mut response = self.client.get(&self.url)?;
+        if !response.status().is_success() {
+            return Err(format!("Smart plug {} answered {}", self.url, response.status()).into());
+        }
+        let reading = parse_reading(self.plug_type, &response.text()?)?;
+        if let Some(energy) = reading.energy_watthours {
+            debug!("Smart plug energy counter: {} Wh", energy);
+        }
+        Ok(reading.power_watts)
+    }
+}
+
+/// Parses the meter status returned by a plug.
+fn parse_reading(plug_type: SmartPlugType, body: &str) -> Result<SmartPlugReading, Box<dyn Error>> {
+    let status: serde_json::Value = serde_json::from_str(body)?;
+    let (power, energy) = match plug_type {
+        SmartPlugType::Tasmota => {
+            let energy = &status["StatusSNS"]["ENERGY"];
+            // Tasmota gives the total energy in kWh
+            (
+                energy["Power"].as_f64(),
+                energy["Total"].as_f64().map(|kwh| kwh * 1000.0),
+            )
+        }
+        // Gen1 Shelly gives the total energy in watt-minutes
+        SmartPlugType::Shelly => (
+            status["power"].as_f64(),
+            status["total"].as_f64().map(|wmin| wmin / 60.0),
+        ),
+        SmartPlugType::ShellyGen2 => (
+            status["apower"].as_f64(),
+            status["aenergy"]["total"].as_f64(),
+        ),
+    };
+    match power {
+        Some(power_watts) => Ok(SmartPlugReading {
+            power_watts,
+            energy_watthours: energy,
+        }),
+        None => Err(format!("No power found in {plug_type:?} smart plug status").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tasmota_reading() {
+        let body = r#"{"StatusSNS":{"Time":"2021-03-01T10:00:00","ENERGY":{"Total":12.345,"Yesterday":0.2,"Today":0.1,"Power":42,"Voltage":230,"Current":0.2}}}"#;
+        assert_eq!(
+            parse_reading(SmartPlugType::Tasmota, body).unwrap(),
+            SmartPlugReading {
+                power_watts: 42.0,
+                energy_watthours: Some(12345.0),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_shelly_readings() {
+        let body = r#"{"power":30.5,"overpower":0.0,"is_valid":true,"timestamp":0,"counters":[30.5,30.4,30.6],"total":600}"#;
+        assert_eq!(
+            parse_reading(SmartPlugType::Shelly, body).unwrap(),
+            SmartPlugReading {
+                power_watts: 30.5,
+                energy_watthours: Some(10.0),
+            }
+        );
+        let body = r#"{"id":0,"source":"init","output":true,"apower":18.2,"voltage":231.1,"aenergy":{"total":3.5,"by_minute":[0,0,0]}}"#;
+        assert_eq!(
+            parse_reading(SmartPlugType::ShellyGen2, body).unwrap(),
+            SmartPlugReading {
+                power_watts: 18.2,
+                energy_watthours: Some(3.5),
+            }
+        );
+        assert!(parse_reading(SmartPlugType::ShellyGen2, r#"{"id":0}"#).is_err());
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
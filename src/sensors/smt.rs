@@ -0,0 +1,233 @@
+//! # SMT
+//!
+//! The smt module weights the CPU time of processes on hosts with simultaneous
+//! multithreading (hyperthreading). Two logical CPUs of the same physical
+//! core share its execution resources: a jiffy spent on a CPU whose sibling
+//! was busy too costs less of the core than a jiffy spent alone on it. Siblings
+//! are read from `/sys/devices/system/cpu/cpuN/topology/thread_siblings_list`,
+//! and the busy time of each CPU from `/proc/stat`.
+//!
+//! The time of each CPU is weighted by the busy time of its core (the busy
+//! time of its busiest sibling, as siblings run at the same time as much as
+//! they can) divided by the busy time of all its siblings. Weighting the host
+//! the same way keeps the power of all the processes equal to the power of
+//! the host.
+//!
+//! The time of a process is weighted with the weight of the CPU it last ran
+//! on, as the time it spent on each CPU is not known: this is approximate for
+//! processes moving between cores, or running on several of them at once.
+use crate::sensors::utils::ProcessTracker;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Keeps track of the busy time of logical CPUs sharing physical cores.
+#[derive(Debug, Clone)]
+pub struct SmtTracker {
+    /// Logical CPUs of the physical core of each CPU, itself included, sorted.
+    siblings: HashMap<u16, Vec<u16>>,
+    /// Busy time of each CPU at the last refresh, in jiffies.
+    last_busy: HashMap<u16, u64>,
+    /// Weight of the time spent on each CPU between the last two refreshes.
+    weights: HashMap<u16, f64>,
+    /// Busy time of the physical cores divided by the busy time of the logical
+    /// CPUs, between the last two refreshes.
+    host_factor: Option<f64>,
+}
+
+impl SmtTracker {
+    /// Instantiates SmtTracker for the CPUs described in `root`, like
+    /// `/sys/devices/system/cpu`. Returns an error if no core has several
+    /// logical CPUs.
+    pub fn new(root: &str) -> Result<SmtTracker, String> {
+        let siblings = read_thread_siblings(Path::new(root));
+        if siblings.values().all(|s| s.len() < 2) {
+            return Err(format!(
+                "No core has several threads in {root}, SMT is disabled or not supported"
+            ));
+        }
+        Ok(SmtTracker {
+            siblings,
+            last_busy: HashMap::new(),
+            weights: HashMap::new(),
+            host_factor: None,
+        })
+    }
+
+    /// Reads the busy time of each CPU from the kernel stats of the procfs of
+    /// `proc_tracker` and updates the weights.
+    pub fn refresh(&mut self, proc_tracker: &ProcessTracker) {
+        match proc_tracker.read_kernel_stats() {
+            Ok(stats) => {
+                let busy = stats
+                    .cpu_time
+                    .iter()
+                    .enumerate()
+                    .map(|(cpu, time)| {
+                        (
+                            cpu as u16,
+                            time.user + time.nice + time.system + time.guest.unwrap_or_default(),
+                        )
+                    })
+                    .collect();
+                self.update(busy);
+            }
+            Err(err) => warn!(
+                "Couldn't read {}: {}",
+                proc_tracker.get_procfs_root().join("stat").display(),
+                err
+            ),
+        }
+    }
+
+    /// Updates the weights from the busy time of each CPU, in jiffies.
+    fn update(&mut self, busy: HashMap<u16, u64>) {
+        if !self.last_busy.is_empty() {
+            let diff = busy
+                .iter()
+                .map(|(cpu, time)| {
+                    let previous = self.last_busy.get(cpu).copied().unwrap_or(*time);
+                    (*cpu, time.saturating_sub(previous))
+                })
+                .collect();
+            let (weights, host_factor) = compute_weights(&self.siblings, &diff);
+            self.weights = weights;
+            self.host_factor = Some(host_factor);
+        }
+        self.last_busy = busy;
+    }
+
+    /// Returns the weight of the time spent on `cpu` between the last two
+    /// refreshes, between 0.5 (siblings always busy together) and 1.
+    pub fn get_weight(&self, cpu: u16) -> Option<f64> {
+        self.weights.get(&cpu).copied()
+    }
+
+    /// Returns the factor to apply to the CPU time of the host, so that it
+    /// matches the sum of the weighted time of its CPUs.
+    pub fn get_host_factor(&self) -> Option<f64> {
+        self.host_factor
+    }
+}
+
+/// Returns the weight of each CPU given its busy time, and the factor of the
+/// host, from the siblings of each CPU.
+fn compute_weights(
+    siblings: &HashMap<u16, Vec<u16>>,
+    busy: &HashMap<u16, u64>,
+) -> (HashMap<u16, f64>, f64) {
+    let mut weights = HashMap::new();
+    let mut cores_busy = HashMap::new();
+    for cpu in busy.keys() {
+        let group = siblings.get(cpu).cloned().unwrap_or_else(|| vec![*cpu]);
+        let times: Vec<u64> = group
+            .iter()
+            .map(|c| busy.get(c).copied().unwrap_or(0))
+            .collect();
+        let core_busy = times.iter().copied().max().unwrap_or(0);
+        let siblings_busy: u64 = times.iter().sum();
+        let weight = if siblings_busy > 0 {
+            core_busy as f64 / siblings_busy as f64
+        } else {
+            1.0
+        };
+        weights.insert(*cpu, weight);
+        cores_busy.insert(group, core_busy);
+    }
+    let cpus_busy: u64 = busy.values().sum();
+    let host_factor = if cpus_busy > 0 {
+        cores_busy.values().sum::<u64>() as f64 / cpus_busy as f64
+    } else {
+        1.0
+    };
+    (weights, host_factor)
+}
+
+/// Reads the logical CPUs sharing the physical core of each CPU found in `root`.
+fn read_thread_siblings(root: &Path) -> HashMap<u16, Vec<u16>> {
+    let mut siblings = HashMap::new();
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return siblings,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name();
+        let cpu = match name.to_str().and_then(|n| n.strip_prefix("cpu")) {
+            Some(id) => match id.parse::<u16>() {
+                Ok(id) => id,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        if let Ok(list) = fs::read_to_string(entry.path().join("topology/thread_siblings_list")) {
+            if let Some(mut cpus) = parse_cpu_list(list.trim()) {
+                cpus.sort_unstable();
+                siblings.insert(cpu, cpus);
+            }
+        }
+    }
+    siblings
+}
+
+/// Parses a list of CPUs like `0,4` or `0-1`.
+fn parse_cpu_list(list: &str) -> Option<Vec<u16>> {
+    let mut cpus = vec![];
+    for range in list.split(',') {
+        match range.split_once('-') {
+            Some((first, last)) => cpus.extend(first.parse::<u16>().ok()?..=last.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn siblings_time_weighted() {
+        // 2 cores of 2 threads, cpu0 and cpu2 on the first one, cpu1 and cpu3 on the second
+        let root = std::env::temp_dir().join(format!("scaphandre_test_smt_{}", std::process::id()));
+        for (cpu, list) in [(0, "0,2"), (1, "1,3"), (2, "0,2"), (3, "1,3")] {
+            let topology = root.join(format!("cpu{cpu}/topology"));
+            fs::create_dir_all(&topology).unwrap();
+            fs::write(topology.join("thread_siblings_list"), format!("{list}\n")).unwrap();
+        }
+        let mut tracker = SmtTracker::new(root.to_str().unwrap()).unwrap();
+        assert_eq!(tracker.siblings[&2], vec![0, 2]);
+
+        tracker.update(HashMap::from([(0, 0), (1, 0), (2, 0), (3, 0)]));
+        assert_eq!(tracker.get_weight(0), None);
+        // the first core busy on both threads, the second on one only
+        tracker.update(HashMap::from([(0, 100), (1, 100), (2, 100), (3, 0)]));
+        assert_eq!(tracker.get_weight(0), Some(0.5));
+        assert_eq!(tracker.get_weight(2), Some(0.5));
+        assert_eq!(tracker.get_weight(1), Some(1.0));
+        assert_eq!(tracker.get_weight(3), Some(1.0));
+        // cores busy for 200 jiffies, threads for 300
+        let factor = tracker.get_host_factor().unwrap();
+        assert!((factor - 2.0 / 3.0).abs() < 1e-9);
+
+        assert_eq!(parse_cpu_list("0-2,5"), Some(vec![0, 1, 2, 5]));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("cpu0/topology")).unwrap();
+        fs::write(root.join("cpu0/topology/thread_siblings_list"), "0\n").unwrap();
+        assert!(SmtTracker::new(root.to_str().unwrap()).is_err());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
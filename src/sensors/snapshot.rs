@@ -0,0 +1,142 @@
+//! # Snapshot
+//!
+//! The snapshot module gives library users the power and energy measured on
+//! the host, its sockets, their domains and the processes at a moment, as
+//! plain structs, in one call to [Topology::snapshot](crate::sensors::Topology::snapshot).
+//! Structs are serializable with serde when the `serde` feature is enabled
+//! (it is by the `json` feature).
+//!
+//! To react to each measurement without writing an exporter, register a
+//! [RefreshObserver] with [Topology::add_refresh_observer](crate::sensors::Topology::add_refresh_observer):
+//! it is given the snapshot after each refresh.
+//!
+//! ```
+//! use scaphandre::sensors::{snapshot::TopologySnapshot, Topology};
+//! use std::sync::Arc;
+//!
+//! let mut topology = Topology::default();
+//! topology.add_refresh_observer(Arc::new(|snapshot: &TopologySnapshot| {
+//!     if snapshot.host.power_microwatts.unwrap_or(0) > 200_000_000 {
+//!         println!("The host draws more than 200W");
+//!     }
+//! }));
+//! ```
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+
+/// Power and energy of the whole topology at a moment.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TopologySnapshot {
+    /// Time of the last measurement of the host, in seconds since the epoch.
+    pub timestamp: f64,
+    pub host: HostSnapshot,
+    pub sockets: Vec<SocketSnapshot>,
+    pub processes: Vec<ProcessSnapshot>,
+}
+
+/// Power and energy of the whole host.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct HostSnapshot {
+    /// Power between the last two measurements, in microwatts.
+    pub power_microwatts: Option<u64>,
+    /// Last value of the energy counter, in microjoules.
+    pub energy_microjoules: Option<u64>,
+    /// Energy consumed since scaphandre started, in microjoules.
+    pub energy_since_start_microjoules: Option<u64>,
+}
+
+/// Power and energy of a CPU socket.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SocketSnapshot {
+    pub id: u16,
+    /// Power between the last two measurements, in microwatts.
+    pub power_microwatts: Option<u64>,
+    /// Last value of the energy counter, in microjoules.
+    pub energy_microjoules: Option<u64>,
+    /// Energy consumed since scaphandre started, in microjoules.
+    pub energy_since_start_microjoules: Option<u64>,
+    pub domains: Vec<DomainSnapshot>,
+}
+
+/// Power and energy of a RAPL domain.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct DomainSnapshot {
+    pub id: u16,
+    pub name: String,
+    /// Power between the last two measurements, in microwatts.
+    pub power_microwatts: Option<u64>,
+    /// Last value of the energy counter, in microjoules.
+    pub energy_microjoules: Option<u64>,
+}
+
+/// Power attributed to a process.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ProcessSnapshot {
+    pub pid: i32,
+    pub exe: String,
+    pub cmdline: Option<String>,
+    /// Power attributed to the process between the last two measurements, in microwatts.
+    pub power_microwatts: Option<u64>,
+}
+
+/// Called after each refresh of a topology, with the snapshot of what it measured.
+/// Implemented by closures taking a `&TopologySnapshot`.
+pub trait RefreshObserver: Send + Sync {
+    fn on_refresh(&self, snapshot: &TopologySnapshot);
+}
+
+impl<F: Fn(&TopologySnapshot) + Send + Sync> RefreshObserver for F {
+    fn on_refresh(&self, snapshot: &TopologySnapshot) {
+        self(snapshot)
+    }
+}
+
+/// Observers registered on a topology, called in the order they were added.
+#[derive(Clone, Default)]
+pub struct RefreshObservers(Vec<Arc<dyn RefreshObserver>>);
+
+impl RefreshObservers {
+    /// Adds an observer, called after the ones already added.
+    pub fn add(&mut self, observer: Arc<dyn RefreshObserver>) {
+        self.0.push(observer);
+    }
+
+    /// Returns true if no observer was added.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Calls every observer with `snapshot`.
+    pub fn notify(&self, snapshot: &TopologySnapshot) {
+        for observer in &self.0 {
+            observer.on_refresh(snapshot);
+        }
+    }
+}
+
+impl fmt::Debug for RefreshObservers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RefreshObservers({})", self.0.len())
+    }
+}
+
+//  Copyright 2020 The scaphandre authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
@@ -1,8 +1,12 @@
+#[cfg(all(target_os = "linux", feature = "kubelet"))]
+use crate::sensors::kubelet::KubeletPods;
+#[cfg(target_os = "windows")]
+use crate::sensors::pdh::{PdhProcessCounters, ProcessesCpuUsage};
+use crate::sensors::Record;
 #[cfg(target_os = "linux")]
 use procfs::{self, process::Process};
 use regex::Regex;
-#[cfg(feature = "containers")]
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 #[cfg(target_os = "windows")]
 use sysinfo::{get_current_pid, Process, ProcessExt, ProcessorExt, System, SystemExt};
 //use std::error::Error;
@@ -158,7 +162,7 @@ pub struct IStatus {
     pub pid: i32,
     pub ppid: i32,
     //pub tracerpid: i32,
-    //pub ruid: u32,
+    pub ruid: u32,
     //pub euid: u32,
     //pub suid: u32,
     //pub fuid: u32,
@@ -321,6 +325,7 @@ impl IProcess {
                     ppid: original_status.ppid,
                     state: original_status.state,
                     umask: original_status.umask,
+                    ruid: original_status.ruid,
                 };
                 Ok(status)
             } else {
@@ -335,6 +340,7 @@ impl IProcess {
                 ppid: 42,
                 state: String::from("X"),
                 umask: None,
+                ruid: self.owner,
             })
         }
     }
@@ -354,6 +360,22 @@ impl IProcess {
     }
 }
 
+/// Opens the performance counters giving the CPU time of processes, or returns
+/// None if they are unavailable, sysinfo being used instead.
+#[cfg(target_os = "windows")]
+fn open_pdh_counters() -> Option<PdhProcessCounters> {
+    match PdhProcessCounters::new() {
+        Ok(counters) => Some(counters),
+        Err(e) => {
+            warn!(
+                "Couldn't open the processes performance counters, falling back to sysinfo: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
 pub fn page_size() -> Result<i64, String> {
     let res;
     #[cfg(target_os = "linux")]
@@ -379,12 +401,66 @@ pub struct ProcessTracker {
     pub max_records_per_process: u16,
     #[cfg(target_os = "windows")]
     pub sysinfo: System,
+    /// Performance counters giving the CPU time of processes, if they could be opened.
+    #[cfg(target_os = "windows")]
+    pdh_counters: Option<PdhProcessCounters>,
+    /// CPU usage of processes read from pdh_counters at the last refresh.
+    #[cfg(target_os = "windows")]
+    cpu_usage: ProcessesCpuUsage,
     #[cfg(feature = "containers")]
     pub regex_cgroup_docker: Regex,
     #[cfg(feature = "containers")]
     pub regex_cgroup_kubernetes: Regex,
     #[cfg(feature = "containers")]
     pub regex_cgroup_containerd: Regex,
+    /// Real user id of each process, by pid. Read once, when the process is first seen.
+    uids: HashMap<i32, u32>,
+    /// Labels describing each process (pid, exe and cmdline), by pid.
+    /// Computed once per process by cache_process_labels().
+    labels: HashMap<i32, Vec<(String, String)>>,
+    /// If set, only these pids are tracked.
+    pid_allowlist: Option<HashSet<i32>>,
+    /// Time the latest refresh of the processes started.
+    last_refresh: Option<Duration>,
+    /// If set, processes not seen by a refresh are dropped after that time.
+    stale_process_grace: Option<Duration>,
+    /// Energy attributed to each process since it was first seen, by pid.
+    energy: HashMap<i32, ProcessEnergy>,
+    /// What identifies processes in their labels, besides their pid.
+    pub process_identity: ProcessIdentity,
+    /// Time the host booted, since the epoch, to turn the start time of
+    /// processes, counted from the boot, into a date.
+    boot_time: Option<Duration>,
+    /// Pods of the node, if processes are resolved to their Kubernetes pod.
+    #[cfg(all(target_os = "linux", feature = "kubelet"))]
+    kubelet_pods: Option<KubeletPods>,
+    /// Procfs processes and kernel stats are read from, like a snapshot of the
+    /// procfs of another host, if not the one mounted at /proc.
+    #[cfg(target_os = "linux")]
+    procfs_root: Option<PathBuf>,
+    /// If set, only the processes of this PID namespace are tracked. It is the
+    /// target of the `ns/pid` link of its processes, like `pid:[4026532281]`.
+    #[cfg(target_os = "linux")]
+    pid_namespace: Option<PathBuf>,
+    /// Pid of each process in pid_namespace, by pid on the host.
+    /// Read once, when the process is first seen.
+    #[cfg(target_os = "linux")]
+    namespace_pids: HashMap<i32, i32>,
+}
+
+/// Time the energy of a process that exited is still given after its exit.
+const EXITED_PROCESS_ENERGY_RETENTION: Duration = Duration::from_secs(600);
+
+/// Energy attributed to a process, accumulated from its power at each refresh.
+#[derive(Debug, Clone)]
+struct ProcessEnergy {
+    /// Start time of the process, telling a reused pid apart.
+    starttime: u64,
+    microjoules: f64,
+    /// End of the last measurement window accumulated.
+    last_timestamp: Duration,
+    /// True once the process exited: the energy won't grow anymore.
+    exited: bool,
 }
 
 impl Clone for ProcessTracker {
@@ -394,6 +470,10 @@ impl Clone for ProcessTracker {
             max_records_per_process: self.max_records_per_process,
             #[cfg(target_os = "windows")]
             sysinfo: System::new_all(),
+            #[cfg(target_os = "windows")]
+            pdh_counters: open_pdh_counters(),
+            #[cfg(target_os = "windows")]
+            cpu_usage: ProcessesCpuUsage::default(),
             #[cfg(feature = "containers")]
             regex_cgroup_docker: self.regex_cgroup_docker.clone(),
             #[cfg(feature = "containers")]
@@ -401,6 +481,22 @@ impl Clone for ProcessTracker {
             #[cfg(feature = "containers")]
             regex_cgroup_containerd: self.regex_cgroup_containerd.clone(),
             nb_cores: self.nb_cores,
+            uids: self.uids.clone(),
+            labels: self.labels.clone(),
+            pid_allowlist: self.pid_allowlist.clone(),
+            last_refresh: self.last_refresh,
+            stale_process_grace: self.stale_process_grace,
+            energy: self.energy.clone(),
+            process_identity: self.process_identity.clone(),
+            boot_time: self.boot_time,
+            #[cfg(all(target_os = "linux", feature = "kubelet"))]
+            kubelet_pods: self.kubelet_pods.clone(),
+            #[cfg(target_os = "linux")]
+            procfs_root: self.procfs_root.clone(),
+            #[cfg(target_os = "linux")]
+            pid_namespace: self.pid_namespace.clone(),
+            #[cfg(target_os = "linux")]
+            namespace_pids: self.namespace_pids.clone(),
         }
     }
 }
@@ -428,6 +524,10 @@ impl ProcessTracker {
             max_records_per_process,
             #[cfg(target_os = "windows")]
             sysinfo: System::new_all(),
+            #[cfg(target_os = "windows")]
+            pdh_counters: open_pdh_counters(),
+            #[cfg(target_os = "windows")]
+            cpu_usage: ProcessesCpuUsage::default(),
             #[cfg(feature = "containers")]
             regex_cgroup_docker,
             #[cfg(feature = "containers")]
@@ -438,6 +538,68 @@ impl ProcessTracker {
             nb_cores: System::new_all().processors().len(),
             #[cfg(target_os = "linux")]
             nb_cores: 0, // TODO implement
+            uids: HashMap::new(),
+            labels: HashMap::new(),
+            pid_allowlist: None,
+            last_refresh: None,
+            stale_process_grace: None,
+            energy: HashMap::new(),
+            process_identity: ProcessIdentity::default(),
+            #[cfg(target_os = "linux")]
+            boot_time: procfs::boot_time_secs().ok().map(Duration::from_secs),
+            #[cfg(target_os = "windows")]
+            boot_time: None,
+            #[cfg(all(target_os = "linux", feature = "kubelet"))]
+            kubelet_pods: None,
+            #[cfg(target_os = "linux")]
+            procfs_root: None,
+            #[cfg(target_os = "linux")]
+            pid_namespace: None,
+            #[cfg(target_os = "linux")]
+            namespace_pids: HashMap::new(),
+        }
+    }
+
+    /// Reads processes and kernel stats from the procfs at `root` (like a
+    /// snapshot of `/proc` captured on another host) instead of `/proc`. The
+    /// boot time is read from its `stat` file.
+    #[cfg(target_os = "linux")]
+    pub fn set_procfs_root(&mut self, root: &str) {
+        self.procfs_root = Some(PathBuf::from(root));
+        self.boot_time = self
+            .read_kernel_stats()
+            .ok()
+            .map(|stats| Duration::from_secs(stats.btime));
+    }
+
+    /// Returns the root of the procfs processes and kernel stats are read from.
+    #[cfg(target_os = "linux")]
+    pub fn get_procfs_root(&self) -> PathBuf {
+        self.procfs_root
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("/proc"))
+    }
+
+    /// Returns the processes found in the procfs of the tracker, restricted to
+    /// the allowed ones if an allowlist is set, to avoid reading all the others.
+    #[cfg(target_os = "linux")]
+    pub fn read_processes(&self) -> procfs::ProcResult<Vec<Process>> {
+        let root = self.get_procfs_root();
+        match self.get_pid_allowlist() {
+            Some(pids) => Ok(pids
+                .into_iter()
+                .filter_map(|pid| Process::new_with_root(root.join(pid.to_string())).ok())
+                .collect()),
+            None => procfs::process::all_processes_with_root(root),
+        }
+    }
+
+    /// Reads the `stat` file of the procfs of the tracker.
+    #[cfg(target_os = "linux")]
+    pub fn read_kernel_stats(&self) -> procfs::ProcResult<procfs::KernelStats> {
+        match &self.procfs_root {
+            Some(root) => procfs::KernelStats::from_reader(std::fs::File::open(root.join("stat"))?),
+            None => procfs::KernelStats::new(),
         }
     }
 
@@ -457,12 +619,20 @@ impl ProcessTracker {
     /// }
     /// ```
     pub fn add_process_record(&mut self, process: IProcess) -> Result<String, String> {
+        if !self.is_pid_allowed(process.pid) {
+            return Ok(String::from("Process not in the allowlist, ignored."));
+        }
+        #[cfg(target_os = "linux")]
+        if !self.is_in_pid_namespace(process.pid) {
+            return Ok(String::from("Process not in the PID namespace, ignored."));
+        }
         let iterator = self.procs.iter_mut();
         let pid = process.pid;
         // find the vector containing Process instances with the same pid
         let mut filtered = iterator.filter(|x| !x.is_empty() && x[0].process.pid == pid);
         let result = filtered.next();
         let process_record = ProcessRecord::new(process);
+        let mut new_process = result.is_none();
         if let Some(vector) = result {
             // if a vector of process records has been found
             // check if the previous records in the vector are from the same process
@@ -471,18 +641,220 @@ impl ProcessTracker {
                 && process_record.process.comm != vector.get(0).unwrap().process.comm
             {
                 *vector = vec![];
+                new_process = true;
+                self.labels.remove(&pid);
+                #[cfg(target_os = "linux")]
+                self.namespace_pids.remove(&pid);
             }
             //ProcessTracker::check_pid_changes(&process_record, vector);
+            if new_process || !self.uids.contains_key(&pid) {
+                self.uids
+                    .insert(pid, ProcessTracker::read_uid(&process_record.process));
+            }
             vector.insert(0, process_record); // we add the process record to the vector
             ProcessTracker::clean_old_process_records(vector, self.max_records_per_process);
         } else {
             // if no vector of process records with the same pid has been found in self.procs
+            self.uids
+                .insert(pid, ProcessTracker::read_uid(&process_record.process));
             self.procs.push(vec![process_record]); // we create a new vector in self.procs
         }
 
         Ok(String::from("Successfully added record to process."))
     }
 
+    /// Restricts the processes tracked to `pids`, or tracks all of them if None.
+    /// Records of processes not in the allowlist are dropped.
+    pub fn set_pid_allowlist(&mut self, pids: Option<Vec<i32>>) {
+        self.pid_allowlist = pids.map(|pids| pids.into_iter().collect());
+        if let Some(allowlist) = &self.pid_allowlist {
+            self.procs
+                .retain(|v| matches!(v.first(), Some(r) if allowlist.contains(&r.process.pid)));
+        }
+    }
+
+    /// Returns the pids allowed to be tracked, if an allowlist is set.
+    pub fn get_pid_allowlist(&self) -> Option<Vec<i32>> {
+        self.pid_allowlist
+            .as_ref()
+            .map(|allowlist| allowlist.iter().copied().collect())
+    }
+
+    /// Returns true if the process `pid` should be tracked.
+    pub fn is_pid_allowed(&self, pid: i32) -> bool {
+        match &self.pid_allowlist {
+            Some(allowlist) => allowlist.contains(&pid),
+            None => true,
+        }
+    }
+
+    /// Restricts the processes tracked to the ones in the PID namespace of
+    /// process `pid` (a pid on the host, like the one of the init process of a
+    /// container), so they can be identified by their pid in this namespace.
+    /// Returns an error if the namespace of `pid` can't be read.
+    #[cfg(target_os = "linux")]
+    pub fn set_pid_namespace(&mut self, pid: i32) -> Result<(), String> {
+        let namespace = self.read_pid_namespace(pid).ok_or_else(|| {
+            format!("Couldn't read the PID namespace of process {pid}, is it running?")
+        })?;
+        self.pid_namespace = Some(namespace);
+        self.namespace_pids.clear();
+        self.procs.clear();
+        self.labels.clear();
+        Ok(())
+    }
+
+    /// Returns the pid of a process in the PID namespace set with
+    /// set_pid_namespace(), if any.
+    #[cfg(target_os = "linux")]
+    pub fn get_namespace_pid(&self, pid: i32) -> Option<i32> {
+        self.namespace_pids.get(&pid).copied()
+    }
+
+    /// Returns true if no PID namespace is set or if process `pid` is in it,
+    /// reading its pid in the namespace when it is first seen.
+    #[cfg(target_os = "linux")]
+    fn is_in_pid_namespace(&mut self, pid: i32) -> bool {
+        let namespace = match &self.pid_namespace {
+            Some(namespace) => namespace,
+            None => return true,
+        };
+        if self.namespace_pids.contains_key(&pid) {
+            return true;
+        }
+        if self.read_pid_namespace(pid).as_ref() != Some(namespace) {
+            return false;
+        }
+        let namespace_pid = self.read_namespace_pid(pid).unwrap_or(pid);
+        self.namespace_pids.insert(pid, namespace_pid);
+        true
+    }
+
+    /// Reads the PID namespace of process `pid`, the target of its `ns/pid` link.
+    #[cfg(target_os = "linux")]
+    fn read_pid_namespace(&self, pid: i32) -> Option<PathBuf> {
+        std::fs::read_link(self.get_procfs_root().join(pid.to_string()).join("ns/pid")).ok()
+    }
+
+    /// Reads the pid of process `pid` in its own PID namespace: the last pid of
+    /// the NSpid line of its status file.
+    #[cfg(target_os = "linux")]
+    fn read_namespace_pid(&self, pid: i32) -> Option<i32> {
+        let status =
+            std::fs::read_to_string(self.get_procfs_root().join(pid.to_string()).join("status"))
+                .ok()?;
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("NSpid:"))?
+            .split_whitespace()
+            .last()?
+            .parse()
+            .ok()
+    }
+
+    /// Returns the real user id of a process, as found in /proc/PID/status, or
+    /// the owner of the process if the status can't be read.
+    fn read_uid(process: &IProcess) -> u32 {
+        process
+            .status()
+            .map(|status| status.ruid)
+            .unwrap_or(process.owner)
+    }
+
+    /// Computes the labels of the alive processes that don't have cached labels yet,
+    /// so that they are not computed again at each measurement.
+    pub fn cache_process_labels(&mut self) {
+        for pid in self.get_alive_pids() {
+            if !self.labels.contains_key(&pid) {
+                let mut labels = vec![(String::from("pid"), pid.to_string())];
+                #[cfg(target_os = "linux")]
+                if let Some(namespace_pid) = self.get_namespace_pid(pid) {
+                    labels.push((String::from("ns_pid"), namespace_pid.to_string()));
+                }
+                labels.extend(self.get_process_identity_labels(pid));
+                self.labels.insert(pid, labels);
+            }
+        }
+    }
+
+    /// Sets what identifies processes in their labels, forgetting the labels
+    /// cached until then.
+    pub fn set_process_identity(&mut self, identity: ProcessIdentity) {
+        self.process_identity = identity;
+        self.labels.clear();
+    }
+
+    /// Returns the labels identifying process `pid` according to
+    /// process_identity: an exe label, and a cmdline label with
+    /// ProcessIdentity::Cmdline. The exe is the short name (comm) of the
+    /// process if the identity can't be found.
+    pub fn get_process_identity_labels(&self, pid: i32) -> Vec<(String, String)> {
+        let comm = self.get_process_name(pid);
+        let exe = match &self.process_identity {
+            ProcessIdentity::Cmdline { max_length } => {
+                let mut labels = vec![(String::from("exe"), comm)];
+                if let Some(cmdline) = self.get_process_cmdline(pid) {
+                    let cmdline = match max_length {
+                        Some(max_length) => cmdline.chars().take(*max_length).collect(),
+                        None => cmdline,
+                    };
+                    labels.push((String::from("cmdline"), cmdline));
+                }
+                return labels;
+            }
+            ProcessIdentity::Comm => None,
+            ProcessIdentity::Exe => self.get_process_exe(pid),
+            ProcessIdentity::Regex(regex) => self
+                .get_process_cmdline(pid)
+                .and_then(|cmdline| regex.captures(&cmdline).map(|c| extract_identity(&c))),
+        };
+        vec![(
+            String::from("exe"),
+            exe.filter(|exe| !exe.is_empty()).unwrap_or(comm),
+        )]
+    }
+
+    /// Returns the path of the executable of process `pid`, if it can be read.
+    fn get_process_exe(&self, pid: i32) -> Option<String> {
+        let record = self.find_records(pid)?.first()?;
+        #[cfg(target_os = "linux")]
+        let exe = record.process.exe();
+        #[cfg(target_os = "windows")]
+        let exe = record.process.exe(self);
+        exe.ok().map(|path| path.to_string_lossy().to_string())
+    }
+
+    /// Returns the cached labels of a process (pid, exe and cmdline if any),
+    /// if cache_process_labels() was called since the process was first seen.
+    pub fn get_process_labels(&self, pid: i32) -> Option<&Vec<(String, String)>> {
+        self.labels.get(&pid)
+    }
+
+    /// Returns the real user id of a process, if it is tracked.
+    pub fn get_process_uid(&self, pid: i32) -> Option<u32> {
+        self.uids.get(&pid).copied()
+    }
+
+    /// Returns the pids of the alive processes run by the user `uid`.
+    pub fn get_alive_pids_for_uid(&self, uid: u32) -> Vec<i32> {
+        self.get_alive_pids()
+            .into_iter()
+            .filter(|pid| self.get_process_uid(*pid) == Some(uid))
+            .collect()
+    }
+
+    /// Returns the user ids running at least one of the alive processes, sorted.
+    pub fn get_alive_uids(&self) -> Vec<u32> {
+        let mut uids: Vec<u32> = self
+            .get_alive_pids()
+            .iter()
+            .filter_map(|pid| self.get_process_uid(*pid))
+            .collect();
+        uids.sort_unstable();
+        uids.dedup();
+        uids
+    }
+
     /// Removes as many ProcessRecords as needed from the vector (passed as a mutable ref in parameters)
     /// in order for the vector length to match self.max_records_per_process.
     fn clean_old_process_records(records: &mut Vec<ProcessRecord>, max_records_per_process: u16) {
@@ -638,29 +1010,10 @@ impl ProcessTracker {
                                 containers.iter().find(|x| x.Id == container_id)
                             {
                                 debug!("found container with id: {}", &container_id);
-                                let mut names = String::from("");
-                                for n in &container.Names {
-                                    debug!("adding container name: {}", &n.trim().replace('/', ""));
-                                    names.push_str(&n.trim().replace('/', ""));
-                                }
-                                description.insert(String::from("container_names"), names);
-                                description.insert(
-                                    String::from("container_docker_version"),
-                                    docker_version.clone(),
-                                );
-                                if let Some(labels) = &container.Labels {
-                                    for (k, v) in labels {
-                                        let escape_list = ["-", ".", ":", " "];
-                                        let mut key = k.clone();
-                                        for e in escape_list.iter() {
-                                            key = key.replace(e, "_");
-                                        }
-                                        description.insert(
-                                            format!("container_label_{key}"),
-                                            v.to_string(),
-                                        );
-                                    }
-                                }
+                                description.extend(get_docker_container_labels(
+                                    container,
+                                    &docker_version,
+                                ));
                             }
                             found = true;
                         }
@@ -755,6 +1108,44 @@ impl ProcessTracker {
             .collect()
     }
 
+    /// Returns the parent pid of a process, as found in its most recent record.
+    pub fn get_process_ppid(&self, pid: i32) -> Option<i32> {
+        self.find_records(pid)
+            .and_then(|records| records.first())
+            .and_then(|record| record.process.stat.as_ref())
+            .map(|stat| stat.ppid)
+    }
+
+    /// Returns the pids of all alive descendants of root_pid (children, grand-children...),
+    /// based on the current parent pid of each process. A process whose parent
+    /// died is reparented (to init or a subreaper) by the kernel, so it only
+    /// belongs to the tree of its new parent and is never counted twice.
+    pub fn get_descendants_pids(&self, root_pid: i32) -> Vec<i32> {
+        let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+        for pid in self.get_alive_pids() {
+            if let Some(ppid) = self.get_process_ppid(pid) {
+                if ppid != pid {
+                    children.entry(ppid).or_default().push(pid);
+                }
+            }
+        }
+        let mut descendants = vec![];
+        let mut visited = HashSet::new();
+        visited.insert(root_pid);
+        let mut to_visit = vec![root_pid];
+        while let Some(pid) = to_visit.pop() {
+            if let Some(pids) = children.get(&pid) {
+                for child in pids {
+                    if visited.insert(*child) {
+                        descendants.push(*child);
+                        to_visit.push(*child);
+                    }
+                }
+            }
+        }
+        descendants
+    }
+
     /// Returns a vector containing pids of all processes being tracked.
     pub fn get_all_pids(&self) -> Vec<i32> {
         self.procs
@@ -817,8 +1208,28 @@ impl ProcessTracker {
         diff
     }
 
+    /// Reads the CPU time of processes from the performance counters, if they
+    /// could be opened.
+    #[cfg(target_os = "windows")]
+    pub fn refresh_cpu_usage(&mut self) {
+        if let Some(counters) = &self.pdh_counters {
+            match counters.collect() {
+                Ok(cpu_usage) => self.cpu_usage = cpu_usage,
+                Err(e) => warn!("Couldn't read the CPU time of processes: {}", e),
+            }
+        }
+    }
+
+    /// Returns the share of the CPU used by the process, in %. It is read from
+    /// the performance counters if available, estimated by sysinfo otherwise.
     #[cfg(target_os = "windows")]
     pub fn get_cpu_usage_percentage(&self, pid: usize, nb_cores: usize) -> f32 {
+        if self.pdh_counters.is_some() {
+            return self
+                .cpu_usage
+                .get_share_percentage(pid as i32)
+                .unwrap_or(0.0) as f32;
+        }
         let mut cpu_current_usage = 0.0;
         for c in self.sysinfo.processors() {
             cpu_current_usage += c.cpu_usage();
@@ -929,6 +1340,16 @@ impl ProcessTracker {
         result
     }
 
+    /// Removes the ProcessRecord preceding the last one for each process,
+    /// keeping at least two records per process to compute its usage.
+    pub fn drop_previous_records(&mut self) {
+        for v in &mut self.procs {
+            if v.len() > 2 {
+                v.remove(1);
+            }
+        }
+    }
+
     /// Drops a vector of ProcessRecord instances from self.procs
     /// if the last ProcessRecord from the vector is of state Terminated
     /// (if the process is not running anymore)
@@ -995,20 +1416,369 @@ impl ProcessTracker {
             unknown
         );
         self.drop_empty_process_records_vectors();
+        if let Some(grace) = self.stale_process_grace {
+            self.drop_stale_processes(grace);
+        }
+        let tracked_pids: HashSet<i32> = self
+            .procs
+            .iter()
+            .filter_map(|v| v.first())
+            .map(|record| record.process.pid)
+            .collect();
+        self.uids.retain(|pid, _| tracked_pids.contains(pid));
+        self.labels.retain(|pid, _| tracked_pids.contains(pid));
+        #[cfg(target_os = "linux")]
+        self.namespace_pids
+            .retain(|pid, _| tracked_pids.contains(pid));
     }
 
     /// Removes empty Vectors from self.procs
     fn drop_empty_process_records_vectors(&mut self) {
-        let procs = &mut self.procs;
-        if !procs.is_empty() {
-            for i in 0..(procs.len() - 1) {
-                if let Some(v) = procs.get(i) {
-                    if v.is_empty() {
-                        procs.remove(i);
-                    }
-                }
+        self.procs.retain(|v| !v.is_empty());
+    }
+
+    /// Tells the tracker that a refresh of the processes starts, so that
+    /// processes that are not seen by it can be told apart.
+    pub fn start_refresh(&mut self) {
+        self.last_refresh = Some(current_system_time_since_epoch());
+        #[cfg(all(target_os = "linux", feature = "kubelet"))]
+        if let Some(kubelet_pods) = &mut self.kubelet_pods {
+            kubelet_pods.refresh();
+        }
+    }
+
+    /// Resolves processes to the Kubernetes pod and container they run in,
+    /// with the pods listed by the kubelet at `url`.
+    #[cfg(all(target_os = "linux", feature = "kubelet"))]
+    pub fn enable_kubelet_pods(&mut self, url: &str) {
+        self.kubelet_pods = Some(KubeletPods::new(url));
+    }
+
+    /// Returns the `namespace`, `pod` and `container` labels of process `pid`,
+    /// if processes are resolved to their pod and this one runs in a pod.
+    pub fn get_process_pod_labels(&self, pid: i32) -> Vec<(String, String)> {
+        #[cfg(all(target_os = "linux", feature = "kubelet"))]
+        if let Some(kubelet_pods) = &self.kubelet_pods {
+            return kubelet_pods.get_process_labels(&self.get_procfs_root(), pid);
+        }
+        let _ = pid;
+        vec![]
+    }
+
+    /// Returns the time process `pid` started, since the epoch, as told by its
+    /// last record.
+    pub fn get_process_start_time(&self, pid: i32) -> Option<Duration> {
+        #[cfg(target_os = "linux")]
+        {
+            let stat = self.find_records(pid)?.first()?.process.stat.as_ref()?;
+            let ticks_per_second = procfs::ticks_per_second().ok().filter(|t| *t > 0)?;
+            Some(
+                self.boot_time?
+                    + Duration::from_secs_f64(stat.starttime as f64 / ticks_per_second as f64),
+            )
+        }
+        #[cfg(target_os = "windows")]
+        {
+            let _ = pid;
+            None
+        }
+    }
+
+    /// Returns how long process `pid` had been running at its last record.
+    pub fn get_process_age(&self, pid: i32) -> Option<Duration> {
+        let last = self.find_records(pid)?.first()?;
+        Some(
+            last.timestamp
+                .saturating_sub(self.get_process_start_time(pid)?),
+        )
+    }
+
+    /// Returns the fraction of the time between the last two records of
+    /// process `pid` during which it was alive, between 0 and 1. Lower than 1
+    /// when the process started after the previous record, like a pid reused
+    /// by a short-lived process.
+    pub fn get_alive_fraction(&self, pid: i32) -> Option<f64> {
+        let records = self.find_records(pid)?;
+        let (last, previous) = (records.first()?, records.get(1)?);
+        let window = last.timestamp.checked_sub(previous.timestamp)?;
+        if window.is_zero() {
+            return None;
+        }
+        let start = self.get_process_start_time(pid)?.max(previous.timestamp);
+        let alive = last.timestamp.saturating_sub(start);
+        Some((alive.as_secs_f64() / window.as_secs_f64()).min(1.0))
+    }
+
+    /// Adds the energy consumed by process `pid` with `power`, its power over
+    /// its last two records, since the last time it was added. A pid reused by
+    /// another process starts from zero.
+    pub fn add_process_energy(&mut self, pid: i32, power: &Record) {
+        let (starttime, window_start) = match self.find_records(pid) {
+            Some(records) if records.len() > 1 => (
+                records[0].process.stat.as_ref().map_or(0, |s| s.starttime),
+                records[1].timestamp,
+            ),
+            _ => return,
+        };
+        let microwatts = match power.value_f64() {
+            Some(microwatts) => microwatts,
+            None => return,
+        };
+        let energy = self.energy.entry(pid).or_insert(ProcessEnergy {
+            starttime,
+            microjoules: 0.0,
+            last_timestamp: window_start,
+            exited: false,
+        });
+        if energy.starttime != starttime || energy.exited {
+            *energy = ProcessEnergy {
+                starttime,
+                microjoules: 0.0,
+                last_timestamp: window_start,
+                exited: false,
+            };
+        }
+        // a window overlapping the previous one (after intermediate records) is only counted once
+        let start = energy.last_timestamp.max(window_start);
+        if power.timestamp > start {
+            energy.microjoules += microwatts * (power.timestamp - start).as_secs_f64();
+            energy.last_timestamp = power.timestamp;
+        }
+    }
+
+    /// Marks the energy of the processes that aren't alive anymore as final,
+    /// and forgets it EXITED_PROCESS_ENERGY_RETENTION after their exit.
+    pub fn finalize_exited_processes_energy(&mut self, now: Duration) {
+        let alive: HashSet<i32> = self.get_alive_pids().into_iter().collect();
+        for (pid, energy) in self.energy.iter_mut() {
+            if !alive.contains(pid) {
+                energy.exited = true;
             }
         }
+        self.energy.retain(|_, energy| {
+            !energy.exited || now < energy.last_timestamp + EXITED_PROCESS_ENERGY_RETENTION
+        });
+    }
+
+    /// Returns the energy attributed to process `pid` since it was first seen,
+    /// in microjoules. It is kept for a while once the process exited.
+    pub fn get_process_energy_microjoules(&self, pid: i32) -> Option<u64> {
+        self.energy.get(&pid).map(|e| e.microjoules as u64)
+    }
+
+    /// Makes clean_terminated_process_records_vectors() also drop the processes
+    /// that were not seen by the refreshes of the last `grace`, or not if None.
+    pub fn set_stale_process_grace(&mut self, grace: Option<Duration>) {
+        self.stale_process_grace = grace;
+    }
+
+    /// Drops the processes whose most recent record was taken more than `grace`
+    /// before the latest refresh started, that is, the processes that were not
+    /// seen by the refreshes of the last `grace`. Terminated processes are then
+    /// forgotten, with their labels, even if their state couldn't be checked.
+    pub fn drop_stale_processes(&mut self, grace: Duration) {
+        let oldest_allowed = match self.last_refresh {
+            Some(last_refresh) => last_refresh.saturating_sub(grace),
+            None => return,
+        };
+        let before = self.procs.len();
+        self.procs.retain(|v| match v.first() {
+            Some(record) => record.timestamp >= oldest_allowed,
+            None => false,
+        });
+        if self.procs.len() != before {
+            debug!("Dropped {} stale processes.", before - self.procs.len());
+            let tracked_pids: HashSet<i32> = self
+                .procs
+                .iter()
+                .filter_map(|v| v.first())
+                .map(|record| record.process.pid)
+                .collect();
+            self.uids.retain(|pid, _| tracked_pids.contains(pid));
+            self.labels.retain(|pid, _| tracked_pids.contains(pid));
+        }
+    }
+}
+
+/// CPU time of processes their share of the power is computed from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum AttributionBasis {
+    /// Time spent in user mode only.
+    UserTime,
+    /// Time spent in user and kernel (system) mode, the default.
+    #[default]
+    UserAndSystemTime,
+    /// Time spent in user mode, plus the time spent in kernel mode multiplied
+    /// by a weight.
+    Weighted { system_weight: f64 },
+}
+
+impl AttributionBasis {
+    /// Returns the name of the basis, as given to `--attribution-basis`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttributionBasis::UserTime => "user",
+            AttributionBasis::UserAndSystemTime => "user+system",
+            AttributionBasis::Weighted { .. } => "weighted",
+        }
+    }
+
+    /// Returns the weight of the time spent in kernel mode, relatively to the
+    /// time spent in user mode.
+    pub fn system_weight(&self) -> f64 {
+        match self {
+            AttributionBasis::UserTime => 0.0,
+            AttributionBasis::UserAndSystemTime => 1.0,
+            AttributionBasis::Weighted { system_weight } => *system_weight,
+        }
+    }
+
+    /// Returns the basis named `name`, weighting the system time with
+    /// `system_weight` if it is "weighted".
+    pub fn from_name(name: &str, system_weight: f64) -> Result<AttributionBasis, String> {
+        match name {
+            "user" => Ok(AttributionBasis::UserTime),
+            "user+system" => Ok(AttributionBasis::UserAndSystemTime),
+            "weighted" if system_weight >= 0.0 => Ok(AttributionBasis::Weighted { system_weight }),
+            "weighted" => Err(format!(
+                "System time weight should not be negative, got {system_weight}"
+            )),
+            _ => Err(format!(
+                "Unknown attribution basis {name}, should be user, user+system or weighted"
+            )),
+        }
+    }
+}
+
+/// What identifies processes in their labels, besides their pid. The full
+/// command line can be long and makes many distinct series, the other
+/// identities keep the number of series lower.
+#[derive(Debug, Clone)]
+pub enum ProcessIdentity {
+    /// Short name (comm) as exe, and the command line, truncated to
+    /// max_length characters if set, as cmdline. The default.
+    Cmdline { max_length: Option<usize> },
+    /// Short name (comm) as exe, no cmdline.
+    Comm,
+    /// Path of the executable as exe, no cmdline.
+    Exe,
+    /// First group captured by the regex in the command line (or the whole
+    /// match if it has no group) as exe, no cmdline.
+    Regex(Regex),
+}
+
+impl Default for ProcessIdentity {
+    fn default() -> Self {
+        ProcessIdentity::Cmdline { max_length: None }
+    }
+}
+
+impl ProcessIdentity {
+    /// Returns the identity named `name`, as given to `--process-identity`.
+    /// `max_length` applies to "cmdline", `regex` is needed by "regex".
+    pub fn from_name(
+        name: &str,
+        max_length: Option<usize>,
+        regex: Option<&str>,
+    ) -> Result<ProcessIdentity, String> {
+        match name {
+            "cmdline" => Ok(ProcessIdentity::Cmdline { max_length }),
+            "comm" => Ok(ProcessIdentity::Comm),
+            "exe" => Ok(ProcessIdentity::Exe),
+            "regex" => match regex {
+                Some(regex) => Regex::new(regex)
+                    .map(ProcessIdentity::Regex)
+                    .map_err(|e| format!("Invalid process identity regex {regex}: {e}")),
+                None => Err(String::from(
+                    "A regex is needed to identify processes with a regex",
+                )),
+            },
+            _ => Err(format!(
+                "Unknown process identity {name}, should be cmdline, comm, exe or regex"
+            )),
+        }
+    }
+}
+
+/// Returns the first group captured, or the whole match if there is no group.
+fn extract_identity(captures: &regex::Captures) -> String {
+    captures
+        .get(1)
+        .or_else(|| captures.get(0))
+        .map(|m| m.as_str().to_string())
+        .unwrap_or_default()
+}
+
+/// Exponentially weighted moving average of a measurement, smoothing the
+/// jitter of power gauges measured at short steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ewma {
+    /// Weight of the last sample, between 0 (excluded) and 1 (no smoothing).
+    pub alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ewma {
+    /// Instantiates Ewma, checking that `alpha` is in ]0, 1].
+    pub fn new(alpha: f64) -> Result<Ewma, String> {
+        if alpha > 0.0 && alpha <= 1.0 {
+            Ok(Ewma { alpha, value: None })
+        } else {
+            Err(format!(
+                "Smoothing factor should be greater than 0 and at most 1, got {alpha}"
+            ))
+        }
+    }
+
+    /// Adds a sample and returns the new average. The first sample is taken as is.
+    pub fn update(&mut self, sample: f64) -> f64 {
+        let value = match self.value {
+            Some(previous) => self.alpha * sample + (1.0 - self.alpha) * previous,
+            None => sample,
+        };
+        self.value = Some(value);
+        value
+    }
+
+    /// Returns the current average, if a sample was added.
+    pub fn get(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+/// Lowest and highest power measured since the start, or since the last reset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PowerExtremes {
+    min_microwatts: Option<u64>,
+    max_microwatts: Option<u64>,
+}
+
+impl PowerExtremes {
+    /// Takes a measurement of the power into account, in microwatts.
+    pub fn update(&mut self, microwatts: u64) {
+        self.min_microwatts = Some(
+            self.min_microwatts
+                .map_or(microwatts, |m| m.min(microwatts)),
+        );
+        self.max_microwatts = Some(
+            self.max_microwatts
+                .map_or(microwatts, |m| m.max(microwatts)),
+        );
+    }
+
+    /// Forgets the measurements taken into account so far.
+    pub fn reset(&mut self) {
+        *self = PowerExtremes::default();
+    }
+
+    /// Returns the lowest power measured, in microwatts, if any.
+    pub fn get_min_microwatts(&self) -> Option<u64> {
+        self.min_microwatts
+    }
+
+    /// Returns the highest power measured, in microwatts, if any.
+    pub fn get_max_microwatts(&self) -> Option<u64> {
+        self.max_microwatts
     }
 }
 
@@ -1030,6 +1800,17 @@ impl ProcessRecord {
         }
     }
 
+    /// Returns the CPU time consumed by this process since its creation, as
+    /// counted by `basis`.
+    pub fn attributed_time_jiffies(&self, basis: &AttributionBasis) -> f64 {
+        #[cfg(target_os = "linux")]
+        if let Some(stat) = &self.process.stat {
+            return stat.utime as f64 + stat.stime as f64 * basis.system_weight();
+        }
+        let _ = basis;
+        0.0
+    }
+
     // Returns the total CPU time consumed by this process since its creation
     pub fn total_time_jiffies(&self) -> u64 {
         #[cfg(target_os = "linux")]
@@ -1060,6 +1841,44 @@ impl ProcessRecord {
     }
 }
 
+/// Returns the labels describing a docker container, attached to the metrics
+/// of its processes: its name, image, names and labels, and the docker version.
+#[cfg(feature = "containers")]
+pub fn get_docker_container_labels(
+    container: &Container,
+    docker_version: &str,
+) -> HashMap<String, String> {
+    let mut description = HashMap::new();
+    let mut names = String::from("");
+    for n in &container.Names {
+        debug!("adding container name: {}", &n.trim().replace('/', ""));
+        names.push_str(&n.trim().replace('/', ""));
+    }
+    if let Some(name) = container.Names.first() {
+        description.insert(
+            String::from("container_name"),
+            String::from(name.trim().trim_start_matches('/')),
+        );
+    }
+    description.insert(String::from("container_names"), names);
+    description.insert(String::from("container_image"), container.Image.clone());
+    description.insert(
+        String::from("container_docker_version"),
+        String::from(docker_version),
+    );
+    if let Some(labels) = &container.Labels {
+        for (k, v) in labels {
+            let escape_list = ["-", ".", ":", " "];
+            let mut key = k.clone();
+            for e in escape_list.iter() {
+                key = key.replace(e, "_");
+            }
+            description.insert(format!("container_label_{key}"), v.to_string());
+        }
+    }
+    description
+}
+
 /// Returns a Duration instance with the current timestamp
 pub fn current_system_time_since_epoch() -> Duration {
     SystemTime::now()
@@ -1070,6 +1889,8 @@ pub fn current_system_time_since_epoch() -> Duration {
 #[cfg(all(test, target_os = "linux"))]
 mod tests {
     use super::*;
+    use crate::sensors::units::Unit;
+    use std::fs;
     #[test]
     fn process_records_added() {
         let proc = Process::myself().unwrap();
@@ -1086,6 +1907,111 @@ mod tests {
         assert_eq!(tracker.procs[0].len(), 3);
     }
 
+    #[test]
+    fn process_descendants_found() {
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        // 10 -> 11 -> 12, and 13 whose parent died, reparented to init
+        for (pid, ppid) in [(10, 1), (11, 10), (12, 11), (13, 1)] {
+            let mut process = IProcess::from_linux_process(&proc);
+            process.pid = pid;
+            process.stat.as_mut().unwrap().ppid = ppid;
+            assert!(tracker.add_process_record(process).is_ok());
+        }
+        let mut descendants = tracker.get_descendants_pids(10);
+        descendants.sort_unstable();
+        assert_eq!(descendants, vec![11, 12]);
+        assert_eq!(tracker.get_process_ppid(13), Some(1));
+        assert!(tracker.get_descendants_pids(12).is_empty());
+    }
+
+    #[test]
+    fn process_uid_cached() {
+        let proc = Process::myself().unwrap();
+        let uid = proc.status().unwrap().ruid;
+        let mut tracker = ProcessTracker::new(3);
+        assert!(tracker
+            .add_process_record(IProcess::from_linux_process(&proc))
+            .is_ok());
+        assert_eq!(tracker.get_process_uid(proc.pid), Some(uid));
+        assert_eq!(tracker.get_alive_pids_for_uid(uid), vec![proc.pid]);
+        assert!(tracker.get_alive_pids_for_uid(uid + 1).is_empty());
+        assert_eq!(tracker.get_alive_uids(), vec![uid]);
+    }
+
+    #[test]
+    fn process_labels_cached() {
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        assert!(tracker
+            .add_process_record(IProcess::from_linux_process(&proc))
+            .is_ok());
+        assert!(tracker.get_process_labels(proc.pid).is_none());
+        tracker.cache_process_labels();
+        let labels = tracker.get_process_labels(proc.pid).unwrap();
+        assert_eq!(labels[0], (String::from("pid"), proc.pid.to_string()));
+        assert_eq!(labels[1], (String::from("exe"), proc.stat.comm.clone()));
+    }
+
+    #[test]
+    fn process_identity_labels() {
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        assert!(tracker
+            .add_process_record(IProcess::from_linux_process(&proc))
+            .is_ok());
+        let comm = proc.stat.comm.clone();
+        let labels = |tracker: &ProcessTracker| tracker.get_process_identity_labels(proc.pid);
+
+        tracker.set_process_identity(ProcessIdentity::from_name("cmdline", Some(4), None).unwrap());
+        let identity = labels(&tracker);
+        assert_eq!(identity[0], (String::from("exe"), comm.clone()));
+        assert_eq!(identity[1].1.chars().count(), 4);
+
+        tracker.set_process_identity(ProcessIdentity::from_name("comm", None, None).unwrap());
+        assert_eq!(labels(&tracker), vec![(String::from("exe"), comm.clone())]);
+
+        tracker.set_process_identity(ProcessIdentity::from_name("exe", None, None).unwrap());
+        let exe = proc.exe().unwrap().to_string_lossy().to_string();
+        assert_eq!(labels(&tracker), vec![(String::from("exe"), exe)]);
+
+        // the test binary is named after the crate
+        let identity = ProcessIdentity::from_name("regex", None, Some("(scaphandre)")).unwrap();
+        tracker.set_process_identity(identity);
+        assert_eq!(
+            labels(&tracker),
+            vec![(String::from("exe"), String::from("scaphandre"))]
+        );
+        // no match, the comm is used
+        let identity = ProcessIdentity::from_name("regex", None, Some("^nomatch$")).unwrap();
+        tracker.set_process_identity(identity);
+        assert_eq!(labels(&tracker), vec![(String::from("exe"), comm)]);
+
+        assert!(ProcessIdentity::from_name("regex", None, None).is_err());
+        assert!(ProcessIdentity::from_name("regex", None, Some("(")).is_err());
+        assert!(ProcessIdentity::from_name("cgroup", None, None).is_err());
+    }
+
+    #[test]
+    fn process_allowlist_honored() {
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        assert!(tracker
+            .add_process_record(IProcess::from_linux_process(&proc))
+            .is_ok());
+        tracker.set_pid_allowlist(Some(vec![proc.pid + 1]));
+        assert!(tracker.get_alive_pids().is_empty());
+        assert!(tracker
+            .add_process_record(IProcess::from_linux_process(&proc))
+            .is_ok());
+        assert!(tracker.get_alive_pids().is_empty());
+        tracker.set_pid_allowlist(Some(vec![proc.pid]));
+        assert!(tracker
+            .add_process_record(IProcess::from_linux_process(&proc))
+            .is_ok());
+        assert_eq!(tracker.get_alive_pids(), vec![proc.pid]);
+    }
+
     #[test]
     fn process_records_cleaned() {
         let proc = Process::myself().unwrap();
@@ -1111,6 +2037,174 @@ mod tests {
         assert_eq!(tracker.procs.len(), 1);
         assert_eq!(tracker.procs[0].len(), 3);
     }
+
+    #[cfg(feature = "containers")]
+    #[test]
+    fn docker_container_labels_described() {
+        let container: Container = serde_json::from_str(
+            r#"{"Id": "4c01db0b339c", "Image": "nginx:1.21", "Status": "Up", "Command": "nginx",
+                "Created": 0, "Names": ["/web"], "Ports": [], "SizeRootFs": 0,
+                "Labels": {"com.example.team": "front"}, "HostConfig": {"NetworkMode": "bridge"}}"#,
+        )
+        .unwrap();
+        let labels = get_docker_container_labels(&container, "20.10.7");
+        assert_eq!(labels["container_name"], "web");
+        assert_eq!(labels["container_image"], "nginx:1.21");
+        assert_eq!(labels["container_docker_version"], "20.10.7");
+        assert_eq!(labels["container_label_com_example_team"], "front");
+    }
+
+    #[test]
+    fn process_alive_fraction_clamped() {
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        let start = current_system_time_since_epoch();
+        for secs in [0, 10] {
+            assert!(tracker
+                .add_process_record(IProcess::from_linux_process(&proc))
+                .is_ok());
+            tracker.procs[0][0].timestamp = start + Duration::from_secs(secs);
+        }
+        let pid = proc.pid;
+        let since_boot = Duration::from_secs_f64(
+            tracker.procs[0][0].process.stat.as_ref().unwrap().starttime as f64
+                / procfs::ticks_per_second().unwrap() as f64,
+        );
+        // started 5s after the previous record
+        tracker.boot_time = Some(start + Duration::from_secs(5) - since_boot);
+        assert_eq!(
+            tracker.get_process_start_time(pid),
+            Some(start + Duration::from_secs(5))
+        );
+        assert_eq!(tracker.get_process_age(pid), Some(Duration::from_secs(5)));
+        assert_eq!(tracker.get_alive_fraction(pid), Some(0.5));
+        // started before the previous record
+        tracker.boot_time = Some(start - since_boot);
+        assert_eq!(tracker.get_alive_fraction(pid), Some(1.0));
+        tracker.boot_time = None;
+        assert_eq!(tracker.get_alive_fraction(pid), None);
+    }
+
+    #[test]
+    fn process_energy_accumulated_until_exit() {
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        let start = current_system_time_since_epoch();
+        let power = |secs| {
+            Record::new(
+                start + Duration::from_secs(secs),
+                String::from("2000000"),
+                Unit::MicroWatt,
+            )
+        };
+        for secs in [0, 1] {
+            assert!(tracker
+                .add_process_record(IProcess::from_linux_process(&proc))
+                .is_ok());
+            tracker.procs[0][0].timestamp = start + Duration::from_secs(secs);
+        }
+        let pid = proc.pid;
+        tracker.add_process_energy(pid, &power(1));
+        // the same window isn't counted twice
+        tracker.add_process_energy(pid, &power(1));
+        assert_eq!(tracker.get_process_energy_microjoules(pid), Some(2000000));
+        assert!(tracker
+            .add_process_record(IProcess::from_linux_process(&proc))
+            .is_ok());
+        tracker.procs[0][0].timestamp = start + Duration::from_secs(3);
+        tracker.add_process_energy(pid, &power(3));
+        assert_eq!(tracker.get_process_energy_microjoules(pid), Some(6000000));
+
+        // a process with another start time reusing the pid starts from zero
+        tracker.procs[0][0].process.stat.as_mut().unwrap().starttime += 1;
+        tracker.procs[0][1].timestamp = start + Duration::from_secs(4);
+        tracker.add_process_energy(pid, &power(5));
+        assert_eq!(tracker.get_process_energy_microjoules(pid), Some(2000000));
+
+        tracker.procs.clear();
+        tracker.finalize_exited_processes_energy(start + Duration::from_secs(5));
+        assert_eq!(tracker.get_process_energy_microjoules(pid), Some(2000000));
+        tracker.finalize_exited_processes_energy(start + Duration::from_secs(3600));
+        assert_eq!(tracker.get_process_energy_microjoules(pid), None);
+    }
+
+    #[test]
+    fn stale_processes_dropped() {
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        for pid in [10, 11] {
+            let mut process = IProcess::from_linux_process(&proc);
+            process.pid = pid;
+            assert!(tracker.add_process_record(process).is_ok());
+        }
+        // 10 died, it was last seen by a refresh 5 seconds ago
+        tracker.procs[0][0].timestamp -= Duration::from_secs(5);
+        tracker.start_refresh();
+        tracker.set_stale_process_grace(Some(Duration::from_secs(10)));
+        tracker.clean_terminated_process_records_vectors();
+        assert_eq!(tracker.get_alive_pids().len(), 2);
+        tracker.drop_stale_processes(Duration::from_secs(2));
+        assert_eq!(tracker.get_alive_pids(), vec![11]);
+        assert_eq!(tracker.get_process_uid(10), None);
+    }
+
+    #[test]
+    fn processes_restricted_to_pid_namespace() {
+        let root = std::env::temp_dir().join(format!("scaph-pidns-{}", std::process::id()));
+        // 100 is the init process of a container, 101 another of its
+        // processes, and 200 a process of the host
+        for (pid, namespace, nspid) in [
+            (100, "pid:[4026532281]", "100\t1"),
+            (101, "pid:[4026532281]", "101\t7"),
+            (200, "pid:[4026531836]", "200"),
+        ] {
+            fs::create_dir_all(root.join(format!("{pid}/ns"))).unwrap();
+            std::os::unix::fs::symlink(namespace, root.join(format!("{pid}/ns/pid"))).unwrap();
+            fs::write(
+                root.join(format!("{pid}/status")),
+                format!("Name:\tsh\nNSpid:\t{nspid}\n"),
+            )
+            .unwrap();
+        }
+        let proc = Process::myself().unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        tracker.set_procfs_root(root.to_str().unwrap());
+        assert!(tracker.set_pid_namespace(300).is_err());
+        tracker.set_pid_namespace(100).unwrap();
+        for pid in [100, 101, 200] {
+            let mut process = IProcess::from_linux_process(&proc);
+            process.pid = pid;
+            assert!(tracker.add_process_record(process).is_ok());
+        }
+        let mut pids = tracker.get_alive_pids();
+        pids.sort_unstable();
+        assert_eq!(pids, vec![100, 101]);
+        assert_eq!(tracker.get_namespace_pid(100), Some(1));
+        assert_eq!(tracker.get_namespace_pid(101), Some(7));
+        assert_eq!(tracker.get_namespace_pid(200), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn processes_read_from_procfs_snapshot() {
+        let root = std::env::temp_dir().join(format!("scaph-procfs-{}", std::process::id()));
+        let pid = std::process::id();
+        fs::create_dir_all(root.join(pid.to_string())).unwrap();
+        fs::copy("/proc/self/stat", root.join(format!("{pid}/stat"))).unwrap();
+        fs::write(
+            root.join("stat"),
+            "cpu  10 0 5 100 0 0 0 0 0 0\ncpu0 10 0 5 100 0 0 0 0 0 0\nctxt 42\nbtime 1600000000\nprocesses 7\n",
+        )
+        .unwrap();
+        let mut tracker = ProcessTracker::new(3);
+        tracker.set_procfs_root(root.to_str().unwrap());
+        assert_eq!(tracker.boot_time, Some(Duration::from_secs(1600000000)));
+        assert_eq!(tracker.read_kernel_stats().unwrap().ctxt, 42);
+        let procs = tracker.read_processes().unwrap();
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].pid, pid as i32);
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
 
 //  Copyright 2020 The scaphandre authors.